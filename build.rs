@@ -1,7 +1,11 @@
 use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    prost_build::compile_protos(&["proto/messages.proto"], &["proto/"])?;
+    prost_build::Config::new()
+        // Lets tools print messages as JSON
+        .type_attribute(".", "#[derive(serde::Serialize)]")
+        .type_attribute(".", "#[serde(rename_all = \"snake_case\")]")
+        .compile_protos(&["proto/messages.proto"], &["proto/"])?;
 
     Ok(())
 }
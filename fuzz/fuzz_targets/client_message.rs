@@ -0,0 +1,16 @@
+#![no_main]
+
+use embedded_recruitment_task::message::ClientMessage;
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = ClientMessage::decode(data) else {
+        return;
+    };
+
+    // Anything the server accepts survives a round trip unchanged.
+    let encoded = message.encode_to_vec();
+    let decoded = ClientMessage::decode(encoded.as_slice()).expect("re-encoded message decodes");
+    assert_eq!(decoded, message);
+});
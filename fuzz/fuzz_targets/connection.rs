@@ -0,0 +1,74 @@
+#![no_main]
+
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::server::Server;
+use libfuzzer_sys::fuzz_target;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+
+// Once the input has been sent, the server must close the connection within this long.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Builder::new_current_thread().enable_all().build().unwrap())
+}
+
+// A server with file transfer, firmware updates and persistence on, each in a temporary
+// directory kept for the whole run.
+fn start() -> (TempDir, Server) {
+    let dir = tempfile::tempdir().unwrap();
+    let firmware_dir = dir.path().join("firmware");
+    fs::create_dir(&firmware_dir).unwrap();
+    fs::write(firmware_dir.join("manifest.toml"), "images = []\n").unwrap();
+    let config = ServerConfig {
+        address: "127.0.0.1:0".to_string(),
+        storage_root: Some(dir.path().join("files")),
+        firmware_dir: Some(firmware_dir),
+        state_dir: Some(dir.path().join("state")),
+        ..ServerConfig::default()
+    };
+    let server = runtime().block_on(Server::with_config(config)).unwrap();
+    (dir, server)
+}
+
+fn server() -> SocketAddr {
+    static SERVER: OnceLock<(TempDir, Server)> = OnceLock::new();
+    static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+    *ADDR.get_or_init(|| {
+        let (_, server) = SERVER.get_or_init(start);
+        runtime().spawn(server.run());
+        server.local_addr().unwrap()
+    })
+}
+
+// Sends the input over a connection to a running server, so it goes through everything the
+// server does with bytes it reads: frame decoding, connection-level messages like Hello,
+// Subscribe or JoinRoom, and every handler the server registers. A panic in the server
+// aborts the run, and a connection the server doesn't close after the input is a hang.
+fuzz_target!(|data: &[u8]| {
+    let addr = server();
+    runtime().block_on(async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        // Replies are read as the input is written, so neither end stalls on a full buffer.
+        let replies = tokio::spawn(async move {
+            let mut replies = Vec::new();
+            // The server may reset a connection it gave up on.
+            let _ = reader.read_to_end(&mut replies).await;
+        });
+        // Nor can it be written to after closing the connection.
+        let _ = writer.write_all(data).await;
+        let _ = writer.shutdown().await;
+        tokio::time::timeout(CLOSE_TIMEOUT, replies)
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+    });
+});
@@ -0,0 +1,98 @@
+#![no_main]
+
+use embedded_recruitment_task::codec::{encode_frame, FrameDecoder, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::message::{client_message, server_message, ClientMessage};
+use embedded_recruitment_task::router::{Context, Router, MAX_BATCH_REQUESTS};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+// Handlers answer immediately; anything slower than this is treated as a hang.
+const HANDLER_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Builder::new_current_thread().enable_time().build().unwrap())
+}
+
+fn router() -> &'static Router {
+    static ROUTER: OnceLock<Router> = OnceLock::new();
+    ROUTER.get_or_init(Router::default)
+}
+
+// Check that `reply` answers `request`. Only requests that may fail can get an Error.
+fn check(
+    request: Option<client_message::Message>,
+    reply: Option<server_message::Message>,
+    may_fail: bool,
+) {
+    match (request, reply) {
+        (
+            Some(client_message::Message::EchoMessage(echo)),
+            Some(server_message::Message::EchoMessage(reply)),
+        ) => assert_eq!(echo, reply),
+        (
+            Some(client_message::Message::AddRequest(add)),
+            Some(server_message::Message::AddResponse(sum)),
+        ) => assert_eq!(sum.result, add.a.wrapping_add(add.b)),
+        (
+            Some(client_message::Message::BatchRequest(batch)),
+            Some(server_message::Message::BatchResponse(response)),
+        ) => {
+            assert_eq!(batch.requests.len(), response.responses.len());
+            // Requests in a batch fail when they can't be batched, or one before them did
+            for (request, reply) in batch.requests.into_iter().zip(response.responses) {
+                assert_eq!(request.request_id, reply.request_id);
+                check(request.message, reply.message, true);
+            }
+        }
+        (
+            Some(client_message::Message::BatchRequest(batch)),
+            Some(server_message::Message::Error(_)),
+        ) if batch.requests.len() > MAX_BATCH_REQUESTS => {}
+        (Some(_), Some(server_message::Message::Error(_))) => {
+            assert!(may_fail, "only requests with a deadline may fail")
+        }
+        (request, reply) => panic!("{:?} was answered with {:?}", request, reply),
+    }
+}
+
+// Runs the input through the same path as bytes arriving on a connection: frame decoding,
+// ClientMessage decoding and the default router.
+fuzz_target!(|data: &[u8]| {
+    let ctx = Context::new(SocketAddr::from(([127, 0, 0, 1], 9000)));
+    let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
+    decoder.extend(data);
+
+    runtime().block_on(async {
+        while let Ok(Some(frame)) = decoder.decode() {
+            let Ok(request) = ClientMessage::decode(frame.as_slice()) else {
+                continue;
+            };
+
+            // Deadlines count from now, as if the frame had just been read.
+            let deadline = (request.deadline_ms > 0).then(|| {
+                tokio::time::Instant::now() + Duration::from_millis(request.deadline_ms.into())
+            });
+            let reply = tokio::time::timeout(
+                HANDLER_TIMEOUT,
+                router().dispatch(ctx.clone().with_deadline(deadline), request.clone()),
+            )
+            .await
+            .expect("handler did not finish");
+            let Some(reply) = reply else {
+                continue;
+            };
+
+            // A reply always fits in a frame the client accepts.
+            let mut encoded = Vec::new();
+            encode_frame(&reply.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut encoded)
+                .expect("reply exceeds the frame limit");
+
+            check(request.message, reply.message, deadline.is_some());
+        }
+    });
+});
@@ -0,0 +1,46 @@
+#![no_main]
+
+use embedded_recruitment_task::codec::{encode_frame, FrameDecoder, HEADER_LEN};
+use libfuzzer_sys::fuzz_target;
+
+// Small enough that the fuzzer regularly runs into the limit.
+const MAX_FRAME_LEN: usize = 1024;
+
+// The first byte picks the read size, so the same stream gets split at many different
+// boundaries the way a socket would deliver it.
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_size, stream)) = data.split_first() else {
+        return;
+    };
+    let read_size = usize::from(read_size).max(1);
+
+    let mut decoder = FrameDecoder::new(MAX_FRAME_LEN);
+    let mut consumed = 0;
+
+    for read in stream.chunks(read_size) {
+        decoder.extend(read);
+
+        loop {
+            match decoder.decode() {
+                Ok(Some(frame)) => {
+                    assert!(frame.len() <= MAX_FRAME_LEN);
+
+                    // Re-encoding a frame reproduces exactly the bytes it was read from.
+                    let mut encoded = Vec::new();
+                    encode_frame(&frame, MAX_FRAME_LEN, &mut encoded).unwrap();
+                    assert_eq!(
+                        &stream[consumed..consumed + encoded.len()],
+                        encoded.as_slice()
+                    );
+                    consumed += encoded.len();
+                }
+                Ok(None) => break,
+                // The server closes the connection at this point.
+                Err(_) => return,
+            }
+        }
+
+        // Waiting for more input never holds more than one incomplete frame.
+        assert!(decoder.buffered() < HEADER_LEN + MAX_FRAME_LEN);
+    }
+});
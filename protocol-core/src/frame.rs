@@ -0,0 +1,211 @@
+// Framing shared with the std server: a 4-byte big-endian payload length followed by the
+// payload. The encoder and decoder only move bytes between buffers, so they work over a UART,
+// a TCP socket or anything else that carries a byte stream.
+use crate::message::Encode;
+use crate::wire::EncodeError;
+use core::fmt;
+
+pub const HEADER_LEN: usize = 4;
+
+// The server's default upper bound on a frame payload.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+pub fn encode_header(len: u32) -> [u8; HEADER_LEN] {
+    len.to_be_bytes()
+}
+
+pub fn decode_header(header: [u8; HEADER_LEN]) -> usize {
+    u32::from_be_bytes(header) as usize
+}
+
+// Write `payload` to the start of `out` as a single frame, returning the frame's length.
+pub fn encode_frame(payload: &[u8], out: &mut [u8]) -> Result<usize, EncodeError> {
+    let len = HEADER_LEN + payload.len();
+    let frame = out
+        .get_mut(..len)
+        .ok_or(EncodeError::BufferTooSmall { needed: len })?;
+    frame[..HEADER_LEN].copy_from_slice(&encode_header(payload.len() as u32));
+    frame[HEADER_LEN..].copy_from_slice(payload);
+    Ok(len)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    // The peer announced a payload larger than the decoder's buffer.
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Header { have: usize },
+    Payload { len: usize },
+    // Discarding the rest of an oversized frame.
+    Skip { remaining: usize },
+    // A payload was returned and is dropped on the next call.
+    Complete,
+}
+
+// Incremental frame decoder holding at most one payload of up to `N` bytes.
+//
+// Unlike the server's decoder, an oversized frame doesn't end the stream: it is reported once
+// and its payload skipped as it arrives, so a device isn't cut off by a reply it has no room
+// for.
+#[derive(Debug)]
+pub struct FrameDecoder<const N: usize> {
+    header: [u8; HEADER_LEN],
+    payload: heapless::Vec<u8, N>,
+    state: State,
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            header: [0; HEADER_LEN],
+            payload: heapless::Vec::new(),
+            state: State::Header { have: 0 },
+        }
+    }
+
+    // Consume bytes from the front of `input` until a frame is complete, returning its
+    // payload, or until `input` is empty, returning `None`. Call again with the rest of
+    // `input` to continue.
+    pub fn decode<'a>(&'a mut self, input: &mut &[u8]) -> Result<Option<&'a [u8]>, FrameError> {
+        loop {
+            match self.state {
+                State::Complete => {
+                    self.payload.clear();
+                    self.state = State::Header { have: 0 };
+                }
+                State::Header { have } => {
+                    let n = take(input, HEADER_LEN - have, |bytes| {
+                        self.header[have..have + bytes.len()].copy_from_slice(bytes)
+                    });
+                    if have + n < HEADER_LEN {
+                        self.state = State::Header { have: have + n };
+                        return Ok(None);
+                    }
+                    let len = decode_header(self.header);
+                    if len > N {
+                        self.state = State::Skip { remaining: len };
+                        return Err(FrameError::TooLarge { len, max: N });
+                    }
+                    self.state = State::Payload { len };
+                }
+                State::Payload { len } => {
+                    take(input, len - self.payload.len(), |bytes| {
+                        // Fits, as `len` was checked against the capacity
+                        let _ = self.payload.extend_from_slice(bytes);
+                    });
+                    if self.payload.len() < len {
+                        return Ok(None);
+                    }
+                    self.state = State::Complete;
+                    return Ok(Some(&self.payload));
+                }
+                State::Skip { remaining } => {
+                    let n = take(input, remaining, |_| ());
+                    if n < remaining {
+                        self.state = State::Skip {
+                            remaining: remaining - n,
+                        };
+                        return Ok(None);
+                    }
+                    self.state = State::Header { have: 0 };
+                }
+            }
+        }
+    }
+
+    // Whether the decoder is between frames, rather than part way through one.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Header { have: 0 } | State::Complete)
+    }
+}
+
+// Pass up to `max` bytes from the front of `input` to `f`, returning how many.
+fn take(input: &mut &[u8], max: usize, f: impl FnOnce(&[u8])) -> usize {
+    let (taken, rest) = input.split_at(max.min(input.len()));
+    f(taken);
+    *input = rest;
+    taken.len()
+}
+
+// Queue of outgoing frames in a fixed buffer of `N` bytes, drained by the transport at
+// whatever pace it accepts bytes.
+#[derive(Debug)]
+pub struct FrameEncoder<const N: usize> {
+    buffer: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> Default for FrameEncoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameEncoder<N> {
+    pub const fn new() -> Self {
+        FrameEncoder {
+            buffer: heapless::Vec::new(),
+        }
+    }
+
+    // Queue `payload` as a frame. Fails without queueing anything if it doesn't fit in the
+    // space left.
+    pub fn push(&mut self, payload: &[u8]) -> Result<(), EncodeError> {
+        self.push_with(payload.len(), |out| out.copy_from_slice(payload))
+    }
+
+    // Queue `message` as a frame, encoding it in place.
+    pub fn push_message(&mut self, message: &impl Encode) -> Result<(), EncodeError> {
+        self.push_with(message.encoded_len(), |out| {
+            // Can't fail, as `out` is exactly the encoded length
+            let _ = message.encode(out);
+        })
+    }
+
+    fn push_with(&mut self, len: usize, write: impl FnOnce(&mut [u8])) -> Result<(), EncodeError> {
+        let start = self.buffer.len();
+        let end = start + HEADER_LEN + len;
+        if end > N {
+            return Err(EncodeError::BufferTooSmall { needed: end });
+        }
+        // Within capacity, as checked above
+        let _ = self.buffer.resize(end, 0);
+        self.buffer[start..start + HEADER_LEN].copy_from_slice(&encode_header(len as u32));
+        write(&mut self.buffer[start + HEADER_LEN..]);
+        Ok(())
+    }
+
+    // Bytes waiting to be sent.
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    // Drop the first `n` pending bytes once the transport has taken them.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.buffer.len());
+        self.buffer.copy_within(n.., 0);
+        self.buffer.truncate(self.buffer.len() - n);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
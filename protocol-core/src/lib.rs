@@ -0,0 +1,16 @@
+// The server's wire protocol for clients that can't link std or tokio, such as MCU firmware.
+//
+// Nothing here allocates or does I/O: messages hold their text in fixed-capacity buffers,
+// encode into caller-provided slices, and frames are decoded from whatever bytes the
+// transport hands over. Only the messages such clients need are covered; see `message`.
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod frame;
+pub mod message;
+pub mod serial;
+mod wire;
+
+pub use wire::{DecodeError, EncodeError};
@@ -0,0 +1,401 @@
+// The messages a device needs to talk to the server, encoded exactly as the server's prost
+// types are. Names follow the generated code, so firmware reads like the std client.
+//
+// Anything else the server might send, such as a Chunk, decodes with `message: None`, and
+// is left for the caller to ignore.
+//
+// These types are written by hand, not generated from proto/messages.proto, so that they fit
+// in fixed-size buffers. Every change to the messages or error codes below in the .proto must
+// be mirrored here by hand, and checked against prost in tests/interop_test.rs.
+use crate::wire::{
+    int32_len, message_len, string_len, uint64_len, DecodeError, EncodeError, Reader, Value, Writer,
+};
+
+// Capacity of every string field. Longer strings fail to decode with `DecodeError::TooLong`.
+pub const MAX_TEXT_LEN: usize = 128;
+
+pub type Text = heapless::String<MAX_TEXT_LEN>;
+
+pub trait Encode {
+    fn encoded_len(&self) -> usize;
+
+    // Write the message to the start of `out`, returning its length.
+    fn encode(&self, out: &mut [u8]) -> Result<usize, EncodeError>;
+
+    // Write the message to the start of `out` as a frame, returning the frame's length.
+    fn encode_frame(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let header = crate::frame::HEADER_LEN;
+        let needed = header + self.encoded_len();
+        if out.len() < needed {
+            return Err(EncodeError::BufferTooSmall { needed });
+        }
+        out[..header].copy_from_slice(&crate::frame::encode_header((needed - header) as u32));
+        self.encode(&mut out[header..needed])
+            .map(|len| header + len)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn encode_to_vec(&self) -> alloc::vec::Vec<u8> {
+        let mut out = alloc::vec![0; self.encoded_len()];
+        // Can't fail, as `out` is exactly the encoded length
+        let _ = self.encode(&mut out);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(payload: &[u8]) -> Result<Self, DecodeError>;
+}
+
+// The fields of a message embedded in an envelope.
+trait Fields {
+    fn fields_len(&self) -> usize;
+    fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError>;
+    // Unknown fields are ignored.
+    fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError>;
+}
+
+fn write_message(
+    writer: &mut Writer<'_>,
+    field: u32,
+    message: &dyn Fields,
+) -> Result<(), EncodeError> {
+    writer.message(field, message.fields_len())?;
+    message.write_fields(writer)
+}
+
+fn read_message<M: Fields + Default>(value: &Value<'_>) -> Result<M, DecodeError> {
+    let mut message = M::default();
+    let mut reader = Reader::new(value.bytes()?);
+    while let Some((field, value)) = reader.field()? {
+        message.read_field(field, &value)?;
+    }
+    Ok(message)
+}
+
+fn encode_with(
+    len: usize,
+    out: &mut [u8],
+    write: impl FnOnce(&mut Writer<'_>) -> Result<(), EncodeError>,
+) -> Result<usize, EncodeError> {
+    if out.len() < len {
+        return Err(EncodeError::BufferTooSmall { needed: len });
+    }
+    let mut writer = Writer::new(out);
+    write(&mut writer)?;
+    Ok(writer.position())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EchoMessage {
+    pub content: Text,
+}
+
+impl Fields for EchoMessage {
+    fn fields_len(&self) -> usize {
+        string_len(1, &self.content)
+    }
+
+    fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError> {
+        writer.string(1, &self.content)
+    }
+
+    fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError> {
+        if field == 1 {
+            self.content = value.string()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AddRequest {
+    pub a: i32,
+    pub b: i32,
+}
+
+impl Fields for AddRequest {
+    fn fields_len(&self) -> usize {
+        int32_len(1, self.a) + int32_len(2, self.b)
+    }
+
+    fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError> {
+        writer.int32(1, self.a)?;
+        writer.int32(2, self.b)
+    }
+
+    fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError> {
+        match field {
+            1 => self.a = value.varint()? as i32,
+            2 => self.b = value.varint()? as i32,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AddResponse {
+    pub result: i32,
+}
+
+impl Fields for AddResponse {
+    fn fields_len(&self) -> usize {
+        int32_len(1, self.result)
+    }
+
+    fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError> {
+        writer.int32(1, self.result)
+    }
+
+    fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError> {
+        if field == 1 {
+            self.result = value.varint()? as i32;
+        }
+        Ok(())
+    }
+}
+
+// Cancel, Ping and Pong each carry a single uint64 in field 1.
+macro_rules! uint64_message {
+    ($name:ident, $field:ident) => {
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $name {
+            pub $field: u64,
+        }
+
+        impl Fields for $name {
+            fn fields_len(&self) -> usize {
+                uint64_len(1, self.$field)
+            }
+
+            fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError> {
+                writer.uint64(1, self.$field)
+            }
+
+            fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError> {
+                if field == 1 {
+                    self.$field = value.varint()?;
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+uint64_message!(Cancel, request_id);
+uint64_message!(Ping, timestamp_us);
+uint64_message!(Pong, timestamp_us);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Error {
+    pub code: i32,
+    pub message: Text,
+}
+
+impl Error {
+    // The code, or `Unspecified` for one this crate doesn't know.
+    pub fn code(&self) -> error::Code {
+        error::Code::from_i32(self.code)
+    }
+}
+
+impl Fields for Error {
+    fn fields_len(&self) -> usize {
+        int32_len(1, self.code) + string_len(2, &self.message)
+    }
+
+    fn write_fields(&self, writer: &mut Writer<'_>) -> Result<(), EncodeError> {
+        writer.int32(1, self.code)?;
+        writer.string(2, &self.message)
+    }
+
+    fn read_field(&mut self, field: u32, value: &Value<'_>) -> Result<(), DecodeError> {
+        match field {
+            1 => self.code = value.varint()? as i32,
+            2 => self.message = value.string()?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+pub mod error {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(i32)]
+    pub enum Code {
+        Unspecified = 0,
+        DeadlineExceeded = 1,
+        ResourceExhausted = 2,
+        NotFound = 3,
+        InvalidArgument = 4,
+        DataLoss = 5,
+        Aborted = 6,
+    }
+
+    impl Code {
+        pub fn from_i32(code: i32) -> Self {
+            match code {
+                1 => Code::DeadlineExceeded,
+                2 => Code::ResourceExhausted,
+                3 => Code::NotFound,
+                4 => Code::InvalidArgument,
+                5 => Code::DataLoss,
+                6 => Code::Aborted,
+                _ => Code::Unspecified,
+            }
+        }
+    }
+}
+
+const REQUEST_ID: u32 = 14;
+const DEADLINE_MS: u32 = 15;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientMessage {
+    pub message: Option<client_message::Message>,
+    // See the field of the same name in messages.proto.
+    pub request_id: u64,
+    pub deadline_ms: u32,
+}
+
+pub mod client_message {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Message {
+        EchoMessage(super::EchoMessage),
+        AddRequest(super::AddRequest),
+        Cancel(super::Cancel),
+        Ping(super::Ping),
+        Pong(super::Pong),
+    }
+}
+
+impl client_message::Message {
+    fn field(&self) -> (u32, &dyn Fields) {
+        match self {
+            client_message::Message::EchoMessage(message) => (1, message),
+            client_message::Message::AddRequest(message) => (2, message),
+            client_message::Message::Cancel(message) => (3, message),
+            client_message::Message::Ping(message) => (4, message),
+            client_message::Message::Pong(message) => (5, message),
+        }
+    }
+}
+
+impl Encode for ClientMessage {
+    fn encoded_len(&self) -> usize {
+        let message = self.message.as_ref().map_or(0, |message| {
+            let (field, message) = message.field();
+            message_len(field, message.fields_len())
+        });
+        message
+            + uint64_len(REQUEST_ID, self.request_id)
+            + uint64_len(DEADLINE_MS, self.deadline_ms.into())
+    }
+
+    fn encode(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_with(self.encoded_len(), out, |writer| {
+            if let Some(message) = &self.message {
+                let (field, message) = message.field();
+                write_message(writer, field, message)?;
+            }
+            writer.uint64(REQUEST_ID, self.request_id)?;
+            writer.uint64(DEADLINE_MS, self.deadline_ms.into())
+        })
+    }
+}
+
+impl Decode for ClientMessage {
+    fn decode(payload: &[u8]) -> Result<Self, DecodeError> {
+        use client_message::Message;
+
+        let mut decoded = ClientMessage::default();
+        let mut reader = Reader::new(payload);
+        while let Some((field, value)) = reader.field()? {
+            match field {
+                1 => decoded.message = Some(Message::EchoMessage(read_message(&value)?)),
+                2 => decoded.message = Some(Message::AddRequest(read_message(&value)?)),
+                3 => decoded.message = Some(Message::Cancel(read_message(&value)?)),
+                4 => decoded.message = Some(Message::Ping(read_message(&value)?)),
+                5 => decoded.message = Some(Message::Pong(read_message(&value)?)),
+                REQUEST_ID => decoded.request_id = value.varint()?,
+                DEADLINE_MS => decoded.deadline_ms = value.varint()? as u32,
+                6..=12 | 16.. => decoded.message = None,
+                _ => {}
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerMessage {
+    pub message: Option<server_message::Message>,
+    pub request_id: u64,
+}
+
+pub mod server_message {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Message {
+        EchoMessage(super::EchoMessage),
+        AddResponse(super::AddResponse),
+        Ping(super::Ping),
+        Pong(super::Pong),
+        Error(super::Error),
+    }
+}
+
+impl server_message::Message {
+    fn field(&self) -> (u32, &dyn Fields) {
+        match self {
+            server_message::Message::EchoMessage(message) => (1, message),
+            server_message::Message::AddResponse(message) => (2, message),
+            server_message::Message::Ping(message) => (3, message),
+            server_message::Message::Pong(message) => (4, message),
+            server_message::Message::Error(message) => (15, message),
+        }
+    }
+}
+
+impl Encode for ServerMessage {
+    fn encoded_len(&self) -> usize {
+        let message = self.message.as_ref().map_or(0, |message| {
+            let (field, message) = message.field();
+            message_len(field, message.fields_len())
+        });
+        message + uint64_len(REQUEST_ID, self.request_id)
+    }
+
+    fn encode(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        encode_with(self.encoded_len(), out, |writer| {
+            if let Some(message) = &self.message {
+                let (field, message) = message.field();
+                write_message(writer, field, message)?;
+            }
+            writer.uint64(REQUEST_ID, self.request_id)
+        })
+    }
+}
+
+impl Decode for ServerMessage {
+    fn decode(payload: &[u8]) -> Result<Self, DecodeError> {
+        use server_message::Message;
+
+        let mut decoded = ServerMessage::default();
+        let mut reader = Reader::new(payload);
+        while let Some((field, value)) = reader.field()? {
+            match field {
+                1 => decoded.message = Some(Message::EchoMessage(read_message(&value)?)),
+                2 => decoded.message = Some(Message::AddResponse(read_message(&value)?)),
+                3 => decoded.message = Some(Message::Ping(read_message(&value)?)),
+                4 => decoded.message = Some(Message::Pong(read_message(&value)?)),
+                15 => decoded.message = Some(Message::Error(read_message(&value)?)),
+                REQUEST_ID => decoded.request_id = value.varint()?,
+                // A message this crate doesn't know still replaces any before it
+                5..=13 => decoded.message = None,
+                _ => {}
+            }
+        }
+        Ok(decoded)
+    }
+}
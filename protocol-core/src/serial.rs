@@ -0,0 +1,203 @@
+// Framing for links that can drop or corrupt bytes, like a UART. Each packet is the payload
+// followed by its CRC-16 (big-endian), COBS-encoded so that it contains no zero bytes, then
+// a zero delimiter. A receiver that misses the start of a packet or gets a corrupted one
+// throws it away at the next delimiter and carries on with the packet after it.
+use crate::wire::EncodeError;
+use core::fmt;
+
+pub const DELIMITER: u8 = 0;
+
+pub const CRC_LEN: usize = 2;
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xffff, no reflection.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// The most bytes a packet with `payload_len` bytes of payload can take on the wire,
+// delimiter included.
+pub const fn max_encoded_len(payload_len: usize) -> usize {
+    let len = payload_len + CRC_LEN;
+    len + len / 254 + 1 + 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    // More bytes arrived without a delimiter than the decoder has room for.
+    TooLarge { max: usize },
+    // The bytes between two delimiters aren't valid COBS.
+    Corrupt,
+    // The packet decoded but its CRC doesn't match.
+    BadCrc,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::TooLarge { max } => {
+                write!(f, "packet exceeds the {} byte buffer", max)
+            }
+            PacketError::Corrupt => f.write_str("packet is not valid COBS"),
+            PacketError::BadCrc => f.write_str("packet failed its CRC check"),
+        }
+    }
+}
+
+// Write `payload` to the start of `out` as a packet, delimiter included, returning its
+// length. `out` must have room for `max_encoded_len(payload.len())` bytes.
+pub fn encode_packet(payload: &[u8], out: &mut [u8]) -> Result<usize, EncodeError> {
+    let needed = max_encoded_len(payload.len());
+    if out.len() < needed {
+        return Err(EncodeError::BufferTooSmall { needed });
+    }
+
+    let mut cobs = CobsWriter {
+        out: &mut *out,
+        code_pos: 0,
+        pos: 1,
+        code: 1,
+    };
+    for &byte in payload.iter().chain(&crc16(payload).to_be_bytes()) {
+        cobs.push(byte);
+    }
+    let len = cobs.finish();
+    out[len] = DELIMITER;
+    Ok(len + 1)
+}
+
+// COBS encoder: every run of up to 254 non-zero bytes is preceded by a code byte giving its
+// length plus one, and a code below 0xff stands for a zero after the run.
+struct CobsWriter<'a> {
+    out: &'a mut [u8],
+    code_pos: usize,
+    pos: usize,
+    code: u8,
+}
+
+impl CobsWriter<'_> {
+    fn push(&mut self, byte: u8) {
+        if byte != 0 {
+            self.out[self.pos] = byte;
+            self.pos += 1;
+            self.code += 1;
+        }
+        if byte == 0 || self.code == 0xff {
+            self.out[self.code_pos] = self.code;
+            self.code_pos = self.pos;
+            self.pos += 1;
+            self.code = 1;
+        }
+    }
+
+    fn finish(self) -> usize {
+        self.out[self.code_pos] = self.code;
+        self.pos
+    }
+}
+
+// Decode the bytes of one packet, without its delimiter, in place, and check its CRC.
+// Returns the payload's length; the payload is left at the start of `packet`.
+pub fn decode_packet(packet: &mut [u8]) -> Result<usize, PacketError> {
+    let (mut read, mut write) = (0, 0);
+    while read < packet.len() {
+        let code = usize::from(packet[read]);
+        let end = read + code;
+        if code == 0 || end > packet.len() {
+            return Err(PacketError::Corrupt);
+        }
+        packet.copy_within(read + 1..end, write);
+        write += code - 1;
+        read = end;
+        if code < 0xff && read < packet.len() {
+            packet[write] = 0;
+            write += 1;
+        }
+    }
+
+    let len = write.checked_sub(CRC_LEN).ok_or(PacketError::Corrupt)?;
+    let crc = u16::from_be_bytes([packet[len], packet[len + 1]]);
+    if crc != crc16(&packet[..len]) {
+        return Err(PacketError::BadCrc);
+    }
+    Ok(len)
+}
+
+// Incremental packet decoder holding at most `N` encoded bytes. Payloads of up to `len`
+// bytes fit when `N` is at least `max_encoded_len(len) - 1`.
+//
+// Errors are only reported; the decoder has already dropped the bad packet and is ready for
+// the next one.
+#[derive(Debug)]
+pub struct PacketDecoder<const N: usize> {
+    buffer: heapless::Vec<u8, N>,
+    // The last call returned a payload from `buffer`, dropped on the next call.
+    complete: bool,
+    // Skipping to the next delimiter after an overflow.
+    discarding: bool,
+}
+
+impl<const N: usize> Default for PacketDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PacketDecoder<N> {
+    pub const fn new() -> Self {
+        PacketDecoder {
+            buffer: heapless::Vec::new(),
+            complete: false,
+            discarding: false,
+        }
+    }
+
+    // Consume bytes from the front of `input` until a packet is complete, returning its
+    // payload, or until `input` is empty, returning `None`. Call again with the rest of
+    // `input` to continue.
+    pub fn decode<'a>(&'a mut self, input: &mut &[u8]) -> Result<Option<&'a [u8]>, PacketError> {
+        if self.complete {
+            self.complete = false;
+            self.buffer.clear();
+        }
+
+        while let Some((&byte, rest)) = input.split_first() {
+            *input = rest;
+            if byte != DELIMITER {
+                if !self.discarding && self.buffer.push(byte).is_err() {
+                    self.buffer.clear();
+                    self.discarding = true;
+                    return Err(PacketError::TooLarge { max: N });
+                }
+                continue;
+            }
+
+            // Consecutive delimiters are empty packets, which senders may use to flush noise.
+            if self.discarding || self.buffer.is_empty() {
+                self.discarding = false;
+                continue;
+            }
+            return match decode_packet(&mut self.buffer) {
+                Ok(len) => {
+                    self.complete = true;
+                    Ok(Some(&self.buffer[..len]))
+                }
+                Err(e) => {
+                    self.buffer.clear();
+                    Err(e)
+                }
+            };
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,257 @@
+// Just enough of the protobuf wire format for the messages in `message`.
+use core::fmt;
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LEN: u8 = 2;
+const FIXED32: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    // The output buffer can't hold the encoding, which needs this many bytes.
+    BufferTooSmall { needed: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { needed } => {
+                write!(f, "encoding needs a {} byte buffer", needed)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    // The input ended in the middle of a field.
+    Truncated,
+    // A varint ran past 10 bytes.
+    InvalidVarint,
+    // A field used a wire type this decoder doesn't know, or the wrong one for its number.
+    InvalidWireType(u8),
+    InvalidUtf8,
+    // A string is longer than the buffer that would hold it.
+    TooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => f.write_str("message is truncated"),
+            DecodeError::InvalidVarint => f.write_str("invalid varint"),
+            DecodeError::InvalidWireType(wire_type) => write!(f, "invalid wire type {}", wire_type),
+            DecodeError::InvalidUtf8 => f.write_str("string is not valid UTF-8"),
+            DecodeError::TooLong { len, max } => {
+                write!(f, "string of {} bytes exceeds the {} byte buffer", len, max)
+            }
+        }
+    }
+}
+
+pub(crate) fn varint_len(value: u64) -> usize {
+    // Each byte carries 7 bits; zero still takes one byte.
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+pub(crate) fn key_len(field: u32) -> usize {
+    varint_len(u64::from(field) << 3)
+}
+
+// Writes into a fixed slice, failing once it is full.
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn byte(&mut self, byte: u8) -> Result<(), EncodeError> {
+        let slot = self
+            .buf
+            .get_mut(self.pos)
+            .ok_or(EncodeError::BufferTooSmall {
+                needed: self.pos + 1,
+            })?;
+        *slot = byte;
+        self.pos += 1;
+        Ok(())
+    }
+
+    pub(crate) fn varint(&mut self, mut value: u64) -> Result<(), EncodeError> {
+        while value >= 0x80 {
+            self.byte(value as u8 | 0x80)?;
+            value >>= 7;
+        }
+        self.byte(value as u8)
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        let end = self.pos + bytes.len();
+        let slot = self
+            .buf
+            .get_mut(self.pos..end)
+            .ok_or(EncodeError::BufferTooSmall { needed: end })?;
+        slot.copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) -> Result<(), EncodeError> {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type))
+    }
+
+    // Scalars equal to zero are left out, as proto3 does.
+    pub(crate) fn uint64(&mut self, field: u32, value: u64) -> Result<(), EncodeError> {
+        if value == 0 {
+            return Ok(());
+        }
+        self.key(field, VARINT)?;
+        self.varint(value)
+    }
+
+    pub(crate) fn int32(&mut self, field: u32, value: i32) -> Result<(), EncodeError> {
+        // Negative int32s are sign-extended to 64 bits, so they always take 10 bytes.
+        self.uint64(field, value as i64 as u64)
+    }
+
+    pub(crate) fn string(&mut self, field: u32, value: &str) -> Result<(), EncodeError> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        self.key(field, LEN)?;
+        self.varint(value.len() as u64)?;
+        self.bytes(value.as_bytes())
+    }
+
+    // The header of an embedded message of `len` bytes, whose fields follow.
+    pub(crate) fn message(&mut self, field: u32, len: usize) -> Result<(), EncodeError> {
+        self.key(field, LEN)?;
+        self.varint(len as u64)
+    }
+}
+
+pub(crate) fn uint64_len(field: u32, value: u64) -> usize {
+    if value == 0 {
+        0
+    } else {
+        key_len(field) + varint_len(value)
+    }
+}
+
+pub(crate) fn int32_len(field: u32, value: i32) -> usize {
+    uint64_len(field, value as i64 as u64)
+}
+
+pub(crate) fn string_len(field: u32, value: &str) -> usize {
+    if value.is_empty() {
+        0
+    } else {
+        key_len(field) + varint_len(value.len() as u64) + value.len()
+    }
+}
+
+pub(crate) fn message_len(field: u32, len: usize) -> usize {
+    key_len(field) + varint_len(len as u64) + len
+}
+
+// One field read off the wire.
+pub(crate) enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // Fixed-width fields aren't used by these messages and are only skipped.
+    Fixed,
+}
+
+impl Value<'_> {
+    pub(crate) fn varint(&self) -> Result<u64, DecodeError> {
+        match self {
+            Value::Varint(value) => Ok(*value),
+            _ => Err(DecodeError::InvalidWireType(VARINT)),
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    pub(crate) fn bytes(&self) -> Result<&'a [u8], DecodeError> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(DecodeError::InvalidWireType(LEN)),
+        }
+    }
+
+    pub(crate) fn string<const N: usize>(&self) -> Result<heapless::String<N>, DecodeError> {
+        let bytes = self.bytes()?;
+        let text = core::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+        heapless::String::try_from(text).map_err(|_| DecodeError::TooLong {
+            len: bytes.len(),
+            max: N,
+        })
+    }
+}
+
+// Reads the fields of one message in order.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for i in 0..10 {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    // The next field number and its value, or `None` at the end of the message.
+    pub(crate) fn field(&mut self) -> Result<Option<(u32, Value<'a>)>, DecodeError> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+        let value = match (key & 7) as u8 {
+            VARINT => Value::Varint(self.varint()?),
+            LEN => {
+                let len = self.varint()?;
+                let len = usize::try_from(len).map_err(|_| DecodeError::Truncated)?;
+                Value::Bytes(self.take(len)?)
+            }
+            FIXED64 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            FIXED32 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => return Err(DecodeError::InvalidWireType(wire_type)),
+        };
+        Ok(Some((field, value)))
+    }
+}
@@ -0,0 +1,363 @@
+use embedded_recruitment_task::message as std_message;
+use embedded_recruitment_task::test_util::TestServer;
+use prost::Message as _;
+use protocol_core::frame::{FrameDecoder, FrameEncoder, FrameError, HEADER_LEN};
+use protocol_core::message::{
+    client_message, error, server_message, AddRequest, AddResponse, ClientMessage, Decode,
+    EchoMessage, Encode, Ping, ServerMessage, Text,
+};
+use protocol_core::DecodeError;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn text(content: &str) -> Text {
+    Text::try_from(content).unwrap()
+}
+
+fn encode(message: &impl Encode) -> Vec<u8> {
+    let mut out = [0; 512];
+    let len = message.encode(&mut out).unwrap();
+    assert_eq!(len, message.encoded_len());
+    out[..len].to_vec()
+}
+
+// A device's end of a connection: fixed buffers, fed from a blocking socket.
+struct Device {
+    stream: TcpStream,
+    encoder: FrameEncoder<256>,
+    decoder: FrameDecoder<256>,
+    received: Vec<u8>,
+}
+
+impl Device {
+    fn connect(server: &TestServer) -> Self {
+        let stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Device {
+            stream,
+            encoder: FrameEncoder::new(),
+            decoder: FrameDecoder::new(),
+            received: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, message: &ClientMessage) {
+        self.encoder.push_message(message).unwrap();
+        while !self.encoder.is_empty() {
+            let n = self.stream.write(self.encoder.pending()).unwrap();
+            self.encoder.consume(n);
+        }
+    }
+
+    fn receive(&mut self) -> ServerMessage {
+        loop {
+            let mut input = &self.received[..];
+            let frame = self.decoder.decode(&mut input).unwrap();
+            let consumed = self.received.len() - input.len();
+            if let Some(payload) = frame {
+                let message = ServerMessage::decode(payload).unwrap();
+                self.received.drain(..consumed);
+                return message;
+            }
+            self.received.clear();
+
+            let mut buf = [0; 64];
+            let n = self.stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0, "Server closed the connection");
+            self.received.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+#[test]
+fn test_client_messages_match_prost() {
+    let messages = [
+        ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: text("héllo, wörld"),
+            })),
+            request_id: 7,
+            deadline_ms: 250,
+        },
+        ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest {
+                a: -5,
+                b: i32::MAX,
+            })),
+            request_id: u64::MAX,
+            deadline_ms: 0,
+        },
+        ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage::default())),
+            ..ClientMessage::default()
+        },
+        ClientMessage::default(),
+    ];
+
+    for message in messages {
+        let bytes = encode(&message);
+        let decoded = std_message::ClientMessage::decode(&bytes[..]).unwrap();
+        assert_eq!(decoded.encode_to_vec(), bytes, "{:?}", message);
+        assert_eq!(ClientMessage::decode(&bytes).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_server_messages_from_prost_decode() {
+    let messages = [
+        (
+            std_message::server_message::Message::AddResponse(std_message::AddResponse {
+                result: -42,
+            }),
+            Some(server_message::Message::AddResponse(AddResponse {
+                result: -42,
+            })),
+        ),
+        (
+            std_message::server_message::Message::Error(std_message::Error {
+                code: std_message::error::Code::NotFound.into(),
+                message: "No handler".to_string(),
+            }),
+            Some(server_message::Message::Error(
+                protocol_core::message::Error {
+                    code: error::Code::NotFound as i32,
+                    message: text("No handler"),
+                },
+            )),
+        ),
+        // Messages the core doesn't know come through empty
+        (
+            std_message::server_message::Message::Chunk(std_message::Chunk {
+                seq: 3,
+                data: vec![1, 2, 3],
+                last: true,
+            }),
+            None,
+        ),
+    ];
+
+    for (message, expected) in messages {
+        let bytes = std_message::ServerMessage {
+            message: Some(message),
+            request_id: 99,
+        }
+        .encode_to_vec();
+        let decoded = ServerMessage::decode(&bytes).unwrap();
+        assert_eq!(decoded.request_id, 99);
+        assert_eq!(decoded.message, expected);
+        if expected.is_some() {
+            assert_eq!(encode(&decoded), bytes);
+        }
+    }
+}
+
+#[test]
+fn test_error_codes_match_prost() {
+    for code in 0..=i32::from(u8::MAX) {
+        match std_message::error::Code::try_from(code) {
+            Ok(std_code) => assert_eq!(error::Code::from_i32(code) as i32, std_code as i32),
+            Err(_) => assert_eq!(error::Code::from_i32(code), error::Code::Unspecified),
+        }
+    }
+}
+
+#[test]
+fn test_malformed_payloads_are_errors() {
+    let long = std_message::ServerMessage {
+        message: Some(std_message::server_message::Message::EchoMessage(
+            std_message::EchoMessage {
+                content: "x".repeat(200),
+            },
+        )),
+        request_id: 0,
+    }
+    .encode_to_vec();
+    assert_eq!(
+        ServerMessage::decode(&long),
+        Err(DecodeError::TooLong { len: 200, max: 128 })
+    );
+    assert_eq!(
+        ServerMessage::decode(&long[..50]),
+        Err(DecodeError::Truncated)
+    );
+    assert_eq!(
+        ServerMessage::decode(&[0x70, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+        Err(DecodeError::InvalidVarint)
+    );
+    assert_eq!(
+        ServerMessage::decode(&[0x0f]),
+        Err(DecodeError::InvalidWireType(7))
+    );
+
+    let mut small = [0; 3];
+    let message = ClientMessage {
+        message: Some(client_message::Message::Ping(Ping { timestamp_us: 1 })),
+        ..ClientMessage::default()
+    };
+    assert!(message.encode(&mut small).is_err());
+    assert!(message.encode_frame(&mut small).is_err());
+}
+
+#[test]
+fn test_frames_decode_byte_at_a_time() {
+    let mut stream = Vec::new();
+    let mut frame = [0; 64];
+    for content in ["one", "", "three"] {
+        let message = ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage {
+                content: text(content),
+            })),
+            request_id: 0,
+        };
+        let len = message.encode_frame(&mut frame).unwrap();
+        stream.extend_from_slice(&frame[..len]);
+    }
+
+    let mut decoder = FrameDecoder::<16>::new();
+    let mut contents = Vec::new();
+    for byte in &stream {
+        let mut input = std::slice::from_ref(byte);
+        if let Some(payload) = decoder.decode(&mut input).unwrap() {
+            let message = ServerMessage::decode(payload).unwrap();
+            let Some(server_message::Message::EchoMessage(echo)) = message.message else {
+                panic!("Expected an echo, got {:?}", message);
+            };
+            contents.push(echo.content.to_string());
+        }
+        assert!(input.is_empty());
+    }
+    assert_eq!(contents, ["one", "", "three"]);
+    assert!(decoder.is_idle());
+}
+
+#[test]
+fn test_oversized_frame_is_skipped() {
+    let mut stream = Vec::new();
+    stream.extend_from_slice(&100u32.to_be_bytes());
+    stream.extend_from_slice(&[0xaa; 100]);
+    stream.extend_from_slice(&3u32.to_be_bytes());
+    stream.extend_from_slice(b"abc");
+
+    let mut decoder = FrameDecoder::<32>::new();
+    let mut input = &stream[..];
+    assert_eq!(
+        decoder.decode(&mut input),
+        Err(FrameError::TooLarge { len: 100, max: 32 })
+    );
+    assert_eq!(input.len(), stream.len() - HEADER_LEN);
+    assert_eq!(decoder.decode(&mut input), Ok(Some(&b"abc"[..])));
+    assert_eq!(decoder.decode(&mut input), Ok(None));
+
+    let mut encoder = FrameEncoder::<8>::new();
+    encoder.push(b"abcd").unwrap();
+    assert!(encoder.push(b"e").is_err());
+    encoder.consume(5);
+    assert_eq!(encoder.pending(), b"bcd");
+}
+
+#[test]
+fn test_device_talks_to_server() {
+    let server = TestServer::start();
+    let mut device = Device::connect(&server);
+
+    device.send(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: text("sensor online"),
+        })),
+        ..ClientMessage::default()
+    });
+    assert_eq!(
+        device.receive().message,
+        Some(server_message::Message::EchoMessage(EchoMessage {
+            content: text("sensor online"),
+        }))
+    );
+
+    device.send(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest {
+            a: -7,
+            b: 3,
+        })),
+        request_id: 12,
+        deadline_ms: 1000,
+    });
+    let reply = device.receive();
+    assert_eq!(reply.request_id, 12);
+    assert_eq!(
+        reply.message,
+        Some(server_message::Message::AddResponse(AddResponse {
+            result: -4
+        }))
+    );
+
+    device.send(&ClientMessage {
+        message: Some(client_message::Message::Ping(Ping {
+            timestamp_us: 123_456,
+        })),
+        ..ClientMessage::default()
+    });
+    let Some(server_message::Message::Pong(pong)) = device.receive().message else {
+        panic!("Expected a pong");
+    };
+    assert_eq!(pong.timestamp_us, 123_456);
+}
+
+#[test]
+fn test_serial_packets_match_server() {
+    use embedded_recruitment_task::codec::{encode_packet, Framing};
+    use protocol_core::serial::{self, PacketDecoder, PacketError};
+
+    let request = ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest {
+            a: 0,
+            b: 1,
+        })),
+        request_id: 256,
+        deadline_ms: 0,
+    };
+    let mut out = [0; 64];
+    let len = request.encode(&mut out).unwrap();
+    let mut packet = [0; 64];
+    let packet_len = serial::encode_packet(&out[..len], &mut packet).unwrap();
+
+    // The server reads what a device writes
+    let mut decoder = Framing::Serial.decoder(1024);
+    decoder.extend(&packet[..packet_len]);
+    let payload = decoder.decode().unwrap().unwrap();
+    assert_eq!(
+        std_message::ClientMessage::decode(payload.as_slice())
+            .unwrap()
+            .request_id,
+        256
+    );
+
+    // And a device reads what the server writes, one byte at a time, after line noise
+    let reply = std_message::ServerMessage {
+        message: Some(std_message::server_message::Message::AddResponse(
+            std_message::AddResponse { result: 1 },
+        )),
+        request_id: 256,
+    };
+    let mut stream = b"noise\x00".to_vec();
+    encode_packet(&reply.encode_to_vec(), 1024, &mut stream).unwrap();
+    let mut decoder = PacketDecoder::<32>::new();
+    let mut replies = Vec::new();
+    for byte in &stream {
+        match decoder.decode(&mut std::slice::from_ref(byte)) {
+            Ok(Some(payload)) => replies.push(ServerMessage::decode(payload).unwrap()),
+            Ok(None) => {}
+            Err(e) => assert_eq!(e, PacketError::Corrupt),
+        }
+    }
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].request_id, 256);
+    assert_eq!(
+        replies[0].message,
+        Some(server_message::Message::AddResponse(AddResponse {
+            result: 1
+        }))
+    );
+}
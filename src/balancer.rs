@@ -0,0 +1,272 @@
+use crate::message::{client_message, server_message};
+use crate::pool::{Pool, PoolConfig};
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    // Take turns across the healthy endpoints.
+    RoundRobin,
+    // Pick the healthy endpoint with the fewest requests in flight, so a slow server gets
+    // less traffic.
+    LeastOutstanding,
+}
+
+#[derive(Debug, Clone)]
+pub struct BalancerConfig {
+    pub strategy: Strategy,
+    // Consecutive failed requests before an endpoint is taken out of rotation.
+    pub eject_after: u32,
+    // How long an ejected endpoint sits out. Afterwards it gets traffic again, and the first
+    // request decides whether it stays (success) or is ejected again (failure).
+    pub ejection_time: Duration,
+    // Connection pool settings for each endpoint.
+    pub pool: PoolConfig,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        BalancerConfig {
+            strategy: Strategy::RoundRobin,
+            eject_after: 3,
+            ejection_time: Duration::from_secs(10),
+            pool: PoolConfig {
+                min_connections: 0,
+                ..PoolConfig::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStatus {
+    pub addr: SocketAddr,
+    pub ejected: bool,
+    pub outstanding: usize,
+    pub consecutive_failures: u32,
+}
+
+struct Endpoint {
+    addr: SocketAddr,
+    pool: Pool,
+    outstanding: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| now < until)
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.ejected_until.lock().unwrap().take().is_some() {
+            info!("Endpoint {} is healthy again", self.addr);
+        }
+    }
+
+    fn record_failure(&self, config: &BalancerConfig, error: &io::Error) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= config.eject_after {
+            warn!(
+                "Ejecting endpoint {} for {:?} after {} failures: {}",
+                self.addr, config.ejection_time, failures, error
+            );
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + config.ejection_time);
+        }
+    }
+}
+
+// Counts a request against an endpoint while it is in flight.
+struct Outstanding<'a>(&'a AtomicUsize);
+
+impl<'a> Outstanding<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Outstanding(counter)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Spreads requests over several servers, each with its own connection pool.
+//
+// Endpoints that keep failing are ejected for a while, and a request that fails on one
+// endpoint is retried on the others. A request may therefore reach more than one server.
+// Cloning a `Balancer` is cheap and every clone shares the same endpoints.
+#[derive(Clone)]
+pub struct Balancer {
+    endpoints: Arc<Vec<Endpoint>>,
+    config: Arc<BalancerConfig>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Balancer {
+    // Balance over `addrs`. Must be called within a Tokio runtime; connections are opened
+    // on demand.
+    pub fn new(addrs: Vec<SocketAddr>, config: BalancerConfig) -> io::Result<Self> {
+        let mut unique = Vec::new();
+        for addr in addrs {
+            if !unique.contains(&addr) {
+                unique.push(addr);
+            }
+        }
+        if unique.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No endpoints to balance over",
+            ));
+        }
+
+        let endpoints = unique
+            .into_iter()
+            .map(|addr| {
+                Ok(Endpoint {
+                    addr,
+                    pool: Pool::new(addr.to_string(), config.pool.clone())?,
+                    outstanding: AtomicUsize::new(0),
+                    consecutive_failures: AtomicU32::new(0),
+                    ejected_until: Mutex::new(None),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Balancer {
+            endpoints: Arc::new(endpoints),
+            config: Arc::new(config),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    // Balance over every address that `hosts` (e.g. "servers.local:5000") resolve to.
+    pub async fn resolve(
+        hosts: impl IntoIterator<Item = impl AsRef<str>>,
+        config: BalancerConfig,
+    ) -> io::Result<Self> {
+        let mut addrs = Vec::new();
+        for host in hosts {
+            addrs.extend(lookup_host(host.as_ref()).await?);
+        }
+        Self::new(addrs, config)
+    }
+
+    // Send a request to one endpoint, failing over to the others if it fails.
+    pub async fn request(
+        &self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        self.send(message, None).await
+    }
+
+    // Like `request`, failing with `TimedOut` unless the request is answered within
+    // `timeout`. A request that runs out of time isn't retried elsewhere, and doesn't count
+    // against the endpoint.
+    pub async fn request_with_timeout(
+        &self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        self.send(message, Some(Instant::now() + timeout)).await
+    }
+
+    async fn send(
+        &self,
+        message: client_message::Message,
+        deadline: Option<Instant>,
+    ) -> io::Result<server_message::Message> {
+        let mut tried = Vec::new();
+        let mut last_error = None;
+
+        while let Some(index) = self.pick(&tried) {
+            tried.push(index);
+            let endpoint = &self.endpoints[index];
+
+            let result = {
+                let _outstanding = Outstanding::new(&endpoint.outstanding);
+                match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        endpoint
+                            .pool
+                            .request_with_timeout(message.clone(), remaining)
+                            .await
+                    }
+                    None => endpoint.pool.request(message.clone()).await,
+                }
+            };
+            match result {
+                Ok(response) => {
+                    endpoint.record_success();
+                    return Ok(response);
+                }
+                // A bad response isn't the connection's fault, another server won't fix it,
+                // and a request out of time has no time left for another server.
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e),
+                Err(e) if deadline.is_some() && e.kind() == io::ErrorKind::TimedOut => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    endpoint.record_failure(&self.config, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.expect("There is always at least one endpoint"))
+    }
+
+    // Choose the next endpoint that hasn't been tried for this request. Ejected endpoints
+    // are only used once every endpoint is ejected, starting with the one due back soonest.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let untried: Vec<usize> = (0..self.endpoints.len())
+            .filter(|index| !tried.contains(index))
+            .collect();
+        let healthy: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|&index| !self.endpoints[index].ejected(now))
+            .collect();
+
+        if healthy.is_empty() {
+            return untried
+                .into_iter()
+                .min_by_key(|&index| *self.endpoints[index].ejected_until.lock().unwrap());
+        }
+
+        // Rotate the starting point so equally loaded endpoints take turns.
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+        let rotated = healthy[start..].iter().chain(&healthy[..start]).copied();
+        match self.config.strategy {
+            Strategy::RoundRobin => Some(healthy[start]),
+            Strategy::LeastOutstanding => rotated
+                .min_by_key(|&index| self.endpoints[index].outstanding.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<EndpointStatus> {
+        let now = Instant::now();
+        self.endpoints
+            .iter()
+            .map(|endpoint| EndpointStatus {
+                addr: endpoint.addr,
+                ejected: endpoint.ejected(now),
+                outstanding: endpoint.outstanding.load(Ordering::Relaxed),
+                consecutive_failures: endpoint.consecutive_failures.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
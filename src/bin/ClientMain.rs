@@ -1,96 +1,398 @@
-use embedded_recruitment_task::message::EchoMessage;
-use embedded_recruitment_task::message::{client_message, ServerMessage};
-use log::{error, info, warn};
-use prost::Message;
+use clap::{Parser, Subcommand};
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{encode_frame, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::message::{
+    client_message, server_message, AddRequest, DeviceInfo, DeviceQuery, EchoMessage, ServerMessage,
+};
+use embedded_recruitment_task::router::error_from_reply;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 use std::{
-    io::{self, ErrorKind, Read, Write}, net::{TcpListener, TcpStream}, process, sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    }, thread, time::Duration
+    env, io,
+    path::PathBuf,
+    process,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
 
-#[path = "../../tests/client.rs"]
-mod client;
+/// Command-line client for the server. Runs a single command, or an interactive session
+/// when no command is given.
+#[derive(Parser, Debug)]
+#[command(name = "ClientMain")]
+struct Args {
+    /// Server address
+    #[arg(long, default_value = "127.0.0.1:5000", global = true)]
+    addr: String,
 
-#[tokio::main]
-async fn main() {
-    // Server details
-    let ip = "127.0.0.1";
-    let port = 5000;
-    let timeout_ms = 5000; // Timeout in milliseconds
+    /// Print responses as JSON instead of the readable form
+    #[arg(long, global = true)]
+    json: bool,
 
-    // Number of clients to simulate
-    let client_count = 10;
+    /// How long to wait for a response
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration, global = true)]
+    timeout: Duration,
 
-    let mut tasks = vec![];
+    /// Connect over TLS (not supported by this server, so it is refused)
+    #[arg(long, global = true)]
+    tls: bool,
 
-    for client_id in 1..=client_count {
-        let client_task = tokio::spawn(async move {
-            // Create a new client for each task
-            let mut client = client::Client::new(ip, port, timeout_ms);
+    /// Authenticate with this token (not supported by this server, so it is refused)
+    #[arg(long, global = true)]
+    token: Option<String>,
 
-            // Connect to the server
-            if let Err(e) = client.connect() {
-                error!("Client {}: Failed to connect to server: {}", client_id, e);
-                return;
-            }
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send an EchoMessage and print the reply
+    Echo {
+        /// Text to echo; multiple words are joined with spaces
+        #[arg(required = true, num_args = 1..)]
+        text: Vec<String>,
+    },
+    /// Ask the server to add two numbers
+    #[command(allow_negative_numbers = true)]
+    Add { a: i32, b: i32 },
+    /// Measure the round trip time to the server
+    Ping,
+    /// Send hex-encoded bytes as the payload of a single frame
+    Raw { hex: String },
+    /// List the devices the server knows, and whether they are online
+    Devices {
+        /// Just this device
+        device_id: Option<String>,
+        /// Only the devices that are online
+        #[arg(long)]
+        online: bool,
+    },
+    /// Start an interactive session (the default without a command)
+    Repl,
+}
+
+// A command typed at the interactive prompt, parsed with the same rules as the command line.
+#[derive(Parser, Debug)]
+#[command(multicall = true)]
+struct ReplLine {
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Owns the connection for a session and reconnects lazily after it is lost.
+struct Session {
+    addr: String,
+    json: bool,
+    timeout: Duration,
+    client: Option<Client>,
+}
+
+impl Session {
+    fn new(args: &Args) -> Self {
+        Session {
+            addr: args.addr.clone(),
+            json: args.json,
+            timeout: args.timeout,
+            client: None,
+        }
+    }
 
-            let mut message_count = 1;
+    async fn client(&mut self) -> io::Result<&mut Client> {
+        if self.client.is_none() {
+            let client = timeout(self.timeout, Client::connect(&self.addr))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"))??;
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
 
-            // Loop to send multiple messages
-            loop {
-                // Create a message to send to the server
-                let echo_message = EchoMessage {
-                    content: format!("Client #{}: Hello, Server! This is Mostafa! Message #{}", client_id, message_count),
+    // Send one request with `--timeout` as its deadline and wait for the reply, dropping the
+    // connection on failure.
+    async fn request(
+        &mut self,
+        message: client_message::Message,
+    ) -> io::Result<(ServerMessage, Duration)> {
+        let wait = self.timeout;
+        let client = self.client().await?;
+        let start = Instant::now();
+
+        match client.request_with_timeout(message, wait).await {
+            Ok(response) => {
+                let response = ServerMessage {
+                    message: Some(response),
+                    ..ServerMessage::default()
                 };
+                Ok((response, start.elapsed()))
+            }
+            Err(e) => {
+                self.client = None;
+                Err(e)
+            }
+        }
+    }
 
-                // Wrap the EchoMessage in the ClientMessage using the `Message` enum
-                let message = client_message::Message::EchoMessage(echo_message);
+    // Ping the server, giving up after `--timeout` and dropping the connection on failure.
+    // Returns this round trip and the average over the session.
+    async fn ping(&mut self) -> io::Result<(Duration, Duration)> {
+        let wait = self.timeout;
+        let client = self.client().await?;
 
-                // Send the message to the server
-                if let Err(e) = client.send(message) {
-                    error!("Client {}: Failed to send message: {}", client_id, e);
-                    if let Err(disconnect_error) = client.disconnect() {
-                        error!("Client {}: Failed to disconnect after error: {}", client_id, disconnect_error);
-                    }
-                    return;
-                }
+        let result = match timeout(wait, client.ping()).await {
+            Ok(Ok(rtt)) => Ok((rtt, client.rtt().smoothed.unwrap_or(rtt))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for a pong",
+            )),
+        };
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
 
-                // Receive the server's response
-                match client.receive() {
-                    Ok(response) => {
-                        info!("Client {}: Received response from server: {:?}", client_id, response);
-                    }
-                    Err(e) => {
-                        error!("Client {}: Failed to receive response: {}", client_id, e);
-                    }
-                }
+    // Send `payload` as-is inside a frame. The server doesn't answer payloads it can't decode,
+    // so a missing reply is reported rather than treated as an error.
+    async fn raw(&self, payload: &[u8]) -> io::Result<Option<(ServerMessage, Duration)>> {
+        let mut frame = Vec::new();
+        encode_frame(payload, DEFAULT_MAX_FRAME_LEN, &mut frame)?;
+
+        let mut stream = timeout(self.timeout, TcpStream::connect(&self.addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"))??;
+        let start = Instant::now();
+        stream.write_all(&frame).await?;
 
-                // Increment the message count
-                message_count += 1;
+        // Read the reply through a regular client on the same connection.
+        let mut client = Client::from_stream(stream)?;
+        match timeout(self.timeout, client.receive()).await {
+            Ok(Ok(response)) => Ok(Some((response, start.elapsed()))),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionAborted => Ok(None),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Ok(None),
+        }
+    }
 
-                // Add a condition to break the loop (for example, send 5 messages and then stop)
-                if message_count > 5 {
-                    break;
+    // Every device matching `query`, asking for page after page until the list is complete.
+    async fn devices(&mut self, mut query: DeviceQuery) -> io::Result<Vec<DeviceInfo>> {
+        let mut devices = Vec::new();
+        loop {
+            let message = client_message::Message::DeviceQuery(query.clone());
+            let (response, _) = self.request(message).await?;
+            let list = match response.message {
+                Some(server_message::Message::DeviceList(list)) => list,
+                Some(server_message::Message::Error(error)) => return Err(error_from_reply(error)),
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unexpected response to a device query: {:?}", other),
+                    ))
                 }
+            };
+            devices.extend(list.devices);
+            match devices.last() {
+                Some(last) if list.truncated => query.after.clone_from(&last.device_id),
+                _ => return Ok(devices),
+            }
+        }
+    }
 
-                // Optional: Add a delay between messages
-                tokio::time::sleep(Duration::from_secs(1)).await;
+    async fn run(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::Echo { text } => {
+                let message = client_message::Message::EchoMessage(EchoMessage {
+                    content: text.join(" "),
+                });
+                let (response, rtt) = self.request(message).await?;
+                self.print(&response, rtt);
+            }
+            Command::Add { a, b } => {
+                let message = client_message::Message::AddRequest(AddRequest { a, b });
+                let (response, rtt) = self.request(message).await?;
+                self.print(&response, rtt);
+            }
+            Command::Ping => {
+                let (rtt, smoothed) = self.ping().await?;
+                if self.json {
+                    let output = serde_json::json!({
+                        "rtt_us": rtt.as_micros() as u64,
+                        "smoothed_rtt_us": smoothed.as_micros() as u64,
+                    });
+                    println!("{}", output);
+                } else {
+                    println!(
+                        "pong from {} in {} (average {})",
+                        self.addr,
+                        format_duration(rtt),
+                        format_duration(smoothed)
+                    );
+                }
+            }
+            Command::Raw { hex } => {
+                let payload = hex::decode(hex.trim_start_matches("0x")).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid hex: {}", e))
+                })?;
+                match self.raw(&payload).await? {
+                    Some((response, rtt)) => self.print(&response, rtt),
+                    None if self.json => println!("{}", serde_json::json!({ "response": null })),
+                    None => println!("no response"),
+                }
+            }
+            Command::Devices { device_id, online } => {
+                let query = DeviceQuery {
+                    device_id: device_id.unwrap_or_default(),
+                    online_only: online,
+                    ..DeviceQuery::default()
+                };
+                let devices = self.devices(query).await?;
+                if self.json {
+                    println!("{}", serde_json::json!({ "devices": devices }));
+                } else if devices.is_empty() {
+                    println!("no devices");
+                }
+                for device in devices.iter().filter(|_| !self.json) {
+                    print_device(device);
+                }
+            }
+            Command::Repl => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Already in interactive mode",
+                ));
             }
+        }
+        Ok(())
+    }
+
+    fn print(&self, response: &ServerMessage, rtt: Duration) {
+        if self.json {
+            let output = serde_json::json!({
+                "rtt_us": rtt.as_micros() as u64,
+                "response": response,
+            });
+            println!("{}", output);
+            return;
+        }
+
+        match &response.message {
+            Some(message) => println!("{:#?}", message),
+            None => println!("(empty response)"),
+        }
+        println!("({})", format_duration(rtt));
+    }
+}
+
+// One line per device: its id, what it runs, and when and where it was last connected.
+fn print_device(device: &DeviceInfo) {
+    let presence = if device.online { "online" } else { "offline" };
+    let last_seen = SystemTime::UNIX_EPOCH + Duration::from_millis(device.last_seen_ms);
+    println!(
+        "{}  {} {}  {}  last seen {}  from {}",
+        device.device_id,
+        device.model,
+        device.firmware_version,
+        presence,
+        humantime::format_rfc3339_seconds(last_seen),
+        device.peer
+    );
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".embedded_client_history"))
+}
+
+fn repl(runtime: &Runtime, mut session: Session) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
 
-            // Disconnect from the server
-            if let Err(e) = client.disconnect() {
-                error!("Client {}: Failed to disconnect: {}", client_id, e);
+    println!(
+        "Client for {}. Type `help` for commands, `quit` to exit.",
+        session.addr
+    );
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if matches!(line, "quit" | "exit") {
+            break;
+        }
+
+        match ReplLine::try_parse_from(line.split_whitespace()) {
+            Ok(ReplLine { command }) => {
+                if let Err(e) = runtime.block_on(session.run(command)) {
+                    eprintln!("error: {}", e);
+                }
+            }
+            // Covers `help` and usage errors alike
+            Err(e) => {
+                let _ = e.print();
             }
-        });
+        }
+    }
 
-        tasks.push(client_task);
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
     }
+    Ok(())
+}
 
-    // Await all the client tasks to complete
-    for task in tasks {
-        if let Err(e) = task.await {
-            error!("A client task failed: {}", e);
+fn main() {
+    env_logger::init();
+    let args = Args::parse();
+    // The server has neither TLS nor authentication, and sending a token in the clear, or
+    // quietly connecting without the TLS the user asked for, would be worse than refusing.
+    let unsupported = match (args.tls, &args.token) {
+        (true, _) => Some("--tls"),
+        (false, Some(_)) => Some("--token"),
+        (false, None) => None,
+    };
+    if let Some(flag) = unsupported {
+        eprintln!("error: {} is not supported by this server", flag);
+        process::exit(2);
+    }
+
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start runtime: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut session = Session::new(&args);
+    match args.command {
+        None | Some(Command::Repl) => {
+            if let Err(e) = repl(&runtime, session) {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(command) => {
+            if let Err(e) = runtime.block_on(session.run(command)) {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
         }
     }
-}
\ No newline at end of file
+}
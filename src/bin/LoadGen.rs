@@ -0,0 +1,554 @@
+use clap::Parser;
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::message::{
+    client_message, server_message, AddRequest, AddResponse, EchoMessage,
+};
+use hdrhistogram::Histogram;
+use log::{error, warn};
+use rand::distributions::{Alphanumeric, Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::{self, timeout};
+
+/// Load generator for the server: drives a configurable mix of requests over many connections
+/// and reports latency percentiles, throughput and errors.
+#[derive(Parser, Debug)]
+#[command(name = "LoadGen")]
+struct Args {
+    /// Server address
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    addr: String,
+
+    /// Number of connections. In closed-loop mode this is also the number of requests in flight.
+    #[arg(short, long, default_value_t = 10)]
+    connections: usize,
+
+    /// Total request rate across all connections, in requests per second (open loop).
+    /// Without it every connection sends its next request as soon as the previous one is
+    /// answered (closed loop).
+    #[arg(short, long)]
+    rate: Option<f64>,
+
+    /// How long to generate load for, e.g. "30s" or "2m"
+    #[arg(short, long, default_value = "10s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+
+    /// Operation mix as weighted operations, e.g. "echo=80,add=20"
+    #[arg(short, long, default_value = "echo=1")]
+    mix: Mix,
+
+    /// Echo payload size in bytes, either fixed ("64") or a uniform range ("16-1024")
+    #[arg(short, long, default_value = "64")]
+    payload_size: PayloadSize,
+
+    /// Per-request timeout
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    timeout: Duration,
+
+    /// Write the report as JSON to this file
+    #[arg(long)]
+    json: Option<PathBuf>,
+
+    /// Write per-operation results as CSV to this file
+    #[arg(long)]
+    csv: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operation {
+    Echo,
+    Add,
+}
+
+impl Operation {
+    const ALL: [Operation; 2] = [Operation::Echo, Operation::Add];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Echo => "echo",
+            Operation::Add => "add",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mix {
+    operations: Vec<Operation>,
+    weights: Vec<u32>,
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mix = Mix {
+            operations: Vec::new(),
+            weights: Vec::new(),
+        };
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, weight) = entry.split_once('=').unwrap_or((entry, "1"));
+            let operation = Operation::ALL
+                .into_iter()
+                .find(|operation| operation.name() == name.trim())
+                .ok_or_else(|| format!("unknown operation {:?}", name))?;
+            let weight = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid weight {:?} for {}", weight, name))?;
+
+            mix.operations.push(operation);
+            mix.weights.push(weight);
+        }
+
+        if mix.weights.iter().all(|&weight| weight == 0) {
+            return Err("the mix needs at least one operation with a non-zero weight".to_string());
+        }
+        Ok(mix)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PayloadSize {
+    min: usize,
+    max: usize,
+}
+
+impl FromStr for PayloadSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| {
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid payload size {:?}", value))
+        };
+
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min > max {
+            return Err(format!("payload size range {}-{} is empty", min, max));
+        }
+        Ok(PayloadSize { min, max })
+    }
+}
+
+// Results collected by one connection, merged into the final report.
+struct Stats {
+    latencies: BTreeMap<Operation, Histogram<u64>>,
+    errors: BTreeMap<Operation, u64>,
+    error_kinds: BTreeMap<&'static str, u64>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            latencies: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            error_kinds: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, operation: Operation, latency: Duration) {
+        self.latencies
+            .entry(operation)
+            .or_insert_with(new_histogram)
+            .saturating_record(latency.as_micros() as u64);
+    }
+
+    fn record_error(&mut self, operation: Option<Operation>, kind: &'static str) {
+        if let Some(operation) = operation {
+            *self.errors.entry(operation).or_default() += 1;
+        }
+        *self.error_kinds.entry(kind).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: Stats) {
+        for (operation, histogram) in other.latencies {
+            let merged = self
+                .latencies
+                .entry(operation)
+                .or_insert_with(new_histogram);
+            if let Err(e) = merged.add(&histogram) {
+                warn!("Failed to merge {} latencies: {}", operation.name(), e);
+            }
+        }
+        for (operation, count) in other.errors {
+            *self.errors.entry(operation).or_default() += count;
+        }
+        for (kind, count) in other.error_kinds {
+            *self.error_kinds.entry(kind).or_default() += count;
+        }
+    }
+}
+
+// Latencies are recorded in microseconds, from 1us up to one minute.
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 60_000_000, 3).expect("valid histogram bounds")
+}
+
+// Build a random request and the response the server should answer it with.
+fn build_request(
+    operation: Operation,
+    payload_size: PayloadSize,
+    rng: &mut impl Rng,
+) -> (client_message::Message, server_message::Message) {
+    match operation {
+        Operation::Echo => {
+            let size = rng.gen_range(payload_size.min..=payload_size.max);
+            let content: String = rng
+                .sample_iter(&Alphanumeric)
+                .take(size)
+                .map(char::from)
+                .collect();
+            let echo = EchoMessage { content };
+            (
+                client_message::Message::EchoMessage(echo.clone()),
+                server_message::Message::EchoMessage(echo),
+            )
+        }
+        Operation::Add => {
+            let add_request = AddRequest {
+                a: rng.gen_range(-1_000_000..1_000_000),
+                b: rng.gen_range(-1_000_000..1_000_000),
+            };
+            let result = add_request.a + add_request.b;
+            (
+                client_message::Message::AddRequest(add_request),
+                server_message::Message::AddResponse(AddResponse { result }),
+            )
+        }
+    }
+}
+
+// Drive one connection until `deadline`, reconnecting after failures.
+async fn run_connection(
+    args: &Args,
+    interval: Option<Duration>,
+    start: Instant,
+    deadline: Instant,
+) -> Stats {
+    let mut stats = Stats::new();
+    let mut rng = StdRng::from_entropy();
+    let operations = WeightedIndex::new(&args.mix.weights).expect("mix was validated");
+    let mut client: Option<Client> = None;
+    let mut next_send = start;
+
+    while Instant::now() < deadline {
+        // In open-loop mode latency is measured from when the request was due rather than when
+        // it was sent, so a stalled server can't hide its queueing delay.
+        let due = match interval {
+            Some(interval) => {
+                let due = next_send;
+                // Past the end of time is past the deadline too
+                next_send = next_send.checked_add(interval).unwrap_or(deadline);
+                if due >= deadline {
+                    break;
+                }
+                time::sleep_until(due.into()).await;
+                due
+            }
+            None => Instant::now(),
+        };
+
+        let connection = match client.as_mut() {
+            Some(connection) => connection,
+            None => match timeout(args.timeout, Client::connect(&args.addr)).await {
+                Ok(Ok(connection)) => client.insert(connection),
+                Ok(Err(_)) | Err(_) => {
+                    stats.record_error(None, "connect");
+                    time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+        };
+
+        let operation = args.mix.operations[operations.sample(&mut rng)];
+        let (request, expected) = build_request(operation, args.payload_size, &mut rng);
+
+        match timeout(args.timeout, connection.request(request)).await {
+            Ok(Ok(response)) if response == expected => stats.record(operation, due.elapsed()),
+            Ok(Ok(_)) => stats.record_error(Some(operation), "unexpected_response"),
+            Ok(Err(_)) => {
+                stats.record_error(Some(operation), "io");
+                client = None;
+            }
+            Err(_) => {
+                // The late response would be read as the answer to the next request.
+                stats.record_error(Some(operation), "timeout");
+                client = None;
+            }
+        }
+    }
+
+    if let Some(client) = client {
+        let _ = client.disconnect().await;
+    }
+    stats
+}
+
+#[derive(Serialize, Clone)]
+struct Latency {
+    p50: u64,
+    p90: u64,
+    p99: u64,
+    p999: u64,
+    max: u64,
+    mean: f64,
+}
+
+impl Latency {
+    fn from_histogram(histogram: &Histogram<u64>) -> Self {
+        Latency {
+            p50: histogram.value_at_quantile(0.5),
+            p90: histogram.value_at_quantile(0.9),
+            p99: histogram.value_at_quantile(0.99),
+            p999: histogram.value_at_quantile(0.999),
+            max: histogram.max(),
+            mean: histogram.mean(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OperationReport {
+    operation: &'static str,
+    requests: u64,
+    errors: u64,
+    throughput: f64,
+    latency_us: Latency,
+}
+
+#[derive(Serialize)]
+struct Report {
+    addr: String,
+    connections: usize,
+    target_rate: Option<f64>,
+    elapsed_secs: f64,
+    requests: u64,
+    errors: u64,
+    throughput: f64,
+    latency_us: Latency,
+    operations: Vec<OperationReport>,
+    error_counts: BTreeMap<&'static str, u64>,
+}
+
+impl Report {
+    fn new(args: &Args, stats: &Stats, elapsed: Duration) -> Self {
+        let seconds = elapsed.as_secs_f64();
+        let mut all = new_histogram();
+        let mut operations = Vec::new();
+
+        for operation in Operation::ALL {
+            let histogram = stats.latencies.get(&operation);
+            let errors = stats.errors.get(&operation).copied().unwrap_or(0);
+            if histogram.is_none() && errors == 0 {
+                continue;
+            }
+
+            let histogram = histogram.cloned().unwrap_or_else(new_histogram);
+            let _ = all.add(&histogram);
+            operations.push(OperationReport {
+                operation: operation.name(),
+                requests: histogram.len(),
+                errors,
+                throughput: histogram.len() as f64 / seconds,
+                latency_us: Latency::from_histogram(&histogram),
+            });
+        }
+
+        Report {
+            addr: args.addr.clone(),
+            connections: args.connections,
+            target_rate: args.rate,
+            elapsed_secs: seconds,
+            requests: all.len(),
+            errors: stats.error_kinds.values().sum(),
+            throughput: all.len() as f64 / seconds,
+            latency_us: Latency::from_histogram(&all),
+            operations,
+            error_counts: stats.error_kinds.clone(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        let mode = match self.target_rate {
+            Some(rate) => format!("open loop at {} req/s", rate),
+            None => "closed loop".to_string(),
+        };
+        let _ = writeln!(
+            text,
+            "Target:     {} ({} connections, {})",
+            self.addr, self.connections, mode
+        );
+        let _ = writeln!(
+            text,
+            "Requests:   {} ok, {} errors in {:.2}s",
+            self.requests, self.errors, self.elapsed_secs
+        );
+        let _ = writeln!(text, "Throughput: {:.1} req/s", self.throughput);
+        let _ = writeln!(text);
+        let _ = writeln!(
+            text,
+            "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "latency", "p50", "p90", "p99", "p99.9", "max", "mean"
+        );
+
+        let rows = std::iter::once(("all", &self.latency_us)).chain(
+            self.operations
+                .iter()
+                .map(|op| (op.operation, &op.latency_us)),
+        );
+        for (name, latency) in rows {
+            let _ = writeln!(
+                text,
+                "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                format_micros(latency.p50),
+                format_micros(latency.p90),
+                format_micros(latency.p99),
+                format_micros(latency.p999),
+                format_micros(latency.max),
+                format_micros(latency.mean as u64),
+            );
+        }
+
+        if !self.error_counts.is_empty() {
+            let _ = writeln!(text);
+            let _ = writeln!(text, "Errors:");
+            for (kind, count) in &self.error_counts {
+                let _ = writeln!(text, "  {:<20} {}", kind, count);
+            }
+        }
+        text
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "operation,requests,errors,throughput,p50_us,p90_us,p99_us,p999_us,max_us,mean_us\n",
+        );
+        let all = OperationReport {
+            operation: "all",
+            requests: self.requests,
+            errors: self.errors,
+            throughput: self.throughput,
+            latency_us: self.latency_us.clone(),
+        };
+        for op in std::iter::once(&all).chain(&self.operations) {
+            let l = &op.latency_us;
+            let _ = writeln!(
+                csv,
+                "{},{},{},{:.3},{},{},{},{},{},{:.1}",
+                op.operation,
+                op.requests,
+                op.errors,
+                op.throughput,
+                l.p50,
+                l.p90,
+                l.p99,
+                l.p999,
+                l.max,
+                l.mean
+            );
+        }
+        csv
+    }
+}
+
+fn format_micros(micros: u64) -> String {
+    if micros >= 1_000_000 {
+        format!("{:.2}s", micros as f64 / 1_000_000.0)
+    } else if micros >= 1_000 {
+        format!("{:.2}ms", micros as f64 / 1_000.0)
+    } else {
+        format!("{}us", micros)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let args = Args::parse();
+
+    if args.connections == 0 {
+        eprintln!("--connections must be at least 1");
+        process::exit(2);
+    }
+    // Spread the target rate evenly over the connections.
+    let interval = match args.rate {
+        Some(rate) if rate.is_finite() && rate > 0.0 => {
+            match Duration::try_from_secs_f64(args.connections as f64 / rate) {
+                Ok(interval) => Some(interval),
+                Err(_) => {
+                    eprintln!("--rate is too low to send at");
+                    process::exit(2);
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("--rate must be a number greater than zero");
+            process::exit(2);
+        }
+        None => None,
+    };
+
+    let args = std::sync::Arc::new(args);
+    let start = Instant::now();
+    let deadline = start + args.duration;
+
+    let mut tasks = Vec::with_capacity(args.connections);
+    for index in 0..args.connections {
+        let args = std::sync::Arc::clone(&args);
+        // Stagger open-loop connections so their requests don't all land at once.
+        let offset = interval.map_or(Duration::ZERO, |interval| {
+            interval.mul_f64(index as f64 / args.connections as f64)
+        });
+        let first = start.checked_add(offset).unwrap_or(deadline);
+        tasks.push(tokio::spawn(async move {
+            run_connection(&args, interval, first, deadline).await
+        }));
+    }
+
+    let mut stats = Stats::new();
+    for task in tasks {
+        match task.await {
+            Ok(connection_stats) => stats.merge(connection_stats),
+            Err(e) => error!("A connection task failed: {}", e),
+        }
+    }
+
+    let report = Report::new(&args, &stats, start.elapsed());
+    print!("{}", report.to_text());
+
+    if let Some(path) = &args.json {
+        let json = serde_json::to_string_pretty(&report).expect("report serializes to JSON");
+        if let Err(e) = fs::write(path, json + "\n") {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &args.csv {
+        if let Err(e) = fs::write(path, report.to_csv()) {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,26 +1,148 @@
+use embedded_recruitment_task::config::ServerConfig;
 use embedded_recruitment_task::server::Server;
-use log::{info, error};
-use std::process;
+use log::{error, info, warn, LevelFilter};
+use std::{env, io, process, sync::Arc};
 
-#[tokio::main]  // Set up Tokio runtime
+// Signals the server reacts to while running.
+enum Signal {
+    Shutdown(&'static str),
+    Reload,
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.terminate.recv() => Signal::Shutdown("SIGTERM"),
+            _ = self.interrupt.recv() => Signal::Shutdown("SIGINT"),
+            _ = self.hangup.recv() => Signal::Reload,
+        }
+    }
+}
+
+// Without unix signals only Ctrl-C is available, so there is no reload trigger.
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> Signal {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+        }
+        Signal::Shutdown("Ctrl-C")
+    }
+}
+
+// Re-read the configuration file and apply it to the running server. The server speaks
+// plain TCP, so unlike limits and the log level there are no TLS certificates to reload.
+fn reload(server: &Server, config_path: Option<&str>) {
+    let Some(path) = config_path else {
+        warn!("Received SIGHUP but no configuration file was given; nothing to reload");
+        return;
+    };
+
+    match ServerConfig::load(path) {
+        Ok(config) => {
+            log::set_max_level(config.log_level);
+            server.reload(&config);
+        }
+        // Keep running with the previous configuration rather than dying on a typo.
+        Err(e) => error!("Failed to reload configuration from {}: {}", path, e),
+    }
+}
+
+#[tokio::main] // Set up Tokio runtime
 async fn main() {
-    env_logger::init(); // Initialize logging
-    
-    let addr = "127.0.0.1:5000"; // Server address
-    info!("Starting server at {}", addr);
+    // Optional path to a TOML configuration file; defaults are used without one.
+    let config_path = env::args().nth(1);
+    let config = match config_path.as_deref().map(ServerConfig::load) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("Failed to load configuration: {}", e);
+            process::exit(1);
+        }
+        None => ServerConfig::default(),
+    };
+
+    // The logger itself lets everything through so the level can be changed on reload.
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+    log::set_max_level(config.log_level);
+
+    info!("Starting server at {}", config.address);
 
     // Create the server asynchronously
-    let server = match Server::new(addr).await {
-        Ok(server) => server,
+    let server = match Server::with_config(config).await {
+        Ok(server) => Arc::new(server),
         Err(e) => {
             error!("Failed to create server: {}", e);
             process::exit(1);
         }
     };
 
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Failed to install signal handlers: {}", e);
+            process::exit(1);
+        }
+    };
+
     // Run the server asynchronously
-    if let Err(e) = server.run().await {
-        error!("Server failed: {}", e);
-        process::exit(1);
+    let mut running = tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.run().await }
+    });
+
+    let result = loop {
+        tokio::select! {
+            result = &mut running => break result,
+            signal = signals.recv() => match signal {
+                Signal::Shutdown(name) => {
+                    info!("Received {}, shutting down", name);
+                    server.stop();
+                }
+                Signal::Reload => {
+                    info!("Received SIGHUP, reloading configuration");
+                    reload(&server, config_path.as_deref());
+                }
+            },
+        }
+    };
+
+    match result {
+        Ok(Ok(())) => info!("Server exited cleanly"),
+        Ok(Err(e)) => {
+            error!("Server failed: {}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("Server task panicked: {}", e);
+            process::exit(1);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,181 @@
+use crate::connections::Connections;
+use crate::files::invalid_input;
+use crate::heartbeat::timestamp_us;
+use crate::message::{
+    room_event, server_message, JoinRoom, PostToRoom, RoomEvent, RoomJoined, ServerMessage,
+};
+use crate::router::Peer;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+// Messages a room keeps by default to replay to those who join.
+pub const DEFAULT_ROOM_HISTORY_LEN: usize = 50;
+
+#[derive(Debug, Default)]
+struct Room {
+    members: BTreeMap<u64, String>, // Names by connection id
+    history: VecDeque<RoomEvent>,   // The latest messages, oldest first
+}
+
+// Chat rooms that connections join to hear what their members send, such as consoles
+// broadcasting operator messages. A room exists while it has members, and is forgotten
+// along with its history once the last one leaves.
+#[derive(Debug, Clone)]
+pub(crate) struct Rooms {
+    connections: Connections,
+    history_len: usize,
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+}
+
+fn not_a_member(room: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Not a member of room {}", room),
+    )
+}
+
+impl Rooms {
+    // Rooms whose events are pushed through `connections`, each replaying up to
+    // `history_len` messages.
+    pub(crate) fn new(connections: Connections, history_len: usize) -> Self {
+        Rooms {
+            connections,
+            history_len,
+            rooms: Arc::default(),
+        }
+    }
+
+    // Add connection `id` to the room `join` names, under its name or else as `peer`.
+    // Joining a room again changes nothing.
+    pub(crate) fn join(&self, id: u64, peer: &Peer, join: JoinRoom) -> io::Result<RoomJoined> {
+        if join.room.is_empty() {
+            return Err(invalid_input("Joining a room without a name"));
+        }
+        let name = match join.name {
+            name if name.is_empty() => peer.to_string(),
+            name => name,
+        };
+
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(join.room.clone()).or_default();
+        if !room.members.contains_key(&id) {
+            if room.members.values().any(|member| *member == name) {
+                return Err(invalid_input(format!(
+                    "{} is already in room {}",
+                    name, join.room
+                )));
+            }
+            info!("{} joined room {}", name, join.room);
+            self.broadcast(&join.room, room, room_event::Kind::Joined, &name, "");
+            room.members.insert(id, name);
+        }
+
+        let mut members: Vec<_> = room.members.values().cloned().collect();
+        members.sort();
+        Ok(RoomJoined {
+            members,
+            history: room.history.iter().cloned().collect(),
+        })
+    }
+
+    // Take connection `id` out of `room`.
+    pub(crate) fn leave(&self, id: u64, room_name: &str) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .get_mut(room_name)
+            .ok_or_else(|| not_a_member(room_name))?;
+        let name = room
+            .members
+            .remove(&id)
+            .ok_or_else(|| not_a_member(room_name))?;
+        info!("{} left room {}", name, room_name);
+        self.broadcast(room_name, room, room_event::Kind::Left, &name, "");
+        if room.members.is_empty() {
+            rooms.remove(room_name);
+        }
+        Ok(())
+    }
+
+    // Take connection `id` out of every room, as it has closed.
+    pub(crate) fn leave_all(&self, id: u64) {
+        let joined: Vec<_> = {
+            let rooms = self.rooms.lock().unwrap();
+            rooms
+                .iter()
+                .filter(|(_, room)| room.members.contains_key(&id))
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        for room in joined {
+            let _ = self.leave(id, &room);
+        }
+    }
+
+    // Send a message from connection `id` to everyone in the room, and keep it for those
+    // who join later.
+    pub(crate) fn post(&self, id: u64, post: PostToRoom) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .get_mut(&post.room)
+            .ok_or_else(|| not_a_member(&post.room))?;
+        let name = room
+            .members
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| not_a_member(&post.room))?;
+
+        let event = self.broadcast(
+            &post.room,
+            room,
+            room_event::Kind::Message,
+            &name,
+            &post.text,
+        );
+        if self.history_len > 0 {
+            if room.history.len() >= self.history_len {
+                room.history.pop_front();
+            }
+            room.history.push_back(event);
+        }
+        Ok(())
+    }
+
+    // Push an event to every member of `room`, returning it.
+    fn broadcast(
+        &self,
+        room_name: &str,
+        room: &Room,
+        kind: room_event::Kind,
+        member: &str,
+        text: &str,
+    ) -> RoomEvent {
+        let event = RoomEvent {
+            room: room_name.to_string(),
+            kind: kind.into(),
+            member: member.to_string(),
+            text: text.to_string(),
+            timestamp_ms: timestamp_us() / 1000,
+        };
+        for (&id, name) in &room.members {
+            let message = ServerMessage {
+                message: Some(server_message::Message::RoomEvent(event.clone())),
+                ..ServerMessage::default()
+            };
+            if !self.connections.push(id, message) {
+                warn!(
+                    "{} in room {} is too far behind to be sent an event; closing it",
+                    name, room_name
+                );
+            }
+        }
+        debug!(
+            "Sent {:?} in room {} to {} member(s)",
+            kind,
+            room_name,
+            room.members.len()
+        );
+        event
+    }
+}
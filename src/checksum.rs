@@ -0,0 +1,63 @@
+use crate::codec::FrameError;
+use std::borrow::Cow;
+
+// Once checksums are negotiated, every frame payload ends with this many bytes: the CRC32C
+// of the rest of the payload, big-endian.
+pub const CHECKSUM_LEN: usize = 4;
+
+// Whether frame payloads carry a checksum on one connection, in both directions.
+//
+// Length-prefixed frames trust the transport, and prost decodes most corrupted payloads
+// into some message rather than failing. A checksum catches the corruption instead. It
+// covers the payload as sent, so after compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameChecksum {
+    enabled: bool,
+}
+
+impl FrameChecksum {
+    // Payloads go on the wire unchanged.
+    pub fn off() -> Self {
+        FrameChecksum { enabled: false }
+    }
+
+    // Every payload ends with its CRC32C if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        FrameChecksum { enabled }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // The payload to put in a frame for `payload`.
+    pub fn encode<'a>(&self, payload: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        if !self.enabled {
+            return payload;
+        }
+        let mut encoded = payload.into_owned();
+        encoded.extend_from_slice(&crc32c::crc32c(&encoded).to_be_bytes());
+        Cow::Owned(encoded)
+    }
+
+    // The payload of a received frame, once its checksum has been checked.
+    pub fn decode<'a>(&self, frame: &'a [u8]) -> Result<&'a [u8], FrameError> {
+        if !self.enabled {
+            return Ok(frame);
+        }
+        // A frame too short for a trailer lost bytes on the way.
+        let Some(split) = frame.len().checked_sub(CHECKSUM_LEN) else {
+            return Err(FrameError::Checksum {
+                expected: 0,
+                actual: crc32c::crc32c(frame),
+            });
+        };
+        let (payload, trailer) = frame.split_at(split);
+        let expected = u32::from_be_bytes(trailer.try_into().unwrap());
+        let actual = crc32c::crc32c(payload);
+        if expected != actual {
+            return Err(FrameError::Checksum { expected, actual });
+        }
+        Ok(payload)
+    }
+}
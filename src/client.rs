@@ -0,0 +1,615 @@
+use crate::checksum::FrameChecksum;
+use crate::codec::{encode_frame, FrameDecoder, DEFAULT_MAX_FRAME_LEN};
+use crate::compression::{Algorithm, FrameCompression};
+use crate::heartbeat::{timestamp_us, RoundTripTime};
+use crate::message::{
+    client_message, error, server_message, BatchRequest, ClientMessage, Delivery, Hello, JoinRoom,
+    KvChange, KvCompareAndSwap, KvDelete, KvEntries, KvEntry, KvGet, KvList, KvSet, KvSwapped,
+    KvUnwatch, KvWatch, LeaveRoom, Ping, Pong, PostToRoom, Publish, RoomEvent, RoomJoined,
+    ServerMessage, Subscribe, Unsubscribe,
+};
+use crate::metrics::Metrics;
+use crate::router::unexpected;
+use log::{info, warn};
+use prost::Message;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+// How long past a request's deadline to keep waiting for the server's reply, which has to
+// cross the network after the server gives up.
+pub const DEADLINE_GRACE: Duration = Duration::from_millis(500);
+
+// Asynchronous TCP client for the server protocol.
+pub struct Client {
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    desynchronized: bool,
+    rtt: RoundTripTime,
+    compression: FrameCompression,
+    checksum: FrameChecksum,
+    metrics: Metrics,
+    // Pushed by the server while waiting for something else
+    deliveries: VecDeque<Delivery>,
+    room_events: VecDeque<RoomEvent>,
+    kv_changes: VecDeque<KvChange>,
+}
+
+impl Client {
+    // Connect to the server at `addr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        info!("Connected to {}", stream.peer_addr()?);
+        Self::from_stream(stream)
+    }
+
+    // Use an already connected stream.
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            stream,
+            decoder: FrameDecoder::new(DEFAULT_MAX_FRAME_LEN),
+            desynchronized: false,
+            rtt: RoundTripTime::default(),
+            compression: FrameCompression::off(),
+            checksum: FrameChecksum::off(),
+            metrics: Metrics::default(),
+            deliveries: VecDeque::new(),
+            room_events: VecDeque::new(),
+            kv_changes: VecDeque::new(),
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    // True once a request timed out without any reply, or a frame from the server failed its
+    // checksum. Either way a reply may be taken for the answer to a later request, so the
+    // connection should be dropped.
+    pub fn is_desynchronized(&self) -> bool {
+        self.desynchronized
+    }
+
+    // Send a single request to the server.
+    pub async fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+        self.send_envelope(ClientMessage {
+            message: Some(message),
+            ..ClientMessage::default()
+        })
+        .await
+    }
+
+    async fn send_envelope(&mut self, request: ClientMessage) -> io::Result<()> {
+        if self.desynchronized {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Connection abandoned after losing track of a reply",
+            ));
+        }
+
+        let encoded = request.encode_to_vec();
+        let mut payload = Vec::new();
+        encode_frame(
+            &self.checksum.encode(self.compression.encode(&encoded)?),
+            DEFAULT_MAX_FRAME_LEN,
+            &mut payload,
+        )?;
+
+        self.stream.write_all(&payload).await?;
+        self.stream.flush().await
+    }
+
+    // Wait for the next message from the server. Heartbeats from the server are answered
+    // here rather than returned, so a connection stays alive while it is waiting.
+    pub async fn receive(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            let message = self.receive_frame().await?;
+            let Some(server_message::Message::Ping(ping)) = message.message else {
+                return Ok(message);
+            };
+
+            let pong = Pong {
+                timestamp_us: ping.timestamp_us,
+            };
+            self.send(client_message::Message::Pong(pong)).await?;
+        }
+    }
+
+    // Wait for the next message from the server, heartbeats included. This only reads, so
+    // unlike `receive` it can be cancelled, say by `select!`, without cutting a frame short.
+    pub(crate) async fn receive_frame(&mut self) -> io::Result<ServerMessage> {
+        let mut buffer = [0; 4096];
+        let frame = loop {
+            if let Some(frame) = self.decoder.decode()? {
+                break frame;
+            }
+
+            let bytes_read = self.stream.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Server disconnected",
+                ));
+            }
+            self.decoder.extend(&buffer[..bytes_read]);
+        };
+
+        // A corrupted frame could have been any message, a push as much as the reply being
+        // waited for, so which reply answers which request is no longer known.
+        let frame = self.checksum.decode(&frame).inspect_err(|e| {
+            warn!("Rejecting corrupted frame: {}", e);
+            self.metrics.record_integrity_error();
+            self.desynchronized = true;
+        })?;
+        let payload = self.compression.decode(frame)?;
+        ServerMessage::decode(payload.as_ref()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to decode ServerMessage: {}", e),
+            )
+        })
+    }
+
+    // Ask the server to compress frames with one of `offer`, preferred first. Outgoing frames
+    // of at least `threshold` bytes are compressed from then on. This has to be the first
+    // request on the connection. Returns the algorithm in use, if any.
+    pub async fn negotiate_compression(
+        &mut self,
+        offer: &[Algorithm],
+        threshold: usize,
+    ) -> io::Result<Option<Algorithm>> {
+        self.negotiate(offer, threshold, false).await?;
+        Ok(self.compression())
+    }
+
+    // Set up the connection with the server: compression as for `negotiate_compression`,
+    // and CRC32C checksums on every frame if `checksum` is set and the server allows them.
+    // This has to be the first request on the connection.
+    pub async fn negotiate(
+        &mut self,
+        offer: &[Algorithm],
+        threshold: usize,
+        checksum: bool,
+    ) -> io::Result<()> {
+        let hello = Hello {
+            compression: offer
+                .iter()
+                .map(|&algorithm| Algorithm::to_message(Some(algorithm)).into())
+                .collect(),
+            checksum,
+        };
+        let reply = match self.request(client_message::Message::Hello(hello)).await? {
+            server_message::Message::HelloReply(reply) => reply,
+            response => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected a HelloReply, got {:?}", response),
+                ))
+            }
+        };
+
+        let algorithm = Algorithm::from_message(reply.compression());
+        if algorithm.is_some_and(|algorithm| !offer.contains(&algorithm)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Server chose {:?}, which wasn't offered", algorithm),
+            ));
+        }
+        if reply.checksum && !checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Server turned on checksums, which weren't asked for",
+            ));
+        }
+        self.compression = FrameCompression::new(algorithm, threshold, DEFAULT_MAX_FRAME_LEN);
+        self.checksum = FrameChecksum::new(reply.checksum);
+        Ok(())
+    }
+
+    // The compression algorithm agreed with the server, if any.
+    pub fn compression(&self) -> Option<Algorithm> {
+        self.compression.algorithm()
+    }
+
+    // Whether frames carry checksums, as agreed with the server.
+    pub fn checksum(&self) -> bool {
+        self.checksum.is_enabled()
+    }
+
+    // Counters for this connection.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // Ping the server and return the round-trip time, which is also added to `rtt`.
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        let timestamp_us = timestamp_us();
+        let start = Instant::now();
+        match self
+            .request(client_message::Message::Ping(Ping { timestamp_us }))
+            .await?
+        {
+            server_message::Message::Pong(pong) if pong.timestamp_us == timestamp_us => {
+                let rtt = start.elapsed();
+                self.rtt.record(rtt);
+                Ok(rtt)
+            }
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected a Pong, got {:?}", response),
+            )),
+        }
+    }
+
+    // Round-trip times measured by `ping` on this connection.
+    pub fn rtt(&self) -> RoundTripTime {
+        self.rtt
+    }
+
+    // Send a request and wait for its response.
+    pub async fn request(
+        &mut self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        self.send(message).await?;
+        self.response().await
+    }
+
+    // Send a request that the server must answer within `timeout`, and wait for its
+    // response. Fails with `TimedOut` when the server reports that the deadline passed, or
+    // when no reply arrives at all, in which case the connection is desynchronized.
+    pub async fn request_with_timeout(
+        &mut self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        // Round up so a sub-millisecond timeout doesn't turn into "no deadline".
+        let deadline_ms = timeout
+            .as_micros()
+            .div_ceil(1000)
+            .clamp(1, u32::MAX as u128) as u32;
+        self.send_envelope(ClientMessage {
+            message: Some(message),
+            deadline_ms,
+            ..ClientMessage::default()
+        })
+        .await?;
+
+        let response = match time::timeout(timeout + DEADLINE_GRACE, self.response()).await {
+            Ok(response) => response?,
+            Err(_) => {
+                self.desynchronized = true;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No response from the server before the deadline",
+                ));
+            }
+        };
+
+        match response {
+            server_message::Message::Error(error)
+                if error.code() == error::Code::DeadlineExceeded =>
+            {
+                Err(io::Error::new(io::ErrorKind::TimedOut, error.message))
+            }
+            response => Ok(response),
+        }
+    }
+
+    // The next message that the server didn't push unasked.
+    async fn response(&mut self) -> io::Result<server_message::Message> {
+        loop {
+            let message = self.receive().await?.message.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Received an empty ServerMessage",
+                )
+            })?;
+            if let Some(message) = self.keep_pushed(message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    // Keep `message` for `next_delivery`, `next_room_event` or `next_kv_change` if the server
+    // pushed it unasked, or else hand it back.
+    fn keep_pushed(&mut self, message: server_message::Message) -> Option<server_message::Message> {
+        match message {
+            server_message::Message::Delivery(delivery) => self.deliveries.push_back(delivery),
+            server_message::Message::RoomEvent(event) => self.room_events.push_back(event),
+            server_message::Message::KvChange(change) => self.kv_changes.push_back(change),
+            message => return Some(message),
+        }
+        None
+    }
+
+    // Wait for the server to push something, which is kept for `next_delivery`,
+    // `next_room_event` or `next_kv_change`.
+    async fn receive_pushed(&mut self) -> io::Result<()> {
+        loop {
+            match self.receive().await?.message {
+                Some(message) => match self.keep_pushed(message) {
+                    None => return Ok(()),
+                    Some(message) => warn!("Ignoring unexpected {:?}", message),
+                },
+                None => warn!("Ignoring an empty ServerMessage"),
+            }
+        }
+    }
+
+    // Subscribe to the topics matching `pattern`, in which '+' stands for any one level and
+    // a last level of '#' for any number of them.
+    pub async fn subscribe(&mut self, pattern: &str) -> io::Result<()> {
+        let subscribe = Subscribe {
+            pattern: pattern.to_string(),
+        };
+        match self
+            .request(client_message::Message::Subscribe(subscribe))
+            .await?
+        {
+            server_message::Message::SubscribeAck(_) => Ok(()),
+            response => Err(unexpected("SubscribeAck", response)),
+        }
+    }
+
+    pub async fn unsubscribe(&mut self, pattern: &str) -> io::Result<()> {
+        let unsubscribe = Unsubscribe {
+            pattern: pattern.to_string(),
+        };
+        match self
+            .request(client_message::Message::Unsubscribe(unsubscribe))
+            .await?
+        {
+            server_message::Message::SubscribeAck(_) => Ok(()),
+            response => Err(unexpected("SubscribeAck", response)),
+        }
+    }
+
+    // Publish `payload` to `topic`, returning how many subscribers it was queued for.
+    pub async fn publish(&mut self, topic: &str, payload: Vec<u8>) -> io::Result<u32> {
+        let publish = Publish {
+            topic: topic.to_string(),
+            payload,
+        };
+        match self
+            .request(client_message::Message::Publish(publish))
+            .await?
+        {
+            server_message::Message::PublishAck(ack) => Ok(ack.subscribers),
+            response => Err(unexpected("PublishAck", response)),
+        }
+    }
+
+    // Wait for the next message published to a topic this connection is subscribed to,
+    // including those that arrived while waiting for a response.
+    pub async fn next_delivery(&mut self) -> io::Result<Delivery> {
+        loop {
+            if let Some(delivery) = self.deliveries.pop_front() {
+                return Ok(delivery);
+            }
+            self.receive_pushed().await?;
+        }
+    }
+
+    // Join `room` as `name`, or as this connection's address if it is empty. Returns the
+    // room's members and its latest messages.
+    pub async fn join_room(&mut self, room: &str, name: &str) -> io::Result<RoomJoined> {
+        let join = JoinRoom {
+            room: room.to_string(),
+            name: name.to_string(),
+        };
+        match self
+            .request(client_message::Message::JoinRoom(join))
+            .await?
+        {
+            server_message::Message::RoomJoined(joined) => Ok(joined),
+            response => Err(unexpected("RoomJoined", response)),
+        }
+    }
+
+    pub async fn leave_room(&mut self, room: &str) -> io::Result<()> {
+        let leave = LeaveRoom {
+            room: room.to_string(),
+        };
+        match self
+            .request(client_message::Message::LeaveRoom(leave))
+            .await?
+        {
+            server_message::Message::RoomAck(_) => Ok(()),
+            response => Err(unexpected("RoomAck", response)),
+        }
+    }
+
+    // Send `text` to everyone in `room`, which this connection has to be in.
+    pub async fn post_to_room(&mut self, room: &str, text: &str) -> io::Result<()> {
+        let post = PostToRoom {
+            room: room.to_string(),
+            text: text.to_string(),
+        };
+        match self
+            .request(client_message::Message::PostToRoom(post))
+            .await?
+        {
+            server_message::Message::RoomAck(_) => Ok(()),
+            response => Err(unexpected("RoomAck", response)),
+        }
+    }
+
+    // Wait for the next message, join or leave in a room this connection is in, including
+    // those that arrived while waiting for a response.
+    pub async fn next_room_event(&mut self) -> io::Result<RoomEvent> {
+        loop {
+            if let Some(event) = self.room_events.pop_front() {
+                return Ok(event);
+            }
+            self.receive_pushed().await?;
+        }
+    }
+
+    // The entry for `key` in the server's key-value store, if it has one.
+    pub async fn kv_get(&mut self, key: &str) -> io::Result<Option<KvEntry>> {
+        let get = KvGet {
+            key: key.to_string(),
+        };
+        match self.request(client_message::Message::KvGet(get)).await? {
+            server_message::Message::KvEntry(entry) => Ok(Some(entry)),
+            response => match unexpected("KvEntry", response) {
+                e if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                e => Err(e),
+            },
+        }
+    }
+
+    // Set `key` to `value`, to be removed after `ttl` if there is one.
+    pub async fn kv_set(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> io::Result<KvEntry> {
+        let set = KvSet {
+            key: key.to_string(),
+            value,
+            ttl_ms: ttl_ms(ttl),
+        };
+        match self.request(client_message::Message::KvSet(set)).await? {
+            server_message::Message::KvEntry(entry) => Ok(entry),
+            response => Err(unexpected("KvEntry", response)),
+        }
+    }
+
+    // Delete `key`, returning whether it existed.
+    pub async fn kv_delete(&mut self, key: &str) -> io::Result<bool> {
+        let delete = KvDelete {
+            key: key.to_string(),
+        };
+        match self
+            .request(client_message::Message::KvDelete(delete))
+            .await?
+        {
+            server_message::Message::KvDeleted(deleted) => Ok(deleted.existed),
+            response => Err(unexpected("KvDeleted", response)),
+        }
+    }
+
+    // Set `key` to `value` only if its version is still `version`, or if it doesn't exist
+    // when `version` is 0.
+    pub async fn kv_compare_and_swap(
+        &mut self,
+        key: &str,
+        version: u64,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> io::Result<KvSwapped> {
+        let swap = KvCompareAndSwap {
+            key: key.to_string(),
+            version,
+            value,
+            ttl_ms: ttl_ms(ttl),
+        };
+        match self
+            .request(client_message::Message::KvCompareAndSwap(swap))
+            .await?
+        {
+            server_message::Message::KvSwapped(swapped) => Ok(swapped),
+            response => Err(unexpected("KvSwapped", response)),
+        }
+    }
+
+    // The entries whose keys start with `prefix` and sort after `after`, as many as the
+    // server returns at once.
+    pub async fn kv_list(&mut self, prefix: &str, after: &str) -> io::Result<KvEntries> {
+        let list = KvList {
+            prefix: prefix.to_string(),
+            after: after.to_string(),
+            limit: 0,
+        };
+        match self.request(client_message::Message::KvList(list)).await? {
+            server_message::Message::KvEntries(entries) => Ok(entries),
+            response => Err(unexpected("KvEntries", response)),
+        }
+    }
+
+    // Have changes to `key` pushed to this connection, or with `prefix`, to every key
+    // starting with it.
+    pub async fn kv_watch(&mut self, key: &str, prefix: bool) -> io::Result<()> {
+        let watch = KvWatch {
+            key: key.to_string(),
+            prefix,
+        };
+        match self
+            .request(client_message::Message::KvWatch(watch))
+            .await?
+        {
+            server_message::Message::KvWatchAck(_) => Ok(()),
+            response => Err(unexpected("KvWatchAck", response)),
+        }
+    }
+
+    pub async fn kv_unwatch(&mut self, key: &str, prefix: bool) -> io::Result<()> {
+        let unwatch = KvUnwatch {
+            key: key.to_string(),
+            prefix,
+        };
+        match self
+            .request(client_message::Message::KvUnwatch(unwatch))
+            .await?
+        {
+            server_message::Message::KvWatchAck(_) => Ok(()),
+            response => Err(unexpected("KvWatchAck", response)),
+        }
+    }
+
+    // Wait for the next change to a watched key, including those that arrived while waiting
+    // for a response.
+    pub async fn next_kv_change(&mut self) -> io::Result<KvChange> {
+        loop {
+            if let Some(change) = self.kv_changes.pop_front() {
+                return Ok(change);
+            }
+            self.receive_pushed().await?;
+        }
+    }
+
+    // Send many requests in one frame and wait for the response to each, in the same order.
+    // A request that failed, or wasn't run because one before it failed, gets an Error.
+    pub async fn batch(&mut self, batch: BatchRequest) -> io::Result<Vec<server_message::Message>> {
+        let responses = match self
+            .request(client_message::Message::BatchRequest(batch))
+            .await?
+        {
+            server_message::Message::BatchResponse(batch) => batch.responses,
+            response => return Err(unexpected("BatchResponse", response)),
+        };
+        responses
+            .into_iter()
+            .map(|response| {
+                response.message.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Empty response in a batch")
+                })
+            })
+            .collect()
+    }
+
+    // Close the connection.
+    pub async fn disconnect(mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+// A time to live in the milliseconds KvSet takes, where 0 means none. Rounded up, so a short
+// one doesn't become none.
+fn ttl_ms(ttl: Option<Duration>) -> u64 {
+    ttl.map_or(0, |ttl| {
+        u64::try_from(ttl.as_micros().div_ceil(1000)).unwrap_or(u64::MAX)
+    })
+}
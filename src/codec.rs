@@ -0,0 +1,220 @@
+use log::warn;
+use std::fmt;
+
+use protocol_core::frame::{decode_header, encode_header};
+use protocol_core::serial::{decode_packet, max_encoded_len, DELIMITER};
+
+// Every message on the wire is a frame: a 4-byte big-endian payload length followed by the
+// protobuf-encoded payload. The layout is defined in protocol-core, shared with devices.
+pub use protocol_core::frame::HEADER_LEN;
+
+// Default upper bound on a frame payload. Anything larger is treated as a protocol error
+// rather than buffered.
+pub use protocol_core::frame::DEFAULT_MAX_FRAME_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    // The peer announced (or the caller tried to send) a payload above the limit.
+    TooLarge { len: usize, max: usize },
+    // The payload doesn't match its checksum, so it was corrupted on the way.
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+            FrameError::Checksum { expected, actual } => write!(
+                f,
+                "frame checksum {:08x} doesn't match its payload's {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// Append `payload` to `out` as a single frame.
+pub fn encode_frame(
+    payload: &[u8],
+    max_frame_len: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), FrameError> {
+    if payload.len() > max_frame_len || payload.len() > u32::MAX as usize {
+        return Err(FrameError::TooLarge {
+            len: payload.len(),
+            max: max_frame_len,
+        });
+    }
+
+    out.reserve(HEADER_LEN + payload.len());
+    out.extend_from_slice(&encode_header(payload.len() as u32));
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+// Append `payload` to `out` as a single serial packet: COBS-encoded with a CRC-16 and ended
+// by a zero byte, as described in `protocol_core::serial`.
+pub fn encode_packet(
+    payload: &[u8],
+    max_frame_len: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), FrameError> {
+    if payload.len() > max_frame_len {
+        return Err(FrameError::TooLarge {
+            len: payload.len(),
+            max: max_frame_len,
+        });
+    }
+
+    let start = out.len();
+    out.resize(start + max_encoded_len(payload.len()), 0);
+    let len = protocol_core::serial::encode_packet(payload, &mut out[start..])
+        .expect("The buffer was sized for the packet");
+    out.truncate(start + len);
+    Ok(())
+}
+
+// How frames are delimited on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    // Length-prefixed frames, for streams that deliver every byte intact, like TCP.
+    #[default]
+    Length,
+    // Serial packets (see `encode_packet`), for lines that can corrupt or drop bytes. A
+    // damaged packet is dropped and the decoder picks up again at the next one.
+    Serial,
+}
+
+impl Framing {
+    // Append `payload` to `out` as a single frame.
+    pub fn encode(
+        self,
+        payload: &[u8],
+        max_frame_len: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), FrameError> {
+        match self {
+            Framing::Length => encode_frame(payload, max_frame_len, out),
+            Framing::Serial => encode_packet(payload, max_frame_len, out),
+        }
+    }
+
+    pub fn decoder(self, max_frame_len: usize) -> FrameDecoder {
+        FrameDecoder {
+            framing: self,
+            ..FrameDecoder::new(max_frame_len)
+        }
+    }
+}
+
+// Incremental frame decoder that doesn't do any I/O itself: feed it whatever bytes arrive with
+// `extend` and pull complete frames out with `decode`.
+//
+// The decoder never buffers more than one maximum-sized frame plus whatever was fed to it
+// since the last `decode` call, and rejects an oversized frame as soon as its header arrives.
+// With serial framing an oversized packet is dropped instead, like a damaged one.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_len: usize,
+    framing: Framing,
+    discarding: bool, // Skipping the rest of an oversized serial packet
+}
+
+impl FrameDecoder {
+    // A decoder for length-prefixed frames; see `Framing::decoder` for the others.
+    pub fn new(max_frame_len: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            max_frame_len,
+            framing: Framing::Length,
+            discarding: false,
+        }
+    }
+
+    // Add bytes received from the transport.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // Bytes received but not yet returned as part of a frame.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Take the next complete frame payload, or `None` if more bytes are needed.
+    //
+    // After an error the stream can't be resynchronised and should be closed. Serial framing
+    // never fails, as it can always resynchronise.
+    pub fn decode(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.framing == Framing::Serial {
+            return Ok(self.decode_packet());
+        }
+
+        let Some(header) = self.buffer.first_chunk::<HEADER_LEN>() else {
+            return Ok(None);
+        };
+
+        let len = decode_header(*header);
+        if len > self.max_frame_len {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buffer.drain(..HEADER_LEN + len);
+        Ok(Some(payload))
+    }
+
+    fn decode_packet(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let Some(end) = self.buffer.iter().position(|&byte| byte == DELIMITER) else {
+                if self.buffer.len() >= max_encoded_len(self.max_frame_len) {
+                    if !self.discarding {
+                        warn!(
+                            "Dropping serial packet over the {} byte limit",
+                            self.max_frame_len
+                        );
+                    }
+                    self.buffer.clear();
+                    self.discarding = true;
+                }
+                return None;
+            };
+
+            let mut packet: Vec<u8> = self.buffer.drain(..=end).collect();
+            packet.pop();
+            // Empty packets are only there to flush line noise
+            if std::mem::take(&mut self.discarding) || packet.is_empty() {
+                continue;
+            }
+            match decode_packet(&mut packet) {
+                Ok(len) if len <= self.max_frame_len => {
+                    packet.truncate(len);
+                    return Some(packet);
+                }
+                Ok(len) => warn!(
+                    "Dropping serial packet of {} bytes, over the {} byte limit",
+                    len, self.max_frame_len
+                ),
+                Err(e) => warn!("Dropping serial packet: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::codec::FrameError;
+use crate::message;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io;
+
+// Frames smaller than this aren't worth compressing by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+// Once compression is negotiated, every frame payload starts with one of these.
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1; // Followed by the 4-byte big-endian uncompressed length
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    // Better ratio, for slow links.
+    Zstd,
+    // Much faster, for when CPU is the scarcer resource.
+    Lz4,
+}
+
+impl Algorithm {
+    pub fn from_message(compression: message::Compression) -> Option<Self> {
+        match compression {
+            message::Compression::None => None,
+            message::Compression::Zstd => Some(Algorithm::Zstd),
+            message::Compression::Lz4 => Some(Algorithm::Lz4),
+        }
+    }
+
+    pub fn to_message(algorithm: Option<Self>) -> message::Compression {
+        match algorithm {
+            None => message::Compression::None,
+            Some(Algorithm::Zstd) => message::Compression::Zstd,
+            Some(Algorithm::Lz4) => message::Compression::Lz4,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Algorithm::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Algorithm::Lz4 => Ok(lz4_flex::block::compress(data)),
+        }
+    }
+
+    // Decompress `data`, failing rather than producing more than `len` bytes.
+    fn decompress(self, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        let decompressed = match self {
+            Algorithm::Zstd => zstd::bulk::decompress(data, len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Algorithm::Lz4 => lz4_flex::block::decompress(data, len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        if decompressed.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Compressed frame decompressed to {} bytes instead of {}",
+                    decompressed.len(),
+                    len
+                ),
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+// How frame payloads are compressed on one connection, in both directions.
+//
+// Before negotiation (or if the peers agreed on none) payloads go on the wire unchanged.
+// Afterwards each one is prefixed by a byte saying whether it is compressed, so small frames
+// and ones that don't shrink can still be sent as they are.
+#[derive(Debug, Clone)]
+pub struct FrameCompression {
+    algorithm: Option<Algorithm>,
+    threshold: usize,
+    max_len: usize,
+}
+
+impl FrameCompression {
+    // No compression, and no prefix byte.
+    pub fn off() -> Self {
+        FrameCompression {
+            algorithm: None,
+            threshold: usize::MAX,
+            max_len: usize::MAX,
+        }
+    }
+
+    // Compress payloads of at least `threshold` bytes with `algorithm`. A compressed payload
+    // may not expand to more than `max_len` bytes, which guards against decompression bombs.
+    pub fn new(algorithm: Option<Algorithm>, threshold: usize, max_len: usize) -> Self {
+        match algorithm {
+            Some(_) => FrameCompression {
+                algorithm,
+                threshold,
+                max_len,
+            },
+            None => Self::off(),
+        }
+    }
+
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        self.algorithm
+    }
+
+    // The payload to put in a frame for `payload`.
+    pub fn encode<'a>(&self, payload: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let Some(algorithm) = self.algorithm else {
+            return Ok(Cow::Borrowed(payload));
+        };
+        if payload.len() > self.max_len || payload.len() > u32::MAX as usize {
+            return Err(FrameError::TooLarge {
+                len: payload.len(),
+                max: self.max_len,
+            }
+            .into());
+        }
+
+        if payload.len() >= self.threshold {
+            let compressed = algorithm.compress(payload)?;
+            if compressed.len() + 4 < payload.len() {
+                let mut encoded = Vec::with_capacity(5 + compressed.len());
+                encoded.push(COMPRESSED);
+                encoded.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                encoded.extend_from_slice(&compressed);
+                return Ok(Cow::Owned(encoded));
+            }
+        }
+
+        let mut encoded = Vec::with_capacity(1 + payload.len());
+        encoded.push(RAW);
+        encoded.extend_from_slice(payload);
+        Ok(Cow::Owned(encoded))
+    }
+
+    // The original payload of a received frame.
+    pub fn decode<'a>(&self, frame: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let Some(algorithm) = self.algorithm else {
+            return Ok(Cow::Borrowed(frame));
+        };
+
+        match frame.split_first() {
+            Some((&RAW, payload)) => Ok(Cow::Borrowed(payload)),
+            Some((&COMPRESSED, rest)) if rest.len() >= 4 => {
+                let (len, compressed) = rest.split_at(4);
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                if len > self.max_len {
+                    return Err(FrameError::TooLarge {
+                        len,
+                        max: self.max_len,
+                    }
+                    .into());
+                }
+                Ok(Cow::Owned(algorithm.decompress(compressed, len)?))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame without a valid compression prefix",
+            )),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::chat::DEFAULT_ROOM_HISTORY_LEN;
+use crate::codec::DEFAULT_MAX_FRAME_LEN;
+use crate::compression::{Algorithm, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::pubsub::{SlowSubscriber, DEFAULT_SUBSCRIBER_QUEUE_LEN};
+use crate::router::DEFAULT_BATCH_CONCURRENCY;
+use crate::serial::DEFAULT_BAUD_RATE;
+use crate::wal::{SyncPolicy, WalOptions};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Runtime configuration for the server, loaded from a TOML file.
+//
+// Every field is optional in the file; missing keys fall back to the defaults
+// below so an empty file is a valid configuration.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Address the listener binds to. Only read at startup, a reload cannot move the listener.
+    pub address: String,
+    // Maximum log level emitted by the process.
+    pub log_level: LevelFilter,
+    // Maximum number of concurrently connected TCP clients; extra connections are refused.
+    // Serial ports don't count against it.
+    pub max_connections: usize,
+    // Largest request or response payload in bytes; bigger frames close the connection.
+    pub max_frame_len: usize,
+    // Requests with a request_id one connection may have running at once. As many again may
+    // wait their turn; beyond that the server stops reading from the connection.
+    pub max_pipelined_requests: usize,
+    // Requests of one batch that run at once, unless the batch asks for them to run in
+    // turn. Only read at startup.
+    pub max_batch_concurrency: usize,
+    // A connection quiet for this long is sent a Ping. 0 turns heartbeats off.
+    pub heartbeat_interval_ms: u64,
+    // How long a pinged connection has to send anything before it is closed as dead.
+    pub heartbeat_timeout_ms: u64,
+    // Compression algorithms clients may choose from. Empty turns compression off.
+    pub compression: Vec<Algorithm>,
+    // Frames smaller than this many bytes are sent uncompressed.
+    pub compression_threshold: usize,
+    // Whether clients may ask for a CRC32C checksum on every frame.
+    pub checksum: bool,
+    // How long shutdown waits for connected clients to finish before aborting them.
+    pub shutdown_timeout_ms: u64,
+    // Directory clients upload files to and download them from. File transfer is off
+    // without one. Only read at startup.
+    pub storage_root: Option<PathBuf>,
+    // Directory of firmware images and the manifest.toml describing them. Firmware updates
+    // are off without one. Only read at startup.
+    pub firmware_dir: Option<PathBuf>,
+    // Serial ports devices are attached to, each served like a client connection. Only read
+    // at startup.
+    pub serial_ports: Vec<SerialPortConfig>,
+    // How long device telemetry is kept, by the time each reading was taken. 0 turns
+    // telemetry off. Only read at startup.
+    pub telemetry_retention_ms: u64,
+    // Published messages waiting to be sent to one subscriber before it counts as slow.
+    pub subscriber_queue_len: usize,
+    // What happens to messages for a slow subscriber: "drop_oldest", "disconnect" or "block".
+    pub slow_subscriber: SlowSubscriber,
+    // Messages each chat room keeps to replay to those who join. Only read at startup.
+    pub room_history_len: usize,
+    // Directory the key-value store, device registry and telemetry are kept in, so they
+    // survive restarts. They are only kept in memory without one. Only read at startup.
+    pub state_dir: Option<PathBuf>,
+    // When their write-ahead logs are flushed to disk: "always" after every change,
+    // "interval" every wal_sync_interval_ms, or "never", leaving it to the operating system.
+    // Only read at startup.
+    pub wal_sync: SyncPolicy,
+    pub wal_sync_interval_ms: u64,
+    // How often each store is snapshotted and its log emptied. Only read at startup.
+    pub snapshot_interval_ms: u64,
+}
+
+// A serial port to serve, in raw 8N1 mode.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SerialPortConfig {
+    pub path: String,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    // Whether the device is pinged like a TCP client, and the port reopened when it doesn't
+    // answer. Off by default, as a device may stay quiet for longer than a TCP client would.
+    #[serde(default)]
+    pub heartbeat: bool,
+}
+
+fn default_baud_rate() -> u32 {
+    DEFAULT_BAUD_RATE
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1:5000".to_string(),
+            log_level: LevelFilter::Info,
+            max_connections: 1024,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            max_pipelined_requests: 32,
+            max_batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            heartbeat_interval_ms: 30_000,
+            heartbeat_timeout_ms: 10_000,
+            compression: vec![Algorithm::Zstd, Algorithm::Lz4],
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            checksum: true,
+            shutdown_timeout_ms: 5000,
+            storage_root: None,
+            firmware_dir: None,
+            serial_ports: Vec::new(),
+            telemetry_retention_ms: 24 * 60 * 60 * 1000,
+            subscriber_queue_len: DEFAULT_SUBSCRIBER_QUEUE_LEN,
+            slow_subscriber: SlowSubscriber::DropOldest,
+            room_history_len: DEFAULT_ROOM_HISTORY_LEN,
+            state_dir: None,
+            wal_sync: SyncPolicy::Interval,
+            wal_sync_interval_ms: 1000,
+            snapshot_interval_ms: 60_000,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Read and parse a configuration file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    // Parse a configuration from TOML source.
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let config: ServerConfig = toml::from_str(contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        if config.max_connections == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "max_connections must be greater than zero",
+            ));
+        }
+        if config.max_frame_len == 0 || config.max_frame_len > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "max_frame_len must be between 1 and 4294967295",
+            ));
+        }
+
+        if config.max_pipelined_requests == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "max_pipelined_requests must be greater than zero",
+            ));
+        }
+
+        if config.max_batch_concurrency == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "max_batch_concurrency must be greater than zero",
+            ));
+        }
+
+        if config.subscriber_queue_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "subscriber_queue_len must be greater than zero",
+            ));
+        }
+
+        if config.serial_ports.iter().any(|port| port.baud_rate == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "baud_rate must be greater than zero",
+            ));
+        }
+
+        if config.wal_sync == SyncPolicy::Interval && config.wal_sync_interval_ms == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wal_sync_interval_ms must be greater than zero when wal_sync is \"interval\"",
+            ));
+        }
+
+        if config.snapshot_interval_ms == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot_interval_ms must be greater than zero",
+            ));
+        }
+
+        if config.heartbeat_interval_ms > 0 && config.heartbeat_timeout_ms == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "heartbeat_timeout_ms must be greater than zero when heartbeats are on",
+            ));
+        }
+
+        Ok(config)
+    }
+
+    // The heartbeat interval and timeout, or `None` if heartbeats are off.
+    pub fn heartbeat(&self) -> Option<(Duration, Duration)> {
+        (self.heartbeat_interval_ms > 0).then(|| {
+            (
+                Duration::from_millis(self.heartbeat_interval_ms),
+                Duration::from_millis(self.heartbeat_timeout_ms),
+            )
+        })
+    }
+
+    // How long telemetry is kept, or `None` if telemetry is off.
+    pub fn telemetry_retention(&self) -> Option<Duration> {
+        (self.telemetry_retention_ms > 0)
+            .then(|| Duration::from_millis(self.telemetry_retention_ms))
+    }
+
+    // How the stores under `state_dir` keep their write-ahead logs.
+    pub fn wal_options(&self) -> WalOptions {
+        WalOptions {
+            sync: self.wal_sync,
+            sync_interval: Duration::from_millis(self.wal_sync_interval_ms),
+            snapshot_interval: Duration::from_millis(self.snapshot_interval_ms),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+}
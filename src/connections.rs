@@ -0,0 +1,76 @@
+use crate::message::ServerMessage;
+use crate::router::Peer;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Messages that may wait to be pushed to one connection. A connection that falls further
+// behind is closed once it has sent what is queued, rather than holding up whoever pushes or
+// silently missing messages. Its client can reconnect, then rejoin its rooms and read again
+// the keys it watches.
+pub const PUSH_QUEUE_LEN: usize = 256;
+
+#[derive(Debug)]
+struct Connection {
+    peer: Peer,
+    pushed: Option<mpsc::Sender<ServerMessage>>, // Taken once the connection falls behind
+}
+
+// The connections a server has open, each with a queue of messages the server pushes to it
+// unasked.
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    open: Arc<Mutex<BTreeMap<u64, Connection>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Connections {
+    // Track a new connection to `peer`. Returns its id, and where the messages pushed to it
+    // arrive.
+    pub(crate) fn open(&self, peer: Peer) -> (u64, mpsc::Receiver<ServerMessage>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (pushed, receiver) = mpsc::channel(PUSH_QUEUE_LEN);
+        let mut open = self.open.lock().unwrap();
+        let pushed = Some(pushed);
+        open.insert(id, Connection { peer, pushed });
+        (id, receiver)
+    }
+
+    pub(crate) fn close(&self, id: u64) {
+        self.open.lock().unwrap().remove(&id);
+    }
+
+    // Queue `message` for connection `id`. Returns false if it is closed or too far behind,
+    // in which case nothing more is pushed to it and it closes after what is already queued.
+    pub(crate) fn push(&self, id: u64, message: ServerMessage) -> bool {
+        let mut open = self.open.lock().unwrap();
+        let Some(connection) = open.get_mut(&id) else {
+            return false;
+        };
+        let pushed = connection
+            .pushed
+            .as_ref()
+            .is_some_and(|pushed| pushed.try_send(message).is_ok());
+        if !pushed {
+            connection.pushed = None;
+        }
+        pushed
+    }
+
+    // Every open connection's peer, in the order they connected.
+    pub fn peers(&self) -> Vec<Peer> {
+        let open = self.open.lock().unwrap();
+        open.values()
+            .map(|connection| connection.peer.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,434 @@
+use crate::message::{
+    client_message, server_message, DownloadRequest, FileStatus, FileStatusRequest, UploadRequest,
+};
+use crate::pipelined::PipelinedClient;
+use crate::router::{error_reply, unexpected, Context, MessageKind, Router};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+
+// Suffix of a file whose upload or download hasn't finished. Stored files can't have it, so
+// the server's partial uploads can't be read or overwritten by name.
+const PARTIAL_SUFFIX: &str = ".part";
+
+// Files stored under one directory and served to clients. Paths from clients are relative
+// to the root and may not leave it, whether by `..`, an absolute path or a symlink.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+    uploading: Arc<Mutex<HashSet<PathBuf>>>, // Files with an upload running, by path
+}
+
+// An upload's claim on its file, so no other upload writes the same partial file meanwhile.
+// Released when dropped.
+struct Uploading<'a> {
+    uploading: &'a Mutex<HashSet<PathBuf>>,
+    path: PathBuf,
+}
+
+impl Drop for Uploading<'_> {
+    fn drop(&mut self) {
+        self.uploading.lock().unwrap().remove(&self.path);
+    }
+}
+
+impl FileStore {
+    // Serve the files under `root`, creating it if needed.
+    pub fn open(root: impl AsRef<Path>) -> io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Ok(FileStore {
+            root: std::fs::canonicalize(root)?,
+            uploading: Arc::default(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Register the FileStatus, Upload and Download handlers on `router`.
+    pub fn routes(self, router: Router) -> Router {
+        let store = Arc::new(self);
+        let handler = move |ctx, request| {
+            let store = Arc::clone(&store);
+            async move { store.handle(ctx, request).await }
+        };
+        router
+            .route(MessageKind::FileStatus, handler.clone())
+            .route(MessageKind::Upload, handler.clone())
+            .route(MessageKind::Download, handler)
+    }
+
+    async fn handle(
+        &self,
+        ctx: Context,
+        request: client_message::Message,
+    ) -> Option<server_message::Message> {
+        let result = match request {
+            client_message::Message::FileStatus(request) => self
+                .status(&request.path)
+                .await
+                .map(|status| Some(server_message::Message::FileStatus(status))),
+            client_message::Message::Upload(request) => self
+                .upload(&ctx, request)
+                .await
+                .map(|status| Some(server_message::Message::FileStatus(status))),
+            client_message::Message::Download(request) => {
+                self.download(&ctx, request).await.map(|()| None)
+            }
+            _ => return None,
+        };
+
+        result.unwrap_or_else(|e| {
+            warn!("File request from {} failed: {}", ctx.peer(), e);
+            Some(error_reply(&e))
+        })
+    }
+
+    async fn status(&self, path: &str) -> io::Result<FileStatus> {
+        let path = self.resolve(path)?;
+        let mut status = FileStatus::default();
+        match self.open_file(&path).await {
+            Ok(file) => {
+                let (size, sha256) = hash(file).await?;
+                status.exists = true;
+                status.size = size;
+                status.sha256 = sha256;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Ok(metadata) = fs::symlink_metadata(partial(&path)).await {
+            status.uploaded = metadata.len();
+        }
+        Ok(status)
+    }
+
+    // Write the body to the partial file, and move it into place once it is complete and
+    // matches its checksum. If the body is cut off, what arrived stays for a resumed upload.
+    async fn upload(&self, ctx: &Context, request: UploadRequest) -> io::Result<FileStatus> {
+        if request.sha256.len() != Sha256::output_size() {
+            return Err(invalid_input("sha256 must be 32 bytes"));
+        }
+        let path = self.resolve(&request.path)?;
+        let mut body = ctx
+            .take_body()
+            .ok_or_else(|| invalid_input("Upload without a streamed body"))?;
+
+        let name = path
+            .file_name()
+            .expect("Resolved paths are inside the root");
+        let path = self.create_parent(&path).await?.join(name);
+        let _uploading = self.start_upload(&path, &request.path)?;
+
+        let partial = partial(&path);
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        // A symlink left in place of the partial file mustn't send the upload elsewhere.
+        #[cfg(unix)]
+        options.custom_flags(libc::O_NOFOLLOW);
+        let mut file = options.open(&partial).await?;
+        let uploaded = file.metadata().await?.len();
+        if request.offset > uploaded {
+            return Err(invalid_input(format!(
+                "Can't resume at {}, only {} bytes were uploaded",
+                request.offset, uploaded
+            )));
+        }
+        file.set_len(request.offset).await?;
+        file.seek(SeekFrom::Start(request.offset)).await?;
+        tokio::io::copy(&mut body, &mut file).await?;
+        file.flush().await?;
+        file.seek(SeekFrom::Start(0)).await?;
+
+        let (size, sha256) = hash(file).await?;
+        if sha256 != request.sha256 {
+            fs::remove_file(&partial).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Upload of {} doesn't match its checksum", request.path),
+            ));
+        }
+        fs::rename(&partial, &path).await?;
+        info!("Stored {} ({} bytes)", request.path, size);
+
+        Ok(FileStatus {
+            exists: true,
+            size,
+            sha256,
+            uploaded: 0,
+        })
+    }
+
+    async fn download(&self, ctx: &Context, request: DownloadRequest) -> io::Result<()> {
+        let path = self.resolve(&request.path)?;
+        let file = self.open_file(&path).await?;
+        send_file(ctx, file, request.offset).await?;
+        info!("Sent {} from {}", request.path, request.offset);
+        Ok(())
+    }
+
+    // Where a client's path points, if it is a plain relative path.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(path);
+        let valid = !path.is_empty()
+            && !path.contains(['\0', '\\'])
+            && !path.ends_with(PARTIAL_SUFFIX)
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !valid {
+            return Err(invalid_input(format!("Invalid path {:?}", path)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    // Create the directories leading to `path` one at a time, checking each is inside the root
+    // before creating anything in it, and return the last one with symlinks resolved.
+    async fn create_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        let parent = path.parent().expect("Resolved paths are inside the root");
+        let relative = parent
+            .strip_prefix(&self.root)
+            .expect("Resolved paths are inside the root");
+        let mut dir = self.root.clone();
+        for component in relative.components() {
+            let next = dir.join(component);
+            match fs::create_dir(&next).await {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            }
+            dir = fs::canonicalize(&next).await?;
+            if !dir.starts_with(&self.root) {
+                return Err(invalid_input("Path leads outside the storage root"));
+            }
+        }
+        Ok(dir)
+    }
+
+    // Claim `path` for an upload, failing with `ResourceBusy` if another upload has it.
+    fn start_upload(&self, path: &Path, name: &str) -> io::Result<Uploading<'_>> {
+        if !self.uploading.lock().unwrap().insert(path.to_path_buf()) {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("{} is already being uploaded", name),
+            ));
+        }
+        Ok(Uploading {
+            uploading: &self.uploading,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Open a stored file, following symlinks only as far as they stay inside the root.
+    async fn open_file(&self, path: &Path) -> io::Result<File> {
+        self.check_inside(path.parent().expect("Resolved paths are inside the root"))
+            .await?;
+        self.check_inside(path).await?;
+        if !fs::metadata(path).await?.is_file() {
+            return Err(invalid_input("Not a file"));
+        }
+        File::open(path).await
+    }
+
+    // Check that `path` is inside the root once symlinks are resolved. If it doesn't exist,
+    // the nearest directory above it that does is checked instead.
+    async fn check_inside(&self, path: &Path) -> io::Result<()> {
+        let mut existing = path;
+        let resolved = loop {
+            match fs::canonicalize(existing).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && existing != self.root => {
+                    existing = existing
+                        .parent()
+                        .expect("Resolved paths are inside the root");
+                }
+                result => break result?,
+            }
+        };
+        if !resolved.starts_with(&self.root) {
+            return Err(invalid_input("Path leads outside the storage root"));
+        }
+        Ok(())
+    }
+}
+
+// Uploads and downloads files through a server's `FileStore`. Transfers resume where an
+// earlier attempt stopped, and every file is checked against its SHA-256 at the end.
+#[derive(Clone)]
+pub struct FileTransfer {
+    client: PipelinedClient,
+}
+
+impl FileTransfer {
+    pub fn new(client: PipelinedClient) -> Self {
+        FileTransfer { client }
+    }
+
+    pub async fn status(&self, remote: &str) -> io::Result<FileStatus> {
+        let request = client_message::Message::FileStatus(FileStatusRequest {
+            path: remote.to_string(),
+        });
+        match self.client.request(request).await? {
+            server_message::Message::FileStatus(status) => Ok(status),
+            response => Err(unexpected("FileStatus", response)),
+        }
+    }
+
+    // Upload `local` as `remote`, resuming an unfinished upload of it. If what the server
+    // already had turns out to be from a different file, the upload starts over.
+    pub async fn upload(&self, local: impl AsRef<Path>, remote: &str) -> io::Result<FileStatus> {
+        let local = local.as_ref();
+        let (size, sha256) = hash(File::open(local).await?).await?;
+        let uploaded = self.status(remote).await?.uploaded;
+
+        let offset = if uploaded <= size { uploaded } else { 0 };
+        if offset > 0 {
+            info!("Resuming upload of {} at {} bytes", remote, offset);
+        }
+        match self.upload_from(local, remote, offset, &sha256).await {
+            Err(e) if offset > 0 && e.kind() == io::ErrorKind::InvalidData => {
+                warn!("Resumed upload of {} was corrupt, starting over", remote);
+                self.upload_from(local, remote, 0, &sha256).await
+            }
+            result => result,
+        }
+    }
+
+    async fn upload_from(
+        &self,
+        local: &Path,
+        remote: &str,
+        offset: u64,
+        sha256: &[u8],
+    ) -> io::Result<FileStatus> {
+        let mut file = File::open(local).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let request = client_message::Message::Upload(UploadRequest {
+            path: remote.to_string(),
+            offset,
+            sha256: sha256.to_vec(),
+        });
+        match self.client.upload(request, file).await? {
+            server_message::Message::FileStatus(status) => Ok(status),
+            response => Err(unexpected("FileStatus", response)),
+        }
+    }
+
+    // Download `remote` to `local`, by way of `local` with `.part` appended. A partial file
+    // left by an earlier attempt is resumed, and discarded if the result doesn't match.
+    pub async fn download(&self, remote: &str, local: impl AsRef<Path>) -> io::Result<FileStatus> {
+        let local = local.as_ref();
+        let status = self.status(remote).await?;
+        if !status.exists {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't exist on the server", remote),
+            ));
+        }
+
+        download_resumable(&self.client, local, status.size, &status.sha256, |offset| {
+            client_message::Message::Download(DownloadRequest {
+                path: remote.to_string(),
+                offset,
+            })
+        })
+        .await?;
+        Ok(status)
+    }
+}
+
+// Download a stream of `size` bytes to `local`, by way of `local` with `.part` appended.
+// `request` asks for the stream from an offset, so a partial file left by an earlier attempt
+// is resumed. The result must have the SHA-256 `sha256`, or the partial file is discarded.
+pub(crate) async fn download_resumable(
+    client: &PipelinedClient,
+    local: &Path,
+    size: u64,
+    sha256: &[u8],
+    request: impl FnOnce(u64) -> client_message::Message,
+) -> io::Result<()> {
+    let partial = partial(local);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&partial)
+        .await?;
+    let mut offset = file.metadata().await?.len();
+    if offset > size {
+        offset = 0;
+        file.set_len(0).await?;
+    }
+    if offset > 0 {
+        info!(
+            "Resuming download of {} at {} bytes",
+            local.display(),
+            offset
+        );
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut stream = client.download(request(offset)).await?;
+    tokio::io::copy(&mut stream, &mut file).await?;
+    file.flush().await?;
+    drop(file);
+
+    let (_, downloaded) = hash(File::open(&partial).await?).await?;
+    if downloaded != sha256 {
+        fs::remove_file(&partial).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Download to {} doesn't match its checksum", local.display()),
+        ));
+    }
+    fs::rename(&partial, local).await
+}
+
+// Stream `file` from `offset` as the response to the request in `ctx`.
+pub(crate) async fn send_file(ctx: &Context, mut file: File, offset: u64) -> io::Result<()> {
+    let size = file.metadata().await?.len();
+    if offset > size {
+        return Err(invalid_input(format!(
+            "Offset {} is past the end of the file ({} bytes)",
+            offset, size
+        )));
+    }
+    let mut response = ctx
+        .response_stream()
+        .ok_or_else(|| invalid_input("Downloads need a request_id to stream on"))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    response.send_from(file).await?;
+    response.finish().await
+}
+
+// The size and SHA-256 of everything `reader` produces.
+pub async fn hash(mut reader: impl AsyncRead + Unpin) -> io::Result<(u64, Vec<u8>)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok((size, hasher.finalize().to_vec()));
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+}
+
+// Where a download to `path` is written until it completes.
+pub(crate) fn partial(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+pub(crate) fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
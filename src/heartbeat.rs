@@ -0,0 +1,31 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The current time as carried by `Ping` and `Pong`: microseconds since the Unix epoch.
+pub fn timestamp_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// Round-trip times measured with pings on one connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoundTripTime {
+    // The most recent measurement.
+    pub latest: Option<Duration>,
+    // Moving average that gives each new measurement a weight of 1/8, like TCP's SRTT.
+    pub smoothed: Option<Duration>,
+    // The fastest round trip so far, a floor set by the network path.
+    pub min: Option<Duration>,
+}
+
+impl RoundTripTime {
+    pub fn record(&mut self, rtt: Duration) {
+        self.latest = Some(rtt);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+    }
+}
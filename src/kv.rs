@@ -0,0 +1,545 @@
+use crate::connections::Connections;
+use crate::files::invalid_input;
+use crate::heartbeat::timestamp_us;
+use crate::message::{
+    client_message, kv_change, kv_record, server_message, KvChange, KvCompareAndSwap, KvDeleted,
+    KvEntries, KvEntry, KvList, KvRecord, KvSet, KvSnapshot, KvSwapped, KvUnwatch, KvWatch,
+    ServerMessage,
+};
+use crate::router::{error_reply, Context, MessageKind, Router};
+use crate::wal::{Mark, Wal, WalOptions};
+use log::{debug, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+// The most entries one KvEntries holds, which keeps replies well inside the default frame
+// limit for small values.
+pub const MAX_ENTRIES_PER_LIST: usize = 500;
+
+// The most keys the store holds. Any peer can add keys, so writes of new keys are refused
+// once it is full.
+pub const MAX_KEYS: usize = 100_000;
+
+// The most watches one connection can have at once.
+pub const MAX_WATCHES_PER_CONNECTION: usize = 100;
+
+// How long the expiry task sleeps when no key has a TTL.
+const IDLE_EXPIRY_CHECK: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: BTreeMap<String, KvEntry>,
+    expiring: BTreeSet<(u64, String)>, // Keys with a TTL by when they expire
+    version: u64,                      // Of the latest write
+}
+
+impl Entries {
+    // The entry for `key`, unless it has expired by `now_ms`.
+    fn live(&self, key: &str, now_ms: u64) -> Option<&KvEntry> {
+        self.entries
+            .get(key)
+            .filter(|entry| !expired(entry, now_ms))
+    }
+
+    // The entry the next write of `value` to `key` makes.
+    fn next(&self, key: String, value: Vec<u8>, ttl_ms: u64, now_ms: u64) -> KvEntry {
+        KvEntry {
+            key,
+            value,
+            version: self.version + 1,
+            expires_ms: match ttl_ms {
+                0 => 0,
+                ttl_ms => now_ms.saturating_add(ttl_ms),
+            },
+        }
+    }
+
+    fn put(&mut self, entry: KvEntry) {
+        self.remove(&entry.key);
+        self.version = self.version.max(entry.version);
+        if entry.expires_ms > 0 {
+            self.expiring.insert((entry.expires_ms, entry.key.clone()));
+        }
+        self.entries.insert(entry.key.clone(), entry);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<KvEntry> {
+        let entry = self.entries.remove(key)?;
+        if entry.expires_ms > 0 {
+            self.expiring.remove(&(entry.expires_ms, entry.key.clone()));
+        }
+        Some(entry)
+    }
+
+    // Remove the keys that expired by `now_ms`, returning them.
+    fn expire(&mut self, now_ms: u64) -> Vec<KvEntry> {
+        let mut expired = Vec::new();
+        while let Some((expires_ms, key)) = self.expiring.first().cloned() {
+            if expires_ms > now_ms {
+                break;
+            }
+            expired.extend(self.remove(&key));
+        }
+        expired
+    }
+}
+
+fn expired(entry: &KvEntry, now_ms: u64) -> bool {
+    entry.expires_ms != 0 && entry.expires_ms <= now_ms
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Watch {
+    key: String,
+    prefix: bool,
+}
+
+// Who watches what, indexed so a write only looks up the watches that could cover its key.
+#[derive(Debug, Default)]
+struct Watches {
+    keys: HashMap<String, BTreeSet<u64>>, // Connections watching each key
+    prefixes: HashMap<String, BTreeSet<u64>>, // Connections watching every key under each prefix
+    connections: HashMap<u64, HashSet<Watch>>, // The watches of each connection
+}
+
+impl Watches {
+    fn add(&mut self, connection: u64, watch: Watch) -> io::Result<()> {
+        let watching = self.connections.entry(connection).or_default();
+        if watching.contains(&watch) {
+            return Ok(());
+        }
+        if watching.len() >= MAX_WATCHES_PER_CONNECTION {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "Connection already has the most watches, {}",
+                    MAX_WATCHES_PER_CONNECTION
+                ),
+            ));
+        }
+        watching.insert(Watch {
+            key: watch.key.clone(),
+            prefix: watch.prefix,
+        });
+        self.index(watch.prefix)
+            .entry(watch.key)
+            .or_default()
+            .insert(connection);
+        Ok(())
+    }
+
+    // Remove `watch` of `connection`, returning whether it had it.
+    fn remove(&mut self, connection: u64, watch: &Watch) -> bool {
+        let Some(watching) = self.connections.get_mut(&connection) else {
+            return false;
+        };
+        if !watching.remove(watch) {
+            return false;
+        }
+        if watching.is_empty() {
+            self.connections.remove(&connection);
+        }
+        self.unindex(connection, watch);
+        true
+    }
+
+    fn remove_all(&mut self, connection: u64) {
+        for watch in self.connections.remove(&connection).unwrap_or_default() {
+            self.unindex(connection, &watch);
+        }
+    }
+
+    fn index(&mut self, prefix: bool) -> &mut HashMap<String, BTreeSet<u64>> {
+        if prefix {
+            &mut self.prefixes
+        } else {
+            &mut self.keys
+        }
+    }
+
+    fn unindex(&mut self, connection: u64, watch: &Watch) {
+        let index = self.index(watch.prefix);
+        if let Some(watchers) = index.get_mut(&watch.key) {
+            watchers.remove(&connection);
+            if watchers.is_empty() {
+                index.remove(&watch.key);
+            }
+        }
+    }
+
+    // The connections watching `key`, each once however many of its watches cover it.
+    fn watchers(&self, key: &str) -> BTreeSet<u64> {
+        let mut watchers = self.keys.get(key).cloned().unwrap_or_default();
+        if !self.prefixes.is_empty() {
+            let prefixes = key.char_indices().map(|(at, _)| &key[..at]).chain([key]);
+            for prefix in prefixes {
+                watchers.extend(self.prefixes.get(prefix).into_iter().flatten());
+            }
+        }
+        watchers
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: RwLock<Entries>,
+    watches: Mutex<Watches>,
+    connections: Connections, // Where changes to watched keys are pushed
+    wal: Option<Arc<Wal>>,    // Where changes are logged, if the store is kept on disk
+}
+
+// A key-value store that devices share configuration and state through, in memory.
+//
+// Keys can expire after a time to live. Every write gets a new version, which a
+// compare-and-swap checks to update a key only if nobody else did since it was read.
+// Connections can watch keys and have every change pushed to them. The store can be kept
+// on disk, with a write-ahead log, to survive restarts.
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    inner: Arc<Inner>,
+    expiry: Arc<Notify>, // Wakes the expiry task when a key may expire sooner than it knows
+}
+
+impl KvStore {
+    // An empty store, pushing changes to watched keys through `connections`. Must be
+    // called within a Tokio runtime, which expires keys in the background.
+    pub fn new(connections: Connections) -> Self {
+        Self::with_entries(connections, Entries::default(), None)
+    }
+
+    // A store kept in `dir`, holding what it held when the server last stopped.
+    pub fn open(connections: Connections, dir: &Path, options: WalOptions) -> io::Result<Self> {
+        let (wal, recovered) = Wal::open::<KvSnapshot, KvRecord>(dir, options)?;
+        let mut entries = Entries::default();
+        if let Some(snapshot) = recovered.snapshot {
+            entries.version = snapshot.version;
+            for entry in snapshot.entries {
+                entries.put(entry);
+            }
+        }
+        for record in recovered.records {
+            match record.change {
+                Some(kv_record::Change::Set(entry)) => entries.put(entry),
+                Some(kv_record::Change::Deleted(key)) => {
+                    entries.remove(&key);
+                }
+                None => {}
+            }
+        }
+
+        let wal = Arc::new(wal);
+        let store = Self::with_entries(connections, entries, Some(Arc::clone(&wal)));
+        wal.maintain(Arc::downgrade(&store.inner), Inner::copy_for_snapshot);
+        Ok(store)
+    }
+
+    fn with_entries(connections: Connections, entries: Entries, wal: Option<Arc<Wal>>) -> Self {
+        let store = KvStore {
+            inner: Arc::new(Inner {
+                entries: RwLock::new(entries),
+                watches: Mutex::default(),
+                connections,
+                wal,
+            }),
+            expiry: Arc::default(),
+        };
+        tokio::spawn(expire_keys(
+            Arc::downgrade(&store.inner),
+            Arc::clone(&store.expiry),
+        ));
+        store
+    }
+
+    // The entry for `key`, if it exists.
+    pub fn get(&self, key: &str) -> Option<KvEntry> {
+        let entries = self.inner.entries.read().unwrap();
+        entries.live(key, now_ms()).cloned()
+    }
+
+    pub fn set(&self, set: KvSet) -> io::Result<KvEntry> {
+        check_key(&set.key)?;
+        let now_ms = now_ms();
+        let mut entries = self.write(now_ms);
+        let entry = entries.next(set.key, set.value, set.ttl_ms, now_ms);
+        self.put(&mut entries, entry.clone())?;
+        Ok(entry)
+    }
+
+    // Delete `key`, returning whether it existed.
+    pub fn delete(&self, key: &str) -> io::Result<bool> {
+        let mut entries = self.write(now_ms());
+        if !entries.entries.contains_key(key) {
+            return Ok(false);
+        }
+        self.inner
+            .log(kv_record::Change::Deleted(key.to_string()))?;
+        if let Some(entry) = entries.remove(key) {
+            self.changed(kv_change::Kind::Deleted, &entry);
+        }
+        Ok(true)
+    }
+
+    pub fn compare_and_swap(&self, swap: KvCompareAndSwap) -> io::Result<KvSwapped> {
+        check_key(&swap.key)?;
+        let now_ms = now_ms();
+        let mut entries = self.write(now_ms);
+        let current = entries.entries.get(&swap.key);
+        if current.map_or(0, |entry| entry.version) != swap.version {
+            return Ok(KvSwapped {
+                swapped: false,
+                current: current.cloned(),
+            });
+        }
+
+        let entry = entries.next(swap.key, swap.value, swap.ttl_ms, now_ms);
+        self.put(&mut entries, entry.clone())?;
+        Ok(KvSwapped {
+            swapped: true,
+            current: Some(entry),
+        })
+    }
+
+    // The entries matching `list`, at most `MAX_ENTRIES_PER_LIST` of them.
+    pub fn list(&self, list: &KvList) -> KvEntries {
+        let limit = match list.limit as usize {
+            0 => MAX_ENTRIES_PER_LIST,
+            limit => limit.min(MAX_ENTRIES_PER_LIST),
+        };
+        let start = if list.after.as_str() >= list.prefix.as_str() {
+            Bound::Excluded(list.after.as_str())
+        } else {
+            Bound::Included(list.prefix.as_str())
+        };
+
+        let now_ms = now_ms();
+        let entries = self.inner.entries.read().unwrap();
+        let mut matching = entries
+            .entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&list.prefix))
+            .map(|(_, entry)| entry)
+            .filter(|entry| !expired(entry, now_ms));
+        let found: Vec<_> = matching.by_ref().take(limit).cloned().collect();
+        KvEntries {
+            entries: found,
+            truncated: matching.next().is_some(),
+        }
+    }
+
+    // Push changes to what `watch` asks for to connection `connection`, which can have at
+    // most `MAX_WATCHES_PER_CONNECTION` watches.
+    pub(crate) fn watch(&self, connection: u64, watch: KvWatch) -> io::Result<()> {
+        if watch.key.is_empty() && !watch.prefix {
+            return Err(invalid_input("Watching without a key"));
+        }
+        let watch = Watch {
+            key: watch.key,
+            prefix: watch.prefix,
+        };
+        let mut watches = self.inner.watches.lock().unwrap();
+        watches.add(connection, watch)
+    }
+
+    pub(crate) fn unwatch(&self, connection: u64, unwatch: KvUnwatch) -> io::Result<()> {
+        let watch = Watch {
+            key: unwatch.key,
+            prefix: unwatch.prefix,
+        };
+        let mut watches = self.inner.watches.lock().unwrap();
+        if !watches.remove(connection, &watch) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Not watching {}", watch.key),
+            ));
+        }
+        Ok(())
+    }
+
+    // Drop every watch of a connection that closed.
+    pub(crate) fn unwatch_all(&self, connection: u64) {
+        let mut watches = self.inner.watches.lock().unwrap();
+        watches.remove_all(connection);
+    }
+
+    // Register the KvGet, KvSet, KvDelete, KvCompareAndSwap and KvList handlers on
+    // `router`. Watches are handled by the connection, which they belong to.
+    pub fn routes(&self, router: Router) -> Router {
+        let store = self.clone();
+        let handler = move |ctx, request| {
+            let store = store.clone();
+            async move { store.handle(ctx, request) }
+        };
+        router
+            .route(MessageKind::KvGet, handler.clone())
+            .route(MessageKind::KvSet, handler.clone())
+            .route(MessageKind::KvDelete, handler.clone())
+            .route(MessageKind::KvCompareAndSwap, handler.clone())
+            .route(MessageKind::KvList, handler)
+    }
+
+    fn handle(
+        &self,
+        ctx: Context,
+        request: client_message::Message,
+    ) -> Option<server_message::Message> {
+        let result = match request {
+            client_message::Message::KvGet(get) => self
+                .get(&get.key)
+                .map(server_message::Message::KvEntry)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("No key {}", get.key))
+                }),
+            client_message::Message::KvSet(set) => {
+                self.set(set).map(server_message::Message::KvEntry)
+            }
+            client_message::Message::KvDelete(delete) => self
+                .delete(&delete.key)
+                .map(|existed| server_message::Message::KvDeleted(KvDeleted { existed })),
+            client_message::Message::KvCompareAndSwap(swap) => self
+                .compare_and_swap(swap)
+                .map(server_message::Message::KvSwapped),
+            client_message::Message::KvList(list) => {
+                Ok(server_message::Message::KvEntries(self.list(&list)))
+            }
+            _ => return None,
+        };
+
+        Some(match result {
+            Ok(reply) => reply,
+            // A missing key is an answer, not a failure worth a warning.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => error_reply(&e),
+            Err(e) => {
+                warn!("Key-value request from {} failed: {}", ctx.peer(), e);
+                error_reply(&e)
+            }
+        })
+    }
+
+    // Log and make a write of `entry`, telling whoever watches its key. A new key is refused
+    // once the store holds `MAX_KEYS`.
+    fn put(&self, entries: &mut Entries, entry: KvEntry) -> io::Result<()> {
+        if entries.entries.len() >= MAX_KEYS && !entries.entries.contains_key(&entry.key) {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("Store is full, with {} keys", MAX_KEYS),
+            ));
+        }
+        self.inner.log(kv_record::Change::Set(entry.clone()))?;
+        self.changed(kv_change::Kind::Set, &entry);
+        if entry.expires_ms > 0 {
+            self.expiry.notify_one();
+        }
+        entries.put(entry);
+        Ok(())
+    }
+
+    // Lock the entries for writing, once the keys due to expire by `now_ms` are gone.
+    fn write(&self, now_ms: u64) -> std::sync::RwLockWriteGuard<'_, Entries> {
+        let mut entries = self.inner.entries.write().unwrap();
+        for entry in entries.expire(now_ms) {
+            self.changed(kv_change::Kind::Expired, &entry);
+        }
+        entries
+    }
+
+    // Tell the connections watching `entry`'s key. Called with the entries locked, so
+    // changes are pushed in the order they were made.
+    fn changed(&self, kind: kv_change::Kind, entry: &KvEntry) {
+        self.inner.changed(kind, entry);
+    }
+}
+
+impl Inner {
+    // Record `change` in the write-ahead log, if there is one, before it is made.
+    fn log(&self, change: kv_record::Change) -> io::Result<()> {
+        match &self.wal {
+            Some(wal) => wal.append(&KvRecord {
+                change: Some(change),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Copy every entry that hasn't expired for a snapshot, and mark the log they were copied
+    // at, holding off writes meanwhile.
+    fn copy_for_snapshot(&self, wal: &Wal) -> (KvSnapshot, Mark) {
+        let entries = self.entries.read().unwrap();
+        let now_ms = now_ms();
+        let snapshot = KvSnapshot {
+            entries: entries
+                .entries
+                .values()
+                .filter(|entry| !expired(entry, now_ms))
+                .cloned()
+                .collect(),
+            version: entries.version,
+        };
+        (snapshot, wal.mark())
+    }
+
+    fn changed(&self, kind: kv_change::Kind, entry: &KvEntry) {
+        let watchers = self.watches.lock().unwrap().watchers(&entry.key);
+        for connection in watchers {
+            let change = KvChange {
+                kind: kind.into(),
+                entry: Some(entry.clone()),
+            };
+            let message = ServerMessage {
+                message: Some(server_message::Message::KvChange(change)),
+                ..ServerMessage::default()
+            };
+            if !self.connections.push(connection, message) {
+                warn!(
+                    "Connection watching {} is too far behind to be sent a change; closing it",
+                    entry.key
+                );
+            }
+        }
+    }
+}
+
+fn check_key(key: &str) -> io::Result<()> {
+    if key.is_empty() {
+        return Err(invalid_input("Key-value request without a key"));
+    }
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    timestamp_us() / 1000
+}
+
+// Remove keys as they expire, telling whoever watches them, until the store is dropped.
+async fn expire_keys(inner: Weak<Inner>, expiry: Arc<Notify>) {
+    loop {
+        let next_ms = {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            let now_ms = now_ms();
+            let mut entries = inner.entries.write().unwrap();
+            let expired = entries.expire(now_ms);
+            if !expired.is_empty() {
+                debug!("Expired {} key(s)", expired.len());
+            }
+            for entry in &expired {
+                inner.changed(kv_change::Kind::Expired, entry);
+            }
+            entries.expiring.first().map(|(expires_ms, _)| *expires_ms)
+        };
+
+        let wait = match next_ms {
+            Some(next_ms) => Duration::from_millis(next_ms.saturating_sub(now_ms())),
+            None => IDLE_EXPIRY_CHECK,
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => {}
+            _ = expiry.notified() => {}
+        }
+    }
+}
@@ -1,4 +1,30 @@
+pub mod balancer;
+pub mod chat;
+pub mod checksum;
+pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod connections;
+pub mod files;
+pub mod heartbeat;
+pub mod kv;
+pub mod metrics;
+pub mod ota;
+pub mod pipelined;
+pub mod pool;
+pub mod pubsub;
+pub mod reconnect;
+pub mod registry;
+pub mod router;
+pub mod serial;
 pub mod server;
+pub mod stream;
+pub mod telemetry;
+pub mod wal;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
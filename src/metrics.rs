@@ -0,0 +1,55 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters kept by a server across all its connections, or by a client for its own.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    integrity_errors: AtomicU64,
+    dropped_deliveries: AtomicU64,
+    slow_subscribers: AtomicU64,
+}
+
+// The counters at one moment, for reports and logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    // Frames rejected because they didn't match their checksum.
+    pub integrity_errors: u64,
+    // Published messages dropped because a subscriber fell behind.
+    pub dropped_deliveries: u64,
+    // Subscribers disconnected because they fell behind.
+    pub slow_subscribers: u64,
+}
+
+impl Metrics {
+    pub fn record_integrity_error(&self) {
+        self.integrity_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn integrity_errors(&self) -> u64 {
+        self.integrity_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn record_dropped_delivery(&self) {
+        self.dropped_deliveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_deliveries(&self) -> u64 {
+        self.dropped_deliveries.load(Ordering::Relaxed)
+    }
+
+    pub fn record_slow_subscriber(&self) {
+        self.slow_subscribers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_subscribers(&self) -> u64 {
+        self.slow_subscribers.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            integrity_errors: self.integrity_errors(),
+            dropped_deliveries: self.dropped_deliveries(),
+            slow_subscribers: self.slow_subscribers(),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::files::{download_resumable, invalid_input, send_file};
+use crate::message::{
+    client_message, server_message, FirmwareDownload, FirmwareInfo, FirmwareQuery, InstallReport,
+    InstallReportAck, InstallStatus,
+};
+use crate::pipelined::PipelinedClient;
+use crate::router::{error_reply, unexpected, Context, MessageKind, Router};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+
+// The manifest in a firmware directory, listing its images:
+//
+//     [[images]]
+//     model = "sensor-v2"
+//     version = "1.4.0"
+//     file = "sensor-v2-1.4.0.bin"
+//     signature = "<hex Ed25519 signature of the model, version and image's SHA-256>"
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Manifest {
+    images: Vec<ManifestImage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestImage {
+    model: String,
+    version: String,
+    file: String,
+    signature: String,
+}
+
+// A dotted numeric version like 1.4.0, compared number by number so 1.10 is newer than 1.9.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(Vec<u64>);
+
+impl Version {
+    pub fn parse(version: &str) -> Option<Self> {
+        version
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<_>>()
+            .map(Version)
+    }
+}
+
+// Devices whose latest install report is kept. Reports come from anyone who connects, so the
+// oldest device's is forgotten to make room for a new one.
+pub const MAX_INSTALL_REPORTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Image {
+    model: String,
+    version: Version,
+    info: FirmwareInfo,
+    path: PathBuf,
+}
+
+// Firmware images served to devices from a directory with a `manifest.toml`, and the
+// install reports devices send back.
+//
+// The server doesn't hold the signing key or check signatures; it hands out the ones in the
+// manifest, and devices check them against the public key they trust.
+#[derive(Debug, Clone)]
+pub struct FirmwareStore {
+    images: Arc<Vec<Image>>,
+    reports: Arc<Mutex<Reports>>,
+}
+
+// The latest install report from each device, and the devices in the order they first
+// reported.
+#[derive(Debug, Default)]
+struct Reports {
+    latest: HashMap<String, InstallReport>,
+    order: VecDeque<String>,
+}
+
+impl Reports {
+    fn insert(&mut self, report: InstallReport) {
+        if let Some(latest) = self.latest.get_mut(&report.device_id) {
+            *latest = report;
+            return;
+        }
+        if self.latest.len() >= MAX_INSTALL_REPORTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.latest.remove(&oldest);
+            }
+        }
+        self.order.push_back(report.device_id.clone());
+        self.latest.insert(report.device_id.clone(), report);
+    }
+}
+
+impl FirmwareStore {
+    // Load the manifest in `dir` and checksum every image it lists.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let manifest = fs::read_to_string(dir.join("manifest.toml"))?;
+        let manifest: Manifest = toml::from_str(&manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let mut images: Vec<Image> = Vec::new();
+        for entry in manifest.images {
+            let image = load_image(dir, entry)?;
+            if images
+                .iter()
+                .any(|other| other.model == image.model && other.version == image.version)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} {} is listed twice in the manifest",
+                        image.model, image.info.version
+                    ),
+                ));
+            }
+            info!(
+                "Serving firmware {} {} ({} bytes)",
+                image.model, image.info.version, image.info.size
+            );
+            images.push(image);
+        }
+
+        Ok(FirmwareStore {
+            images: Arc::new(images),
+            reports: Arc::default(),
+        })
+    }
+
+    // The newest image for `model`.
+    pub fn latest(&self, model: &str) -> Option<FirmwareInfo> {
+        self.images
+            .iter()
+            .filter(|image| image.model == model)
+            .max_by(|a, b| a.version.cmp(&b.version))
+            .map(|image| image.info.clone())
+    }
+
+    // The last install report from `device_id`.
+    pub fn install_report(&self, device_id: &str) -> Option<InstallReport> {
+        self.reports.lock().unwrap().latest.get(device_id).cloned()
+    }
+
+    // Register the FirmwareQuery, FirmwareDownload and InstallReport handlers on `router`.
+    pub fn routes(&self, router: Router) -> Router {
+        let store = self.clone();
+        let handler = move |ctx, request| {
+            let store = store.clone();
+            async move { store.handle(ctx, request).await }
+        };
+        router
+            .route(MessageKind::FirmwareQuery, handler.clone())
+            .route(MessageKind::FirmwareDownload, handler.clone())
+            .route(MessageKind::InstallReport, handler)
+    }
+
+    async fn handle(
+        &self,
+        ctx: Context,
+        request: client_message::Message,
+    ) -> Option<server_message::Message> {
+        let result = match request {
+            client_message::Message::FirmwareQuery(query) => Ok(Some(
+                server_message::Message::FirmwareInfo(self.query(&query)),
+            )),
+            client_message::Message::FirmwareDownload(request) => {
+                self.download(&ctx, request).await.map(|()| None)
+            }
+            client_message::Message::InstallReport(report) => self.report(&ctx, report).map(|()| {
+                Some(server_message::Message::InstallReportAck(
+                    InstallReportAck {},
+                ))
+            }),
+            _ => return None,
+        };
+
+        result.unwrap_or_else(|e| {
+            warn!("Firmware request from {} failed: {}", ctx.peer(), e);
+            Some(error_reply(&e))
+        })
+    }
+
+    fn query(&self, query: &FirmwareQuery) -> FirmwareInfo {
+        let current = Version::parse(&query.current_version);
+        match self.latest(&query.model) {
+            // A device running something unrecognisable is offered the latest image.
+            Some(latest) if current.is_none() || current < Version::parse(&latest.version) => {
+                latest
+            }
+            _ => FirmwareInfo::default(),
+        }
+    }
+
+    async fn download(&self, ctx: &Context, request: FirmwareDownload) -> io::Result<()> {
+        let image = self
+            .images
+            .iter()
+            .find(|image| image.model == request.model && image.info.version == request.version)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No firmware {} {}", request.model, request.version),
+                )
+            })?;
+
+        send_file(ctx, File::open(&image.path).await?, request.offset).await?;
+        info!(
+            "Sent firmware {} {} to {} from {}",
+            request.model,
+            request.version,
+            ctx.peer(),
+            request.offset
+        );
+        Ok(())
+    }
+
+    fn report(&self, ctx: &Context, report: InstallReport) -> io::Result<()> {
+        if report.device_id.is_empty() {
+            return Err(invalid_input("Install report without a device_id"));
+        }
+        let log = match report.status() {
+            InstallStatus::Failed => log::Level::Warn,
+            _ => log::Level::Info,
+        };
+        log::log!(
+            log,
+            "Device {} at {}: {} {} {:?} {}",
+            report.device_id,
+            ctx.peer(),
+            report.model,
+            report.version,
+            report.status(),
+            report.detail
+        );
+        self.reports.lock().unwrap().insert(report);
+        Ok(())
+    }
+}
+
+fn load_image(dir: &Path, entry: ManifestImage) -> io::Result<Image> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let name = format!("{} {}", entry.model, entry.version);
+
+    let version = Version::parse(&entry.version)
+        .ok_or_else(|| invalid(format!("{}: version must be dotted numbers", name)))?;
+    let mut components = Path::new(&entry.file).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(invalid(format!(
+            "{}: file must be in the firmware directory",
+            name
+        )));
+    }
+    let signature = hex::decode(&entry.signature)
+        .ok()
+        .filter(|signature| signature.len() == Signature::BYTE_SIZE)
+        .ok_or_else(|| invalid(format!("{}: signature must be 64 hex-encoded bytes", name)))?;
+
+    let path = dir.join(&entry.file);
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+
+    Ok(Image {
+        model: entry.model,
+        version,
+        info: FirmwareInfo {
+            available: true,
+            version: entry.version,
+            size,
+            sha256: hasher.finalize().to_vec(),
+            signature,
+        },
+        path,
+    })
+}
+
+// The hex-encoded manifest signature of `image` as `version` for `model`: an Ed25519
+// signature of the model, version and image's SHA-256, so a signed image can't be listed as
+// another version or for another model.
+pub fn sign_image(key: &SigningKey, model: &str, version: &str, image: &[u8]) -> String {
+    let message = signed_message(model, version, &Sha256::digest(image));
+    hex::encode(key.sign(&message).to_bytes())
+}
+
+// What an image's signature covers. The model and version are each preceded by their length,
+// so no two pairs of them give the same bytes.
+fn signed_message(model: &str, version: &str, sha256: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + model.len() + version.len() + sha256.len());
+    for field in [model, version] {
+        message.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        message.extend_from_slice(field.as_bytes());
+    }
+    message.extend_from_slice(sha256);
+    message
+}
+
+// Checks for, downloads and reports on firmware updates for a device, trusting only images
+// signed by the key matching `public_key`.
+#[derive(Clone)]
+pub struct FirmwareUpdater {
+    client: PipelinedClient,
+    public_key: VerifyingKey,
+}
+
+impl FirmwareUpdater {
+    pub fn new(client: PipelinedClient, public_key: VerifyingKey) -> Self {
+        FirmwareUpdater { client, public_key }
+    }
+
+    // The newest image for `model` if it is newer than `current_version`. Fails with
+    // `InvalidData` if the server offers an image with a bad signature, or one older than
+    // `current_version`.
+    pub async fn check(
+        &self,
+        model: &str,
+        current_version: &str,
+    ) -> io::Result<Option<FirmwareInfo>> {
+        let query = client_message::Message::FirmwareQuery(FirmwareQuery {
+            model: model.to_string(),
+            current_version: current_version.to_string(),
+        });
+        let info = match self.client.request(query).await? {
+            server_message::Message::FirmwareInfo(info) => info,
+            response => return Err(unexpected("FirmwareInfo", response)),
+        };
+        if !info.available {
+            return Ok(None);
+        }
+        self.verify(model, current_version, &info)?;
+        Ok(Some(info))
+    }
+
+    // Download the image `info` describes to `local`, resuming a partial download left there
+    // by an earlier attempt. The image is only moved into place once it matches the signed
+    // checksum, and never if it is older than `current_version`.
+    pub async fn download(
+        &self,
+        model: &str,
+        current_version: &str,
+        info: &FirmwareInfo,
+        local: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        self.verify(model, current_version, info)?;
+        download_resumable(
+            &self.client,
+            local.as_ref(),
+            info.size,
+            &info.sha256,
+            |offset| {
+                client_message::Message::FirmwareDownload(FirmwareDownload {
+                    model: model.to_string(),
+                    version: info.version.clone(),
+                    offset,
+                })
+            },
+        )
+        .await
+    }
+
+    // Tell the server how installing an image went.
+    pub async fn report(&self, report: InstallReport) -> io::Result<()> {
+        match self
+            .client
+            .request(client_message::Message::InstallReport(report))
+            .await?
+        {
+            server_message::Message::InstallReportAck(_) => Ok(()),
+            response => Err(unexpected("InstallReportAck", response)),
+        }
+    }
+
+    // Check that `info` was signed as `model` firmware of its version, and that the version
+    // isn't older than `current_version` where both can be compared.
+    fn verify(&self, model: &str, current_version: &str, info: &FirmwareInfo) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let message = signed_message(model, &info.version, &info.sha256);
+        Signature::from_slice(&info.signature)
+            .and_then(|signature| self.public_key.verify_strict(&message, &signature))
+            .map_err(|_| {
+                invalid(format!(
+                    "Firmware {} {} has an invalid signature",
+                    model, info.version
+                ))
+            })?;
+
+        let version = Version::parse(&info.version);
+        match Version::parse(current_version) {
+            Some(current) if version.is_none_or(|version| version < current) => {
+                Err(invalid(format!(
+                    "Firmware {} {} is older than the running {}",
+                    model, info.version, current_version
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,504 @@
+use crate::client::DEADLINE_GRACE;
+use crate::codec::{Framing, DEFAULT_MAX_FRAME_LEN};
+use crate::heartbeat::{timestamp_us, RoundTripTime};
+use crate::message::{
+    client_message, error, server_message, Cancel, ClientMessage, Ping, Pong, ServerMessage,
+    StreamCredit,
+};
+use crate::router::error_from_reply;
+use crate::serial::{self, Transport};
+use crate::stream::{chunk_channel, initial_credit, ByteStream, ChunkResult, ChunkSink};
+use log::{debug, warn};
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::time;
+
+// Where the answer to a request goes.
+enum Reply {
+    Message(oneshot::Sender<io::Result<server_message::Message>>),
+    // A streamed response, ended by its last chunk or by any other message.
+    Stream(mpsc::Sender<ChunkResult>),
+}
+
+enum Command {
+    // A request, and the credit for its streamed body if it has one.
+    Request(ClientMessage, Reply, Option<Arc<Semaphore>>),
+    Cancel(u64),
+    // A chunk or credit for the streams of an outstanding request.
+    Send(ClientMessage),
+}
+
+// A client that can have many requests in flight on one connection.
+//
+// Every request gets an id, so the server may work on them at the same time and answer in
+// any order. Dropping a request's future before it completes sends a `Cancel`, and the
+// server aborts the handler. Bodies and responses too large for one frame can be streamed
+// in chunks, with the slower side setting the pace. Heartbeats from the server are answered
+// in the background. Handles are cheap to clone and share the connection, which closes once
+// every handle is dropped.
+#[derive(Clone)]
+pub struct PipelinedClient {
+    commands: mpsc::UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+    rtt: Arc<Mutex<RoundTripTime>>,
+}
+
+impl PipelinedClient {
+    // Connect to the server at `addr`. Must be called within a Tokio runtime.
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self::with_transport(stream, Framing::Length))
+    }
+
+    // Talk to a server through the serial port at `path`. Must be called within a Tokio
+    // runtime.
+    pub fn open_serial(path: &str, baud_rate: u32) -> io::Result<Self> {
+        let stream = serial::open(path, baud_rate)?;
+        Ok(Self::with_transport(stream, Framing::Serial))
+    }
+
+    // Talk to a server over an already open byte stream, such as one end of a
+    // pseudo-terminal. Must be called within a Tokio runtime.
+    pub fn with_transport(stream: impl Transport, framing: Framing) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(Box::new(stream), framing, receiver));
+        PipelinedClient {
+            commands,
+            next_id: Arc::new(AtomicU64::new(1)),
+            rtt: Arc::default(),
+        }
+    }
+
+    // Send a request and wait for its response.
+    pub async fn request(
+        &self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        self.send(ClientMessage {
+            message: Some(message),
+            ..ClientMessage::default()
+        })
+        .await
+    }
+
+    // Send a request that the server must answer within `timeout`, and wait for its
+    // response. Fails with `TimedOut` when the deadline passes.
+    pub async fn request_with_timeout(
+        &self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        let deadline_ms = timeout
+            .as_micros()
+            .div_ceil(1000)
+            .clamp(1, u32::MAX as u128) as u32;
+        let request = ClientMessage {
+            message: Some(message),
+            deadline_ms,
+            ..ClientMessage::default()
+        };
+
+        // Dropping the request on a local timeout cancels it on the server too.
+        let response = match time::timeout(timeout + DEADLINE_GRACE, self.send(request)).await {
+            Ok(response) => response?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No response from the server before the deadline",
+                ))
+            }
+        };
+
+        match response {
+            server_message::Message::Error(error)
+                if error.code() == error::Code::DeadlineExceeded =>
+            {
+                Err(io::Error::new(io::ErrorKind::TimedOut, error.message))
+            }
+            response => Ok(response),
+        }
+    }
+
+    // Ping the server and return the round-trip time, which is also added to `rtt`.
+    pub async fn ping(&self) -> io::Result<Duration> {
+        let timestamp_us = timestamp_us();
+        let start = Instant::now();
+        match self
+            .request(client_message::Message::Ping(Ping { timestamp_us }))
+            .await?
+        {
+            server_message::Message::Pong(pong) if pong.timestamp_us == timestamp_us => {
+                let rtt = start.elapsed();
+                self.rtt.lock().unwrap().record(rtt);
+                Ok(rtt)
+            }
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected a Pong, got {:?}", response),
+            )),
+        }
+    }
+
+    // Round-trip times measured by `ping` on this connection.
+    pub fn rtt(&self) -> RoundTripTime {
+        *self.rtt.lock().unwrap()
+    }
+
+    // Send a request whose body is streamed from `body`, and wait for its response.
+    // Returns early if the server answers before reading the whole body.
+    pub async fn upload(
+        &self,
+        message: client_message::Message,
+        body: impl AsyncRead + Unpin,
+    ) -> io::Result<server_message::Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let credit = initial_credit();
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(Command::Request(
+                streaming(message, id, true),
+                Reply::Message(reply),
+                Some(Arc::clone(&credit)),
+            ))
+            .map_err(|_| closed())?;
+
+        let mut pending = Pending {
+            id,
+            commands: &self.commands,
+            done: false,
+        };
+        let sending = send_body(self.chunk_sink(id, credit), body);
+        tokio::pin!(sending, response);
+        let mut sent = false;
+        let result = loop {
+            tokio::select! {
+                biased;
+                result = &mut response => break result.unwrap_or_else(|_| Err(closed())),
+                result = &mut sending, if !sent => {
+                    result?;
+                    sent = true;
+                }
+            }
+        };
+        pending.done = true;
+        result
+    }
+
+    // Send a request and read its streamed response. Dropping the stream before the end
+    // cancels the request.
+    pub async fn download(&self, message: client_message::Message) -> io::Result<ByteStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open_stream(streaming(message, id, false), None)
+    }
+
+    // Send a request whose body is streamed from `body` while its streamed response is read.
+    // If `body` fails, so does reading the response.
+    pub async fn stream(
+        &self,
+        message: client_message::Message,
+        body: impl AsyncRead + Send + Unpin + 'static,
+    ) -> io::Result<ByteStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let credit = initial_credit();
+        let response = self.open_stream(streaming(message, id, true), Some(Arc::clone(&credit)))?;
+
+        let sink = self.chunk_sink(id, credit);
+        let commands = self.commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_body(sink, body).await {
+                warn!("Failed to stream the body of request {}: {}", id, e);
+                let _ = commands.send(Command::Cancel(id));
+            }
+        });
+        Ok(response)
+    }
+
+    fn open_stream(
+        &self,
+        request: ClientMessage,
+        credit: Option<Arc<Semaphore>>,
+    ) -> io::Result<ByteStream> {
+        let id = request.request_id;
+        let (sender, chunks) = chunk_channel();
+        self.commands
+            .send(Command::Request(request, Reply::Stream(sender), credit))
+            .map_err(|_| closed())?;
+
+        let commands = self.commands.clone();
+        let abandon = self.commands.clone();
+        let response = ByteStream::new(chunks, move |chunks| {
+            let _ = commands.send(Command::Send(ClientMessage {
+                message: Some(client_message::Message::StreamCredit(StreamCredit {
+                    chunks,
+                })),
+                request_id: id,
+                ..ClientMessage::default()
+            }));
+        });
+        Ok(response.on_abandon(move || {
+            let _ = abandon.send(Command::Cancel(id));
+        }))
+    }
+
+    // The sending end of the body of request `id`.
+    fn chunk_sink(&self, id: u64, credit: Arc<Semaphore>) -> ChunkSink {
+        let commands = self.commands.clone();
+        ChunkSink::new(
+            move |chunk| {
+                let message = ClientMessage {
+                    message: Some(client_message::Message::Chunk(chunk)),
+                    request_id: id,
+                    ..ClientMessage::default()
+                };
+                commands.send(Command::Send(message)).map_err(|_| closed())
+            },
+            credit,
+        )
+    }
+
+    async fn send(&self, mut request: ClientMessage) -> io::Result<server_message::Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        request.request_id = id;
+
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(Command::Request(request, Reply::Message(reply), None))
+            .map_err(|_| closed())?;
+
+        let mut pending = Pending {
+            id,
+            commands: &self.commands,
+            done: false,
+        };
+        let result = response.await.unwrap_or_else(|_| Err(closed()));
+        pending.done = true;
+        result
+    }
+}
+
+fn streaming(message: client_message::Message, id: u64, body: bool) -> ClientMessage {
+    ClientMessage {
+        message: Some(message),
+        request_id: id,
+        streaming: body,
+        ..ClientMessage::default()
+    }
+}
+
+async fn send_body(mut sink: ChunkSink, body: impl AsyncRead + Unpin) -> io::Result<()> {
+    sink.send_from(body).await?;
+    sink.finish().await
+}
+
+// Cancels its request on the server unless it was answered.
+struct Pending<'a> {
+    id: u64,
+    commands: &'a mpsc::UnboundedSender<Command>,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.commands.send(Command::Cancel(self.id));
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Connection closed")
+}
+
+// Owns the connection: writes requests and cancellations, and hands each reply to the
+// request with the same id. Frames are written as the server takes them, while replies
+// keep being read.
+async fn run(
+    stream: Box<dyn Transport>,
+    framing: Framing,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut outbox = Vec::new();
+    let mut pending: HashMap<u64, Reply> = HashMap::new();
+    let mut credits: HashMap<u64, Arc<Semaphore>> = HashMap::new(); // For streamed bodies
+    let mut decoder = framing.decoder(DEFAULT_MAX_FRAME_LEN);
+    let mut buffer = [0; 4096];
+
+    let error = loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let envelope = match command {
+                    Some(Command::Request(request, reply, credit)) => {
+                        pending.insert(request.request_id, reply);
+                        if let Some(credit) = credit {
+                            credits.insert(request.request_id, credit);
+                        }
+                        request
+                    }
+                    // The caller gave up; only tell the server if it still owes an answer.
+                    Some(Command::Cancel(id)) => match pending.remove(&id) {
+                        Some(_) => {
+                            if let Some(credit) = credits.remove(&id) {
+                                credit.close();
+                            }
+                            ClientMessage {
+                                message: Some(client_message::Message::Cancel(Cancel { request_id: id })),
+                                ..ClientMessage::default()
+                            }
+                        }
+                        None => continue,
+                    },
+                    Some(Command::Send(message)) if pending.contains_key(&message.request_id) => message,
+                    Some(Command::Send(_)) => continue, // The request is over
+                    None => return, // Every handle was dropped
+                };
+
+                if let Err(e) = framing.encode(&envelope.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut outbox) {
+                    if let Some(reply) = pending.remove(&envelope.request_id) {
+                        reply.fail(e.into());
+                    }
+                }
+            }
+            result = writer.write(&outbox), if !outbox.is_empty() => match result {
+                Ok(0) => break io::ErrorKind::WriteZero.into(),
+                Ok(n) => {
+                    outbox.drain(..n);
+                }
+                Err(e) => break e,
+            },
+            result = reader.read(&mut buffer) => {
+                match result {
+                    Ok(0) => break io::Error::new(io::ErrorKind::ConnectionAborted, "Server disconnected"),
+                    Ok(n) => decoder.extend(&buffer[..n]),
+                    Err(e) => break e,
+                }
+
+                loop {
+                    let frame = match decoder.decode() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => return fail(pending, e.into()),
+                    };
+                    let reply = match ServerMessage::decode(frame.as_slice()) {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            warn!("Failed to decode ServerMessage: {}", e);
+                            continue;
+                        }
+                    };
+                    let ping = match reply.message {
+                        Some(server_message::Message::Ping(ping)) => ping,
+                        Some(server_message::Message::StreamCredit(credit)) => {
+                            if let Some(semaphore) = credits.get(&reply.request_id) {
+                                semaphore.add_permits(credit.chunks as usize);
+                            }
+                            continue;
+                        }
+                        _ => {
+                            answer(&mut pending, &mut credits, reply);
+                            continue;
+                        }
+                    };
+
+                    let pong = ClientMessage {
+                        message: Some(client_message::Message::Pong(Pong {
+                            timestamp_us: ping.timestamp_us,
+                        })),
+                        ..ClientMessage::default()
+                    };
+                    framing
+                        .encode(&pong.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut outbox)
+                        .expect("A Pong always fits in a frame");
+                }
+            }
+        }
+    };
+
+    fail(pending, error);
+}
+
+fn answer(
+    pending: &mut HashMap<u64, Reply>,
+    credits: &mut HashMap<u64, Arc<Semaphore>>,
+    reply: ServerMessage,
+) {
+    let id = reply.request_id;
+    let Some(sender) = pending.remove(&id) else {
+        // Most likely a request that was cancelled after the server had answered it.
+        debug!("Ignoring reply to unknown request {}", id);
+        return;
+    };
+
+    let message = reply.message.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Received an empty ServerMessage",
+        )
+    });
+    match (sender, message) {
+        (Reply::Message(sender), message) => {
+            let _ = sender.send(message);
+        }
+        (Reply::Stream(sender), Ok(server_message::Message::Chunk(chunk))) => {
+            let last = chunk.last;
+            match sender.try_send(Ok(chunk)) {
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Server sent chunks of request {} beyond its credit", id);
+                    Reply::Stream(sender).fail(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Chunks sent beyond the stream window",
+                    ));
+                }
+                _ if !last => {
+                    pending.insert(id, Reply::Stream(sender));
+                    return; // The body may still be streaming
+                }
+                _ => {}
+            }
+        }
+        (Reply::Stream(sender), Ok(server_message::Message::Error(error))) => {
+            let _ = sender.try_send(Err(error_from_reply(error)));
+        }
+        (Reply::Stream(sender), Ok(message)) => {
+            let _ = sender.try_send(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected a chunk, got {:?}", message),
+            )));
+        }
+        (reply, Err(e)) => reply.fail(e),
+    }
+
+    // Once the response is complete the server reads no more of the body.
+    if let Some(credit) = credits.remove(&id) {
+        credit.close();
+    }
+}
+
+impl Reply {
+    fn fail(self, error: io::Error) {
+        match self {
+            Reply::Message(sender) => {
+                let _ = sender.send(Err(error));
+            }
+            Reply::Stream(sender) => {
+                let _ = sender.try_send(Err(error));
+            }
+        }
+    }
+}
+
+// Fail every outstanding request once the connection is gone.
+fn fail(pending: HashMap<u64, Reply>, error: io::Error) {
+    warn!("Pipelined connection failed: {}", error);
+    for (_, reply) in pending {
+        reply.fail(io::Error::new(error.kind(), error.to_string()));
+    }
+}
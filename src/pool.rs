@@ -0,0 +1,321 @@
+use crate::client::Client;
+use crate::message::{client_message, server_message};
+use log::{debug, warn};
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    // Connections kept open even when nobody is using them.
+    pub min_connections: usize,
+    // Upper bound on open connections; callers wait for one to be returned beyond this.
+    pub max_connections: usize,
+    // How long `get` may take, waiting for a connection and health-checking or opening it,
+    // before it fails with `TimedOut`.
+    pub checkout_timeout: Duration,
+    // Idle connections above `min_connections` are closed after this long.
+    pub idle_timeout: Duration,
+    // A connection idle for longer than this is pinged before it is handed out again.
+    pub health_check_after: Duration,
+    // How often the background task evicts idle connections and tops the pool up.
+    pub maintenance_interval: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_connections: 1,
+            max_connections: 16,
+            checkout_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(60),
+            health_check_after: Duration::from_secs(10),
+            maintenance_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub idle: usize,
+    pub in_use: usize,
+}
+
+struct IdleClient {
+    client: Client,
+    since: Instant,
+}
+
+struct Inner {
+    addr: String,
+    config: PoolConfig,
+    // Most recently returned last, so busy periods reuse warm connections and the rest age out.
+    idle: Mutex<Vec<IdleClient>>,
+    // One permit per connection that may be checked out. Tokio's semaphore is FIFO, so callers
+    // get connections in the order they asked for them.
+    permits: Arc<Semaphore>,
+}
+
+// A bounded set of connections to one server, shared by many tasks.
+//
+// Cloning a `Pool` is cheap and every clone shares the same connections. A background task
+// closes surplus idle connections and keeps `min_connections` open; it stops when the last
+// clone is dropped.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+impl Pool {
+    // Open `min_connections` connections to `addr` and start maintaining the pool. Must be
+    // called within a Tokio runtime.
+    pub async fn connect(addr: impl Into<String>, config: PoolConfig) -> io::Result<Self> {
+        let inner = Inner::new(addr.into(), config)?;
+        for _ in 0..inner.config.min_connections {
+            let client = Client::connect(&inner.addr).await?;
+            inner.push_idle(client);
+        }
+
+        tokio::spawn(maintain(Arc::downgrade(&inner)));
+        Ok(Pool { inner })
+    }
+
+    // Like `connect`, but without waiting for the server: the background task opens
+    // `min_connections` on its first pass, and `get` connects on demand until then. Must be
+    // called within a Tokio runtime.
+    pub fn new(addr: impl Into<String>, config: PoolConfig) -> io::Result<Self> {
+        let inner = Inner::new(addr.into(), config)?;
+        tokio::spawn(maintain(Arc::downgrade(&inner)));
+        Ok(Pool { inner })
+    }
+
+    // Check out a connection, waiting in line behind earlier callers if all are in use.
+    pub async fn get(&self) -> io::Result<PooledClient> {
+        time::timeout(self.inner.config.checkout_timeout, self.checkout())
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for a pooled connection",
+                )
+            })?
+    }
+
+    async fn checkout(&self) -> io::Result<PooledClient> {
+        let permits = Arc::clone(&self.inner.permits);
+        let permit = permits
+            .acquire_owned()
+            .await
+            .expect("Pool semaphore is never closed");
+
+        let client = match self.inner.take_healthy().await {
+            Some(client) => client,
+            None => Client::connect(&self.inner.addr).await?,
+        };
+
+        Ok(PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+            broken: false,
+            _permit: permit,
+        })
+    }
+
+    // Send one request over a pooled connection.
+    pub async fn request(
+        &self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        self.get().await?.request(message).await
+    }
+
+    // Send one request over a pooled connection, failing with `TimedOut` unless it is
+    // answered within `timeout`. Time spent waiting for a connection counts too.
+    pub async fn request_with_timeout(
+        &self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        let deadline = Instant::now() + timeout;
+        let mut client = time::timeout(timeout, self.get()).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for a pooled connection",
+            )
+        })??;
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        client.request_with_timeout(message, remaining).await
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let idle = self.inner.idle.lock().unwrap().len();
+        let in_use = self.inner.config.max_connections - self.inner.permits.available_permits();
+        PoolStatus { idle, in_use }
+    }
+}
+
+impl Inner {
+    fn new(addr: String, config: PoolConfig) -> io::Result<Arc<Self>> {
+        if config.max_connections == 0 || config.min_connections > config.max_connections {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Pool needs 0 < min_connections <= max_connections",
+            ));
+        }
+
+        Ok(Arc::new(Inner {
+            addr,
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+            idle: Mutex::new(Vec::new()),
+            config,
+        }))
+    }
+
+    fn push_idle(&self, client: Client) {
+        self.idle.lock().unwrap().push(IdleClient {
+            client,
+            since: Instant::now(),
+        });
+    }
+
+    // The most recently used idle connection that still works, if any.
+    async fn take_healthy(&self) -> Option<Client> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop()?;
+            if idle.since.elapsed() < self.config.health_check_after {
+                return Some(idle.client);
+            }
+
+            let mut client = idle.client;
+            match ping(&mut client, self.config.checkout_timeout).await {
+                Ok(()) => return Some(client),
+                Err(e) => debug!(
+                    "Dropping pooled connection that failed its health check: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+async fn ping(client: &mut Client, timeout: Duration) -> io::Result<()> {
+    match time::timeout(timeout, client.ping()).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Health check timed out",
+        )),
+    }
+}
+
+// Close idle connections past `idle_timeout` (keeping `min_connections`) and reopen
+// connections lost below `min_connections`, until the pool is dropped.
+async fn maintain(pool: Weak<Inner>) {
+    let interval = match pool.upgrade() {
+        Some(inner) => inner.config.maintenance_interval,
+        None => return,
+    };
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        let Some(inner) = pool.upgrade() else {
+            return;
+        };
+
+        let open = {
+            let mut idle = inner.idle.lock().unwrap();
+            let in_use = inner.config.max_connections - inner.permits.available_permits();
+            let keep = inner.config.min_connections.saturating_sub(in_use);
+
+            // Oldest first; only connections beyond the minimum are eligible.
+            let mut surplus = idle.len().saturating_sub(keep);
+            idle.retain(|client| {
+                let evict = surplus > 0 && client.since.elapsed() >= inner.config.idle_timeout;
+                if evict {
+                    surplus -= 1;
+                }
+                !evict
+            });
+            idle.len() + in_use
+        };
+
+        for _ in open..inner.config.min_connections {
+            match Client::connect(&inner.addr).await {
+                Ok(client) => inner.push_idle(client),
+                Err(e) => {
+                    warn!("Failed to refill connection pool for {}: {}", inner.addr, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// A connection checked out of a `Pool`. It goes back to the pool when dropped, unless a
+// request on it failed, in which case it is closed instead.
+pub struct PooledClient {
+    client: Option<Client>,
+    pool: Arc<Inner>,
+    broken: bool,
+    _permit: OwnedSemaphorePermit, // Released after the client is back in the pool
+}
+
+impl PooledClient {
+    // Send a request and wait for its response. An I/O error, or dropping the future before
+    // the response arrives, leaves the connection in an unknown state, so it won't be reused.
+    pub async fn request(
+        &mut self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        self.broken = true;
+        let client = self.client.as_mut().expect("Client is only taken on drop");
+        let result = client.request(message).await;
+        self.broken = matches!(&result, Err(e) if e.kind() != io::ErrorKind::InvalidData);
+        result
+    }
+
+    // Like `request`, failing with `TimedOut` unless the server answers within `timeout`.
+    // The connection is reused if the server reported the timeout itself.
+    pub async fn request_with_timeout(
+        &mut self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        self.broken = true;
+        let client = self.client.as_mut().expect("Client is only taken on drop");
+        let result = client.request_with_timeout(message, timeout).await;
+        self.broken = client.is_desynchronized()
+            || matches!(&result, Err(e) if !matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::TimedOut));
+        result
+    }
+
+    // Close the connection instead of returning it to the pool.
+    pub fn discard(mut self) {
+        self.broken = true;
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("Client is only taken on drop")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if !self.broken {
+                self.pool.push_idle(client);
+            }
+        }
+    }
+}
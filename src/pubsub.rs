@@ -0,0 +1,277 @@
+use crate::files::invalid_input;
+use crate::message::{Delivery, Publish};
+use crate::metrics::Metrics;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Published messages a subscriber may have waiting to be sent before it counts as slow.
+pub const DEFAULT_SUBSCRIBER_QUEUE_LEN: usize = 1024;
+
+// Queues a published message for its subscribers, resolving to how many it was queued for.
+pub(crate) type Publishing = Pin<Box<dyn Future<Output = u32> + Send>>;
+
+// What happens to a message published to a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowSubscriber {
+    // Make room by dropping the oldest message waiting. The next Delivery says how many
+    // were dropped.
+    #[default]
+    DropOldest,
+    // Close the subscriber's connection.
+    Disconnect,
+    // Hold the publisher until there is room. Messages are published one at a time across
+    // the whole server, so a subscriber that stops reading holds up every publisher.
+    Block,
+}
+
+// Whether `topic` matches the subscription `pattern`: '+' stands for any one level, and a
+// last level of '#' for any number of them, including none.
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for wanted in pattern.split('/') {
+        match (wanted, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (wanted, Some(level)) if wanted == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn check_pattern(pattern: &str) -> io::Result<()> {
+    if pattern.is_empty() {
+        return Err(invalid_input("Subscription without a pattern"));
+    }
+    let mut levels = pattern.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains(['+', '#']) && level != "+" && level != "#" {
+            return Err(invalid_input(format!(
+                "Wildcards in {} must be a whole level",
+                pattern
+            )));
+        }
+        if level == "#" && levels.peek().is_some() {
+            return Err(invalid_input(format!(
+                "'#' in {} must be the last level",
+                pattern
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_topic(topic: &str) -> io::Result<()> {
+    if topic.is_empty() {
+        return Err(invalid_input("Publish without a topic"));
+    }
+    if topic.contains(['+', '#']) {
+        return Err(invalid_input(format!(
+            "Can't publish to {}, which has wildcards",
+            topic
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    deliveries: VecDeque<Delivery>,
+    dropped: u64,     // Since the last delivery was taken
+    overflowed: bool, // Set under `SlowSubscriber::Disconnect`, closing the connection
+    closed: bool,     // The connection is gone
+}
+
+// The published messages waiting for one connection to send them.
+#[derive(Debug)]
+pub(crate) struct Mailbox {
+    id: u64,
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: SlowSubscriber,
+    metrics: Arc<Metrics>, // The server's
+    arrived: Notify,       // Wakes the connection
+    taken: Notify,         // Wakes a publisher waiting for room
+}
+
+impl Mailbox {
+    // Queue `delivery`, doing what the policy says if the mailbox is full. Returns whether
+    // it was queued.
+    async fn push(&self, delivery: Delivery) -> bool {
+        loop {
+            let taken = self.taken.notified();
+            tokio::pin!(taken);
+            taken.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.closed || queue.overflowed {
+                    return false;
+                }
+                if queue.deliveries.len() >= self.capacity {
+                    match self.policy {
+                        SlowSubscriber::DropOldest => {
+                            queue.deliveries.pop_front();
+                            queue.dropped += 1;
+                            self.metrics.record_dropped_delivery();
+                        }
+                        SlowSubscriber::Disconnect => {
+                            queue.overflowed = true;
+                            queue.deliveries.clear();
+                            self.arrived.notify_one();
+                            return false;
+                        }
+                        // Wait below for the connection to take one
+                        SlowSubscriber::Block => {}
+                    }
+                }
+                if queue.deliveries.len() < self.capacity {
+                    queue.deliveries.push_back(delivery);
+                    self.arrived.notify_one();
+                    return true;
+                }
+            }
+            taken.await;
+        }
+    }
+
+    // The next message to send, once there is one. `None` once the connection has fallen
+    // too far behind and should be closed.
+    pub(crate) async fn next(&self) -> Option<Delivery> {
+        loop {
+            let arrived = self.arrived.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.overflowed {
+                    return None;
+                }
+                if let Some(mut delivery) = queue.deliveries.pop_front() {
+                    delivery.dropped = std::mem::take(&mut queue.dropped);
+                    self.taken.notify_waiters();
+                    return Some(delivery);
+                }
+            }
+            arrived.await;
+        }
+    }
+
+    // Refuse anything more, releasing a publisher waiting for room.
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.taken.notify_waiters();
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    mailbox: Arc<Mailbox>,
+    patterns: Vec<String>,
+}
+
+// The subscriptions of every connection, and the fan-out of published messages to them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Broker {
+    subscribers: Arc<Mutex<HashMap<u64, Subscriber>>>,
+    next_id: Arc<AtomicU64>,
+    // Held while a message is queued for its subscribers, so they all get messages in the
+    // same order.
+    publishing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Broker {
+    // A mailbox for a new connection, holding up to `capacity` messages.
+    pub(crate) fn mailbox(
+        &self,
+        capacity: usize,
+        policy: SlowSubscriber,
+        metrics: Arc<Metrics>,
+    ) -> Arc<Mailbox> {
+        Arc::new(Mailbox {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            queue: Mutex::default(),
+            capacity,
+            policy,
+            metrics,
+            arrived: Notify::new(),
+            taken: Notify::new(),
+        })
+    }
+
+    pub(crate) fn subscribe(&self, mailbox: &Arc<Mailbox>, pattern: String) -> io::Result<()> {
+        check_pattern(&pattern)?;
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let subscriber = subscribers.entry(mailbox.id).or_insert_with(|| Subscriber {
+            mailbox: Arc::clone(mailbox),
+            patterns: Vec::new(),
+        });
+        if !subscriber.patterns.contains(&pattern) {
+            subscriber.patterns.push(pattern);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unsubscribe(&self, mailbox: &Mailbox, pattern: &str) -> io::Result<()> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let subscriber = subscribers
+            .get_mut(&mailbox.id)
+            .filter(|subscriber| subscriber.patterns.iter().any(|p| p == pattern))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Not subscribed to {}", pattern),
+                )
+            })?;
+        subscriber.patterns.retain(|p| p != pattern);
+        if subscriber.patterns.is_empty() {
+            subscribers.remove(&mailbox.id);
+        }
+        Ok(())
+    }
+
+    // Drop every subscription of a connection that closed.
+    pub(crate) fn remove(&self, mailbox: &Mailbox) {
+        self.subscribers.lock().unwrap().remove(&mailbox.id);
+        mailbox.close();
+    }
+
+    // Check `publish`, and return what queues it for every subscriber.
+    pub(crate) fn publish(&self, publish: Publish) -> io::Result<Publishing> {
+        check_topic(&publish.topic)?;
+        let broker = self.clone();
+        Ok(Box::pin(async move {
+            let _order = broker.publishing.lock().await;
+            let mailboxes: Vec<_> = {
+                let subscribers = broker.subscribers.lock().unwrap();
+                subscribers
+                    .values()
+                    .filter(|subscriber| {
+                        subscriber
+                            .patterns
+                            .iter()
+                            .any(|pattern| matches(pattern, &publish.topic))
+                    })
+                    .map(|subscriber| Arc::clone(&subscriber.mailbox))
+                    .collect()
+            };
+
+            let mut queued = 0;
+            for mailbox in mailboxes {
+                let delivery = Delivery {
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.clone(),
+                    dropped: 0,
+                };
+                if mailbox.push(delivery).await {
+                    queued += 1;
+                }
+            }
+            queued
+        }))
+    }
+}
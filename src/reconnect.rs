@@ -0,0 +1,452 @@
+use crate::client::{Client, DEADLINE_GRACE};
+use crate::message::{client_message, server_message, Pong};
+use log::{info, warn};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{self, Instant};
+
+// What happens to requests while the connection is down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    // Requests wait until the connection is back, and a request cut off by the disconnect is
+    // sent again. Requests may therefore reach the server more than once.
+    Queue,
+    // Requests fail straight away with `NotConnected`, including one cut off by the disconnect,
+    // while the client keeps reconnecting in the background.
+    Fail,
+}
+
+// How the client reconnects after losing (or failing to establish) its connection.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    // Delay before the first retry.
+    pub initial_backoff: Duration,
+    // Upper bound on the delay between retries.
+    pub max_backoff: Duration,
+    // Factor the delay grows by after every failed attempt. Below 1.0 it counts as 1.0.
+    pub multiplier: f64,
+    // Fraction of each delay that is randomised, from 0.0 (none) to 1.0 (anywhere between
+    // zero and the full delay), so a fleet of devices doesn't reconnect in lockstep. Values
+    // outside that range are clamped to it, and NaN counts as 0.0.
+    pub jitter: f64,
+    // Retries after a failed connection attempt before giving up for good, so giving up takes
+    // one failed attempt more than this; `None` retries forever.
+    pub max_retries: Option<u32>,
+    // How many times a single request is sent again after its connection dropped.
+    pub max_resends: u32,
+    pub on_disconnect: DisconnectPolicy,
+    // Requests that can wait for the connection before callers are held back.
+    pub queue_capacity: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_retries: None,
+            max_resends: 3,
+            on_disconnect: DisconnectPolicy::Queue,
+            queue_capacity: 64,
+        }
+    }
+}
+
+// Exponential backoff with jitter and an optional retry budget.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff {
+            policy,
+            attempts: 0,
+        }
+    }
+
+    // The delay before the next attempt, or `None` once the retry budget is spent.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .policy
+            .max_retries
+            .is_some_and(|max_retries| self.attempts >= max_retries)
+        {
+            return None;
+        }
+
+        let exponent = self.attempts.min(i32::MAX as u32) as i32;
+        self.attempts += 1;
+
+        // Neither factor may make the delay NaN or negative; `max` passes over NaN.
+        let multiplier = self.policy.multiplier.max(1.0);
+        let jitter = match self.policy.jitter {
+            jitter if jitter.is_nan() => 0.0,
+            jitter => jitter.clamp(0.0, 1.0),
+        };
+        let max = self.policy.max_backoff.as_secs_f64();
+        let delay =
+            (self.policy.initial_backoff.as_secs_f64() * multiplier.powi(exponent)).min(max);
+        let delay = delay * (1.0 - jitter * rand::thread_rng().gen::<f64>());
+
+        // Only a cap too long for a Duration to hold in seconds as f64 fails to convert.
+        Some(Duration::try_from_secs_f64(delay).unwrap_or(self.policy.max_backoff))
+    }
+
+    // Failed attempts since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // Start over after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    // Trying to connect; `attempt` counts from 1 since the connection was last up.
+    Connecting { attempt: u32 },
+    Connected { addr: SocketAddr },
+    // The connection failed or was lost, and the client is waiting before the next attempt.
+    Disconnected { error: String },
+    // The retry budget is spent; every request fails from now on.
+    GaveUp,
+}
+
+type StateCallback = Arc<dyn Fn(&ConnectionState) + Send + Sync>;
+
+struct Request {
+    message: client_message::Message,
+    reply: oneshot::Sender<io::Result<server_message::Message>>,
+    resends: u32,
+    deadline: Option<Instant>,
+}
+
+// A client that keeps itself connected to one server.
+//
+// A background task owns the connection and reconnects with exponential backoff whenever it
+// fails. Handles are cheap to clone and share that one connection; requests from all of them
+// are sent one at a time in the order they were made. The background task stops once every
+// handle is dropped.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    requests: mpsc::Sender<Request>,
+    state: watch::Receiver<ConnectionState>,
+}
+
+impl ReconnectingClient {
+    // Start connecting to `addr` in the background. Must be called within a Tokio runtime.
+    pub fn new(addr: impl Into<String>, policy: ReconnectPolicy) -> Self {
+        Self::with_state_callback(addr, policy, |_| {})
+    }
+
+    // Like `new`, calling `callback` on the background task every time the connection state
+    // changes, starting with the first connection attempt.
+    pub fn with_state_callback(
+        addr: impl Into<String>,
+        policy: ReconnectPolicy,
+        callback: impl Fn(&ConnectionState) + Send + Sync + 'static,
+    ) -> Self {
+        let (requests, receiver) = mpsc::channel(policy.queue_capacity.max(1));
+        let (state_sender, state) = watch::channel(ConnectionState::Connecting { attempt: 1 });
+
+        let connection = Connection {
+            addr: addr.into(),
+            backoff: Backoff::new(policy.clone()),
+            policy,
+            requests: receiver,
+            pending: VecDeque::new(),
+            state: state_sender,
+            callback: Arc::new(callback),
+        };
+        tokio::spawn(connection.run());
+
+        ReconnectingClient { requests, state }
+    }
+
+    // The current connection state.
+    pub fn state(&self) -> ConnectionState {
+        self.state.borrow().clone()
+    }
+
+    // Wait until the state satisfies `predicate`, e.g. until the client is connected.
+    pub async fn wait_for_state(
+        &mut self,
+        predicate: impl FnMut(&ConnectionState) -> bool,
+    ) -> ConnectionState {
+        match self.state.wait_for(predicate).await {
+            Ok(state) => state.clone(),
+            Err(_) => ConnectionState::GaveUp,
+        }
+    }
+
+    // Send a request and wait for its response, subject to the disconnect policy.
+    pub async fn request(
+        &self,
+        message: client_message::Message,
+    ) -> io::Result<server_message::Message> {
+        let (reply, response) = oneshot::channel();
+        let request = Request {
+            message,
+            reply,
+            resends: 0,
+            deadline: None,
+        };
+
+        self.requests.send(request).await.map_err(|_| gave_up())?;
+        response.await.map_err(|_| gave_up())?
+    }
+
+    // Like `request`, failing with `TimedOut` unless the request is answered within
+    // `timeout`, including any time spent waiting for the connection.
+    pub async fn request_with_timeout(
+        &self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> io::Result<server_message::Message> {
+        let (reply, response) = oneshot::channel();
+        let request = Request {
+            message,
+            reply,
+            resends: 0,
+            deadline: Some(Instant::now() + timeout),
+        };
+
+        let result = time::timeout(timeout + DEADLINE_GRACE, async {
+            self.requests.send(request).await.map_err(|_| gave_up())?;
+            response.await.map_err(|_| gave_up())?
+        })
+        .await;
+        result.unwrap_or_else(|_| Err(timed_out()))
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Request timed out")
+}
+
+fn gave_up() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Client gave up reconnecting")
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Not connected to the server")
+}
+
+// The background task behind a `ReconnectingClient`.
+struct Connection {
+    addr: String,
+    policy: ReconnectPolicy,
+    backoff: Backoff,
+    requests: mpsc::Receiver<Request>,
+    // A request cut off by a disconnect, waiting to be sent again.
+    pending: VecDeque<Request>,
+    state: watch::Sender<ConnectionState>,
+    callback: StateCallback,
+}
+
+impl Connection {
+    async fn run(mut self) {
+        loop {
+            let Some(client) = self.connect().await else {
+                self.give_up().await;
+                return;
+            };
+
+            match self.serve(client).await {
+                Ok(()) => return, // Every handle was dropped
+                Err(e) => {
+                    warn!("Lost connection to {}: {}", self.addr, e);
+                    self.set_state(ConnectionState::Disconnected {
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        (self.callback)(&state);
+        self.state.send_replace(state);
+    }
+
+    // Connect with backoff. Returns `None` once the retry budget is spent or every handle is
+    // gone.
+    async fn connect(&mut self) -> Option<Client> {
+        loop {
+            self.set_state(ConnectionState::Connecting {
+                attempt: self.backoff.attempts() + 1,
+            });
+
+            match Client::connect(&self.addr).await {
+                Ok(client) => {
+                    self.backoff.reset();
+                    if let Ok(addr) = client.peer_addr() {
+                        info!("Connected to {}", addr);
+                        self.set_state(ConnectionState::Connected { addr });
+                    }
+                    return Some(client);
+                }
+                Err(e) => {
+                    self.set_state(ConnectionState::Disconnected {
+                        error: e.to_string(),
+                    });
+                }
+            }
+
+            let delay = self.backoff.next_delay()?;
+            if !self.wait(delay).await {
+                return None;
+            }
+        }
+    }
+
+    // Sleep for `delay`, failing requests in the meantime if the policy says so. Returns
+    // `false` if every handle was dropped.
+    async fn wait(&mut self, delay: Duration) -> bool {
+        if self.policy.on_disconnect == DisconnectPolicy::Fail {
+            for request in self.pending.drain(..) {
+                let _ = request.reply.send(Err(not_connected()));
+            }
+        }
+
+        let sleep = time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                _ = self.state.closed() => return false,
+                request = self.requests.recv(), if self.policy.on_disconnect == DisconnectPolicy::Fail => {
+                    match request {
+                        Some(request) => {
+                            let _ = request.reply.send(Err(not_connected()));
+                        }
+                        None => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    // Answer requests over `client` until the connection fails (`Err`) or every handle is
+    // dropped (`Ok`).
+    async fn serve(&mut self, mut client: Client) -> io::Result<()> {
+        loop {
+            let request = match self.pending.pop_front() {
+                Some(request) => request,
+                None => tokio::select! {
+                    request = self.requests.recv() => match request {
+                        Some(request) => request,
+                        None => return Ok(()),
+                    },
+                    // Nothing to send, but keep reading so a closed connection is noticed
+                    // before the next request is made. Only the read races new requests: a
+                    // Pong cut short by one would corrupt the stream, so heartbeats are
+                    // answered once the read has won.
+                    result = client.receive_frame() => {
+                        match result?.message {
+                            Some(server_message::Message::Ping(ping)) => {
+                                let pong = Pong {
+                                    timestamp_us: ping.timestamp_us,
+                                };
+                                client.send(client_message::Message::Pong(pong)).await?;
+                            }
+                            message => warn!("Ignoring unsolicited message: {:?}", message),
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            // The caller stopped waiting; don't bother the server.
+            if request.reply.is_closed() {
+                continue;
+            }
+
+            let result = match request.deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        let _ = request.reply.send(Err(timed_out()));
+                        continue;
+                    }
+                    client
+                        .request_with_timeout(request.message.clone(), remaining)
+                        .await
+                }
+                None => client.request(request.message.clone()).await,
+            };
+
+            match result {
+                Ok(response) => {
+                    let _ = request.reply.send(Ok(response));
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::InvalidData | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    // The response was bad (or didn't come in time), but the connection can
+                    // go on being used unless the client lost track of which reply is which.
+                    let kind = e.kind();
+                    let _ = request.reply.send(Err(e));
+                    if client.is_desynchronized() {
+                        return Err(io::Error::new(
+                            kind,
+                            "Dropped connection after losing track of a reply",
+                        ));
+                    }
+                }
+                Err(e) => {
+                    self.requeue(request, &e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn requeue(&mut self, mut request: Request, error: &io::Error) {
+        if self.policy.on_disconnect == DisconnectPolicy::Queue
+            && request.resends < self.policy.max_resends
+        {
+            request.resends += 1;
+            self.pending.push_back(request);
+        } else {
+            let error = match self.policy.on_disconnect {
+                DisconnectPolicy::Queue => io::Error::new(error.kind(), error.to_string()),
+                DisconnectPolicy::Fail => not_connected(),
+            };
+            let _ = request.reply.send(Err(error));
+        }
+    }
+
+    // Fail everything from now on, until every handle is dropped.
+    async fn give_up(&mut self) {
+        warn!(
+            "Giving up on {} after {} attempts",
+            self.addr,
+            self.backoff.attempts() + 1
+        );
+        self.set_state(ConnectionState::GaveUp);
+
+        for request in self.pending.drain(..) {
+            let _ = request.reply.send(Err(gave_up()));
+        }
+        self.requests.close();
+        while let Some(request) = self.requests.recv().await {
+            let _ = request.reply.send(Err(gave_up()));
+        }
+    }
+}
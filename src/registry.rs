@@ -0,0 +1,306 @@
+use crate::files::invalid_input;
+use crate::heartbeat::timestamp_us;
+use crate::message::{
+    client_message, server_message, DeviceInfo, DeviceList, DeviceQuery, DeviceSnapshot,
+    RegisterDevice,
+};
+use crate::router::{error_reply, Context, MessageKind, Peer, Router};
+use crate::wal::{Mark, Wal, WalOptions};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// The most devices one DeviceList holds, which keeps replies well inside the default frame
+// limit.
+pub const MAX_DEVICES_PER_LIST: usize = 500;
+
+// The most devices the registry knows. Anyone who connects can register a new device id, so
+// the offline device seen longest ago is forgotten to make room for one, and registrations
+// are refused while every device known is online.
+pub const MAX_DEVICES: usize = 10_000;
+
+#[derive(Debug)]
+struct Device {
+    info: DeviceInfo,
+    // The connection the device is registered on, while it is open.
+    session: Option<Session>,
+}
+
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    // Closes the connection when the device registers again somewhere else.
+    replaced: Arc<Notify>,
+}
+
+// The devices that have registered since the server started, and which are connected.
+//
+// A device registers on a connection with a RegisterDevice and is online until that
+// connection closes. If it registers again before the server noticed the old connection
+// drop, as a device does after losing its network, the old connection is closed.
+//
+// Nothing proves that a peer is the device it claims to be, so any peer can register under
+// the id of a connected device and take over its session. The registry can only be trusted
+// as far as every peer that can reach the server.
+//
+// Kept on disk, the registry also remembers the devices from before the server restarted,
+// all of them offline until they connect again.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    devices: Arc<Mutex<BTreeMap<String, Device>>>,
+    next_session: Arc<AtomicU64>,
+    // Where registrations and disconnections are logged, if the registry is kept on disk.
+    // Only those are, so a device is recovered as last seen when it did either.
+    wal: Option<Arc<Wal>>,
+}
+
+impl DeviceRegistry {
+    // A registry kept in `dir`, knowing the devices it knew when the server last stopped.
+    pub fn open(dir: &Path, options: WalOptions) -> io::Result<Self> {
+        let (wal, recovered) = Wal::open::<DeviceSnapshot, DeviceInfo>(dir, options)?;
+        let snapshot = recovered.snapshot.unwrap_or_default();
+        let mut devices: BTreeMap<_, _> = snapshot
+            .devices
+            .into_iter()
+            .chain(recovered.records)
+            .map(|info| {
+                let device = Device {
+                    info: DeviceInfo {
+                        online: false,
+                        ..info
+                    },
+                    session: None,
+                };
+                (device.info.device_id.clone(), device)
+            })
+            .collect();
+        // Devices forgotten to make room aren't logged, so they come back with the log and
+        // are forgotten again, seen longest ago first.
+        if devices.len() > MAX_DEVICES {
+            let mut seen: Vec<_> = devices
+                .values()
+                .map(|device| (device.info.last_seen_ms, device.info.device_id.clone()))
+                .collect();
+            seen.sort_unstable();
+            for (_, device_id) in &seen[..devices.len() - MAX_DEVICES] {
+                devices.remove(device_id);
+            }
+        }
+
+        let wal = Arc::new(wal);
+        let registry = DeviceRegistry {
+            devices: Arc::new(Mutex::new(devices)),
+            next_session: Arc::default(),
+            wal: Some(Arc::clone(&wal)),
+        };
+        wal.maintain(Arc::downgrade(&registry.devices), copy_for_snapshot);
+        Ok(registry)
+    }
+
+    // Record `registration` from the connection to `peer`, which is closed by notifying
+    // `replaced` if the device registers again. Returns the session's id and the device.
+    pub(crate) fn register(
+        &self,
+        registration: RegisterDevice,
+        peer: &Peer,
+        replaced: Arc<Notify>,
+    ) -> io::Result<(u64, DeviceInfo)> {
+        if registration.device_id.is_empty() {
+            return Err(invalid_input("Registration without a device_id"));
+        }
+        let now_ms = timestamp_us() / 1000;
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+        let info = DeviceInfo {
+            device_id: registration.device_id,
+            model: registration.model,
+            firmware_version: registration.firmware_version,
+            online: true,
+            last_seen_ms: now_ms,
+            peer: peer.to_string(),
+            connected_ms: now_ms,
+        };
+
+        let device = Device {
+            info: info.clone(),
+            session: Some(Session {
+                id,
+                replaced: Arc::clone(&replaced),
+            }),
+        };
+        let previous = {
+            let mut devices = self.devices.lock().unwrap();
+            let full = devices.len() >= MAX_DEVICES && !devices.contains_key(&info.device_id);
+            let forgotten = match full {
+                true => Some(oldest_offline(&devices).ok_or_else(registry_full)?),
+                false => None,
+            };
+            self.log(&info)?;
+            if let Some(forgotten) = forgotten {
+                info!(
+                    "Forgetting device {} to make room for {}",
+                    forgotten, info.device_id
+                );
+                devices.remove(&forgotten);
+            }
+            devices.insert(info.device_id.clone(), device)
+        };
+        // A connection registering again only updates what is known about the device.
+        let previous = previous.and_then(|previous| {
+            let session = previous.session?;
+            (!Arc::ptr_eq(&session.replaced, &replaced)).then_some((previous.info, session))
+        });
+        match previous {
+            Some((previous, session)) => {
+                info!(
+                    "Device {} reconnected from {}, closing its session from {}",
+                    info.device_id, info.peer, previous.peer
+                );
+                session.replaced.notify_one();
+            }
+            None => info!(
+                "Device {} ({} {}) registered from {}",
+                info.device_id, info.model, info.firmware_version, info.peer
+            ),
+        }
+        Ok((id, info))
+    }
+
+    // Note that something was read from `device_id` on session `session`.
+    pub(crate) fn seen(&self, device_id: &str, session: u64) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(device_id) {
+            if device
+                .session
+                .as_ref()
+                .is_some_and(|current| current.id == session)
+            {
+                device.info.last_seen_ms = timestamp_us() / 1000;
+            }
+        }
+    }
+
+    // Mark `device_id` offline, unless it has registered again since `session`.
+    pub(crate) fn disconnected(&self, device_id: &str, session: u64) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(device_id) {
+            if device
+                .session
+                .as_ref()
+                .is_some_and(|current| current.id == session)
+            {
+                device.session = None;
+                device.info.online = false;
+                info!("Device {} went offline", device_id);
+                if let Err(e) = self.log(&device.info) {
+                    error!("Failed to log device {} going offline: {}", device_id, e);
+                }
+            }
+        }
+    }
+
+    // Record what is now known about a device in the write-ahead log, if there is one.
+    fn log(&self, info: &DeviceInfo) -> io::Result<()> {
+        match &self.wal {
+            Some(wal) => wal.append(info),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<DeviceInfo> {
+        let devices = self.devices.lock().unwrap();
+        devices.get(device_id).map(|device| device.info.clone())
+    }
+
+    // Every known device, ordered by id.
+    pub fn list(&self) -> Vec<DeviceInfo> {
+        let devices = self.devices.lock().unwrap();
+        devices.values().map(|device| device.info.clone()).collect()
+    }
+
+    // The devices matching `query`, at most `MAX_DEVICES_PER_LIST` of them.
+    pub fn query(&self, query: &DeviceQuery) -> DeviceList {
+        let devices = self.devices.lock().unwrap();
+        let start = match query.after.as_str() {
+            "" => Bound::Unbounded,
+            after => Bound::Excluded(after),
+        };
+        let mut matching = devices
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(_, device)| &device.info)
+            .filter(|info| query.device_id.is_empty() || info.device_id == query.device_id)
+            .filter(|info| !query.online_only || info.online);
+
+        let devices: Vec<_> = matching
+            .by_ref()
+            .take(MAX_DEVICES_PER_LIST)
+            .cloned()
+            .collect();
+        DeviceList {
+            devices,
+            truncated: matching.next().is_some(),
+        }
+    }
+
+    // Register the DeviceQuery handler on `router`. Registration itself is handled by the
+    // connection, which the device's session belongs to.
+    pub fn routes(&self, router: Router) -> Router {
+        let registry = self.clone();
+        router.route(MessageKind::DeviceQuery, move |ctx, request| {
+            let registry = registry.clone();
+            async move { registry.handle(ctx, request) }
+        })
+    }
+
+    fn handle(
+        &self,
+        ctx: Context,
+        request: client_message::Message,
+    ) -> Option<server_message::Message> {
+        let client_message::Message::DeviceQuery(query) = request else {
+            return None;
+        };
+        if !query.device_id.is_empty() && self.get(&query.device_id).is_none() {
+            let e = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No device {}", query.device_id),
+            );
+            warn!("Device query from {} failed: {}", ctx.peer(), e);
+            return Some(error_reply(&e));
+        }
+        Some(server_message::Message::DeviceList(self.query(&query)))
+    }
+}
+
+fn registry_full() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        format!("Registry is full, with all {} devices online", MAX_DEVICES),
+    )
+}
+
+// The id of the offline device seen longest ago, if any device is offline.
+fn oldest_offline(devices: &BTreeMap<String, Device>) -> Option<String> {
+    let oldest = devices
+        .values()
+        .filter(|device| device.session.is_none())
+        .min_by_key(|device| device.info.last_seen_ms)?;
+    Some(oldest.info.device_id.clone())
+}
+
+// Copy every known device for a snapshot, and mark the log they were copied at, holding off
+// registrations meanwhile.
+fn copy_for_snapshot(
+    devices: &Mutex<BTreeMap<String, Device>>,
+    wal: &Wal,
+) -> (DeviceSnapshot, Mark) {
+    let devices = devices.lock().unwrap();
+    let snapshot = DeviceSnapshot {
+        devices: devices.values().map(|device| device.info.clone()).collect(),
+    };
+    (snapshot, wal.mark())
+}
@@ -0,0 +1,603 @@
+use crate::message::{
+    batch_request, client_message, error, server_message, AddResponse, BatchRequest, BatchResponse,
+    ClientMessage, Error, ServerMessage,
+};
+use crate::stream::{ByteStream, ChunkSink};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+// Requests of one batch that run at once, unless the router is told otherwise.
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+
+// The most requests one batch may hold. Even if every one of them fails, the response stays
+// well inside the default frame limit; a batch whose responses add up to more than the frame
+// limit is answered with a ResourceExhausted error in place of the BatchResponse.
+pub const MAX_BATCH_REQUESTS: usize = 256;
+
+// The kinds of request a client can send, used to pick a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Echo,
+    Add,
+    // Handled by the connection itself, which aborts the request being cancelled.
+    Cancel,
+    // Heartbeats, also answered by the connection.
+    Ping,
+    Pong,
+    // Connection setup, answered by the connection.
+    Hello,
+    // Parts of streams, passed on by the connection to the request they belong to.
+    Chunk,
+    StreamCredit,
+    // File transfer, handled by `files::FileStore` when the server has a storage root.
+    FileStatus,
+    Upload,
+    Download,
+    // Firmware updates, handled by `ota::FirmwareStore` when the server has a firmware
+    // directory.
+    FirmwareQuery,
+    FirmwareDownload,
+    InstallReport,
+    // Sensor readings, handled by `telemetry::TelemetryStore` unless telemetry is off.
+    TelemetryBatch,
+    TelemetryQuery,
+    // Device registration, handled by the connection, and queries about registered devices,
+    // answered by `registry::DeviceRegistry`.
+    RegisterDevice,
+    DeviceQuery,
+    // Publish/subscribe, handled by the connection, which the subscriptions belong to.
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    // Chat rooms, also handled by the connection, which joins and leaves them.
+    JoinRoom,
+    LeaveRoom,
+    PostToRoom,
+    // The key-value store, answered by `kv::KvStore`, except for watches, which belong to
+    // the connection.
+    KvGet,
+    KvSet,
+    KvDelete,
+    KvCompareAndSwap,
+    KvList,
+    KvWatch,
+    KvUnwatch,
+    // Many requests in one, answered by the router itself with the handlers of each.
+    Batch,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 32] = [
+        MessageKind::Echo,
+        MessageKind::Add,
+        MessageKind::Cancel,
+        MessageKind::Ping,
+        MessageKind::Pong,
+        MessageKind::Hello,
+        MessageKind::Chunk,
+        MessageKind::StreamCredit,
+        MessageKind::FileStatus,
+        MessageKind::Upload,
+        MessageKind::Download,
+        MessageKind::FirmwareQuery,
+        MessageKind::FirmwareDownload,
+        MessageKind::InstallReport,
+        MessageKind::TelemetryBatch,
+        MessageKind::TelemetryQuery,
+        MessageKind::RegisterDevice,
+        MessageKind::DeviceQuery,
+        MessageKind::Subscribe,
+        MessageKind::Unsubscribe,
+        MessageKind::Publish,
+        MessageKind::JoinRoom,
+        MessageKind::LeaveRoom,
+        MessageKind::PostToRoom,
+        MessageKind::KvGet,
+        MessageKind::KvSet,
+        MessageKind::KvDelete,
+        MessageKind::KvCompareAndSwap,
+        MessageKind::KvList,
+        MessageKind::KvWatch,
+        MessageKind::KvUnwatch,
+        MessageKind::Batch,
+    ];
+
+    pub fn of(message: &client_message::Message) -> Self {
+        match message {
+            client_message::Message::EchoMessage(_) => MessageKind::Echo,
+            client_message::Message::AddRequest(_) => MessageKind::Add,
+            client_message::Message::Cancel(_) => MessageKind::Cancel,
+            client_message::Message::Ping(_) => MessageKind::Ping,
+            client_message::Message::Pong(_) => MessageKind::Pong,
+            client_message::Message::Hello(_) => MessageKind::Hello,
+            client_message::Message::Chunk(_) => MessageKind::Chunk,
+            client_message::Message::StreamCredit(_) => MessageKind::StreamCredit,
+            client_message::Message::FileStatus(_) => MessageKind::FileStatus,
+            client_message::Message::Upload(_) => MessageKind::Upload,
+            client_message::Message::Download(_) => MessageKind::Download,
+            client_message::Message::FirmwareQuery(_) => MessageKind::FirmwareQuery,
+            client_message::Message::FirmwareDownload(_) => MessageKind::FirmwareDownload,
+            client_message::Message::InstallReport(_) => MessageKind::InstallReport,
+            client_message::Message::TelemetryBatch(_) => MessageKind::TelemetryBatch,
+            client_message::Message::TelemetryQuery(_) => MessageKind::TelemetryQuery,
+            client_message::Message::RegisterDevice(_) => MessageKind::RegisterDevice,
+            client_message::Message::DeviceQuery(_) => MessageKind::DeviceQuery,
+            client_message::Message::Subscribe(_) => MessageKind::Subscribe,
+            client_message::Message::Unsubscribe(_) => MessageKind::Unsubscribe,
+            client_message::Message::Publish(_) => MessageKind::Publish,
+            client_message::Message::JoinRoom(_) => MessageKind::JoinRoom,
+            client_message::Message::LeaveRoom(_) => MessageKind::LeaveRoom,
+            client_message::Message::PostToRoom(_) => MessageKind::PostToRoom,
+            client_message::Message::KvGet(_) => MessageKind::KvGet,
+            client_message::Message::KvSet(_) => MessageKind::KvSet,
+            client_message::Message::KvDelete(_) => MessageKind::KvDelete,
+            client_message::Message::KvCompareAndSwap(_) => MessageKind::KvCompareAndSwap,
+            client_message::Message::KvList(_) => MessageKind::KvList,
+            client_message::Message::KvWatch(_) => MessageKind::KvWatch,
+            client_message::Message::KvUnwatch(_) => MessageKind::KvUnwatch,
+            client_message::Message::BatchRequest(_) => MessageKind::Batch,
+        }
+    }
+}
+
+// Where a connection comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    // The path of the serial port the device is attached to.
+    Serial(String),
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Serial(path) => path.fmt(f),
+        }
+    }
+}
+
+// Information about the connection a request arrived on.
+#[derive(Debug, Clone)]
+pub struct Context {
+    peer: Peer,
+    deadline: Option<Instant>,
+    streams: Option<Arc<Streams>>,
+}
+
+// The streams of one pipelined request. A handler can take each of them once.
+struct Streams {
+    body: Mutex<Option<ByteStream>>,
+    response: Mutex<Option<ChunkSink>>,
+}
+
+impl fmt::Debug for Streams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Streams")
+            .field("body", &self.body.lock().unwrap().is_some())
+            .field("response", &self.response.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl Context {
+    pub fn new(peer: impl Into<Peer>) -> Self {
+        Context {
+            peer: peer.into(),
+            deadline: None,
+            streams: None,
+        }
+    }
+
+    // The same connection, for a pipelined request that can stream its response, and
+    // whose body may follow as a stream.
+    pub fn with_streams(self, body: Option<ByteStream>, response: ChunkSink) -> Self {
+        let streams = Streams {
+            body: Mutex::new(body),
+            response: Mutex::new(Some(response)),
+        };
+        Context {
+            streams: Some(Arc::new(streams)),
+            ..self
+        }
+    }
+
+    // The streamed body of the request, if the client sent one.
+    pub fn take_body(&self) -> Option<ByteStream> {
+        self.streams.as_ref()?.body.lock().unwrap().take()
+    }
+
+    // Stream the response in chunks instead of replying with a message; the handler then
+    // returns `None`, or an error if the stream fails part way. Only pipelined requests can
+    // stream, as the chunks are tagged with the request's id.
+    pub fn response_stream(&self) -> Option<ChunkSink> {
+        self.streams.as_ref()?.response.lock().unwrap().take()
+    }
+
+    // The same connection, for a request that must be answered by `deadline`.
+    pub fn with_deadline(self, deadline: Option<Instant>) -> Self {
+        Context { deadline, ..self }
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    // When the caller stops waiting for the current request, if it said.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Option<server_message::Message>> + Send>>;
+
+// The reply to a dispatched request, which doesn't borrow the router.
+type ReplyFuture = Pin<Box<dyn Future<Output = Option<ServerMessage>> + Send>>;
+
+// Something that answers one kind of request. Returning `None` sends no reply.
+//
+// Implemented for async closures, so a handler can be registered with
+// `router.route(MessageKind::Echo, |ctx, request| async move { ... })`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, ctx: Context, request: client_message::Message) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Context, client_message::Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<server_message::Message>> + Send + 'static,
+{
+    fn call(&self, ctx: Context, request: client_message::Message) -> HandlerFuture {
+        Box::pin(self(ctx, request))
+    }
+}
+
+// Maps each kind of request to the handler that answers it.
+#[derive(Clone)]
+pub struct Router {
+    handlers: HashMap<MessageKind, Arc<dyn Handler>>,
+    batch_concurrency: usize, // Requests of one batch that run at once
+}
+
+impl Router {
+    // A router with no handlers registered.
+    pub fn new() -> Self {
+        Router {
+            handlers: HashMap::new(),
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+        }
+    }
+
+    // Run up to `limit` requests of a batch at once, and at least one.
+    pub fn batch_concurrency(mut self, limit: usize) -> Self {
+        self.batch_concurrency = limit.max(1);
+        self
+    }
+
+    // Register `handler` for `kind`, replacing any handler already registered for it.
+    pub fn route(mut self, kind: MessageKind, handler: impl Handler) -> Self {
+        self.handlers.insert(kind, Arc::new(handler));
+        self
+    }
+
+    pub fn handler(&self, kind: MessageKind) -> Option<Arc<dyn Handler>> {
+        self.handlers.get(&kind).cloned()
+    }
+
+    // Run the handler registered for `request` and wrap its reply for the wire, tagged with
+    // the request's id.
+    //
+    // If the context has a deadline, a handler is only started before it and is cancelled
+    // when it passes; either way the caller gets a `deadline_exceeded` error instead.
+    //
+    // A BatchRequest is answered here, with a BatchResponse holding what the handlers of its
+    // requests reply.
+    pub async fn dispatch(&self, ctx: Context, request: ClientMessage) -> Option<ServerMessage> {
+        self.start(ctx, request).await
+    }
+
+    // The reply `dispatch` gives, as a future of its own so batched requests can run as
+    // tasks.
+    fn start(&self, ctx: Context, request: ClientMessage) -> ReplyFuture {
+        let request_id = request.request_id;
+        let Some(request) = request.message else {
+            warn!("Received an empty ClientMessage");
+            return Box::pin(async { None });
+        };
+        if let client_message::Message::BatchRequest(batch) = request {
+            return self.batch(ctx, batch, request_id);
+        }
+
+        let kind = MessageKind::of(&request);
+        let Some(handler) = self.handler(kind) else {
+            warn!("No handler registered for {:?}", kind);
+            return Box::pin(async { None });
+        };
+
+        Box::pin(async move {
+            let reply = match ctx.deadline() {
+                Some(deadline) if Instant::now() >= deadline => {
+                    warn!(
+                        "Skipping {:?} request: its deadline has already passed",
+                        kind
+                    );
+                    return Some(deadline_exceeded(request_id));
+                }
+                Some(deadline) => {
+                    match time::timeout_at(deadline, handler.call(ctx, request)).await {
+                        Ok(reply) => reply?,
+                        Err(_) => {
+                            warn!("Cancelled {:?} request: its deadline passed", kind);
+                            return Some(deadline_exceeded(request_id));
+                        }
+                    }
+                }
+                None => handler.call(ctx, request).await?,
+            };
+
+            Some(ServerMessage {
+                message: Some(reply),
+                request_id,
+            })
+        })
+    }
+
+    // Answer every request in `batch`, starting them in order and running up to
+    // `batch_concurrency` at once.
+    fn batch(&self, ctx: Context, batch: BatchRequest, request_id: u64) -> ReplyFuture {
+        if batch.requests.len() > MAX_BATCH_REQUESTS {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Batch of {} requests, more than the {} allowed",
+                    batch.requests.len(),
+                    MAX_BATCH_REQUESTS
+                ),
+            );
+            warn!("Refused a batch from {}: {}", ctx.peer(), e);
+            return ready(error_reply(&e), request_id);
+        }
+        let fail_fast = batch.mode() == batch_request::Mode::FailFast;
+        let limit = if batch.sequential {
+            1
+        } else {
+            self.batch_concurrency
+        };
+
+        // The batch's streams, if it has any, aren't for its requests.
+        let item_ctx = Context::new(ctx.peer().clone()).with_deadline(ctx.deadline());
+        let request_ids: Vec<_> = batch.requests.iter().map(|item| item.request_id).collect();
+        let items: Vec<_> = batch
+            .requests
+            .into_iter()
+            .map(|item| self.start_batched(item_ctx.clone(), item))
+            .collect();
+        let mut items = items.into_iter().enumerate();
+
+        Box::pin(async move {
+            let mut responses = vec![None; request_ids.len()];
+            let mut running = JoinSet::new();
+            let mut indexes = HashMap::new(); // Of the requests running, by task
+            let mut failed = 0;
+            loop {
+                while running.len() < limit && !(fail_fast && failed > 0) {
+                    let Some((i, item)) = items.next() else {
+                        break;
+                    };
+                    indexes.insert(running.spawn(item).id(), i);
+                }
+                let (i, response) = match running.join_next_with_id().await {
+                    Some(Ok((id, response))) => (indexes[&id], response),
+                    Some(Err(e)) => {
+                        error!("Batched request failed: {}", e);
+                        let i = indexes[&e.id()];
+                        let e = io::Error::other("Request failed");
+                        let response = ServerMessage {
+                            message: Some(error_reply(&e)),
+                            request_id: request_ids[i],
+                        };
+                        (i, Some(response))
+                    }
+                    None => break,
+                };
+                if let Some(ServerMessage {
+                    message: Some(server_message::Message::Error(_)),
+                    ..
+                }) = response
+                {
+                    failed += 1;
+                }
+                responses[i] = response;
+            }
+
+            let not_started = responses
+                .iter()
+                .filter(|response| response.is_none())
+                .count();
+            debug!(
+                "Answered a batch of {} requests: {} failed, {} not started",
+                responses.len(),
+                failed,
+                not_started
+            );
+            let responses = responses
+                .into_iter()
+                .zip(request_ids)
+                .map(|(response, request_id)| response.unwrap_or_else(|| aborted(request_id)))
+                .collect();
+            Some(ServerMessage {
+                message: Some(server_message::Message::BatchResponse(BatchResponse {
+                    responses,
+                })),
+                request_id,
+            })
+        })
+    }
+
+    // The reply to `request` as part of a batch, which is an Error if it can't be batched.
+    fn start_batched(&self, ctx: Context, request: ClientMessage) -> ReplyFuture {
+        let request_id = request.request_id;
+        let Some(kind) = request.message.as_ref().map(MessageKind::of) else {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "Empty request in a batch");
+            return ready(error_reply(&e), request_id);
+        };
+        let unbatchable = move || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} requests can't be batched", kind),
+            )
+        };
+        if kind == MessageKind::Batch || self.handler(kind).is_none() {
+            return ready(error_reply(&unbatchable()), request_id);
+        }
+
+        let request = ClientMessage {
+            streaming: false,
+            deadline_ms: 0,
+            ..request
+        };
+        let reply = self.start(ctx, request);
+        Box::pin(async move {
+            // Handlers that only reply through a stream have nothing to put in the response.
+            let reply = reply.await.unwrap_or_else(|| ServerMessage {
+                message: Some(error_reply(&unbatchable())),
+                request_id,
+            });
+            Some(reply)
+        })
+    }
+}
+
+// A reply that is already known.
+fn ready(message: server_message::Message, request_id: u64) -> ReplyFuture {
+    let reply = ServerMessage {
+        message: Some(message),
+        request_id,
+    };
+    Box::pin(async move { Some(reply) })
+}
+
+// The reply to a batched request that wasn't started, as one before it failed.
+fn aborted(request_id: u64) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::Error(Error {
+            code: error::Code::Aborted as i32,
+            message: "Not run, as an earlier request in the batch failed".to_string(),
+        })),
+        request_id,
+    }
+}
+
+// The Error reply for a handler that failed with `e`.
+pub fn error_reply(e: &io::Error) -> server_message::Message {
+    let code = match e.kind() {
+        io::ErrorKind::NotFound => error::Code::NotFound,
+        io::ErrorKind::InvalidInput => error::Code::InvalidArgument,
+        io::ErrorKind::InvalidData => error::Code::DataLoss,
+        io::ErrorKind::TimedOut => error::Code::DeadlineExceeded,
+        io::ErrorKind::ResourceBusy => error::Code::ResourceExhausted,
+        _ => error::Code::Unspecified,
+    };
+    server_message::Message::Error(Error {
+        code: code as i32,
+        message: e.to_string(),
+    })
+}
+
+// The error a client reports for an Error reply; the inverse of `error_reply`.
+pub fn error_from_reply(error: Error) -> io::Error {
+    let kind = match error.code() {
+        error::Code::NotFound => io::ErrorKind::NotFound,
+        error::Code::InvalidArgument => io::ErrorKind::InvalidInput,
+        error::Code::DataLoss => io::ErrorKind::InvalidData,
+        error::Code::DeadlineExceeded => io::ErrorKind::TimedOut,
+        error::Code::ResourceExhausted => io::ErrorKind::ResourceBusy,
+        error::Code::Aborted | error::Code::Unspecified => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, error.message)
+}
+
+// The error for a `response` that isn't the `expected` one, which for an Error is the error
+// it reports.
+pub(crate) fn unexpected(expected: &str, response: server_message::Message) -> io::Error {
+    match response {
+        server_message::Message::Error(error) => error_from_reply(error),
+        response => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected a {}, got {:?}", expected, response),
+        ),
+    }
+}
+
+fn deadline_exceeded(request_id: u64) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::Error(Error {
+            code: error::Code::DeadlineExceeded as i32,
+            message: "Deadline exceeded".to_string(),
+        })),
+        request_id,
+    }
+}
+
+// The built-in Echo and Add handlers.
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+            .route(MessageKind::Echo, echo)
+            .route(MessageKind::Add, add)
+    }
+}
+
+async fn echo(ctx: Context, request: client_message::Message) -> Option<server_message::Message> {
+    let client_message::Message::EchoMessage(echo_message) = request else {
+        return None;
+    };
+
+    // A streamed body is echoed back as a streamed response
+    if let (Some(body), Some(response)) = (ctx.take_body(), ctx.response_stream()) {
+        info!("Echoing a streamed body");
+        return match echo_stream(body, response).await {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("Streamed echo failed: {}", e);
+                Some(error_reply(&e))
+            }
+        };
+    }
+
+    info!("Received EchoMessage: {}", echo_message.content);
+    Some(server_message::Message::EchoMessage(echo_message))
+}
+
+async fn echo_stream(mut body: ByteStream, mut response: ChunkSink) -> io::Result<()> {
+    response.send_from(&mut body).await?;
+    response.finish().await
+}
+
+async fn add(_ctx: Context, request: client_message::Message) -> Option<server_message::Message> {
+    let client_message::Message::AddRequest(add_request) = request else {
+        return None;
+    };
+
+    // Perform the addition, wrapping on overflow like the two's complement i32 it is
+    let sum = add_request.a.wrapping_add(add_request.b);
+    info!("Adding {} + {} = {}", add_request.a, add_request.b, sum);
+
+    Some(server_message::Message::AddResponse(AddResponse {
+        result: sum,
+    }))
+}
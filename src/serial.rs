@@ -0,0 +1,18 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+// A byte stream that frames are carried over, such as a TCP socket or a serial port.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+// Open a serial port in raw 8N1 mode without flow control. Serial connections carry the
+// same messages as TCP, framed with `Framing::Serial` so line noise only costs the packets
+// it hits.
+pub fn open(path: &str, baud_rate: u32) -> io::Result<SerialStream> {
+    let stream = tokio_serial::new(path, baud_rate).open_native_async()?;
+    Ok(stream)
+}
@@ -1,97 +1,978 @@
-use log::{error, info, warn};
+use crate::chat::Rooms;
+use crate::checksum::{FrameChecksum, CHECKSUM_LEN};
+use crate::codec::{FrameError, Framing};
+use crate::compression::{Algorithm, FrameCompression};
+use crate::config::{SerialPortConfig, ServerConfig};
+use crate::connections::Connections;
+use crate::files::{invalid_input, FileStore};
+use crate::heartbeat::timestamp_us;
+use crate::kv::KvStore;
+use crate::message::{
+    client_message, error, server_message, Chunk, ClientMessage, Delivery, Error, Hello,
+    HelloReply, KvWatchAck, Ping, Pong, Publish, PublishAck, RegisterDevice, RoomAck,
+    ServerMessage, StreamCredit, SubscribeAck,
+};
+use crate::metrics::Metrics;
+use crate::ota::FirmwareStore;
+use crate::pubsub::{Broker, Mailbox, Publishing};
+use crate::reconnect::{Backoff, ReconnectPolicy};
+use crate::registry::DeviceRegistry;
+use crate::router::{error_reply, Context, Peer, Router};
+use crate::serial::{self, Transport};
+use crate::stream::{chunk_channel, initial_credit, ByteStream, ChunkResult, ChunkSink};
+use crate::telemetry::TelemetryStore;
+use log::{debug, error, info, warn};
 use prost::Message;
-use std::sync::{Arc, Mutex};
-use tokio::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::io;
-use crate::message::{EchoMessage,AddRequest,AddResponse};
-use tokio::time;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::task::{self, AbortHandle, JoinError, JoinSet};
+use tokio::time::{self, Duration, Instant};
+use tokio_serial::SerialStream;
+
+// How long a serial port's connection has to stay up before the delay for reopening the port
+// starts over.
+const SERIAL_SETTLED: Duration = Duration::from_secs(30);
+
+// What woke up a client connection.
+enum Event {
+    Read(io::Result<usize>),
+    Written(io::Result<usize>),
+    Finished(Result<(task::Id, Option<ServerMessage>), JoinError>),
+    Outgoing(ServerMessage),
+    Pushed(Option<ServerMessage>),
+    Delivery(Option<Delivery>),
+    Published(u32),
+    Heartbeat,
+    Replaced,
+    Shutdown,
+}
+
+// A pipelined request, from when it is read until its handler finishes.
+struct Pipelined {
+    abort: Option<AbortHandle>,              // Set once the handler is running
+    body: Option<mpsc::Sender<ChunkResult>>, // Until the body's last chunk arrives
+    credit: Arc<Semaphore>,                  // Chunks the response stream may still send
+}
+
+// A pipelined request waiting for room to run.
+struct Queued {
+    request_id: u64,
+    context: Context,
+    request: ClientMessage,
+}
+
+// What every connection of a server shares.
+#[derive(Clone)]
+struct Shared {
+    router: Arc<Router>,      // Handlers for requests
+    metrics: Arc<Metrics>,    // Counted across every connection, kept over reloads
+    registry: DeviceRegistry, // Devices registered on any connection
+    broker: Broker,           // Subscriptions of every connection
+    connections: Connections, // Every open connection
+    rooms: Rooms,             // Chat rooms and who is in them
+    kv: KvStore,              // Keys shared by every connection, and who watches them
+}
 
 // Client struct for handling individual client connections.
 struct Client {
-    stream: TcpStream,
+    reader: ReadHalf<Box<dyn Transport>>,
+    writer: WriteHalf<Box<dyn Transport>>,
+    outbox: Vec<u8>, // Frames waiting for the transport to take them
+    framing: Framing,
+    context: Context,
+    router: Arc<Router>,
+    shutdown: watch::Receiver<bool>,
+    max_frame_len: usize,
+    max_pipelined_requests: usize,
+    heartbeat: Option<(Duration, Duration)>, // Ping after this long quiet, close this long after
+    compression_allowed: Vec<Algorithm>,
+    compression_threshold: usize,
+    compression: FrameCompression, // Agreed on by the client's Hello
+    checksum_allowed: bool,
+    checksum: FrameChecksum, // Also agreed on by the Hello
+    metrics: Arc<Metrics>,   // The server's, shared by every connection
+    registry: DeviceRegistry,
+    device: Option<(String, u64)>, // The device registered on this connection, and its session
+    replaced: Arc<Notify>,         // Told when the device registers on another connection
+    broker: Broker,
+    mailbox: Arc<Mailbox>, // Published messages for this connection's subscriptions
+    // The Publish being queued for its subscribers, by request_id
+    publishing: Option<(u64, Publishing)>,
+    connections: Connections,
+    id: u64,                               // This connection's, among `connections`
+    pushed: mpsc::Receiver<ServerMessage>, // Room events and key changes for this connection
+    rooms: Rooms,
+    kv: KvStore,
+    greeted: bool, // Whether the first message has arrived; only it may be a Hello
+    in_flight: JoinSet<Option<ServerMessage>>, // Handlers of pipelined requests (those with an id)
+    queued: VecDeque<Queued>, // Pipelined requests waiting for room in `in_flight`
+    requests: HashMap<u64, Pipelined>, // Queued and in-flight requests by id
+    outgoing: mpsc::UnboundedSender<ServerMessage>, // Stream chunks and credit from handlers
+    outgoing_rx: mpsc::UnboundedReceiver<ServerMessage>,
 }
 
 impl Client {
     // Create a new client instance.
-    pub fn new(stream: TcpStream) -> Self {
-        Client { stream }
+    pub fn new(
+        stream: Box<dyn Transport>,
+        peer: Peer,
+        framing: Framing,
+        shared: Shared,
+        shutdown: watch::Receiver<bool>,
+        config: &ServerConfig,
+    ) -> Self {
+        let Shared {
+            router,
+            metrics,
+            registry,
+            broker,
+            connections,
+            rooms,
+            kv,
+        } = shared;
+        let (id, pushed) = connections.open(peer.clone());
+        let mailbox = broker.mailbox(
+            config.subscriber_queue_len,
+            config.slow_subscriber,
+            Arc::clone(&metrics),
+        );
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (reader, writer) = io::split(stream);
+        Client {
+            reader,
+            writer,
+            outbox: Vec::new(),
+            framing,
+            context: Context::new(peer),
+            router,
+            shutdown,
+            max_frame_len: config.max_frame_len,
+            max_pipelined_requests: config.max_pipelined_requests,
+            heartbeat: config.heartbeat(),
+            compression_allowed: config.compression.clone(),
+            compression_threshold: config.compression_threshold,
+            compression: FrameCompression::off(),
+            checksum_allowed: config.checksum,
+            checksum: FrameChecksum::off(),
+            metrics,
+            registry,
+            device: None,
+            replaced: Arc::default(),
+            broker,
+            mailbox,
+            publishing: None,
+            connections,
+            id,
+            pushed,
+            rooms,
+            kv,
+            greeted: false,
+            in_flight: JoinSet::new(),
+            queued: VecDeque::new(),
+            requests: HashMap::new(),
+            outgoing,
+            outgoing_rx,
+        }
     }
 
     // Asynchronous method to handle the client's communication.
     pub async fn handle(&mut self) -> io::Result<()> {
-        let mut buffer = [0; 512];
-    
+        let mut buffer = [0; 4096];
+        let mut decoder = self.framing.decoder(self.max_frame_len);
+        let mut received = Instant::now(); // When the last read completed, for request deadlines
+        let mut pinged = None; // When a heartbeat was sent that nothing has been read since
+
         loop {
-            // Read data from the stream asynchronously
-            let bytes_read = match self.stream.read(&mut buffer).await {
-                Ok(n) if n > 0 => n, // Successfully read data
-                Ok(_) => {
+            self.start_queued();
+
+            // Handle every complete frame already received before reading more. Requests
+            // beyond the pipelining limit wait in a queue, and once that is as long again the
+            // server stops reading, unless a running stream is waiting on the client.
+            while self.can_read() {
+                let frame = decoder.decode().inspect_err(|e| {
+                    error!("Closing connection after invalid frame: {}", e);
+                })?;
+                let Some(frame) = frame else {
+                    break;
+                };
+                let frame = match self.checksum.decode(&frame) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        self.reject_corrupted(&frame, e)?;
+                        continue;
+                    }
+                };
+                let payload = self.compression.decode(frame).inspect_err(|e| {
+                    error!("Closing connection after invalid compressed frame: {}", e);
+                })?;
+                self.process(&payload, received).await?;
+            }
+            let can_read = self.can_read();
+            let is_publishing = self.publishing.is_some();
+            let publishing = &mut self.publishing;
+
+            // A quiet connection is pinged, and closed if it stays quiet. Only while reading,
+            // as a busy connection might be answering but not being heard.
+            let heartbeat_at = self.heartbeat.map(|(interval, timeout)| match pinged {
+                Some(pinged) => pinged + timeout,
+                None => received + interval,
+            });
+
+            // Read data from the stream asynchronously, unless the server is shutting down.
+            // A request that was already read is always answered before the connection closes.
+            // Replies are written as the client takes them, so a client that is itself busy
+            // writing doesn't stop the server reading.
+            let event = tokio::select! {
+                result = self.reader.read(&mut buffer), if can_read => Event::Read(result),
+                result = self.writer.write(&self.outbox), if !self.outbox.is_empty() => {
+                    Event::Written(result)
+                }
+                Some(message) = self.outgoing_rx.recv() => Event::Outgoing(message),
+                Some(result) = self.in_flight.join_next_with_id(), if !self.in_flight.is_empty() => {
+                    Event::Finished(result)
+                }
+                pushed = self.pushed.recv(), if self.outbox.len() < self.max_frame_len => {
+                    Event::Pushed(pushed)
+                }
+                delivery = self.mailbox.next(), if self.outbox.len() < self.max_frame_len => {
+                    Event::Delivery(delivery)
+                }
+                subscribers = async { publishing.as_mut().expect("Publishing").1.as_mut().await },
+                    if is_publishing => Event::Published(subscribers),
+                _ = time::sleep_until(heartbeat_at.unwrap_or_else(Instant::now)),
+                    if can_read && heartbeat_at.is_some() => Event::Heartbeat,
+                _ = self.replaced.notified(), if self.device.is_some() => Event::Replaced,
+                _ = self.shutdown.wait_for(|stop| *stop) => Event::Shutdown,
+            };
+
+            let bytes_read = match event {
+                Event::Read(Ok(n)) if n > 0 => n, // Successfully read data
+                Event::Read(Ok(_)) => {
                     // No data was read (client closed connection)
                     info!("Client disconnected.");
-                    return Ok(()); // Exit the loop and return
+                    return self.flush().await; // Exit the loop and return
                 }
-                Err(e) => {
+                Event::Read(Err(e)) => {
                     // Error occurred while reading
                     error!("Failed to read from client: {}", e);
                     return Err(e); // Return error
                 }
+                Event::Written(result) => {
+                    self.written(result).await?;
+                    continue;
+                }
+                Event::Finished(result) => {
+                    self.finish(result);
+                    continue;
+                }
+                Event::Outgoing(message) | Event::Pushed(Some(message)) => {
+                    self.send(&message);
+                    continue;
+                }
+                Event::Pushed(None) => {
+                    warn!("Closing connection that fell behind on room events and key changes.");
+                    return Ok(());
+                }
+                Event::Delivery(Some(delivery)) => {
+                    self.send(&ServerMessage {
+                        message: Some(server_message::Message::Delivery(delivery)),
+                        ..ServerMessage::default()
+                    });
+                    continue;
+                }
+                Event::Delivery(None) => {
+                    warn!("Closing connection of a subscriber that fell behind.");
+                    self.metrics.record_slow_subscriber();
+                    return Ok(());
+                }
+                Event::Published(subscribers) => {
+                    let (request_id, _) = self.publishing.take().expect("Publishing");
+                    let ack = PublishAck { subscribers };
+                    self.send(&ServerMessage {
+                        message: Some(server_message::Message::PublishAck(ack)),
+                        request_id,
+                    });
+                    continue;
+                }
+                Event::Heartbeat if pinged.is_some() => {
+                    warn!("Closing connection that didn't answer a heartbeat.");
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "No reply to heartbeat",
+                    ));
+                }
+                Event::Heartbeat => {
+                    let ping = Ping {
+                        timestamp_us: timestamp_us(),
+                    };
+                    self.send(&ServerMessage {
+                        message: Some(server_message::Message::Ping(ping)),
+                        ..ServerMessage::default()
+                    });
+                    pinged = Some(Instant::now());
+                    continue;
+                }
+                Event::Replaced => {
+                    // The device is talking to the server elsewhere, so nothing here is wanted.
+                    info!("Closing stale session of a device that reconnected.");
+                    return Ok(());
+                }
+                Event::Shutdown => {
+                    info!("Closing client connection for shutdown.");
+                    return self.drain().await;
+                }
             };
-    
-            // Try to decode AddRequest message
-            if let Ok(add_request) = AddRequest::decode(&buffer[..bytes_read]) {
-                // Perform the addition
-                let sum = add_request.a + add_request.b;
-    
-                // Log the result
-                info!("Adding {} + {} = {}", add_request.a, add_request.b, sum);
-    
-                // Create an AddResponse with the result
-                let add_response = AddResponse {
-                    result: sum,
+
+            received = Instant::now();
+            pinged = None;
+            if let Some((device_id, session)) = &self.device {
+                self.registry.seen(device_id, *session);
+            }
+            decoder.extend(&buffer[..bytes_read]);
+        }
+    }
+
+    // Whether to read more from the client. Reading stops while the queue is full, except
+    // when a running handler may be waiting for body chunks or credit that would otherwise
+    // be stuck behind the requests not being read.
+    // Nor is anything read while a frame's worth of replies is waiting to be written, or
+    // while a Publish is waiting for room with its subscribers.
+    fn can_read(&self) -> bool {
+        self.publishing.is_none()
+            && self.outbox.len() < self.max_frame_len
+            && (self.queued.len() < self.max_pipelined_requests
+                || self.requests.values().any(|request| {
+                    request.abort.is_some()
+                        && (request.body.is_some() || request.credit.available_permits() == 0)
+                }))
+    }
+
+    // Answer every pipelined request already read, without reading anything more.
+    async fn drain(&mut self) -> io::Result<()> {
+        loop {
+            self.start_queued();
+            if self.in_flight.is_empty() {
+                return self.flush().await;
+            }
+            tokio::select! {
+                result = self.writer.write(&self.outbox), if !self.outbox.is_empty() => {
+                    self.written(result).await?
+                }
+                Some(message) = self.outgoing_rx.recv() => self.send(&message),
+                Some(result) = self.in_flight.join_next_with_id() => self.finish(result),
+            }
+        }
+    }
+
+    // Decode one frame as a ClientMessage and send back whatever the router answers.
+    // A request's deadline counts from `received`, when the frame finished arriving.
+    //
+    // Requests without an id are answered before the next frame is looked at. Requests with
+    // one run alongside each other and are answered as they finish, or not at all if the
+    // client cancels them first.
+    async fn process(&mut self, frame: &[u8], received: Instant) -> io::Result<()> {
+        let request = match ClientMessage::decode(frame) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Failed to decode message: {}", e);
+                return Ok(());
+            }
+        };
+
+        let first = !std::mem::replace(&mut self.greeted, true);
+        let request_id = request.request_id;
+
+        // Messages about the connection itself, rather than requests for the router
+        match request.message {
+            Some(client_message::Message::Hello(hello)) if first => {
+                self.hello(&hello, request_id);
+                return Ok(());
+            }
+            Some(client_message::Message::Hello(_)) => {
+                warn!("Ignoring Hello after the start of the connection");
+                return Ok(());
+            }
+            Some(client_message::Message::RegisterDevice(registration)) => {
+                self.register(registration, request_id);
+                return Ok(());
+            }
+            Some(
+                request @ (client_message::Message::Subscribe(_)
+                | client_message::Message::Unsubscribe(_)),
+            ) => {
+                self.subscription(request, request_id);
+                return Ok(());
+            }
+            Some(client_message::Message::Publish(publish)) => {
+                self.publish(publish, request_id);
+                return Ok(());
+            }
+            Some(
+                request @ (client_message::Message::JoinRoom(_)
+                | client_message::Message::LeaveRoom(_)
+                | client_message::Message::PostToRoom(_)),
+            ) => {
+                self.room(request, request_id);
+                return Ok(());
+            }
+            Some(
+                request @ (client_message::Message::KvWatch(_)
+                | client_message::Message::KvUnwatch(_)),
+            ) => {
+                self.kv_watch(request, request_id);
+                return Ok(());
+            }
+            Some(client_message::Message::Cancel(cancel)) => {
+                self.cancel(cancel.request_id);
+                return Ok(());
+            }
+            Some(client_message::Message::Chunk(chunk)) => {
+                return self.receive_chunk(request_id, chunk)
+            }
+            Some(client_message::Message::StreamCredit(credit)) => {
+                if let Some(request) = self.requests.get(&request_id) {
+                    request.credit.add_permits(credit.chunks as usize);
+                }
+                return Ok(());
+            }
+            Some(client_message::Message::Ping(ping)) => {
+                let pong = Pong {
+                    timestamp_us: ping.timestamp_us,
                 };
-    
-                // Encode the AddResponse message to a buffer
-                let mut buffer = Vec::new();
-                add_response.encode(&mut buffer);
-    
-                // Send the response asynchronously
-                if let Err(e) = self.stream.write_all(&buffer).await {
-                    error!("Failed to send response: {}", e);
-                    return Err(e); // Return error if write fails
-                }
-    
-                // Make sure all data is sent
-                if let Err(e) = self.stream.flush().await {
-                    error!("Failed to flush stream: {}", e);
-                    return Err(e); // Return error if flush fails
-                }
-            }
-            // Try to decode EchoMessage
-            else if let Ok(echo_message) = EchoMessage::decode(&buffer[..bytes_read]) {
-                // Process EchoMessage
-                info!("Received EchoMessage: {}", echo_message.content);
-                println!("Received EchoMessage: {}", echo_message.content);
-    
-                // Echo back the message asynchronously
-                let payload = echo_message.encode_to_vec();
-                if let Err(e) = self.stream.write_all(&payload).await {
-                    error!("Failed to send response: {}", e);
-                    return Err(e); // Return error if write fails
-                }
-    
-                // Make sure all data is sent
-                if let Err(e) = self.stream.flush().await {
-                    error!("Failed to flush stream: {}", e);
-                    return Err(e); // Return error if flush fails
-                }
-            }
-            // Handle unexpected message types
-            else {
-                error!("Failed to decode message");
+                self.send(&ServerMessage {
+                    message: Some(server_message::Message::Pong(pong)),
+                    request_id,
+                });
+                return Ok(());
             }
+            Some(client_message::Message::Pong(pong)) => {
+                let rtt = timestamp_us().saturating_sub(pong.timestamp_us);
+                debug!("Heartbeat answered in {}us", rtt);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let deadline = (request.deadline_ms > 0)
+            .then(|| received + Duration::from_millis(request.deadline_ms.into()));
+        let context = self.context.clone().with_deadline(deadline);
+
+        if request_id == 0 {
+            if request.streaming {
+                warn!("Ignoring the body of a streaming request without a request_id");
+            }
+            if let Some(reply) = self.router.dispatch(context, request).await {
+                self.reply(&reply);
+            }
+            return Ok(());
+        }
+        if self.requests.contains_key(&request_id) {
+            warn!(
+                "Ignoring request {}: that id is already in flight",
+                request_id
+            );
+            return Ok(());
+        }
+        if self.queued.len() >= self.max_pipelined_requests {
+            warn!(
+                "Refusing request {}: too many are already waiting",
+                request_id
+            );
+            let error = Error {
+                code: error::Code::ResourceExhausted as i32,
+                message: "Too many pipelined requests".to_string(),
+            };
+            self.send(&ServerMessage {
+                message: Some(server_message::Message::Error(error)),
+                request_id,
+            });
+            return Ok(());
+        }
+
+        // Chunks from the handler's streams go out through `outgoing`, tagged with the id.
+        let credit = initial_credit();
+        let outgoing = self.outgoing.clone();
+        let response = ChunkSink::new(
+            move |chunk| {
+                let message = ServerMessage {
+                    message: Some(server_message::Message::Chunk(chunk)),
+                    request_id,
+                };
+                outgoing
+                    .send(message)
+                    .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))
+            },
+            Arc::clone(&credit),
+        );
+        let (body, body_stream) = if request.streaming {
+            let (sender, chunks) = chunk_channel();
+            let outgoing = self.outgoing.clone();
+            let body = ByteStream::new(chunks, move |chunks| {
+                let _ = outgoing.send(ServerMessage {
+                    message: Some(server_message::Message::StreamCredit(StreamCredit {
+                        chunks,
+                    })),
+                    request_id,
+                });
+            });
+            (Some(sender), Some(body))
+        } else {
+            (None, None)
+        };
+
+        self.requests.insert(
+            request_id,
+            Pipelined {
+                abort: None,
+                body,
+                credit,
+            },
+        );
+        self.queued.push_back(Queued {
+            request_id,
+            context: context.with_streams(body_stream, response),
+            request,
+        });
+        self.start_queued();
+        Ok(())
+    }
+
+    // Start queued requests while there is room.
+    fn start_queued(&mut self) {
+        while self.in_flight.len() < self.max_pipelined_requests {
+            let Some(queued) = self.queued.pop_front() else {
+                return;
+            };
+            let router = Arc::clone(&self.router);
+            let abort = self
+                .in_flight
+                .spawn(async move { router.dispatch(queued.context, queued.request).await });
+            if let Some(request) = self.requests.get_mut(&queued.request_id) {
+                request.abort = Some(abort);
+            }
+        }
+    }
+
+    fn cancel(&mut self, request_id: u64) {
+        let Some(request) = self.requests.remove(&request_id) else {
+            info!("Nothing to cancel for request {}", request_id);
+            return;
+        };
+
+        info!("Cancelling request {}", request_id);
+        match request.abort {
+            Some(abort) => abort.abort(),
+            None => self.queued.retain(|queued| queued.request_id != request_id),
+        }
+        request.credit.close();
+    }
+
+    // Pass a chunk of a request's body on to its handler.
+    fn receive_chunk(&mut self, request_id: u64, chunk: Chunk) -> io::Result<()> {
+        let Some(request) = self.requests.get_mut(&request_id) else {
+            debug!("Ignoring chunk for unknown request {}", request_id);
+            return Ok(());
+        };
+        let Some(body) = &request.body else {
+            warn!(
+                "Ignoring chunk for request {}, which has no body",
+                request_id
+            );
+            return Ok(());
+        };
+
+        let last = chunk.last;
+        match body.try_send(Ok(chunk)) {
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                error!("Closing connection that sent chunks beyond its credit");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Chunks sent beyond the stream window",
+                ));
+            }
+            // The handler isn't reading the body, which is its choice.
+            Err(mpsc::error::TrySendError::Closed(_)) | Ok(()) => {}
+        }
+        if last {
+            request.body = None;
+        }
+        Ok(())
+    }
+
+    // Set up the connection as the client asks. Compression uses the first algorithm the
+    // client offers that this server allows, and starts after the reply.
+    fn hello(&mut self, hello: &Hello, request_id: u64) {
+        let algorithm = hello
+            .compression()
+            .filter_map(Algorithm::from_message)
+            .find(|algorithm| self.compression_allowed.contains(algorithm));
+
+        let checksum = hello.checksum && self.checksum_allowed;
+
+        let reply = HelloReply {
+            compression: Algorithm::to_message(algorithm).into(),
+            checksum,
+        };
+        self.send(&ServerMessage {
+            message: Some(server_message::Message::HelloReply(reply)),
+            request_id,
+        });
+
+        if let Some(algorithm) = algorithm {
+            info!("Compressing frames with {:?}", algorithm);
+        }
+        if checksum {
+            info!("Checksumming frames with CRC32C");
+        }
+        self.compression =
+            FrameCompression::new(algorithm, self.compression_threshold, self.max_frame_len);
+        self.checksum = FrameChecksum::new(checksum);
+    }
+
+    // Register the device on this connection, which stays its session until it closes or
+    // the device registers on another one. A connection belongs to a single device.
+    fn register(&mut self, registration: RegisterDevice, request_id: u64) {
+        let reply = match &self.device {
+            Some((device_id, _)) if *device_id != registration.device_id => Err(invalid_input(
+                format!("Connection is already registered as {}", device_id),
+            )),
+            _ => self.registry.register(
+                registration,
+                self.context.peer(),
+                Arc::clone(&self.replaced),
+            ),
+        };
+        let reply = match reply {
+            Ok((session, info)) => {
+                self.device = Some((info.device_id.clone(), session));
+                server_message::Message::DeviceInfo(info)
+            }
+            Err(e) => {
+                warn!("Refusing registration from {}: {}", self.context.peer(), e);
+                error_reply(&e)
+            }
+        };
+        self.send(&ServerMessage {
+            message: Some(reply),
+            request_id,
+        });
+    }
+
+    // Subscribe or unsubscribe this connection. Its subscriptions last until it closes.
+    fn subscription(&mut self, request: client_message::Message, request_id: u64) {
+        let result = match request {
+            client_message::Message::Subscribe(subscribe) => {
+                self.broker.subscribe(&self.mailbox, subscribe.pattern)
+            }
+            client_message::Message::Unsubscribe(unsubscribe) => {
+                self.broker.unsubscribe(&self.mailbox, &unsubscribe.pattern)
+            }
+            _ => return,
+        };
+        let reply = match result {
+            Ok(()) => server_message::Message::SubscribeAck(SubscribeAck {}),
+            Err(e) => {
+                warn!("Subscription from {} failed: {}", self.context.peer(), e);
+                error_reply(&e)
+            }
+        };
+        self.send(&ServerMessage {
+            message: Some(reply),
+            request_id,
+        });
+    }
+
+    // Start queueing a published message for its subscribers. Nothing more is read from the
+    // connection until it is queued for all of them, so a connection's messages keep their
+    // order, but deliveries to this connection still go out meanwhile.
+    fn publish(&mut self, publish: Publish, request_id: u64) {
+        match self.broker.publish(publish) {
+            Ok(publishing) => self.publishing = Some((request_id, publishing)),
+            Err(e) => {
+                warn!("Publish from {} failed: {}", self.context.peer(), e);
+                self.send(&ServerMessage {
+                    message: Some(error_reply(&e)),
+                    request_id,
+                });
+            }
+        }
+    }
+
+    // Join or leave a chat room, or post to one. Rooms are left when the connection closes.
+    fn room(&mut self, request: client_message::Message, request_id: u64) {
+        let reply = match request {
+            client_message::Message::JoinRoom(join) => self
+                .rooms
+                .join(self.id, self.context.peer(), join)
+                .map(server_message::Message::RoomJoined),
+            client_message::Message::LeaveRoom(leave) => self
+                .rooms
+                .leave(self.id, &leave.room)
+                .map(|()| server_message::Message::RoomAck(RoomAck {})),
+            client_message::Message::PostToRoom(post) => self
+                .rooms
+                .post(self.id, post)
+                .map(|()| server_message::Message::RoomAck(RoomAck {})),
+            _ => return,
+        };
+        let reply = reply.unwrap_or_else(|e| {
+            warn!("Room request from {} failed: {}", self.context.peer(), e);
+            error_reply(&e)
+        });
+        self.reply(&ServerMessage {
+            message: Some(reply),
+            request_id,
+        });
+    }
+
+    // Start or stop watching keys in the key-value store. Watches end with the connection.
+    fn kv_watch(&mut self, request: client_message::Message, request_id: u64) {
+        let result = match request {
+            client_message::Message::KvWatch(watch) => self.kv.watch(self.id, watch),
+            client_message::Message::KvUnwatch(unwatch) => self.kv.unwatch(self.id, unwatch),
+            _ => return,
+        };
+        let reply = match result {
+            Ok(()) => server_message::Message::KvWatchAck(KvWatchAck {}),
+            Err(e) => {
+                warn!("Watch request from {} failed: {}", self.context.peer(), e);
+                error_reply(&e)
+            }
+        };
+        self.send(&ServerMessage {
+            message: Some(reply),
+            request_id,
+        });
+    }
+
+    // Deal with a frame that failed its checksum. If it still reads as a request that is owed
+    // a reply, it is answered with a DataLoss error under the request_id it seems to carry,
+    // and the frames after it are still read. Anything else owed nothing: answering it would
+    // put the client's replies out of step, and losing it may have left a request or stream
+    // waiting on it, so the connection is closed instead.
+    fn reject_corrupted(&mut self, frame: &[u8], e: FrameError) -> io::Result<()> {
+        self.metrics.record_integrity_error();
+        let payload = &frame[..frame.len().saturating_sub(CHECKSUM_LEN)];
+        let request = self
+            .compression
+            .decode(payload)
+            .ok()
+            .and_then(|payload| ClientMessage::decode(&payload[..]).ok())
+            .filter(|request| self.owes_reply(request));
+        let Some(request) = request else {
+            error!("Closing connection after corrupted frame: {}", e);
+            return Err(e.into());
+        };
+
+        warn!("Rejecting corrupted request {}: {}", request.request_id, e);
+        let error = Error {
+            code: error::Code::DataLoss as i32,
+            message: e.to_string(),
+        };
+        self.send(&ServerMessage {
+            message: Some(server_message::Message::Error(error)),
+            request_id: request.request_id,
+        });
+        Ok(())
+    }
+
+    // Whether `request` gets a reply, rather than being a message about a request or
+    // heartbeat already under way.
+    fn owes_reply(&self, request: &ClientMessage) -> bool {
+        match request.message {
+            Some(client_message::Message::Hello(_)) => !self.greeted,
+            Some(
+                client_message::Message::Pong(_)
+                | client_message::Message::Cancel(_)
+                | client_message::Message::StreamCredit(_)
+                | client_message::Message::Chunk(_),
+            )
+            | None => false,
+            Some(_) => true,
+        }
+    }
+
+    // Send the reply of a pipelined request that finished or was cancelled.
+    fn finish(&mut self, result: Result<(task::Id, Option<ServerMessage>), JoinError>) {
+        let (id, reply) = match result {
+            Ok(finished) => finished,
+            Err(e) => {
+                if !e.is_cancelled() {
+                    error!("Request handler failed: {}", e);
+                }
+                self.forget(e.id());
+                return;
+            }
+        };
+        self.forget(id);
+
+        // Whatever the handler streamed goes out before its reply.
+        while let Ok(message) = self.outgoing_rx.try_recv() {
+            self.send(&message);
+        }
+        if let Some(reply) = reply {
+            self.reply(&reply);
+        }
+    }
+
+    // Drop what is kept for the request whose handler was task `id`.
+    fn forget(&mut self, id: task::Id) {
+        let request_id = self.requests.iter().find_map(|(&request_id, request)| {
+            request
+                .abort
+                .as_ref()
+                .is_some_and(|abort| abort.id() == id)
+                .then_some(request_id)
+        });
+        if let Some(request) = request_id.and_then(|request_id| self.requests.remove(&request_id)) {
+            request.credit.close();
+        }
+    }
+
+    // Queue `reply` to be written as the client takes it.
+    fn send(&mut self, reply: &ServerMessage) {
+        if let Err(e) = self.frame(reply) {
+            error!("Dropping response: {}", e);
+        }
+    }
+
+    // Queue the reply to a request. One that can't be framed, such as a batch whose responses
+    // add up to more than the frame limit, is answered with an error instead, so the client
+    // isn't left waiting for it.
+    fn reply(&mut self, reply: &ServerMessage) {
+        if let Err(e) = self.frame(reply) {
+            warn!(
+                "Can't send the reply to request {}: {}",
+                reply.request_id, e
+            );
+            let e = io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("Response can't be sent: {}", e),
+            );
+            self.send(&ServerMessage {
+                message: Some(error_reply(&e)),
+                request_id: reply.request_id,
+            });
+        }
+    }
+
+    fn frame(&mut self, message: &ServerMessage) -> io::Result<()> {
+        let encoded = message.encode_to_vec();
+        let compressed = self.compression.encode(&encoded)?;
+        let payload = self.checksum.encode(compressed);
+        Ok(self
+            .framing
+            .encode(&payload, self.max_frame_len, &mut self.outbox)?)
+    }
+
+    // Account for `result` of writing the start of the outbox.
+    async fn written(&mut self, result: io::Result<usize>) -> io::Result<()> {
+        let n = result
+            .and_then(|n| match n {
+                0 => Err(io::ErrorKind::WriteZero.into()),
+                n => Ok(n),
+            })
+            .inspect_err(|e| error!("Failed to send response: {}", e))?;
+        self.outbox.drain(..n);
+
+        // Make sure all data is sent
+        if self.outbox.is_empty() {
+            self.writer
+                .flush()
+                .await
+                .inspect_err(|e| error!("Failed to flush stream: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // Write everything still in the outbox, before the connection closes.
+    async fn flush(&mut self) -> io::Result<()> {
+        let outbox = std::mem::take(&mut self.outbox);
+        self.writer.write_all(&outbox).await?;
+        self.writer.flush().await
+    }
+}
+
+impl Drop for Client {
+    // However the connection ends, its device is now offline, its subscriptions and watches
+    // are over and it has left its rooms.
+    fn drop(&mut self) {
+        self.broker.remove(&self.mailbox);
+        self.connections.close(self.id);
+        self.rooms.leave_all(self.id);
+        self.kv.unwatch_all(self.id);
+        if let Some((device_id, session)) = self.device.take() {
+            self.registry.disconnected(&device_id, session);
+        }
+    }
+}
+
+// Keep `old` as the value of setting `name`, which is only read at startup, in the
+// configuration being reloaded, warning if `new` would have changed it.
+fn keep<T: PartialEq + Clone>(name: &str, old: &T, new: &mut T) {
+    if new != old {
+        warn!("Ignoring {} change until restart", name);
+        new.clone_from(old);
+    }
+}
+
+// Serve the device on a serial port until the server stops. Whenever its connection ends,
+// because the device was unplugged or didn't answer a heartbeat, the port is reopened after
+// a delay that grows while reopening keeps failing.
+async fn serve_serial(
+    port: SerialPortConfig,
+    stream: SerialStream,
+    shared: Shared,
+    config: Arc<RwLock<ServerConfig>>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut backoff = Backoff::new(ReconnectPolicy::default());
+    let mut stream = Some(stream);
+    loop {
+        if let Some(stream) = stream.take() {
+            let config = config.read().unwrap().clone();
+            let mut client = Client::new(
+                Box::new(stream),
+                Peer::Serial(port.path.clone()),
+                Framing::Serial,
+                shared.clone(),
+                stop.clone(),
+                &config,
+            );
+            if !port.heartbeat {
+                client.heartbeat = None;
+            }
+            let opened = Instant::now();
+            if let Err(e) = client.handle().await {
+                error!("Error handling serial port {}: {}", port.path, e);
+            }
+            // A port that stayed up for a while starts over with a short delay.
+            if opened.elapsed() >= SERIAL_SETTLED {
+                backoff.reset();
+            }
+        }
+        if *stop.borrow() {
+            return;
+        }
+
+        let delay = backoff.next_delay().unwrap_or(SERIAL_SETTLED);
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+        match serial::open(&port.path, port.baud_rate) {
+            Ok(opened) => {
+                info!("Reopened serial port {}", port.path);
+                stream = Some(opened);
+            }
+            Err(e) => warn!("Failed to reopen serial port {}: {}", port.path, e),
         }
     }
 }
@@ -99,21 +980,119 @@ impl Client {
 // Server struct for managing the listening and handling of incoming connections.
 pub struct Server {
     listener: TcpListener,
-    is_running: Arc<Mutex<bool>>, // Shared state for running status
+    serial_ports: Mutex<Vec<(SerialPortConfig, SerialStream)>>, // Opened at startup, served by `run`
+    is_running: Arc<Mutex<bool>>,                               // Shared state for running status
+    config: Arc<RwLock<ServerConfig>>, // Limits that can be replaced at runtime by `reload`
+    shared: Shared,                    // Handlers and state shared by every client connection
+    shutdown: watch::Sender<bool>, // Broadcasts the stop request to the accept loop and every client
 }
 
 impl Server {
     // Create a new server instance.
     pub async fn new(addr: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr).await?;
+        Self::with_config(ServerConfig {
+            address: addr.to_string(),
+            ..ServerConfig::default()
+        })
+        .await
+    }
+
+    // Create a new server instance bound to the address in `config`.
+    pub async fn with_config(config: ServerConfig) -> io::Result<Self> {
+        Self::with_router(config, Router::default()).await
+    }
+
+    // Create a new server instance that answers requests with the handlers in `router`, and
+    // the file transfer and firmware handlers if `config` has a storage root or a firmware
+    // directory, the telemetry handlers unless `config` turns them off, and the key-value
+    // store handlers. With a state directory, the key-value store, device registry and
+    // telemetry are recovered from it and kept there.
+    pub async fn with_router(config: ServerConfig, router: Router) -> io::Result<Self> {
+        let state_dir = config.state_dir.as_deref();
+        let wal_options = config.wal_options();
+        let router = router.batch_concurrency(config.max_batch_concurrency);
+        let router = match &config.storage_root {
+            Some(root) => FileStore::open(root)?.routes(router),
+            None => router,
+        };
+        let router = match &config.firmware_dir {
+            Some(dir) => FirmwareStore::open(dir)?.routes(router),
+            None => router,
+        };
+        let router = match (config.telemetry_retention(), state_dir) {
+            (Some(retention), Some(dir)) => {
+                TelemetryStore::open(retention, &dir.join("telemetry"), wal_options)?.routes(router)
+            }
+            (Some(retention), None) => TelemetryStore::new(retention).routes(router),
+            (None, _) => router,
+        };
+        let registry = match state_dir {
+            Some(dir) => DeviceRegistry::open(&dir.join("devices"), wal_options)?,
+            None => DeviceRegistry::default(),
+        };
+        let router = registry.routes(router);
+        let connections = Connections::default();
+        let rooms = Rooms::new(connections.clone(), config.room_history_len);
+        let kv = match state_dir {
+            Some(dir) => KvStore::open(connections.clone(), &dir.join("kv"), wal_options)?,
+            None => KvStore::new(connections.clone()),
+        };
+        let router = kv.routes(router);
+        let listener = TcpListener::bind(&config.address).await?;
+        let serial_ports = config
+            .serial_ports
+            .iter()
+            .map(|port| {
+                let stream = serial::open(&port.path, port.baud_rate).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Failed to open {}: {}", port.path, e))
+                })?;
+                Ok((port.clone(), stream))
+            })
+            .collect::<io::Result<_>>()?;
         let is_running = Arc::new(Mutex::new(false));
+        let (shutdown, _) = watch::channel(false);
         Ok(Server {
             listener,
+            serial_ports: Mutex::new(serial_ports),
             is_running,
+            config: Arc::new(RwLock::new(config)),
+            shared: Shared {
+                router: Arc::new(router),
+                metrics: Arc::default(),
+                registry,
+                broker: Broker::default(),
+                connections,
+                rooms,
+                kv,
+            },
+            shutdown,
         })
     }
 
+    // Counters for every connection the server has handled.
+    pub fn metrics(&self) -> &Metrics {
+        &self.shared.metrics
+    }
+
+    // The devices that have registered, and which of them are connected.
+    pub fn devices(&self) -> &DeviceRegistry {
+        &self.shared.registry
+    }
+
+    // The connections open now, from TCP clients and serial ports alike.
+    pub fn connections(&self) -> &Connections {
+        &self.shared.connections
+    }
+
+    // The address the listener is bound to, useful after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
     // Asynchronous method to run the server.
+    //
+    // Returns once `stop` has been called and every client has either finished or been
+    // aborted after the configured shutdown timeout.
     pub async fn run(&self) -> io::Result<()> {
         {
             let mut running = self.is_running.lock().unwrap();
@@ -121,37 +1100,168 @@ impl Server {
         }
         info!("Server is running on {}", self.listener.local_addr()?);
 
+        let mut stop = self.shutdown.subscribe();
+        let mut connections = JoinSet::new();
+
+        // A device on a serial port is served for as long as the server runs. Serial ports
+        // don't count against the connection limit, which is for TCP clients.
+        let serial_ports = {
+            let mut ports = self.serial_ports.lock().unwrap();
+            for (port, stream) in ports.drain(..) {
+                info!(
+                    "Serving serial port {} at {} baud",
+                    port.path, port.baud_rate
+                );
+                connections.spawn(serve_serial(
+                    port,
+                    stream,
+                    self.shared.clone(),
+                    Arc::clone(&self.config),
+                    self.shutdown.subscribe(),
+                ));
+            }
+            connections.len()
+        };
+
         loop {
-            match self.listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr);
-
-                    // Handle the client request asynchronously.
-                    let mut client = Client::new(stream);
-                    tokio::spawn(async move {
-                        if let Err(e) = client.handle().await {
-                            error!("Error handling client {}: {}", addr, e);
+            tokio::select! {
+                result = self.listener.accept() => match result {
+                    Ok((stream, addr)) => {
+                        let config = self.config.read().unwrap().clone();
+                        if connections.len() - serial_ports >= config.max_connections {
+                            warn!(
+                                "Refusing client {}: connection limit of {} reached",
+                                addr, config.max_connections
+                            );
+                            continue;
                         }
-                    });
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);
-                }
+
+                        info!("New client connected: {}", addr);
+                        // Replies often follow a pushed Delivery, and would otherwise wait
+                        // for the client to acknowledge it.
+                        if let Err(e) = stream.set_nodelay(true) {
+                            warn!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+                        }
+
+                        // Handle the client request asynchronously.
+                        let mut client = Client::new(
+                            Box::new(stream),
+                            Peer::Tcp(addr),
+                            Framing::Length,
+                            self.shared.clone(),
+                            self.shutdown.subscribe(),
+                            &config,
+                        );
+                        connections.spawn(async move {
+                            if let Err(e) = client.handle().await {
+                                error!("Error handling client {}: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("Error accepting connection: {}", e);
+                    }
+                },
+                // Reap finished clients so they stop counting against the connection limit.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = stop.wait_for(|stop| *stop) => break,
             }
+        }
+
+        self.drain(connections).await;
 
-            // Optional: Sleep briefly to reduce CPU usage (non-blocking in Tokio).
-            time::sleep(Duration::from_millis(100)).await;
+        let mut running = self.is_running.lock().unwrap();
+        *running = false;
+        info!("Server stopped.");
+        Ok(())
+    }
+
+    // Wait for connected clients to finish, aborting whatever is left after the shutdown timeout.
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        if connections.is_empty() {
+            return;
+        }
+
+        let timeout = self.config.read().unwrap().shutdown_timeout();
+        info!("Draining {} client connection(s)", connections.len());
+
+        let drained = time::timeout(timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "Aborting {} client connection(s) still open after {:?}",
+                connections.len(),
+                timeout
+            );
+            connections.shutdown().await;
         }
     }
 
-    // Stops the server by setting the `is_running` flag to `false`.
-    pub async fn stop(&self) {
-        let mut running = self.is_running.lock().unwrap();
+    // Apply a new configuration to the running server without touching existing connections.
+    pub fn reload(&self, new_config: &ServerConfig) {
+        let mut config = self.config.write().unwrap();
+        if new_config.address != config.address {
+            warn!(
+                "Ignoring address change to {}; the listener stays on {} until restart",
+                new_config.address, config.address
+            );
+        }
+
+        // The settings the server was built with at startup stay as they were.
+        let mut reloaded = ServerConfig {
+            address: config.address.clone(),
+            ..new_config.clone()
+        };
+        let old = &*config;
+        let new = &mut reloaded;
+        keep(
+            "max_batch_concurrency",
+            &old.max_batch_concurrency,
+            &mut new.max_batch_concurrency,
+        );
+        keep("storage_root", &old.storage_root, &mut new.storage_root);
+        keep("firmware_dir", &old.firmware_dir, &mut new.firmware_dir);
+        keep("serial_ports", &old.serial_ports, &mut new.serial_ports);
+        keep(
+            "telemetry_retention_ms",
+            &old.telemetry_retention_ms,
+            &mut new.telemetry_retention_ms,
+        );
+        keep(
+            "room_history_len",
+            &old.room_history_len,
+            &mut new.room_history_len,
+        );
+        keep("state_dir", &old.state_dir, &mut new.state_dir);
+        keep("wal_sync", &old.wal_sync, &mut new.wal_sync);
+        keep(
+            "wal_sync_interval_ms",
+            &old.wal_sync_interval_ms,
+            &mut new.wal_sync_interval_ms,
+        );
+        keep(
+            "snapshot_interval_ms",
+            &old.snapshot_interval_ms,
+            &mut new.snapshot_interval_ms,
+        );
+        *config = reloaded;
+        info!(
+            "Configuration reloaded: max_connections={}, max_frame_len={}, shutdown_timeout_ms={}",
+            config.max_connections, config.max_frame_len, config.shutdown_timeout_ms
+        );
+    }
+
+    // Stops the server: the accept loop exits and connected clients are drained.
+    pub fn stop(&self) {
+        let running = self.is_running.lock().unwrap();
         if *running {
-            *running = false;
             info!("Shutdown signal sent.");
         } else {
             warn!("Server was already stopped or not running.");
         }
+        self.shutdown.send_replace(true);
     }
 }
@@ -0,0 +1,201 @@
+use crate::message::Chunk;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::{mpsc, Semaphore};
+
+// Largest piece of a stream sent in one frame.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+// Chunks a sender may have in flight on one stream before the receiver grants more. The
+// receiver never buffers more than this.
+pub const STREAM_WINDOW: u32 = 8;
+
+// What a receiver hands a `ByteStream`: the next chunk, or why the stream failed.
+pub type ChunkResult = io::Result<Chunk>;
+
+// The channel a `ByteStream` reads from. It has room for a full window plus an error.
+pub fn chunk_channel() -> (mpsc::Sender<ChunkResult>, mpsc::Receiver<ChunkResult>) {
+    mpsc::channel(STREAM_WINDOW as usize + 1)
+}
+
+// The receiving end of a stream, read as bytes.
+//
+// Reading frees room in the window, and every half window the sender is granted that many
+// more chunks. A sender that stops getting read stops getting credit.
+pub struct ByteStream {
+    chunks: mpsc::Receiver<ChunkResult>,
+    grant: Box<dyn Fn(u32) + Send + Sync>,
+    // Called if the stream is dropped before its last chunk, to tell the sender to stop.
+    abandon: Option<Box<dyn FnOnce() + Send + Sync>>,
+    next_seq: u64,
+    data: Vec<u8>,
+    pos: usize,
+    consumed: u32,
+    finished: bool,
+}
+
+impl ByteStream {
+    pub fn new(
+        chunks: mpsc::Receiver<ChunkResult>,
+        grant: impl Fn(u32) + Send + Sync + 'static,
+    ) -> Self {
+        ByteStream {
+            chunks,
+            grant: Box::new(grant),
+            abandon: None,
+            next_seq: 0,
+            data: Vec::new(),
+            pos: 0,
+            consumed: 0,
+            finished: false,
+        }
+    }
+
+    pub fn on_abandon(mut self, abandon: impl FnOnce() + Send + Sync + 'static) -> Self {
+        self.abandon = Some(Box::new(abandon));
+        self
+    }
+
+    // Read the whole stream.
+    pub async fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    fn receive(&mut self, chunk: Chunk) -> io::Result<()> {
+        if chunk.seq != self.next_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected chunk {}, got {}", self.next_seq, chunk.seq),
+            ));
+        }
+        self.next_seq += 1;
+        self.finished = chunk.last;
+        self.data = chunk.data;
+        self.pos = 0;
+
+        if !self.finished {
+            self.consumed += 1;
+            if self.consumed >= STREAM_WINDOW / 2 {
+                (self.grant)(self.consumed);
+                self.consumed = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for ByteStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.data.len() {
+                let n = buf.remaining().min(self.data.len() - self.pos);
+                buf.put_slice(&self.data[self.pos..self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if self.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.receive(chunk)?,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Stream ended before its last chunk",
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ByteStream {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Some(abandon) = self.abandon.take() {
+                abandon();
+            }
+        }
+    }
+}
+
+// The sending end of a stream. Waits for credit before each chunk, so a slow receiver
+// slows the sender down.
+pub struct ChunkSink {
+    send: Box<dyn Fn(Chunk) -> io::Result<()> + Send + Sync>,
+    credit: Arc<Semaphore>,
+    seq: u64,
+}
+
+impl ChunkSink {
+    // `credit` starts with `STREAM_WINDOW` permits and gains more as the receiver grants
+    // them. Closing it ends the stream.
+    pub fn new(
+        send: impl Fn(Chunk) -> io::Result<()> + Send + Sync + 'static,
+        credit: Arc<Semaphore>,
+    ) -> Self {
+        ChunkSink {
+            send: Box::new(send),
+            credit,
+            seq: 0,
+        }
+    }
+
+    // Send `data`, split into chunks of at most `CHUNK_SIZE` bytes.
+    pub async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        for piece in data.chunks(CHUNK_SIZE) {
+            self.send_chunk(piece.to_vec(), false).await?;
+        }
+        Ok(())
+    }
+
+    // Send everything `reader` produces, returning how many bytes that was.
+    pub async fn send_from(&mut self, mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            self.send_chunk(buffer[..n].to_vec(), false).await?;
+            total += n as u64;
+        }
+    }
+
+    // Send the last chunk, which tells the receiver the stream is complete.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.send_chunk(Vec::new(), true).await
+    }
+
+    async fn send_chunk(&mut self, data: Vec<u8>, last: bool) -> io::Result<()> {
+        let permit = self.credit.acquire().await.map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "The receiver closed the stream")
+        })?;
+        permit.forget();
+
+        (self.send)(Chunk {
+            seq: self.seq,
+            data,
+            last,
+        })?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+// Credit for a new stream: one full window.
+pub fn initial_credit() -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(STREAM_WINDOW as usize))
+}
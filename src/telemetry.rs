@@ -0,0 +1,411 @@
+use crate::files::invalid_input;
+use crate::heartbeat::timestamp_us;
+use crate::message::{
+    client_message, reading, server_message, Reading, ReadingSummary, TelemetryAck, TelemetryBatch,
+    TelemetryData, TelemetryQuery, TelemetrySnapshot,
+};
+use crate::router::{error_reply, Context, MessageKind, Router};
+use crate::wal::{Mark, Wal, WalOptions};
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The most readings or summaries one query returns, which keeps replies well inside the
+// default frame limit.
+pub const MAX_QUERY_RESULTS: usize = 1000;
+
+// How far ahead of the server's clock a reading's timestamp may be. Later ones are dropped,
+// as they would otherwise outlive the retention period.
+pub const MAX_CLOCK_SKEW_MS: u64 = 5 * 60_000;
+
+// The most readings one sensor keeps. Beyond that the oldest are dropped, however recent,
+// so a device reporting too often can't exhaust memory within the retention period.
+pub const MAX_READINGS_PER_SERIES: usize = 100_000;
+
+// The most sensors one device keeps readings of. Readings of sensors past that are dropped.
+pub const MAX_SERIES_PER_DEVICE: usize = 100;
+
+// The most devices readings are kept of. Any client can report for a new device id, so the
+// device that reported longest ago is forgotten to make room for one.
+pub const MAX_DEVICES: usize = 10_000;
+
+// How often every series is checked for expired readings, on top of the series that are
+// written to, so devices that stopped reporting don't keep their readings forever.
+const SWEEP_INTERVAL_MS: u64 = 60_000;
+
+// One sensor's readings in time order. The sensor's name is kept as the key rather than in
+// every reading.
+type Series = VecDeque<Reading>;
+
+#[derive(Debug, Default)]
+struct Readings {
+    // Sensors of each device, by name.
+    devices: HashMap<String, BTreeMap<String, Series>>,
+    // When the whole store was last checked for expired readings.
+    swept_ms: u64,
+}
+
+// Sensor readings reported by devices, kept in memory for the retention period.
+//
+// Retention goes by when a reading was taken, not when it arrived, so a device that was
+// offline can upload its backlog as long as it is still within the period.
+#[derive(Debug, Clone)]
+pub struct TelemetryStore {
+    readings: Arc<Mutex<Readings>>,
+    retention_ms: u64,
+    wal: Option<Arc<Wal>>, // Where batches are logged, if the store is kept on disk
+}
+
+impl TelemetryStore {
+    pub fn new(retention: Duration) -> Self {
+        TelemetryStore {
+            readings: Arc::default(),
+            retention_ms: retention.as_millis().try_into().unwrap_or(u64::MAX),
+            wal: None,
+        }
+    }
+
+    // A store kept in `dir`, holding the readings it held when the server last stopped that
+    // are still within the retention period.
+    pub fn open(retention: Duration, dir: &Path, options: WalOptions) -> io::Result<Self> {
+        let (wal, recovered) = Wal::open::<TelemetrySnapshot, TelemetryBatch>(dir, options)?;
+        let mut store = Self::new(retention);
+        let snapshot = recovered.snapshot.unwrap_or_default();
+        for batch in snapshot.devices.into_iter().chain(recovered.records) {
+            store.insert(&batch.device_id, batch.readings)?;
+        }
+
+        let wal = Arc::new(wal);
+        store.wal = Some(Arc::clone(&wal));
+        wal.maintain(Arc::downgrade(&store.readings), copy_for_snapshot);
+        Ok(store)
+    }
+
+    // Store `readings` from `device_id`, returning how many were kept. Those without a
+    // sensor or a value, already past the retention period, timestamped more than
+    // `MAX_CLOCK_SKEW_MS` in the future or of a sensor past `MAX_SERIES_PER_DEVICE`, are
+    // dropped. Fails only if the store is kept on disk and the readings couldn't be logged.
+    pub fn insert(&self, device_id: &str, mut readings: Vec<Reading>) -> io::Result<usize> {
+        let now_ms = timestamp_us() / 1000;
+        let cutoff = now_ms.saturating_sub(self.retention_ms);
+        let latest = now_ms.saturating_add(MAX_CLOCK_SKEW_MS);
+        readings.retain(|reading| {
+            !reading.sensor.is_empty()
+                && reading.value.is_some()
+                && (cutoff..=latest).contains(&reading.timestamp_ms)
+        });
+
+        let mut store = self.readings.lock().unwrap();
+        keep_series_within_cap(store.devices.get(device_id), &mut readings);
+        let stored = readings.len();
+        if readings.is_empty() {
+            return Ok(0);
+        }
+        let full = store.devices.len() >= MAX_DEVICES && !store.devices.contains_key(device_id);
+        let forgotten = full.then(|| least_recent_device(&store.devices)).flatten();
+        let readings = match &self.wal {
+            Some(wal) => {
+                let batch = TelemetryBatch {
+                    device_id: device_id.to_string(),
+                    readings,
+                };
+                wal.append(&batch)?;
+                batch.readings
+            }
+            None => readings,
+        };
+        if let Some(forgotten) = forgotten {
+            debug!(
+                "Forgetting readings of {} to make room for {}",
+                forgotten, device_id
+            );
+            store.devices.remove(&forgotten);
+        }
+
+        let sensors = store.devices.entry(device_id.to_string()).or_default();
+        for mut reading in readings {
+            let series = sensors
+                .entry(std::mem::take(&mut reading.sensor))
+                .or_default();
+            // Readings usually arrive in order, so this is normally the end.
+            let at = series.partition_point(|other| other.timestamp_ms <= reading.timestamp_ms);
+            series.insert(at, reading);
+            if series.len() > MAX_READINGS_PER_SERIES {
+                series.pop_front();
+            }
+        }
+        expire(sensors, cutoff);
+        if sensors.is_empty() {
+            store.devices.remove(device_id);
+        }
+
+        if now_ms.saturating_sub(store.swept_ms) >= SWEEP_INTERVAL_MS {
+            store.devices.retain(|_, sensors| {
+                expire(sensors, cutoff);
+                !sensors.is_empty()
+            });
+            store.swept_ms = now_ms;
+        }
+        Ok(stored)
+    }
+
+    // The readings matching `query`, or summaries of them if it asks for an interval.
+    pub fn query(&self, query: &TelemetryQuery) -> io::Result<TelemetryData> {
+        if query.device_id.is_empty() {
+            return Err(invalid_input("Telemetry query without a device_id"));
+        }
+        if query.end_ms != 0 && query.end_ms <= query.start_ms {
+            return Err(invalid_input("Telemetry query ends before it starts"));
+        }
+        let limit = match query.limit as usize {
+            0 => MAX_QUERY_RESULTS,
+            limit => limit.min(MAX_QUERY_RESULTS),
+        };
+
+        let cutoff = (timestamp_us() / 1000).saturating_sub(self.retention_ms);
+        let start_ms = query.start_ms.max(cutoff);
+        let end_ms = match query.end_ms {
+            0 => u64::MAX,
+            end_ms => end_ms,
+        };
+
+        let store = self.readings.lock().unwrap();
+        let Some(sensors) = store.devices.get(&query.device_id) else {
+            return Ok(TelemetryData::default());
+        };
+        let sensors = sensors
+            .iter()
+            .filter(|(sensor, _)| query.sensor.is_empty() || **sensor == query.sensor);
+
+        // Each sensor contributes at most what could make the cut, then they are merged.
+        let mut data = TelemetryData::default();
+        for (sensor, series) in sensors {
+            let from = series.partition_point(|reading| reading.timestamp_ms < start_ms);
+            let to = series.partition_point(|reading| reading.timestamp_ms < end_ms);
+            let readings = series.range(from..to);
+
+            let truncated = if query.interval_ms == 0 {
+                let readings = readings.map(|reading| Reading {
+                    sensor: sensor.clone(),
+                    ..reading.clone()
+                });
+                take_limited(
+                    readings,
+                    limit,
+                    |reading| reading.timestamp_ms,
+                    &mut data.readings,
+                )
+            } else {
+                let summaries = summarise(sensor, readings, query.start_ms, query.interval_ms);
+                take_limited(
+                    summaries,
+                    limit,
+                    |summary| summary.start_ms,
+                    &mut data.summaries,
+                )
+            };
+            data.truncated |= truncated;
+        }
+
+        data.readings
+            .sort_by(|a, b| (a.timestamp_ms, &a.sensor).cmp(&(b.timestamp_ms, &b.sensor)));
+        data.summaries
+            .sort_by(|a, b| (a.start_ms, &a.sensor).cmp(&(b.start_ms, &b.sensor)));
+        let readings = std::mem::take(&mut data.readings);
+        let summaries = std::mem::take(&mut data.summaries);
+        data.truncated |= take_limited(
+            readings.into_iter(),
+            limit,
+            |reading| reading.timestamp_ms,
+            &mut data.readings,
+        );
+        data.truncated |= take_limited(
+            summaries.into_iter(),
+            limit,
+            |summary| summary.start_ms,
+            &mut data.summaries,
+        );
+        Ok(data)
+    }
+
+    // Register the TelemetryBatch and TelemetryQuery handlers on `router`.
+    pub fn routes(&self, router: Router) -> Router {
+        let store = self.clone();
+        let handler = move |ctx, request| {
+            let store = store.clone();
+            async move { store.handle(ctx, request) }
+        };
+        router
+            .route(MessageKind::TelemetryBatch, handler.clone())
+            .route(MessageKind::TelemetryQuery, handler)
+    }
+
+    fn handle(
+        &self,
+        ctx: Context,
+        request: client_message::Message,
+    ) -> Option<server_message::Message> {
+        let result = match request {
+            client_message::Message::TelemetryBatch(batch) => self
+                .batch(&ctx, batch)
+                .map(server_message::Message::TelemetryAck),
+            client_message::Message::TelemetryQuery(query) => self
+                .query(&query)
+                .map(server_message::Message::TelemetryData),
+            _ => return None,
+        };
+
+        Some(result.unwrap_or_else(|e| {
+            warn!("Telemetry request from {} failed: {}", ctx.peer(), e);
+            error_reply(&e)
+        }))
+    }
+
+    fn batch(&self, ctx: &Context, batch: TelemetryBatch) -> io::Result<TelemetryAck> {
+        if batch.device_id.is_empty() {
+            return Err(invalid_input("Telemetry batch without a device_id"));
+        }
+        let received = batch.readings.len();
+        let stored = self.insert(&batch.device_id, batch.readings)?;
+        debug!(
+            "Stored {} of {} readings from device {} at {}",
+            stored,
+            received,
+            batch.device_id,
+            ctx.peer()
+        );
+        Ok(TelemetryAck {
+            stored: stored as u32,
+        })
+    }
+}
+
+// Copy every device's readings for a snapshot, and mark the log they were copied at, holding
+// off new ones meanwhile.
+fn copy_for_snapshot(readings: &Mutex<Readings>, wal: &Wal) -> (TelemetrySnapshot, Mark) {
+    let store = readings.lock().unwrap();
+    let devices = store
+        .devices
+        .iter()
+        .map(|(device_id, sensors)| TelemetryBatch {
+            device_id: device_id.clone(),
+            readings: sensors
+                .iter()
+                .flat_map(|(sensor, series)| {
+                    series.iter().map(|reading| Reading {
+                        sensor: sensor.clone(),
+                        ..reading.clone()
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    (TelemetrySnapshot { devices }, wal.mark())
+}
+
+// Drop the readings of sensors that would take a device with `sensors` past
+// `MAX_SERIES_PER_DEVICE`, new sensors being added in the order they first appear.
+fn keep_series_within_cap(sensors: Option<&BTreeMap<String, Series>>, readings: &mut Vec<Reading>) {
+    let known = |sensor: &String| sensors.is_some_and(|sensors| sensors.contains_key(sensor));
+    let room = MAX_SERIES_PER_DEVICE.saturating_sub(sensors.map_or(0, BTreeMap::len));
+    let mut added = HashSet::new();
+    readings.retain(|reading| {
+        known(&reading.sensor)
+            || added.contains(&reading.sensor)
+            || (added.len() < room && added.insert(reading.sensor.clone()))
+    });
+}
+
+// The device whose latest reading is the oldest, if any.
+fn least_recent_device(devices: &HashMap<String, BTreeMap<String, Series>>) -> Option<String> {
+    let latest = |sensors: &BTreeMap<String, Series>| {
+        sensors
+            .values()
+            .filter_map(|series| series.back().map(|reading| reading.timestamp_ms))
+            .max()
+    };
+    let (device_id, _) = devices.iter().min_by_key(|(_, sensors)| latest(sensors))?;
+    Some(device_id.clone())
+}
+
+// Drop the readings taken before `cutoff`, and the sensors left without any.
+fn expire(sensors: &mut BTreeMap<String, Series>, cutoff: u64) {
+    sensors.retain(|_, series| {
+        let expired = series.partition_point(|reading| reading.timestamp_ms < cutoff);
+        series.drain(..expired);
+        !series.is_empty()
+    });
+}
+
+// Append the first `limit` of `items` to `out`, along with any after them at the same time
+// as the last, so a result is never cut between items with the same time. Returns whether
+// any were left out.
+fn take_limited<T>(
+    items: impl Iterator<Item = T>,
+    limit: usize,
+    time: impl Fn(&T) -> u64,
+    out: &mut Vec<T>,
+) -> bool {
+    let mut last = None;
+    for (taken, item) in items.enumerate() {
+        let at = time(&item);
+        if taken >= limit && last != Some(at) {
+            return true;
+        }
+        last = Some(at);
+        out.push(item);
+    }
+    false
+}
+
+// The numeric value of a reading, with flags as 0 and 1. Text has none.
+fn numeric(reading: &Reading) -> Option<f64> {
+    match reading.value.as_ref()? {
+        reading::Value::Number(number) => Some(*number),
+        reading::Value::Integer(integer) => Some(*integer as f64),
+        reading::Value::Flag(flag) => Some(f64::from(u8::from(*flag))),
+        reading::Value::Text(_) => None,
+    }
+}
+
+// Summaries of `readings` of `sensor` in intervals of `interval_ms` counted from
+// `start_ms`, skipping intervals without numeric readings. The readings are in time order
+// and none is before `start_ms`.
+fn summarise<'a>(
+    sensor: &'a str,
+    readings: impl Iterator<Item = &'a Reading> + 'a,
+    start_ms: u64,
+    interval_ms: u64,
+) -> impl Iterator<Item = ReadingSummary> + 'a {
+    let mut readings = readings
+        .filter_map(|reading| numeric(reading).map(|value| (reading, value)))
+        .peekable();
+    std::iter::from_fn(move || {
+        let (first, _) = readings.peek()?;
+        let offset = first.timestamp_ms - start_ms;
+        let interval_start = start_ms + offset - offset % interval_ms;
+
+        let mut summary = ReadingSummary {
+            sensor: sensor.to_string(),
+            start_ms: interval_start,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            ..ReadingSummary::default()
+        };
+        let mut sum = 0.0;
+        while let Some((reading, value)) =
+            readings.next_if(|(reading, _)| reading.timestamp_ms - interval_start < interval_ms)
+        {
+            summary.count += 1;
+            summary.min = summary.min.min(value);
+            summary.max = summary.max.max(value);
+            sum += value;
+            summary.unit.clone_from(&reading.unit);
+        }
+        summary.mean = sum / f64::from(summary.count);
+        Some(summary)
+    })
+}
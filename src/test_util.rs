@@ -0,0 +1,224 @@
+use crate::codec::{encode_frame, FrameDecoder, DEFAULT_MAX_FRAME_LEN};
+use crate::config::ServerConfig;
+use crate::files;
+use crate::heartbeat::timestamp_us;
+use crate::message::{client_message, reading, AddRequest, EchoMessage, Reading};
+use crate::router::Router;
+use crate::server::Server;
+use log::error;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+pub fn echo(content: &str) -> client_message::Message {
+    client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    })
+}
+
+pub fn add(a: i32, b: i32) -> client_message::Message {
+    client_message::Message::AddRequest(AddRequest { a, b })
+}
+
+pub fn reading(sensor: &str, timestamp_ms: u64, value: reading::Value, unit: &str) -> Reading {
+    Reading {
+        sensor: sensor.to_string(),
+        timestamp_ms,
+        value: Some(value),
+        unit: unit.to_string(),
+    }
+}
+
+pub fn temperature(timestamp_ms: u64, value: f64) -> Reading {
+    reading(
+        "temperature",
+        timestamp_ms,
+        reading::Value::Number(value),
+        "degC",
+    )
+}
+
+// The server's clock, as telemetry timestamps go by.
+pub fn now_ms() -> u64 {
+    timestamp_us() / 1000
+}
+
+// Where a download to `path` is kept until it completes.
+pub fn partial(path: &Path) -> PathBuf {
+    files::partial(path)
+}
+
+// Write `payload` to `stream` as one length-prefixed frame, for tests that talk to the
+// server without a client.
+pub async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), payload: &[u8]) {
+    let mut frame = Vec::new();
+    encode_frame(payload, DEFAULT_MAX_FRAME_LEN, &mut frame).unwrap();
+    stream.write_all(&frame).await.unwrap();
+}
+
+// Read the payload of the next frame from `stream`, panicking if the peer disconnects first.
+pub async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+    decoder: &mut FrameDecoder,
+) -> Vec<u8> {
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(frame) = decoder.decode().unwrap() {
+            return frame;
+        }
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "Peer disconnected");
+        decoder.extend(&buffer[..n]);
+    }
+}
+
+// A server running on an ephemeral localhost port for the duration of a test.
+//
+// The server gets its own thread and Tokio runtime, so it can be used from plain `#[test]`
+// functions as well as from inside another runtime. Dropping the `TestServer` stops the
+// server and waits for its thread to finish, so nothing outlives the test.
+pub struct TestServer {
+    server: Option<Arc<Server>>, // `None` while shut down, so the listening socket is closed
+    addr: SocketAddr,
+    thread: Option<thread::JoinHandle<()>>,
+    config: ServerConfig,
+    router: Router,
+}
+
+impl TestServer {
+    // Start a server with the built-in handlers.
+    pub fn start() -> Self {
+        Self::with_router(Router::default())
+    }
+
+    // Start a server that answers requests with the handlers in `router`.
+    pub fn with_router(router: Router) -> Self {
+        let config = ServerConfig {
+            shutdown_timeout_ms: 1000,
+            ..ServerConfig::default()
+        };
+        Self::with_config(config, router)
+    }
+
+    // Start a server with a custom configuration. The address is always replaced with an
+    // ephemeral localhost port so tests never collide.
+    pub fn with_config(config: ServerConfig, router: Router) -> Self {
+        let config = ServerConfig {
+            address: "127.0.0.1:0".to_string(),
+            ..config
+        };
+
+        Self::spawn(config, router).unwrap_or_else(|e| panic!("Failed to start test server: {}", e))
+    }
+
+    fn spawn(config: ServerConfig, router: Router) -> io::Result<Self> {
+        let (server, thread) = Self::spawn_thread(config.clone(), router.clone())?;
+        let addr = server.local_addr()?;
+
+        Ok(TestServer {
+            server: Some(server),
+            addr,
+            thread: Some(thread),
+            config,
+            router,
+        })
+    }
+
+    fn spawn_thread(
+        config: ServerConfig,
+        router: Router,
+    ) -> io::Result<(Arc<Server>, thread::JoinHandle<()>)> {
+        let (started, ready) = mpsc::channel();
+
+        // Build the runtime on the server thread so starting a TestServer never blocks on a
+        // runtime owned by the caller.
+        let thread = thread::Builder::new()
+            .name("test-server".to_string())
+            .spawn(move || {
+                let runtime = match Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    }
+                };
+
+                runtime.block_on(async move {
+                    let server = match Server::with_router(config, router).await {
+                        Ok(server) => Arc::new(server),
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                            return;
+                        }
+                    };
+
+                    let _ = started.send(Ok(Arc::clone(&server)));
+                    if let Err(e) = server.run().await {
+                        error!("Test server encountered an error: {}", e);
+                    }
+                });
+            })?;
+
+        let server = ready
+            .recv()
+            .map_err(|_| io::Error::other("Test server thread exited"))??;
+        Ok((server, thread))
+    }
+
+    // The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn ip(&self) -> String {
+        self.addr.ip().to_string()
+    }
+
+    pub fn port(&self) -> u32 {
+        self.addr.port() as u32
+    }
+
+    pub fn server(&self) -> &Server {
+        self.server.as_deref().expect("Test server is shut down")
+    }
+
+    // Stop the server and wait for it to shut down. Equivalent to dropping it.
+    pub fn stop(self) {}
+
+    // Stop the server but keep its address, so `restart` can bring it back where clients
+    // expect it. Does nothing if the server is already down.
+    pub fn shutdown(&mut self) {
+        if let (Some(server), Some(thread)) = (self.server.take(), self.thread.take()) {
+            server.stop();
+            // Don't turn an already failing test into a double panic.
+            if thread.join().is_err() && !thread::panicking() {
+                panic!("Test server thread panicked");
+            }
+        }
+    }
+
+    // Start a fresh server on the same address, with the same configuration and handlers,
+    // shutting the current one down first if it is still running.
+    pub fn restart(&mut self) {
+        self.shutdown();
+
+        let config = ServerConfig {
+            address: self.addr.to_string(),
+            ..self.config.clone()
+        };
+        let (server, thread) = Self::spawn_thread(config, self.router.clone())
+            .unwrap_or_else(|e| panic!("Failed to restart test server: {}", e));
+        self.server = Some(server);
+        self.thread = Some(thread);
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
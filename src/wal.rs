@@ -0,0 +1,484 @@
+use log::{debug, error, info, warn};
+use prost::Message;
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::{task, time};
+
+const LOG_FILE: &str = "log";
+// Records logged while a snapshot was written are copied here, then renamed over the log.
+const NEW_LOG_FILE: &str = "log.new";
+const SNAPSHOT_FILE: &str = "snapshot";
+// A snapshot is written here first, then renamed over the last one.
+const NEW_SNAPSHOT_FILE: &str = "snapshot.new";
+
+// Every record in the log starts with the length of the rest, then its CRC32C, both
+// big-endian. The rest is the record's sequence number, then the record.
+const RECORD_HEADER_LEN: usize = 8;
+const SEQ_LEN: usize = 8;
+
+// When the log is flushed to disk. Records reach the operating system as they are written
+// whatever the policy, so only a crash of the machine, not of the server, loses any.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    // After every record, before the change it records is acknowledged.
+    Always,
+    // Every sync interval, so a crash of the machine loses at most that long.
+    #[default]
+    Interval,
+    // Whenever the operating system gets round to it.
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalOptions {
+    pub sync: SyncPolicy,
+    // How often the log is flushed with `SyncPolicy::Interval`.
+    pub sync_interval: Duration,
+    // How often the state is snapshotted and the log emptied, if anything was logged.
+    pub snapshot_interval: Duration,
+}
+
+// What a store's directory held when its log was opened: the latest snapshot, and the
+// records logged after it, oldest first.
+#[derive(Debug)]
+pub struct Recovered<S, R> {
+    pub snapshot: Option<S>,
+    pub records: Vec<R>,
+}
+
+// Where the log had got to when a store's state was copied for a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    seq: u64,
+    len: u64,
+    records: u64,
+    compactions: u64,
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+    seq: u64,     // Of the last record, or of the snapshot if none came after it
+    records: u64, // In the file, all of which the next snapshot makes redundant
+    unsynced: bool,
+    // Set when a record that failed to be written couldn't be taken back out of the file, so
+    // nothing more may follow it until a snapshot replaces the log.
+    failed: bool,
+    compactions: u64, // Since the log was opened, so a mark from before one isn't used after
+}
+
+// A write-ahead log that keeps one store's state across restarts, in a directory of its
+// own.
+//
+// The store logs every change before making it, and now and then replaces the log with a
+// snapshot of its whole state. On startup it loads the snapshot and replays the records
+// after it. Records carry a sequence number, so a crash between writing a snapshot and
+// emptying the log doesn't replay what the snapshot already holds, and a checksum, so a
+// record cut short by a crash is recognised and dropped.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    options: WalOptions,
+    log: Mutex<Log>,
+    compacting: Mutex<()>, // Held while a snapshot is written
+    #[cfg(feature = "test-util")]
+    syncs_left: Mutex<Option<u32>>, // Flushes to disk that succeed before the rest fail
+}
+
+impl Wal {
+    // Open the log in `dir`, creating it if needed, and recover what it holds.
+    pub fn open<S, R>(dir: &Path, options: WalOptions) -> io::Result<(Self, Recovered<S, R>)>
+    where
+        S: Message + Default,
+        R: Message + Default,
+    {
+        fs::create_dir_all(dir)?;
+        let (snapshot_seq, snapshot) = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => {
+                let (seq, snapshot) = parse_snapshot(&bytes)
+                    .ok_or_else(|| corrupt(format!("Snapshot in {} is corrupt", dir.display())))?;
+                (seq, Some(decode(snapshot, dir)?))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e),
+        };
+        for leftover in [NEW_SNAPSHOT_FILE, NEW_LOG_FILE] {
+            match fs::remove_file(dir.join(leftover)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut log = Log {
+            file,
+            len: 0,
+            seq: snapshot_seq,
+            records: 0,
+            unsynced: false,
+            failed: false,
+            compactions: 0,
+        };
+        let mut records = Vec::new();
+        let mut rest = bytes.as_slice();
+        while let Some((seq, record, after)) = parse_record(rest) {
+            if seq > snapshot_seq {
+                records.push(decode(record, dir)?);
+            }
+            log.seq = log.seq.max(seq);
+            log.len += (rest.len() - after.len()) as u64;
+            log.records += 1;
+            rest = after;
+        }
+        // Only the last record can be cut short by a crash. A damaged one with others after it
+        // means the log itself is damaged, and dropping the rest would lose changes that were
+        // acknowledged.
+        if has_later_record(rest, log.seq) {
+            return Err(corrupt(format!(
+                "Log in {} is corrupt at byte {}, before records that are intact",
+                dir.display(),
+                log.len
+            )));
+        }
+        if !rest.is_empty() {
+            warn!(
+                "Dropping {} byte(s) of a record cut short or corrupted at the end of {}",
+                rest.len(),
+                dir.join(LOG_FILE).display()
+            );
+            log.file.set_len(log.len)?;
+            log.file.sync_all()?;
+        }
+
+        info!(
+            "Recovered {} from {} and {} log record(s)",
+            dir.display(),
+            if snapshot.is_some() {
+                "a snapshot"
+            } else {
+                "no snapshot"
+            },
+            records.len()
+        );
+        let wal = Wal {
+            dir: dir.to_path_buf(),
+            options,
+            log: Mutex::new(log),
+            compacting: Mutex::new(()),
+            #[cfg(feature = "test-util")]
+            syncs_left: Mutex::new(None),
+        };
+        Ok((wal, Recovered { snapshot, records }))
+    }
+
+    // Append `record` to the log, flushing it to disk first if the policy is `Always`. If that
+    // fails, the record is taken back out so it isn't replayed; if even that fails, the log
+    // refuses every record until the next snapshot.
+    pub fn append(&self, record: &impl Message) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.failed {
+            return Err(io::Error::other(format!(
+                "Log in {} failed, and takes no more records until the next snapshot",
+                self.dir.display()
+            )));
+        }
+        let seq = log.seq + 1;
+        let len = SEQ_LEN + record.encoded_len();
+        let len = u32::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Record too large to log"))?;
+
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + len as usize);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&seq.to_be_bytes());
+        record.encode(&mut bytes).map_err(io::Error::other)?;
+        let crc = crc32c::crc32c(&bytes[RECORD_HEADER_LEN..]);
+        bytes[4..RECORD_HEADER_LEN].copy_from_slice(&crc.to_be_bytes());
+
+        // Stores log changes while they hold themselves still, so their callers wait on the
+        // flush anyway; the rest of the runtime shouldn't.
+        let sync = self.options.sync == SyncPolicy::Always;
+        let written = log.file.write_all(&bytes).and_then(|()| {
+            if sync {
+                blocking(|| self.flush(|| log.file.sync_data()))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            // Don't leave the record, or half of one, for the next to follow.
+            let len = log.len;
+            if let Err(truncate) = log.file.set_len(len) {
+                error!(
+                    "Failed to take a record back out of {}: {}",
+                    self.dir.join(LOG_FILE).display(),
+                    truncate
+                );
+                log.failed = true;
+            }
+            return Err(e);
+        }
+        log.unsynced |= !sync;
+        log.len += bytes.len() as u64;
+        log.seq = seq;
+        log.records += 1;
+        Ok(())
+    }
+
+    // Flush what has been logged to disk. Records can go on being appended meanwhile.
+    pub fn sync(&self) -> io::Result<()> {
+        let file = {
+            let mut log = self.log.lock().unwrap();
+            if !log.unsynced {
+                return Ok(());
+            }
+            let file = log.file.try_clone()?;
+            log.unsynced = false;
+            file
+        };
+        self.flush(|| file.sync_data()).inspect_err(|_| {
+            self.log.lock().unwrap().unsynced = true;
+        })
+    }
+
+    // Records in the log, which a snapshot would make redundant.
+    pub fn records(&self) -> u64 {
+        self.log.lock().unwrap().records
+    }
+
+    // Where the log has got to. Taken while the store is held still, it marks what a copy of
+    // the store's state holds.
+    pub fn mark(&self) -> Mark {
+        let log = self.log.lock().unwrap();
+        Mark {
+            seq: log.seq,
+            len: log.len,
+            records: log.records,
+            compactions: log.compactions,
+        }
+    }
+
+    // Replace the snapshot with `snapshot`, the whole state after every record logged so
+    // far, and empty the log. The store must not change until this returns.
+    pub fn compact(&self, snapshot: &impl Message) -> io::Result<()> {
+        self.compact_at(snapshot, self.mark())
+    }
+
+    // Replace the snapshot with `snapshot`, the state as it was at `mark`, and drop the records
+    // it holds from the log. Records logged after `mark` are kept, so the store can go on
+    // changing while the snapshot is written.
+    pub fn compact_at(&self, snapshot: &impl Message, mark: Mark) -> io::Result<()> {
+        let _compacting = self.compacting.lock().unwrap();
+        if self.log.lock().unwrap().compactions != mark.compactions {
+            debug!(
+                "Not snapshotting {}: a later snapshot was taken since",
+                self.dir.display()
+            );
+            return Ok(());
+        }
+
+        let snapshot = snapshot.encode_to_vec();
+        let mut bytes = Vec::with_capacity(SEQ_LEN + 4 + snapshot.len());
+        bytes.extend_from_slice(&mark.seq.to_be_bytes());
+        bytes.extend_from_slice(&crc32c::crc32c(&snapshot).to_be_bytes());
+        bytes.extend_from_slice(&snapshot);
+        let path = self.dir.join(NEW_SNAPSHOT_FILE);
+        let mut file = File::create(&path)?;
+        file.write_all(&bytes)?;
+        self.flush(|| file.sync_all())?;
+        fs::rename(&path, self.dir.join(SNAPSHOT_FILE))?;
+        self.flush(|| sync_dir(&self.dir))?;
+
+        let mut log = self.log.lock().unwrap();
+        let replaced = if log.len == mark.len {
+            if let Err(e) = log.file.set_len(0) {
+                // The log may have been cut anywhere, so its length is no longer known.
+                log.failed = true;
+                return Err(e);
+            }
+            self.flush(|| log.file.sync_all())
+        } else {
+            // Carry the records logged since over to a new log, put in place of this one.
+            let mut tail = vec![0; (log.len - mark.len) as usize];
+            log.file.seek(SeekFrom::Start(mark.len))?;
+            log.file.read_exact(&mut tail)?;
+            let path = self.dir.join(NEW_LOG_FILE);
+            let mut file = File::create(&path)?;
+            file.write_all(&tail)?;
+            self.flush(|| file.sync_all())?;
+            let file = OpenOptions::new().read(true).append(true).open(&path)?;
+            fs::rename(&path, self.dir.join(LOG_FILE))?;
+            log.file = file;
+            self.flush(|| sync_dir(&self.dir))
+        };
+        // The log holds only the records after `mark` now, even if that hasn't reached the
+        // disk, so it is kept in step whether or not the flush failed.
+        log.len -= mark.len;
+        log.records -= mark.records;
+        log.unsynced = replaced.is_err();
+        log.failed = false;
+        log.compactions += 1;
+        replaced?;
+        debug!(
+            "Snapshotted {} after record {}, replacing {} record(s)",
+            self.dir.display(),
+            mark.seq,
+            mark.records
+        );
+        Ok(())
+    }
+
+    // Make every flush to disk after the next `syncs` fail, or none if `None`, to test what
+    // happens when the disk gives out.
+    #[cfg(feature = "test-util")]
+    pub fn fail_syncs_after(&self, syncs: Option<u32>) {
+        *self.syncs_left.lock().unwrap() = syncs;
+    }
+
+    // Flush something to disk with `sync`.
+    fn flush(&self, sync: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        #[cfg(feature = "test-util")]
+        if let Some(left) = self.syncs_left.lock().unwrap().as_mut() {
+            if *left == 0 {
+                return Err(io::Error::other("Sync failed for a test"));
+            }
+            *left -= 1;
+        }
+        sync()
+    }
+
+    // Flush and snapshot the log on the schedule in its options, until `state` is dropped.
+    // `copy` copies the state and marks the log, holding the state still meanwhile; the copy
+    // is written out on a blocking thread, while the state goes on changing.
+    pub(crate) fn maintain<S, M>(self: &Arc<Self>, state: Weak<S>, copy: fn(&S, &Wal) -> (M, Mark))
+    where
+        S: Send + Sync + 'static,
+        M: Message + 'static,
+    {
+        let wal = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut sync = time::interval(wal.options.sync_interval);
+            let mut snapshot = time::interval(wal.options.snapshot_interval);
+            snapshot.tick().await;
+            let syncing = wal.options.sync == SyncPolicy::Interval;
+            loop {
+                tokio::select! {
+                    _ = sync.tick(), if syncing => {
+                        if state.strong_count() == 0 {
+                            return;
+                        }
+                        let syncer = Arc::clone(&wal);
+                        if let Err(e) = off_runtime(move || syncer.sync()).await {
+                            error!("Failed to sync {}: {}", wal.dir.display(), e);
+                        }
+                    }
+                    _ = snapshot.tick() => {
+                        let Some(state) = state.upgrade() else {
+                            return;
+                        };
+                        if wal.records() == 0 {
+                            continue;
+                        }
+                        let (copied, mark) = copy(&state, &wal);
+                        drop(state);
+                        let writer = Arc::clone(&wal);
+                        if let Err(e) = off_runtime(move || writer.compact_at(&copied, mark)).await
+                        {
+                            error!("Failed to snapshot {}: {}", wal.dir.display(), e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Run `f`, which waits on the disk, while the other tasks on this runtime worker are handed to
+// another. Outside a multi-threaded runtime, which has nowhere to hand them, `f` just runs.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(runtime) if runtime.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// Run `f`, which waits on the disk, on a blocking thread rather than the runtime.
+async fn off_runtime(f: impl FnOnce() -> io::Result<()> + Send + 'static) -> io::Result<()> {
+    task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+// Whether `bytes`, from where a record failed to parse, hold an intact record logged after
+// record `seq`. Only offsets holding a plausible sequence number are checksummed.
+fn has_later_record(bytes: &[u8], seq: u64) -> bool {
+    let most = (bytes.len() / (RECORD_HEADER_LEN + SEQ_LEN)) as u64 + 1;
+    (1..bytes.len()).any(|start| {
+        let rest = &bytes[start..];
+        let later = rest
+            .get(RECORD_HEADER_LEN..)
+            .and_then(|body| body.first_chunk::<SEQ_LEN>())
+            .map(|later| u64::from_be_bytes(*later));
+        later.is_some_and(|later| later > seq && later - seq <= most)
+            && parse_record(rest).is_some()
+    })
+}
+
+// The sequence number and encoded state in a snapshot file, if its checksum matches.
+fn parse_snapshot(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let (seq, rest) = bytes.split_first_chunk::<SEQ_LEN>()?;
+    let (crc, snapshot) = rest.split_first_chunk::<4>()?;
+    (crc32c::crc32c(snapshot) == u32::from_be_bytes(*crc))
+        .then_some((u64::from_be_bytes(*seq), snapshot))
+}
+
+// The sequence number and encoding of the record at the start of `bytes`, and what follows
+// it. `None` if there isn't a whole record with a matching checksum.
+fn parse_record(bytes: &[u8]) -> Option<(u64, &[u8], &[u8])> {
+    let (len, rest) = bytes.split_first_chunk::<4>()?;
+    let (crc, rest) = rest.split_first_chunk::<4>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if len < SEQ_LEN || rest.len() < len {
+        return None;
+    }
+    let (body, after) = rest.split_at(len);
+    if crc32c::crc32c(body) != u32::from_be_bytes(*crc) {
+        return None;
+    }
+    let (seq, record) = body.split_first_chunk::<SEQ_LEN>()?;
+    Some((u64::from_be_bytes(*seq), record, after))
+}
+
+fn decode<T: Message + Default>(bytes: &[u8], dir: &Path) -> io::Result<T> {
+    T::decode(bytes).map_err(|e| corrupt(format!("Undecodable state in {}: {}", dir.display(), e)))
+}
+
+fn corrupt(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Make a rename in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use embedded_recruitment_task::balancer::{Balancer, BalancerConfig, Strategy};
+use embedded_recruitment_task::message::{client_message, server_message};
+use embedded_recruitment_task::router::{MessageKind, Router};
+use embedded_recruitment_task::test_util::{echo, TestServer};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+// A server whose echo handler counts its calls and takes `delay` to answer.
+fn counting_server(delay: Duration) -> (TestServer, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = Router::new().route(MessageKind::Echo, {
+        let calls = Arc::clone(&calls);
+        move |_, message| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(delay).await;
+                match message {
+                    client_message::Message::EchoMessage(echo) => {
+                        Some(server_message::Message::EchoMessage(echo))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    });
+    (TestServer::with_router(router), calls)
+}
+
+fn counts(calls: &[Arc<AtomicUsize>]) -> Vec<usize> {
+    calls
+        .iter()
+        .map(|calls| calls.load(Ordering::SeqCst))
+        .collect()
+}
+
+fn config(strategy: Strategy) -> BalancerConfig {
+    BalancerConfig {
+        strategy,
+        eject_after: 1,
+        ejection_time: Duration::from_millis(300),
+        ..BalancerConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_round_robin_spreads_requests_evenly() {
+    let (servers, calls): (Vec<_>, Vec<_>) =
+        (0..3).map(|_| counting_server(Duration::ZERO)).unzip();
+    let addrs = servers.iter().map(TestServer::addr).collect();
+    let balancer = Balancer::new(addrs, config(Strategy::RoundRobin)).unwrap();
+
+    for i in 0..30 {
+        balancer.request(echo(&i.to_string())).await.unwrap();
+    }
+    assert_eq!(counts(&calls), [10, 10, 10]);
+}
+
+#[tokio::test]
+async fn test_least_outstanding_avoids_slow_endpoint() {
+    let (slow, slow_calls) = counting_server(Duration::from_millis(200));
+    let (fast, fast_calls) = counting_server(Duration::ZERO);
+    let balancer = Balancer::new(
+        vec![slow.addr(), fast.addr()],
+        config(Strategy::LeastOutstanding),
+    )
+    .unwrap();
+
+    let requests: Vec<_> = (0..20)
+        .map(|i| {
+            let balancer = balancer.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(i * 10)).await;
+                balancer.request(echo("hello")).await
+            })
+        })
+        .collect();
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+
+    let (slow, fast) = (
+        slow_calls.load(Ordering::SeqCst),
+        fast_calls.load(Ordering::SeqCst),
+    );
+    assert!(slow < fast, "slow={} fast={}", slow, fast);
+}
+
+#[test]
+fn test_failing_endpoint_is_ejected_and_restored() {
+    let runtime = Runtime::new().unwrap();
+    let (mut down, down_calls) = counting_server(Duration::ZERO);
+    let (up, up_calls) = counting_server(Duration::ZERO);
+    let balancer = runtime
+        .block_on(async {
+            Balancer::new(vec![down.addr(), up.addr()], config(Strategy::RoundRobin))
+        })
+        .unwrap();
+
+    down.shutdown();
+    runtime.block_on(async {
+        // Every request fails over to the endpoint that is still up
+        for _ in 0..10 {
+            balancer.request(echo("failover")).await.unwrap();
+        }
+    });
+    assert_eq!(up_calls.load(Ordering::SeqCst), 10);
+    let status = balancer.endpoints();
+    assert!(status[0].ejected);
+    assert!(!status[1].ejected);
+
+    // Once the ejection expires, the next request to the endpoint brings it back
+    down.restart();
+    std::thread::sleep(Duration::from_millis(400));
+    runtime.block_on(async {
+        for _ in 0..10 {
+            balancer.request(echo("restored")).await.unwrap();
+        }
+    });
+    assert_eq!(down_calls.load(Ordering::SeqCst), 5);
+    assert!(balancer
+        .endpoints()
+        .iter()
+        .all(|endpoint| !endpoint.ejected));
+}
+
+#[tokio::test]
+async fn test_request_fails_when_every_endpoint_is_down() {
+    let addrs = (0..2)
+        .map(|_| {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        })
+        .collect();
+    let balancer = Balancer::new(addrs, config(Strategy::RoundRobin)).unwrap();
+
+    let error = balancer.request(echo("nobody")).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(balancer.endpoints().iter().all(|endpoint| endpoint.ejected));
+}
+
+#[tokio::test]
+async fn test_resolve_uses_every_address() {
+    let server = TestServer::start();
+
+    let balancer = Balancer::resolve(
+        [
+            format!("localhost:{}", server.port()),
+            server.addr().to_string(),
+        ],
+        BalancerConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert!(balancer
+        .endpoints()
+        .iter()
+        .any(|endpoint| endpoint.addr == server.addr()));
+
+    let empty: [&str; 0] = [];
+    assert!(Balancer::resolve(empty, BalancerConfig::default())
+        .await
+        .is_err());
+}
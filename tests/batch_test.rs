@@ -0,0 +1,239 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    batch_request, client_message, error, server_message, AddResponse, BatchRequest, ClientMessage,
+    EchoMessage, Ping,
+};
+use embedded_recruitment_task::router::{MessageKind, Router, MAX_BATCH_REQUESTS};
+use embedded_recruitment_task::test_util::{add, echo, TestServer};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn echo_response(content: &str) -> server_message::Message {
+    server_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    })
+}
+
+fn batch(requests: Vec<client_message::Message>, mode: batch_request::Mode) -> BatchRequest {
+    BatchRequest {
+        requests: requests
+            .into_iter()
+            .enumerate()
+            .map(|(i, message)| ClientMessage {
+                message: Some(message),
+                request_id: i as u64 + 1,
+                ..ClientMessage::default()
+            })
+            .collect(),
+        mode: mode as i32,
+        sequential: false,
+    }
+}
+
+// The code of an Error response, or `None` for any other.
+fn error_code(response: &server_message::Message) -> Option<error::Code> {
+    match response {
+        server_message::Message::Error(error) => Some(error.code()),
+        _ => None,
+    }
+}
+
+// Echo waits as many milliseconds as its content says, and `peak` keeps the most Echoes
+// that were running at once.
+fn router(peak: Arc<AtomicUsize>) -> Router {
+    let running = Arc::new(AtomicUsize::new(0));
+    Router::default().route(MessageKind::Echo, move |_, message| {
+        let running = Arc::clone(&running);
+        let peak = Arc::clone(&peak);
+        async move {
+            let client_message::Message::EchoMessage(echo) = message else {
+                return None;
+            };
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            let delay = echo.content.parse().unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+            Some(server_message::Message::EchoMessage(echo))
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_batches_are_answered_in_order() {
+    let peak = Arc::new(AtomicUsize::new(0));
+    let server = TestServer::with_config(
+        ServerConfig {
+            max_batch_concurrency: 2,
+            ..ServerConfig::default()
+        },
+        router(Arc::clone(&peak)),
+    );
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    // Later requests finish first, but the responses keep the order of the requests
+    let delays = ["80", "10", "60", "10", "40", "10"];
+    let requests = delays.iter().map(|delay| echo(delay)).collect();
+    let responses = client
+        .batch(batch(requests, batch_request::Mode::ContinueOnError))
+        .await
+        .unwrap();
+    let expected: Vec<_> = delays.iter().map(|delay| echo_response(delay)).collect();
+    assert_eq!(responses, expected);
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+    // Unless asked to run them in turn
+    peak.store(0, Ordering::SeqCst);
+    let requests = delays.iter().map(|delay| echo(delay)).collect();
+    let responses = client
+        .batch(BatchRequest {
+            sequential: true,
+            ..batch(requests, batch_request::Mode::ContinueOnError)
+        })
+        .await
+        .unwrap();
+    assert_eq!(responses, expected);
+    assert_eq!(peak.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_failures_stop_a_fail_fast_batch() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    // Pings are about the connection, so they can't go in a batch
+    let requests = || {
+        vec![
+            add(2, 3),
+            client_message::Message::Ping(Ping::default()),
+            add(4, 5),
+            echo("pump-1"),
+        ]
+    };
+
+    let responses = client
+        .batch(batch(requests(), batch_request::Mode::ContinueOnError))
+        .await
+        .unwrap();
+    assert_eq!(
+        responses[0],
+        server_message::Message::AddResponse(AddResponse { result: 5 })
+    );
+    assert_eq!(
+        error_code(&responses[1]),
+        Some(error::Code::InvalidArgument)
+    );
+    assert_eq!(
+        responses[2],
+        server_message::Message::AddResponse(AddResponse { result: 9 })
+    );
+    assert_eq!(responses[3], echo_response("pump-1"));
+
+    let responses = client
+        .batch(BatchRequest {
+            sequential: true,
+            ..batch(requests(), batch_request::Mode::FailFast)
+        })
+        .await
+        .unwrap();
+    let codes: Vec<_> = responses.iter().map(error_code).collect();
+    assert_eq!(
+        codes,
+        [
+            None,
+            Some(error::Code::InvalidArgument),
+            Some(error::Code::Aborted),
+            Some(error::Code::Aborted),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_only_plain_requests_can_be_batched() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let nested = client_message::Message::BatchRequest(batch(
+        vec![add(1, 1)],
+        batch_request::Mode::ContinueOnError,
+    ));
+    let mut unbatchable = batch(
+        vec![nested, add(1, 2)],
+        batch_request::Mode::ContinueOnError,
+    );
+    unbatchable.requests.push(ClientMessage {
+        request_id: 3,
+        ..ClientMessage::default()
+    });
+    let responses = client.batch(unbatchable).await.unwrap();
+    let codes: Vec<_> = responses.iter().map(error_code).collect();
+    assert_eq!(
+        codes,
+        [
+            Some(error::Code::InvalidArgument),
+            None,
+            Some(error::Code::InvalidArgument),
+        ]
+    );
+
+    let empty = batch(Vec::new(), batch_request::Mode::FailFast);
+    assert!(client.batch(empty).await.unwrap().is_empty());
+
+    let too_many = vec![add(1, 1); MAX_BATCH_REQUESTS + 1];
+    let error = client
+        .batch(batch(too_many, batch_request::Mode::ContinueOnError))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    // The connection carries on as before
+    let responses = client
+        .batch(batch(vec![add(1, 1)], batch_request::Mode::FailFast))
+        .await
+        .unwrap();
+    assert_eq!(
+        responses,
+        [server_message::Message::AddResponse(AddResponse {
+            result: 2
+        })]
+    );
+}
+
+#[tokio::test]
+async fn test_responses_too_large_for_a_frame_are_refused() {
+    // Echo answers with its content repeated a hundred times
+    let router = Router::default().route(MessageKind::Echo, |_, message| async move {
+        let client_message::Message::EchoMessage(echo) = message else {
+            return None;
+        };
+        Some(echo_response(&echo.content.repeat(100)))
+    });
+    let server = TestServer::with_config(
+        ServerConfig {
+            max_frame_len: 4096,
+            ..ServerConfig::default()
+        },
+        router,
+    );
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    // Each response fits, but not all of them together
+    let requests = vec![echo("0123456789"); 8];
+    let error = client
+        .batch(batch(requests, batch_request::Mode::ContinueOnError))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+
+    // The same goes for a single request
+    let response = client.request(echo(&"x".repeat(100))).await.unwrap();
+    assert_eq!(error_code(&response), Some(error::Code::ResourceExhausted));
+
+    let responses = client
+        .batch(batch(vec![echo("ok")], batch_request::Mode::FailFast))
+        .await
+        .unwrap();
+    assert_eq!(responses, [echo_response(&"ok".repeat(100))]);
+}
@@ -0,0 +1,206 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{encode_frame, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    client_message, server_message, AddResponse, Cancel, ClientMessage, EchoMessage,
+};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::{MessageKind, Router};
+use embedded_recruitment_task::test_util::{add, echo, TestServer};
+use prost::Message;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// Echo sleeps for as many milliseconds as its content says before answering.
+fn sleepy_router() -> Router {
+    Router::default().route(MessageKind::Echo, |_, message| async move {
+        let client_message::Message::EchoMessage(echo) = message else {
+            return None;
+        };
+        let delay = echo.content.parse().unwrap_or(0);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        Some(server_message::Message::EchoMessage(echo))
+    })
+}
+
+// Records whether a handler was dropped before finishing
+struct OnDrop(Arc<AtomicBool>);
+
+impl Drop for OnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+async fn test_pipelined_replies_arrive_out_of_order() {
+    let server = TestServer::with_router(sleepy_router());
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let requests: Vec<_> = ["300", "0", "100"]
+        .into_iter()
+        .map(|delay| {
+            let client = client.clone();
+            let order = Arc::clone(&order);
+            tokio::spawn(async move {
+                let response = client.request(echo(delay)).await.unwrap();
+                assert_eq!(
+                    response,
+                    server_message::Message::EchoMessage(EchoMessage {
+                        content: delay.to_string(),
+                    })
+                );
+                order.lock().unwrap().push(delay);
+            })
+        })
+        .collect();
+    for request in requests {
+        request.await.unwrap();
+    }
+
+    // One connection, yet the fast requests didn't wait behind the slow one
+    assert_eq!(*order.lock().unwrap(), ["0", "100", "300"]);
+}
+
+#[tokio::test]
+async fn test_dropped_request_cancels_handler() {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let router = sleepy_router().route(MessageKind::Add, {
+        let cancelled = Arc::clone(&cancelled);
+        move |_, _| {
+            let guard = OnDrop(Arc::clone(&cancelled));
+            async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                std::mem::forget(guard);
+                None
+            }
+        }
+    });
+    let server = TestServer::with_router(router);
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let result = tokio::time::timeout(Duration::from_millis(100), client.request(add(1, 2))).await;
+    assert!(result.is_err(), "Request should still be running");
+
+    // The Cancel is sent in the background
+    for _ in 0..50 {
+        if cancelled.load(Ordering::SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        cancelled.load(Ordering::SeqCst),
+        "Handler should be cancelled"
+    );
+
+    // The connection is still usable
+    let response = client.request(echo("0")).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::EchoMessage(EchoMessage {
+            content: "0".to_string(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_cancel_message_aborts_only_its_request() {
+    let server = TestServer::with_router(sleepy_router());
+
+    let mut frames = Vec::new();
+    for request in [
+        ClientMessage {
+            message: Some(echo("200")),
+            request_id: 1,
+            ..ClientMessage::default()
+        },
+        ClientMessage {
+            message: Some(echo("100")),
+            request_id: 2,
+            ..ClientMessage::default()
+        },
+        ClientMessage {
+            message: Some(client_message::Message::Cancel(Cancel { request_id: 1 })),
+            ..ClientMessage::default()
+        },
+        // Unknown ids are ignored
+        ClientMessage {
+            message: Some(client_message::Message::Cancel(Cancel { request_id: 42 })),
+            ..ClientMessage::default()
+        },
+    ] {
+        encode_frame(&request.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut frames).unwrap();
+    }
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    stream.write_all(&frames).await.unwrap();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    let reply = client.receive().await.unwrap();
+    assert_eq!(reply.request_id, 2);
+
+    // Request 1 would have been answered by now
+    let next = tokio::time::timeout(Duration::from_millis(300), client.receive()).await;
+    assert!(next.is_err(), "Cancelled request was answered: {:?}", next);
+
+    // Requests without an id are still answered in order
+    let response = client.request(add(2, 2)).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::AddResponse(AddResponse { result: 4 })
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_requests_are_bounded_per_connection() {
+    let running = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let router = Router::default().route(MessageKind::Add, {
+        let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+        move |_, message| {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                let client_message::Message::AddRequest(add) = message else {
+                    return None;
+                };
+                Some(server_message::Message::AddResponse(AddResponse {
+                    result: add.a + add.b,
+                }))
+            }
+        }
+    });
+    let server = TestServer::with_config(
+        ServerConfig {
+            max_pipelined_requests: 2,
+            ..ServerConfig::default()
+        },
+        router,
+    );
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let requests: Vec<_> = (0..8)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.request(add(i, i)).await })
+        })
+        .collect();
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(
+            response,
+            server_message::Message::AddResponse(AddResponse {
+                result: 2 * i as i32
+            })
+        );
+    }
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+}
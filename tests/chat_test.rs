@@ -0,0 +1,126 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{room_event, RoomEvent};
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::TestServer;
+use std::io;
+use std::time::Duration;
+use tokio::time;
+
+async fn next_event(client: &mut Client) -> RoomEvent {
+    time::timeout(Duration::from_secs(5), client.next_room_event())
+        .await
+        .expect("No room event")
+        .unwrap()
+}
+
+// The kind, member and text of an event, leaving out the time.
+fn summary(event: &RoomEvent) -> (room_event::Kind, &str, &str) {
+    (event.kind(), event.member.as_str(), event.text.as_str())
+}
+
+#[tokio::test]
+async fn test_rooms_broadcast_to_their_members() {
+    let server = TestServer::start();
+    let mut alice = Client::connect(server.addr()).await.unwrap();
+    let mut bob = Client::connect(server.addr()).await.unwrap();
+
+    let joined = alice.join_room("ops", "alice").await.unwrap();
+    assert_eq!(joined.members, ["alice"]);
+    assert!(joined.history.is_empty());
+    let joined = bob.join_room("ops", "bob").await.unwrap();
+    assert_eq!(joined.members, ["alice", "bob"]);
+    let event = next_event(&mut alice).await;
+    assert_eq!(event.room, "ops");
+    assert_eq!(summary(&event), (room_event::Kind::Joined, "bob", ""));
+
+    // Everyone hears a message, the sender included
+    alice
+        .post_to_room("ops", "restarting pump-1")
+        .await
+        .unwrap();
+    for client in [&mut alice, &mut bob] {
+        let event = next_event(client).await;
+        assert_eq!(
+            summary(&event),
+            (room_event::Kind::Message, "alice", "restarting pump-1")
+        );
+    }
+
+    // Rooms are separate
+    let mut carol = Client::connect(server.addr()).await.unwrap();
+    carol.join_room("lab", "carol").await.unwrap();
+    carol.post_to_room("lab", "calibrating").await.unwrap();
+    bob.leave_room("ops").await.unwrap();
+    let event = next_event(&mut alice).await;
+    assert_eq!(summary(&event), (room_event::Kind::Left, "bob", ""));
+
+    // Only members can post, and a name is only used once per room
+    let error = bob.post_to_room("ops", "hello?").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    let error = bob.leave_room("ops").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    let error = carol.join_room("ops", "alice").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    // Without a name, a member goes by its address
+    let joined = carol.join_room("ops", "").await.unwrap();
+    let address = carol.local_addr().unwrap().to_string();
+    assert!(joined.members.contains(&address));
+}
+
+#[tokio::test]
+async fn test_joining_replays_recent_messages() {
+    let server = TestServer::with_config(
+        ServerConfig {
+            room_history_len: 3,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let mut operator = Client::connect(server.addr()).await.unwrap();
+    operator.join_room("ops", "operator").await.unwrap();
+    for i in 0..5 {
+        operator
+            .post_to_room("ops", &format!("message {}", i))
+            .await
+            .unwrap();
+    }
+
+    let mut console = Client::connect(server.addr()).await.unwrap();
+    let joined = console.join_room("ops", "console").await.unwrap();
+    let history: Vec<_> = joined.history.iter().map(summary).collect();
+    assert_eq!(
+        history,
+        [
+            (room_event::Kind::Message, "operator", "message 2"),
+            (room_event::Kind::Message, "operator", "message 3"),
+            (room_event::Kind::Message, "operator", "message 4"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_closed_connections_leave_their_rooms() {
+    let server = TestServer::start();
+    let mut alice = Client::connect(server.addr()).await.unwrap();
+    alice.join_room("ops", "alice").await.unwrap();
+    alice.join_room("lab", "alice").await.unwrap();
+    let mut bob = Client::connect(server.addr()).await.unwrap();
+    bob.join_room("ops", "bob").await.unwrap();
+    assert_eq!(server.server().connections().len(), 2);
+
+    bob.disconnect().await.unwrap();
+    let event = next_event(&mut alice).await;
+    assert_eq!(summary(&event), (room_event::Kind::Joined, "bob", ""));
+    let event = next_event(&mut alice).await;
+    assert_eq!(summary(&event), (room_event::Kind::Left, "bob", ""));
+    assert_eq!(server.server().connections().len(), 1);
+
+    // Once everyone has left, the room starts afresh
+    alice.post_to_room("lab", "done").await.unwrap();
+    assert_eq!(next_event(&mut alice).await.text, "done");
+    alice.leave_room("lab").await.unwrap();
+    let joined = alice.join_room("lab", "alice").await.unwrap();
+    assert!(joined.history.is_empty());
+}
@@ -0,0 +1,294 @@
+use embedded_recruitment_task::checksum::{FrameChecksum, CHECKSUM_LEN};
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{FrameDecoder, FrameError, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::compression::Algorithm;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    client_message, error, server_message, Cancel, ClientMessage, Delivery, EchoMessage, Hello,
+    HelloReply, Pong, ServerMessage,
+};
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{echo, read_frame, write_frame, TestServer};
+use prost::Message;
+use std::borrow::Cow;
+use std::io;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+#[test]
+fn test_corruption_is_caught() {
+    let checksum = FrameChecksum::new(true);
+    let payload = ClientMessage {
+        message: Some(echo("temperature 21.5")),
+        ..ClientMessage::default()
+    }
+    .encode_to_vec();
+
+    let mut encoded = checksum.encode(Cow::Borrowed(&payload)).into_owned();
+    assert_eq!(encoded.len(), payload.len() + CHECKSUM_LEN);
+    assert_eq!(checksum.decode(&encoded).unwrap(), &payload[..]);
+
+    // A flipped bit in the text still decodes as a valid message, but not past the checksum
+    encoded[payload.len() - 3] ^= 0x01;
+    assert!(ClientMessage::decode(&encoded[..payload.len()]).is_ok());
+    assert!(matches!(
+        checksum.decode(&encoded),
+        Err(FrameError::Checksum { .. })
+    ));
+    assert!(checksum.decode(&[1, 2]).is_err());
+
+    // Without checksums payloads are untouched
+    let off = FrameChecksum::off();
+    assert_eq!(off.encode(Cow::Borrowed(b"plain")), &b"plain"[..]);
+    assert_eq!(off.decode(b"plain").unwrap(), b"plain");
+}
+
+#[tokio::test]
+async fn test_client_negotiates_checksums() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    client.negotiate(&[Algorithm::Lz4], 64, true).await.unwrap();
+    assert!(client.checksum());
+    assert_eq!(client.compression(), Some(Algorithm::Lz4));
+
+    // Compressed and uncompressed frames both carry the checksum
+    for content in ["short", &"sensor reading ok\n".repeat(100)] {
+        let response = client.request(echo(content)).await.unwrap();
+        assert_eq!(
+            response,
+            server_message::Message::EchoMessage(EchoMessage {
+                content: content.to_string()
+            })
+        );
+    }
+    assert_eq!(client.metrics().integrity_errors(), 0);
+}
+
+#[tokio::test]
+async fn test_server_can_refuse_checksums() {
+    let server = TestServer::with_config(
+        ServerConfig {
+            checksum: false,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    client.negotiate(&[], 0, true).await.unwrap();
+    assert!(!client.checksum());
+    let response = client.request(echo("unchecked")).await.unwrap();
+    assert!(matches!(response, server_message::Message::EchoMessage(_)));
+}
+
+// A raw connection to `server` with checksums negotiated.
+async fn checked_connection(server: &TestServer) -> (TcpStream, FrameDecoder) {
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
+    let hello = ClientMessage {
+        message: Some(client_message::Message::Hello(Hello {
+            checksum: true,
+            ..Hello::default()
+        })),
+        ..ClientMessage::default()
+    };
+    write_frame(&mut stream, &hello.encode_to_vec()).await;
+    let reply = ServerMessage::decode(&read_frame(&mut stream, &mut decoder).await[..]).unwrap();
+    assert!(matches!(
+        reply.message,
+        Some(server_message::Message::HelloReply(HelloReply {
+            checksum: true,
+            ..
+        }))
+    ));
+    (stream, decoder)
+}
+
+#[tokio::test]
+async fn test_server_rejects_corrupted_frames() {
+    let server = TestServer::start();
+    let (mut stream, mut decoder) = checked_connection(&server).await;
+    let checksum = FrameChecksum::new(true);
+
+    let request = |content: &str, request_id| {
+        let encoded = ClientMessage {
+            message: Some(echo(content)),
+            request_id,
+            ..ClientMessage::default()
+        }
+        .encode_to_vec();
+        checksum.encode(Cow::Owned(encoded)).into_owned()
+    };
+
+    let mut corrupted = request("open valve", 1);
+    corrupted[5] ^= 0x20;
+    write_frame(&mut stream, &corrupted).await;
+    write_frame(&mut stream, &request("close valve", 2)).await;
+
+    // The corrupted request is still owed a reply, so it gets an error, and the next one is
+    // answered as usual
+    let frame = read_frame(&mut stream, &mut decoder).await;
+    let reply = ServerMessage::decode(checksum.decode(&frame).unwrap()).unwrap();
+    assert_eq!(reply.request_id, 1);
+    assert!(matches!(
+        reply.message,
+        Some(server_message::Message::Error(error)) if error.code() == error::Code::DataLoss
+    ));
+
+    let frame = read_frame(&mut stream, &mut decoder).await;
+    let reply = ServerMessage::decode(checksum.decode(&frame).unwrap()).unwrap();
+    assert_eq!(reply.request_id, 2);
+    assert_eq!(
+        reply.message,
+        Some(server_message::Message::EchoMessage(EchoMessage {
+            content: "close valve".to_string()
+        }))
+    );
+
+    assert_eq!(server.server().metrics().integrity_errors(), 1);
+}
+
+#[tokio::test]
+async fn test_server_closes_connection_after_corrupted_frame_owed_no_reply() {
+    let server = TestServer::start();
+    let checksum = FrameChecksum::new(true);
+    let frame = |message, request_id| {
+        let encoded = ClientMessage {
+            message: Some(message),
+            request_id,
+            ..ClientMessage::default()
+        }
+        .encode_to_vec();
+        checksum.encode(Cow::Owned(encoded)).into_owned()
+    };
+
+    let unanswered = [
+        client_message::Message::Pong(Pong { timestamp_us: 42 }),
+        client_message::Message::Cancel(Cancel { request_id: 7 }),
+    ];
+    for message in unanswered {
+        let (mut stream, _) = checked_connection(&server).await;
+        let mut corrupted = frame(message, 0);
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x01;
+        write_frame(&mut stream, &corrupted).await;
+        write_frame(&mut stream, &frame(echo("open valve"), 0)).await;
+
+        // Nothing answers the corrupted frame, and the request after it isn't answered in its
+        // place: the connection closes, or is reset as the request was never read
+        let mut buffer = [0; 64];
+        let read = stream.read(&mut buffer).await;
+        assert!(!matches!(read, Ok(n) if n > 0), "{:?}", read);
+    }
+
+    assert_eq!(server.server().metrics().integrity_errors(), 2);
+}
+
+#[tokio::test]
+async fn test_client_rejects_corrupted_replies() {
+    // A server whose first reply after the Hello is damaged on the way
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
+        let checksum = FrameChecksum::new(true);
+
+        read_frame(&mut stream, &mut decoder).await;
+        let reply = ServerMessage {
+            message: Some(server_message::Message::HelloReply(HelloReply {
+                checksum: true,
+                ..HelloReply::default()
+            })),
+            ..ServerMessage::default()
+        };
+        write_frame(&mut stream, &reply.encode_to_vec()).await;
+
+        read_frame(&mut stream, &mut decoder).await;
+        let reply = ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage {
+                content: "reading 42".to_string(),
+            })),
+            ..ServerMessage::default()
+        };
+        let mut frame = checksum
+            .encode(Cow::Owned(reply.encode_to_vec()))
+            .into_owned();
+        frame[4] ^= 0x01;
+        write_frame(&mut stream, &frame).await;
+    });
+
+    let mut client = Client::connect(addr).await.unwrap();
+    client.negotiate(&[], 0, true).await.unwrap();
+
+    let error = client.request(echo("reading")).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(client.metrics().integrity_errors(), 1);
+
+    // The corrupted frame can't be told apart from a push, so the connection is given up
+    assert!(client.is_desynchronized());
+    let error = client.request(echo("reading")).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    drop(client);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_corrupted_push_does_not_shift_replies() {
+    // A server that pushes a delivery, damaged on the way, just before its reply
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
+        let checksum = FrameChecksum::new(true);
+
+        read_frame(&mut stream, &mut decoder).await;
+        let reply = ServerMessage {
+            message: Some(server_message::Message::HelloReply(HelloReply {
+                checksum: true,
+                ..HelloReply::default()
+            })),
+            ..ServerMessage::default()
+        };
+        write_frame(&mut stream, &reply.encode_to_vec()).await;
+
+        read_frame(&mut stream, &mut decoder).await;
+        let messages = [
+            server_message::Message::Delivery(Delivery {
+                topic: "alerts".to_string(),
+                payload: b"overheat".to_vec(),
+                ..Delivery::default()
+            }),
+            server_message::Message::EchoMessage(EchoMessage {
+                content: "first".to_string(),
+            }),
+        ];
+        for (i, message) in messages.into_iter().enumerate() {
+            let encoded = ServerMessage {
+                message: Some(message),
+                ..ServerMessage::default()
+            }
+            .encode_to_vec();
+            let mut frame = checksum.encode(Cow::Owned(encoded)).into_owned();
+            if i == 0 {
+                frame[4] ^= 0x01;
+            }
+            write_frame(&mut stream, &frame).await;
+        }
+    });
+
+    let mut client = Client::connect(addr).await.unwrap();
+    client.negotiate(&[], 0, true).await.unwrap();
+
+    // The damaged push fails the request, whose reply is still on its way, so the next request
+    // must not be sent over the connection and take that reply for its own
+    let error = client.request(echo("first")).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(client.is_desynchronized());
+    let error = client.request(echo("second")).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    drop(client);
+    server.await.unwrap();
+}
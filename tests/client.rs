@@ -1,4 +1,5 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+use embedded_recruitment_task::codec::{encode_frame, FrameDecoder, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::message::{client_message, ClientMessage, ServerMessage};
 use log::error;
 use log::info;
 use prost::Message;
@@ -16,6 +17,7 @@ pub struct Client {
     port: u32,
     timeout: Duration,
     stream: Option<TcpStream>,
+    decoder: FrameDecoder,
 }
 
 impl Client {
@@ -25,6 +27,7 @@ impl Client {
             port,
             timeout: Duration::from_millis(timeout_ms),
             stream: None,
+            decoder: FrameDecoder::new(DEFAULT_MAX_FRAME_LEN),
         }
     }
 
@@ -46,6 +49,7 @@ impl Client {
         // Connect to the server with a timeout
         let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
         self.stream = Some(stream);
+        self.decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
 
         println!("Connected to the server!");
         Ok(())
@@ -64,15 +68,19 @@ impl Client {
     // generic message to send message to the server
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
         if let Some(ref mut stream) = self.stream {
-            // Encode the message to a buffer
+            // Encode the message to a length-prefixed frame
+            let request = ClientMessage {
+                message: Some(message),
+                ..ClientMessage::default()
+            };
             let mut buffer = Vec::new();
-            message.encode(&mut buffer);
+            encode_frame(&request.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut buffer)?;
 
             // Send the buffer to the server
             stream.write_all(&buffer)?;
             stream.flush()?;
 
-            println!("Sent message: {:?}", message);
+            println!("Sent message: {:?}", request.message);
             Ok(())
         } else {
             Err(io::Error::new(
@@ -86,19 +94,28 @@ impl Client {
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
             let mut buffer = vec![0u8; 1024];
-            let bytes_read = stream.read(&mut buffer)?;
-            if bytes_read == 0 {
-                info!("Server disconnected.");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Server disconnected",
-                ));
-            }
 
-            info!("Received {} bytes from the server", bytes_read);
+            // Read until a whole frame has arrived
+            let frame = loop {
+                if let Some(frame) = self.decoder.decode()? {
+                    break frame;
+                }
+
+                let bytes_read = stream.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    info!("Server disconnected.");
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Server disconnected",
+                    ));
+                }
+
+                info!("Received {} bytes from the server", bytes_read);
+                self.decoder.extend(&buffer[..bytes_read]);
+            };
 
             // Decode the received message
-            ServerMessage::decode(&buffer[..bytes_read]).map_err(|e| {
+            ServerMessage::decode(frame.as_slice()).map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     format!("Failed to decode ServerMessage: {}", e),
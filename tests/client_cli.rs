@@ -0,0 +1,218 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::message::{
+    client_message, ClientMessage, EchoMessage, RegisterDevice,
+};
+use embedded_recruitment_task::test_util::TestServer;
+use prost::Message;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use tempfile::tempdir;
+
+fn client(server: &TestServer) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ClientMain"));
+    command.args(["--addr", &server.addr().to_string()]);
+    command
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "ClientMain failed: {:?}", output);
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn json(output: &Output) -> serde_json::Value {
+    serde_json::from_str(stdout(output).trim()).expect("Output is not JSON")
+}
+
+#[test]
+fn test_echo_command() {
+    let server = TestServer::start();
+
+    let output = client(&server)
+        .args(["echo", "hello", "world"])
+        .output()
+        .unwrap();
+    assert!(stdout(&output).contains("content: \"hello world\""));
+
+    let output = client(&server)
+        .args(["--json", "echo", "hi"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        json(&output)["response"]["message"]["echo_message"]["content"],
+        "hi"
+    );
+}
+
+#[test]
+fn test_add_command_accepts_negative_numbers() {
+    let server = TestServer::start();
+
+    let output = client(&server)
+        .args(["add", "-5", "3", "--json"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        json(&output)["response"]["message"]["add_response"]["result"],
+        -2
+    );
+}
+
+#[test]
+fn test_ping_command_reports_round_trip_time() {
+    let server = TestServer::start();
+
+    let output = client(&server).args(["--json", "ping"]).output().unwrap();
+    assert!(json(&output)["rtt_us"].is_u64());
+
+    let output = client(&server).arg("ping").output().unwrap();
+    assert!(stdout(&output).starts_with("pong from"));
+}
+
+#[test]
+fn test_raw_command() {
+    let server = TestServer::start();
+
+    let request = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "raw bytes".to_string(),
+        })),
+        ..ClientMessage::default()
+    };
+    let output = client(&server)
+        .args(["--json", "raw", &hex::encode(request.encode_to_vec())])
+        .output()
+        .unwrap();
+    assert_eq!(
+        json(&output)["response"]["message"]["echo_message"]["content"],
+        "raw bytes"
+    );
+
+    // The server doesn't answer payloads it can't decode
+    let output = client(&server)
+        .args(["--timeout", "200ms", "raw", "ffff"])
+        .output()
+        .unwrap();
+    assert_eq!(stdout(&output).trim(), "no response");
+
+    let output = client(&server).args(["raw", "not hex"]).output().unwrap();
+    assert!(!output.status.success(), "Invalid hex should be rejected");
+}
+
+#[test]
+fn test_repl_session_and_history() {
+    let server = TestServer::start();
+    let home = tempdir().unwrap();
+
+    let mut child = client(&server)
+        .arg("--json")
+        .env("HOME", home.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"echo first line\nadd 2 2\nnot-a-command\nquit\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = stdout(&output);
+
+    let responses: Vec<serde_json::Value> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    assert_eq!(responses.len(), 2, "Unexpected output: {}", stdout);
+    assert_eq!(
+        responses[0]["response"]["message"]["echo_message"]["content"],
+        "first line"
+    );
+    assert_eq!(
+        responses[1]["response"]["message"]["add_response"]["result"],
+        4
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unrecognized subcommand"));
+
+    let history = std::fs::read_to_string(home.path().join(".embedded_client_history")).unwrap();
+    assert!(history.contains("echo first line"));
+    assert!(history.contains("add 2 2"));
+}
+
+#[test]
+fn test_connection_failure_exits_with_error() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ClientMain"))
+        .args(["--addr", &addr.to_string(), "ping"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error:"));
+}
+
+#[test]
+fn test_devices_command_lists_the_registry() {
+    let server = TestServer::start();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    // Keeps the device's connection open, so it stays online
+    let _device = runtime.block_on(async {
+        let mut device = Client::connect(server.addr()).await.unwrap();
+        let registration = client_message::Message::RegisterDevice(RegisterDevice {
+            device_id: "pump-1".to_string(),
+            model: "P100".to_string(),
+            firmware_version: "1.4.2".to_string(),
+        });
+        device.request(registration).await.unwrap();
+        device
+    });
+
+    let output = client(&server)
+        .args(["--json", "devices"])
+        .output()
+        .unwrap();
+    let devices = &json(&output)["devices"];
+    assert_eq!(devices.as_array().unwrap().len(), 1);
+    assert_eq!(devices[0]["device_id"], "pump-1");
+    assert_eq!(devices[0]["online"], true);
+
+    let output = client(&server)
+        .args(["devices", "--online"])
+        .output()
+        .unwrap();
+    let listed = stdout(&output);
+    assert!(
+        listed.starts_with("pump-1  P100 1.4.2  online"),
+        "{}",
+        listed
+    );
+
+    let output = client(&server)
+        .args(["devices", "valve-9"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_tls_and_token_are_refused() {
+    let server = TestServer::start();
+    for flags in [&["--tls"][..], &["--token", "secret"]] {
+        let output = client(&server)
+            .args(flags)
+            .args(["echo", "hi"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("not supported by this server"),
+            "{}",
+            stderr
+        );
+    }
+}
@@ -1,66 +1,17 @@
 use embedded_recruitment_task::{
     message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
+    router::{MessageKind, Router},
+    test_util::TestServer,
 };
-use std::{
-    sync::Arc
-};
-use log::error;
-use tokio::task::JoinHandle;
-use tokio::sync::Mutex;
-use tokio::runtime::Runtime;
-use std::time::Duration;
-use std::thread;
 mod client;
 
-
-
-
-fn run_server_in_background() -> Result<(Arc<Server>, thread::JoinHandle<()>), Box<dyn std::error::Error>> {
-    // Create a Tokio runtime
-    let runtime = Runtime::new()?;
-
-    // Use the runtime to create the server
-    let server = runtime.block_on(async {
-        Server::new("localhost:8080").await.map_err(|e| {
-            error!("Failed to create server: {}", e);
-            e
-        })
-    })?;
-
-    // Wrap the server in an Arc for thread-safe sharing
-    let server = Arc::new(server);
-
-    // Clone the Arc for the thread
-    let server_for_thread = Arc::clone(&server);
-
-    // Spawn the server in a separate thread
-    let handle = thread::spawn(move || {
-        runtime.block_on(async {
-            if let Err(e) = server_for_thread.run().await {
-                error!("Server encountered an error: {}", e);
-            }
-        });
-    });
-
-    // Return the Arc-wrapped server and the thread handle
-    Ok((server, handle))
-}
-
-
 #[test]
 fn test_client_connection() {
-    // Set up the server in a separate thread
-    let result = run_server_in_background();
-    let (server, handle) = match result {
-        Ok(res) => res,
-        Err(e) => {
-            panic!("Failed to run server: {}", e);
-        }
-    };
+    // Set up the server on an ephemeral port
+    let server = TestServer::start();
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Disconnect the client
@@ -75,22 +26,17 @@ fn test_client_connection() {
 
 #[test]
 fn test_client_echo_message() {
-    // Set up the server in a separate thread
-    let result = run_server_in_background();
-    let (server, handle) = match result {
-        Ok(res) => res,
-        Err(e) => {
-            panic!("Failed to run server: {}", e);
-        }
-    };
+    // Set up the server on an ephemeral port
+    let server = TestServer::start();
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
-    let mut echo_message = EchoMessage::default();
-    echo_message.content = "Hello, World!".to_string();
+    let echo_message = EchoMessage {
+        content: "Hello, World!".to_string(),
+    };
     let message = client_message::Message::EchoMessage(echo_message.clone());
 
     // Send the message to the server
@@ -125,17 +71,11 @@ fn test_client_echo_message() {
 
 #[test]
 fn test_multiple_echo_messages() {
-    // Set up the server in a separate thread
-    let result = run_server_in_background();
-    let (server, handle) = match result {
-        Ok(res) => res,
-        Err(e) => {
-            panic!("Failed to run server: {}", e);
-        }
-    };
+    // Set up the server on an ephemeral port
+    let server = TestServer::start();
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare multiple messages
@@ -147,8 +87,9 @@ fn test_multiple_echo_messages() {
 
     // Send and receive multiple messages
     for message_content in messages {
-        let mut echo_message = EchoMessage::default();
-        echo_message.content = message_content.clone();
+        let echo_message = EchoMessage {
+            content: message_content.clone(),
+        };
         let message = client_message::Message::EchoMessage(echo_message);
 
         // Send the message to the server
@@ -184,20 +125,14 @@ fn test_multiple_echo_messages() {
 
 #[test]
 fn test_multiple_clients() {
-    // Set up the server in a separate thread
-    let result = run_server_in_background();
-    let (server, handle) = match result {
-        Ok(res) => res,
-        Err(e) => {
-            panic!("Failed to run server: {}", e);
-        }
-    };
+    // Set up the server on an ephemeral port
+    let server = TestServer::start();
 
     // Create and connect multiple clients
-    let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+    let mut clients = [
+        client::Client::new(&server.ip(), server.port(), 1000),
+        client::Client::new(&server.ip(), server.port(), 1000),
+        client::Client::new(&server.ip(), server.port(), 1000),
     ];
 
     for client in clients.iter_mut() {
@@ -205,7 +140,7 @@ fn test_multiple_clients() {
     }
 
     // Prepare multiple messages
-    let messages = vec![
+    let messages = [
         "Hello, World!".to_string(),
         "How are you?".to_string(),
         "Goodbye!".to_string(),
@@ -213,8 +148,9 @@ fn test_multiple_clients() {
 
     // Send and receive multiple messages for each client
     for message_content in messages {
-        let mut echo_message = EchoMessage::default();
-        echo_message.content = message_content.clone();
+        let echo_message = EchoMessage {
+            content: message_content.clone(),
+        };
         let message = client_message::Message::EchoMessage(echo_message.clone());
 
         for client in clients.iter_mut() {
@@ -256,26 +192,17 @@ fn test_multiple_clients() {
 }
 
 #[test]
-#[ignore]
 fn test_client_add_request() {
-    // Set up the server in a separate thread
-    let result = run_server_in_background();
-    let (server, handle) = match result {
-        Ok(res) => res,
-        Err(e) => {
-            panic!("Failed to run server: {}", e);
-        }
-    };
+    // Set up the server on an ephemeral port
+    let server = TestServer::start();
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
-    let mut add_request = AddRequest::default();
-    add_request.a = 10;
-    add_request.b = 20;
-    let message = client_message::Message::AddRequest(add_request.clone());
+    let add_request = AddRequest { a: 10, b: 20 };
+    let message = client_message::Message::AddRequest(add_request);
 
     // Send the message to the server
     assert!(client.send(message).is_ok(), "Failed to send message");
@@ -307,3 +234,116 @@ fn test_client_add_request() {
     // Stop the server and wait for thread to finish
     server.stop();
 }
+
+#[test]
+fn test_custom_handler() {
+    // Replace the built-in echo handler with one that shouts back
+    let router = Router::default().route(MessageKind::Echo, |_ctx, request| async move {
+        match request {
+            client_message::Message::EchoMessage(echo) => {
+                Some(server_message::Message::EchoMessage(EchoMessage {
+                    content: echo.content.to_uppercase(),
+                }))
+            }
+            _ => None,
+        }
+    });
+    let server = TestServer::with_router(router);
+
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "quiet please".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "QUIET PLEASE");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // The built-in add handler is still registered alongside the custom one
+    let message = client_message::Message::AddRequest(AddRequest { a: 2, b: 3 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 5);
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+}
+
+#[test]
+fn test_servers_use_distinct_ports_and_stop_on_drop() {
+    let first = TestServer::start();
+    let second = TestServer::start();
+    assert_ne!(first.addr(), second.addr(), "Test servers share an address");
+
+    let mut client = client::Client::new(&first.ip(), first.port(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Dropping the server closes connected clients and frees the port
+    let addr = first.addr();
+    drop(first);
+    assert!(
+        client.receive().is_err(),
+        "Connection should be closed once the server is dropped"
+    );
+    assert!(
+        std::net::TcpStream::connect(addr).is_err(),
+        "Server should no longer accept connections"
+    );
+}
+
+#[test]
+fn test_large_echo_message() {
+    let server = TestServer::start();
+
+    let mut client = client::Client::new(&server.ip(), server.port(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Much larger than a single read, so the server has to reassemble the frame
+    let content = "x".repeat(32 * 1024);
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.clone(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    match client
+        .receive()
+        .expect("Failed to receive response")
+        .message
+    {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+}
+
+#[test]
+fn test_oversized_frame_closes_connection() {
+    use std::io::{Read, Write};
+
+    let server = TestServer::start();
+    let mut stream = std::net::TcpStream::connect(server.addr()).expect("Failed to connect");
+
+    // Announce a frame far above the server's limit
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+
+    let mut buffer = [0u8; 16];
+    assert_eq!(
+        stream.read(&mut buffer).expect("Failed to read"),
+        0,
+        "Server should close the connection"
+    );
+}
@@ -0,0 +1,128 @@
+use embedded_recruitment_task::codec::{
+    encode_frame, encode_packet, FrameDecoder, FrameError, Framing, HEADER_LEN,
+};
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_frame(payload, 1024, &mut out).expect("Failed to encode frame");
+    out
+}
+
+#[test]
+fn test_frame_round_trip() {
+    let encoded = frame(b"hello");
+    assert_eq!(encoded, [0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+
+    let mut decoder = FrameDecoder::new(1024);
+    decoder.extend(&encoded);
+    assert_eq!(decoder.decode(), Ok(Some(b"hello".to_vec())));
+    assert_eq!(decoder.decode(), Ok(None));
+    assert_eq!(decoder.buffered(), 0);
+}
+
+#[test]
+fn test_frames_split_across_reads() {
+    let mut stream = frame(b"first");
+    stream.extend(frame(b""));
+    stream.extend(frame(b"third frame"));
+
+    // Feed one byte at a time, as a slow transport would
+    let mut decoder = FrameDecoder::new(1024);
+    let mut frames = Vec::new();
+    for byte in stream {
+        decoder.extend(&[byte]);
+        while let Some(frame) = decoder.decode().unwrap() {
+            frames.push(frame);
+        }
+    }
+
+    assert_eq!(
+        frames,
+        [b"first".to_vec(), Vec::new(), b"third frame".to_vec()]
+    );
+}
+
+#[test]
+fn test_oversized_frame_rejected_from_header() {
+    let mut decoder = FrameDecoder::new(16);
+
+    // Only the header has arrived, but it already announces too much
+    decoder.extend(&u32::MAX.to_be_bytes());
+    assert_eq!(
+        decoder.decode(),
+        Err(FrameError::TooLarge {
+            len: u32::MAX as usize,
+            max: 16
+        })
+    );
+}
+
+#[test]
+fn test_encode_rejects_oversized_payload() {
+    let mut out = Vec::new();
+    assert_eq!(
+        encode_frame(&[0; 17], 16, &mut out),
+        Err(FrameError::TooLarge { len: 17, max: 16 })
+    );
+    assert!(out.is_empty(), "Nothing should be written on error");
+
+    assert!(encode_frame(&[0; 16], 16, &mut out).is_ok());
+    assert_eq!(out.len(), HEADER_LEN + 16);
+}
+
+fn packet(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_packet(payload, 1024, &mut out).expect("Failed to encode packet");
+    out
+}
+
+#[test]
+fn test_packet_round_trip() {
+    // Zeros, and runs longer than one COBS block
+    let payloads = [
+        b"hello".to_vec(),
+        Vec::new(),
+        vec![0; 3],
+        (1..=255).collect(),
+        (0..600).map(|i| (i % 7) as u8).collect(),
+    ];
+
+    let mut decoder = Framing::Serial.decoder(1024);
+    for payload in &payloads {
+        let encoded = packet(payload);
+        assert_eq!(
+            encoded.iter().position(|&byte| byte == 0),
+            Some(encoded.len() - 1)
+        );
+        decoder.extend(&encoded);
+        assert_eq!(decoder.decode(), Ok(Some(payload.clone())));
+    }
+    assert_eq!(decoder.decode(), Ok(None));
+    assert_eq!(decoder.buffered(), 0);
+}
+
+#[test]
+fn test_damaged_packets_are_dropped() {
+    let mut corrupted = packet(b"flipped bit");
+    corrupted[4] ^= 0x10;
+    let mut truncated = packet(b"cut short");
+    truncated.drain(..3);
+
+    let mut stream = b"\xff\x13noise".to_vec();
+    stream.push(0);
+    stream.extend(corrupted);
+    stream.extend(truncated);
+    stream.extend(vec![0x55; 5000]); // Too long to be a packet
+    stream.push(0);
+    stream.extend(packet(b"intact"));
+
+    let mut decoder = Framing::Serial.decoder(1024);
+    let mut packets = Vec::new();
+    for chunk in stream.chunks(100) {
+        decoder.extend(chunk);
+        while let Some(packet) = decoder.decode().unwrap() {
+            packets.push(packet);
+        }
+    }
+    assert_eq!(packets, [b"intact".to_vec()]);
+}
@@ -0,0 +1,110 @@
+// Helpers shared by the tests that run the real `ServerMain` binary. Each test file uses only
+// some of them.
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::NamedTempFile;
+
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A `ServerMain` process running against a temporary configuration file. It is killed when
+// dropped, so a failing test does not leave it holding its port and files.
+pub struct ServerProcess {
+    child: Child,
+    logs: Receiver<String>,
+    config: NamedTempFile,
+    pub addr: SocketAddr,
+}
+
+impl ServerProcess {
+    pub fn spawn(config: &str) -> Self {
+        let file = NamedTempFile::new().expect("Failed to create config file");
+        std::fs::write(file.path(), config).expect("Failed to write config file");
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ServerMain"))
+            .arg(file.path())
+            .env_remove("RUST_LOG")
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start ServerMain");
+
+        // Forward log lines so the test can wait for specific events.
+        let stderr = child.stderr.take().unwrap();
+        let (sender, logs) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut server = ServerProcess {
+            child,
+            logs,
+            config: file,
+            addr: "0.0.0.0:0".parse().unwrap(),
+        };
+        let line = server.wait_for_log("Server is running on ");
+        let addr = line.rsplit(' ').next().unwrap();
+        server.addr = addr.parse().expect("Failed to parse server address");
+        server
+    }
+
+    pub fn wait_for_log(&self, needle: &str) -> String {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.logs.recv_timeout(remaining) {
+                Ok(line) if line.contains(needle) => return line,
+                Ok(_) => continue,
+                Err(_) => panic!("Timed out waiting for log line containing {:?}", needle),
+            }
+        }
+    }
+
+    pub fn rewrite_config(&self, config: &str) {
+        std::fs::write(self.config.path(), config).expect("Failed to rewrite config file");
+    }
+
+    #[cfg(unix)]
+    pub fn signal(&self, signal: libc::c_int) {
+        let result = unsafe { libc::kill(self.child.id() as libc::pid_t, signal) };
+        assert_eq!(result, 0, "Failed to send signal {}", signal);
+    }
+
+    pub fn wait_for_exit(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self
+                .child
+                .try_wait()
+                .expect("Failed to poll server process")
+            {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Server did not exit in time");
+    }
+
+    // SIGKILL the server, so nothing gets the chance to be flushed or cleaned up.
+    pub fn kill(self) {
+        drop(self);
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,158 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{FrameDecoder, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::compression::{Algorithm, FrameCompression};
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    client_message, server_message, ClientMessage, Compression, EchoMessage, Hello, ServerMessage,
+};
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{echo, read_frame, write_frame, TestServer};
+use prost::Message;
+use std::io;
+use tokio::net::TcpStream;
+
+// Text that compresses well, like the logs devices send.
+fn log_lines(lines: usize) -> String {
+    (0..lines)
+        .map(|i| format!("2024-01-01T00:00:{:02}Z INFO sensor reading ok\n", i % 60))
+        .collect()
+}
+
+#[test]
+fn test_frames_round_trip_with_each_algorithm() {
+    let text = log_lines(100);
+    for algorithm in [Algorithm::Zstd, Algorithm::Lz4] {
+        let compression = FrameCompression::new(Some(algorithm), 64, DEFAULT_MAX_FRAME_LEN);
+
+        let encoded = compression.encode(text.as_bytes()).unwrap();
+        assert!(
+            encoded.len() < text.len() / 4,
+            "{:?} barely compressed",
+            algorithm
+        );
+        assert_eq!(compression.decode(&encoded).unwrap(), text.as_bytes());
+
+        // Below the threshold a payload is only prefixed
+        let encoded = compression.encode(b"short").unwrap();
+        assert_eq!(&encoded[..], b"\0short");
+        assert_eq!(compression.decode(&encoded).unwrap(), &b"short"[..]);
+    }
+
+    // Without compression payloads are untouched
+    let off = FrameCompression::off();
+    assert_eq!(off.encode(b"plain").unwrap(), &b"plain"[..]);
+}
+
+#[test]
+fn test_decompression_bomb_is_rejected() {
+    let max_len = 1024;
+    let compression = FrameCompression::new(Some(Algorithm::Zstd), 0, max_len);
+    let bomb = zstd::bulk::compress(&vec![0; 1024 * 1024], 3).unwrap();
+
+    // Announcing more than the limit fails before anything is decompressed
+    let mut frame = vec![1];
+    frame.extend_from_slice(&(1024u32 * 1024).to_be_bytes());
+    frame.extend_from_slice(&bomb);
+    let error = compression.decode(&frame).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // So does lying about the size
+    frame[1..5].copy_from_slice(&100u32.to_be_bytes());
+    let error = compression.decode(&frame).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    let error = compression.decode(&[7, 1, 2, 3]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn test_client_negotiates_compression() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let algorithm = client
+        .negotiate_compression(&[Algorithm::Lz4, Algorithm::Zstd], 64)
+        .await
+        .unwrap();
+    assert_eq!(
+        algorithm,
+        Some(Algorithm::Lz4),
+        "The client's preference wins"
+    );
+    assert_eq!(client.compression(), Some(Algorithm::Lz4));
+
+    for content in ["small", &log_lines(500)] {
+        let response = client.request(echo(content)).await.unwrap();
+        assert_eq!(
+            response,
+            server_message::Message::EchoMessage(EchoMessage {
+                content: content.to_string(),
+            })
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_compressed_frames_on_the_wire() {
+    let server = TestServer::start();
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    let mut decoder = FrameDecoder::new(DEFAULT_MAX_FRAME_LEN);
+
+    let hello = ClientMessage {
+        message: Some(client_message::Message::Hello(Hello {
+            compression: vec![Compression::Zstd.into()],
+            ..Hello::default()
+        })),
+        ..ClientMessage::default()
+    };
+    write_frame(&mut stream, &hello.encode_to_vec()).await;
+    let reply = ServerMessage::decode(&read_frame(&mut stream, &mut decoder).await[..]).unwrap();
+    assert!(matches!(
+        reply.message,
+        Some(server_message::Message::HelloReply(reply)) if reply.compression() == Compression::Zstd
+    ));
+
+    let compression = FrameCompression::new(Some(Algorithm::Zstd), 64, DEFAULT_MAX_FRAME_LEN);
+    let content = log_lines(1000);
+    let request = ClientMessage {
+        message: Some(echo(&content)),
+        ..ClientMessage::default()
+    };
+    let encoded = request.encode_to_vec();
+    write_frame(&mut stream, &compression.encode(&encoded).unwrap()).await;
+
+    let frame = read_frame(&mut stream, &mut decoder).await;
+    assert!(frame.len() < content.len() / 4, "Reply wasn't compressed");
+    let reply = ServerMessage::decode(&compression.decode(&frame).unwrap()[..]).unwrap();
+    assert_eq!(
+        reply.message,
+        Some(server_message::Message::EchoMessage(EchoMessage {
+            content
+        }))
+    );
+}
+
+#[tokio::test]
+async fn test_no_common_algorithm_leaves_compression_off() {
+    let server = TestServer::with_config(
+        ServerConfig {
+            compression: vec![Algorithm::Zstd],
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let algorithm = client
+        .negotiate_compression(&[Algorithm::Lz4], 64)
+        .await
+        .unwrap();
+    assert_eq!(algorithm, None);
+
+    let content = log_lines(100);
+    let response = client.request(echo(&content)).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::EchoMessage(EchoMessage { content })
+    );
+}
@@ -0,0 +1,159 @@
+use embedded_recruitment_task::compression::Algorithm;
+use embedded_recruitment_task::config::{SerialPortConfig, ServerConfig};
+use embedded_recruitment_task::pubsub::SlowSubscriber;
+use embedded_recruitment_task::wal::SyncPolicy;
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[test]
+fn test_empty_config_uses_defaults() {
+    let config = ServerConfig::parse("").expect("Empty configuration should parse");
+    assert_eq!(config, ServerConfig::default());
+}
+
+#[test]
+fn test_config_parses_all_fields() {
+    let config = ServerConfig::parse(
+        r#"
+        address = "0.0.0.0:7000"
+        log_level = "debug"
+        max_connections = 8
+        max_pipelined_requests = 4
+        max_batch_concurrency = 2
+        heartbeat_interval_ms = 1000
+        heartbeat_timeout_ms = 500
+        compression = ["lz4"]
+        compression_threshold = 128
+        shutdown_timeout_ms = 250
+        storage_root = "/var/lib/files"
+        firmware_dir = "/var/lib/firmware"
+        telemetry_retention_ms = 0
+        subscriber_queue_len = 16
+        slow_subscriber = "block"
+        room_history_len = 0
+        state_dir = "/var/lib/state"
+        wal_sync = "always"
+        wal_sync_interval_ms = 200
+        snapshot_interval_ms = 5000
+
+        [[serial_ports]]
+        path = "/dev/ttyUSB0"
+
+        [[serial_ports]]
+        path = "/dev/ttyAMA0"
+        baud_rate = 921600
+        heartbeat = true
+        "#,
+    )
+    .expect("Configuration should parse");
+
+    assert_eq!(config.address, "0.0.0.0:7000");
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert_eq!(config.max_connections, 8);
+    assert_eq!(config.max_pipelined_requests, 4);
+    assert_eq!(config.max_batch_concurrency, 2);
+    assert_eq!(config.compression, [Algorithm::Lz4]);
+    assert_eq!(config.compression_threshold, 128);
+    assert_eq!(
+        config.heartbeat(),
+        Some((Duration::from_secs(1), Duration::from_millis(500)))
+    );
+    assert_eq!(config.shutdown_timeout(), Duration::from_millis(250));
+    assert_eq!(config.storage_root, Some(PathBuf::from("/var/lib/files")));
+    assert_eq!(
+        config.firmware_dir,
+        Some(PathBuf::from("/var/lib/firmware"))
+    );
+    assert_eq!(config.telemetry_retention(), None);
+    assert_eq!(config.subscriber_queue_len, 16);
+    assert_eq!(config.slow_subscriber, SlowSubscriber::Block);
+    assert_eq!(config.room_history_len, 0);
+    assert_eq!(config.state_dir, Some(PathBuf::from("/var/lib/state")));
+    let wal = config.wal_options();
+    assert_eq!(wal.sync, SyncPolicy::Always);
+    assert_eq!(wal.sync_interval, Duration::from_millis(200));
+    assert_eq!(wal.snapshot_interval, Duration::from_secs(5));
+    assert_eq!(
+        config.serial_ports,
+        [
+            SerialPortConfig {
+                path: "/dev/ttyUSB0".to_string(),
+                baud_rate: 115_200,
+                heartbeat: false,
+            },
+            SerialPortConfig {
+                path: "/dev/ttyAMA0".to_string(),
+                baud_rate: 921_600,
+                heartbeat: true,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_config_rejects_invalid_values() {
+    assert!(
+        ServerConfig::parse("max_conections = 3").is_err(),
+        "Misspelled keys should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("max_connections = 0").is_err(),
+        "A zero connection limit should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("max_pipelined_requests = 0").is_err(),
+        "A zero pipelining limit should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("max_batch_concurrency = 0").is_err(),
+        "Batches need at least one request running"
+    );
+    assert!(
+        ServerConfig::parse("heartbeat_timeout_ms = 0").is_err(),
+        "Heartbeats need time to be answered"
+    );
+    assert!(
+        ServerConfig::parse("heartbeat_interval_ms = 0\nheartbeat_timeout_ms = 0")
+            .unwrap()
+            .heartbeat()
+            .is_none(),
+        "A zero interval turns heartbeats off"
+    );
+    assert!(
+        ServerConfig::parse("compression = [\"gzip\"]").is_err(),
+        "Unknown compression algorithms should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("[[serial_ports]]\npath = \"/dev/ttyS0\"\nbaud_rate = 0").is_err(),
+        "A zero baud rate should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("subscriber_queue_len = 0").is_err(),
+        "Subscribers need room for at least one message"
+    );
+    assert!(
+        ServerConfig::parse("slow_subscriber = \"ignore\"").is_err(),
+        "Unknown slow subscriber policies should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("wal_sync = \"sometimes\"").is_err(),
+        "Unknown sync policies should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("wal_sync_interval_ms = 0").is_err(),
+        "Syncing on an interval needs one"
+    );
+    assert!(
+        ServerConfig::parse("wal_sync = \"never\"\nwal_sync_interval_ms = 0").is_ok(),
+        "The interval doesn't matter without interval syncing"
+    );
+    assert!(
+        ServerConfig::parse("snapshot_interval_ms = 0").is_err(),
+        "A zero snapshot interval should be rejected"
+    );
+    assert!(
+        ServerConfig::parse("log_level = \"loud\"").is_err(),
+        "Unknown log levels should be rejected"
+    );
+}
@@ -0,0 +1,158 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{encode_frame, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::message::{
+    client_message, error, server_message, AddResponse, ClientMessage,
+};
+use embedded_recruitment_task::router::{MessageKind, Router};
+use embedded_recruitment_task::test_util::{add, echo, TestServer};
+use prost::Message;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// Echo takes `delay` to answer; Add answers straight away and counts its calls.
+fn router(delay: Duration, add_calls: Arc<AtomicUsize>) -> Router {
+    Router::default()
+        .route(MessageKind::Echo, move |_, message| async move {
+            tokio::time::sleep(delay).await;
+            match message {
+                client_message::Message::EchoMessage(echo) => {
+                    Some(server_message::Message::EchoMessage(echo))
+                }
+                _ => None,
+            }
+        })
+        .route(MessageKind::Add, move |_, message| {
+            add_calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let client_message::Message::AddRequest(add) = message else {
+                    return None;
+                };
+                Some(server_message::Message::AddResponse(AddResponse {
+                    result: add.a + add.b,
+                }))
+            }
+        })
+}
+
+fn is_deadline_exceeded(message: &server_message::Message) -> bool {
+    matches!(
+        message,
+        server_message::Message::Error(error) if error.code() == error::Code::DeadlineExceeded
+    )
+}
+
+#[tokio::test]
+async fn test_request_within_deadline_is_answered() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let response = client
+        .request_with_timeout(add(2, 3), Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::AddResponse(AddResponse { result: 5 })
+    );
+}
+
+#[tokio::test]
+async fn test_expired_request_is_skipped() {
+    let add_calls = Arc::new(AtomicUsize::new(0));
+    let server =
+        TestServer::with_router(router(Duration::from_millis(300), Arc::clone(&add_calls)));
+
+    // Both requests arrive together, but the second one waits behind the slow first one
+    // until its deadline has passed.
+    let mut frames = Vec::new();
+    for request in [
+        ClientMessage {
+            message: Some(echo("slow")),
+            ..ClientMessage::default()
+        },
+        ClientMessage {
+            message: Some(add(1, 1)),
+            deadline_ms: 100,
+            ..ClientMessage::default()
+        },
+    ] {
+        encode_frame(&request.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut frames).unwrap();
+    }
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    stream.write_all(&frames).await.unwrap();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    let first = client.receive().await.unwrap().message.unwrap();
+    assert!(matches!(first, server_message::Message::EchoMessage(_)));
+    let second = client.receive().await.unwrap().message.unwrap();
+    assert!(is_deadline_exceeded(&second), "{:?}", second);
+    assert_eq!(add_calls.load(Ordering::SeqCst), 0, "Add should never run");
+}
+
+#[tokio::test]
+async fn test_running_handler_is_cancelled_at_deadline() {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let router = Router::default().route(MessageKind::Echo, {
+        let cancelled = Arc::clone(&cancelled);
+        move |_, _| {
+            let cancelled = Arc::clone(&cancelled);
+            async move {
+                // Records whether the handler was dropped before finishing
+                struct OnDrop(Arc<AtomicBool>);
+                impl Drop for OnDrop {
+                    fn drop(&mut self) {
+                        self.0.store(true, Ordering::SeqCst);
+                    }
+                }
+                let guard = OnDrop(cancelled);
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                std::mem::forget(guard);
+                None
+            }
+        }
+    });
+    let server = TestServer::with_router(router);
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let start = Instant::now();
+    let error = client
+        .request_with_timeout(echo("slow"), Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(
+        cancelled.load(Ordering::SeqCst),
+        "Handler should be cancelled"
+    );
+
+    // The server answered for the deadline, so the connection is still in step
+    assert!(!client.is_desynchronized());
+    let response = client.request(add(1, 2)).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::AddResponse(AddResponse { result: 3 })
+    );
+}
+
+#[tokio::test]
+async fn test_client_gives_up_without_any_reply() {
+    // A handler that never replies, so only the client-side timeout can end the wait
+    let router = Router::default().route(MessageKind::Echo, |_, _| async { None });
+    let server = TestServer::with_router(router);
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    let error = client
+        .request_with_timeout(echo("ignored"), Duration::from_millis(50))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    assert!(client.is_desynchronized());
+
+    let error = client.request(add(1, 2)).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+}
@@ -0,0 +1,292 @@
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::files::{hash, FileTransfer};
+use embedded_recruitment_task::message::{client_message, error, server_message, UploadRequest};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{partial, TestServer};
+use std::fs;
+use std::io;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+
+// A server storing files in a fresh temporary directory, which is returned with it.
+fn file_server() -> (TestServer, TempDir) {
+    let root = TempDir::new().unwrap();
+    let server = TestServer::with_config(
+        ServerConfig {
+            storage_root: Some(root.path().to_path_buf()),
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    (server, root)
+}
+
+async fn transfer(server: &TestServer) -> FileTransfer {
+    FileTransfer::new(PipelinedClient::connect(server.addr()).await.unwrap())
+}
+
+// A log bundle that doesn't repeat on chunk boundaries.
+fn bundle(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+async fn sha256(data: &[u8]) -> Vec<u8> {
+    hash(data).await.unwrap().1
+}
+
+#[tokio::test]
+async fn test_upload_and_download_round_trip() {
+    let (server, root) = file_server();
+    let files = transfer(&server).await;
+    let local = TempDir::new().unwrap();
+
+    let data = bundle(3 * 1024 * 1024 + 17);
+    fs::write(local.path().join("bundle.tar"), &data).unwrap();
+    let status = files
+        .upload(local.path().join("bundle.tar"), "logs/device-1/bundle.tar")
+        .await
+        .unwrap();
+    assert!(status.exists);
+    assert_eq!(status.size, data.len() as u64);
+    assert_eq!(status.sha256, sha256(&data).await);
+    assert_eq!(
+        fs::read(root.path().join("logs/device-1/bundle.tar")).unwrap(),
+        data
+    );
+
+    let status = files.status("logs/device-1/bundle.tar").await.unwrap();
+    assert_eq!(status.size, data.len() as u64);
+    assert_eq!(status.uploaded, 0);
+
+    files
+        .download("logs/device-1/bundle.tar", local.path().join("copy.tar"))
+        .await
+        .unwrap();
+    assert_eq!(fs::read(local.path().join("copy.tar")).unwrap(), data);
+
+    let missing = files.status("nope.toml").await.unwrap();
+    assert!(!missing.exists);
+    let error = files
+        .download("nope.toml", local.path().join("nope.toml"))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn test_paths_outside_the_root_are_rejected() {
+    let (server, root) = file_server();
+    let files = transfer(&server).await;
+    let outside = TempDir::new().unwrap();
+    fs::write(outside.path().join("secret"), "hunter2").unwrap();
+    std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+    let local = outside.path().join("upload");
+    fs::write(&local, "payload").unwrap();
+    for path in [
+        "",
+        "../secret",
+        "a/../../secret",
+        "/etc/passwd",
+        "./config.toml",
+        "bundle.tar.part",
+        "escape/secret",
+        "escape/new",
+        "escape/logs/new",
+    ] {
+        let error = files.status(path).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", path);
+        let error = files.upload(&local, path).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", path);
+        let error = files
+            .download(path, outside.path().join("download"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", path);
+    }
+
+    assert_eq!(
+        fs::read_to_string(outside.path().join("secret")).unwrap(),
+        "hunter2"
+    );
+    assert!(!outside.path().join("new").exists());
+    assert!(!outside.path().join("logs").exists());
+
+    // Nor can a symlink in place of a partial file redirect an upload
+    let target = outside.path().join("target");
+    std::os::unix::fs::symlink(&target, root.path().join("planted.part")).unwrap();
+    files.upload(&local, "planted").await.unwrap_err();
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn test_one_upload_at_a_time_per_file() {
+    let (server, root) = file_server();
+    let data = bundle(300_000);
+    let local = TempDir::new().unwrap();
+    fs::write(local.path().join("bundle.tar"), &data).unwrap();
+
+    // An upload whose body is still arriving
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+    let request = client_message::Message::Upload(UploadRequest {
+        path: "bundle.tar".to_string(),
+        offset: 0,
+        sha256: sha256(&data).await,
+    });
+    let (mut writer, body) = tokio::io::duplex(64 * 1024);
+    let first = tokio::spawn(async move { client.upload(request, body).await });
+    writer.write_all(&data[..100_000]).await.unwrap();
+    let part = root.path().join("bundle.tar.part");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while fs::metadata(&part).map_or(0, |metadata| metadata.len()) < 100_000 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The first upload never started");
+
+    let files = transfer(&server).await;
+    let error = files
+        .upload(local.path().join("bundle.tar"), "bundle.tar")
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+
+    writer.write_all(&data[100_000..]).await.unwrap();
+    drop(writer);
+    let response = first.await.unwrap().unwrap();
+    assert!(matches!(response, server_message::Message::FileStatus(_)));
+    assert_eq!(fs::read(root.path().join("bundle.tar")).unwrap(), data);
+
+    // Once it is done, the file can be uploaded again
+    files
+        .upload(local.path().join("bundle.tar"), "bundle.tar")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_resumes_from_partial_file() {
+    let (server, root) = file_server();
+    let files = transfer(&server).await;
+    let local = TempDir::new().unwrap();
+    let data = bundle(1024 * 1024);
+    fs::write(local.path().join("bundle.tar"), &data).unwrap();
+
+    // What arrived before a disconnect
+    fs::write(root.path().join("bundle.tar.part"), &data[..400_000]).unwrap();
+    assert_eq!(files.status("bundle.tar").await.unwrap().uploaded, 400_000);
+
+    files
+        .upload(local.path().join("bundle.tar"), "bundle.tar")
+        .await
+        .unwrap();
+    assert_eq!(fs::read(root.path().join("bundle.tar")).unwrap(), data);
+    assert!(!root.path().join("bundle.tar.part").exists());
+
+    // A partial file of something else fails the checksum, and the upload starts over
+    fs::write(root.path().join("other.tar.part"), vec![0xff; 1000]).unwrap();
+    files
+        .upload(local.path().join("bundle.tar"), "other.tar")
+        .await
+        .unwrap();
+    assert_eq!(fs::read(root.path().join("other.tar")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_upload_after_disconnect() {
+    let (server, root) = file_server();
+    let local = TempDir::new().unwrap();
+    let data = bundle(2 * 1024 * 1024);
+    fs::write(local.path().join("bundle.tar"), &data).unwrap();
+
+    // The upload is cut off part way through the body, which cancels it on the server
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+    let request = client_message::Message::Upload(UploadRequest {
+        path: "bundle.tar".to_string(),
+        offset: 0,
+        sha256: sha256(&data).await,
+    });
+    let body = FailAfter {
+        data: &data[..],
+        left: 500_000,
+    };
+    client.upload(request, body).await.unwrap_err();
+    drop(client);
+
+    let files = transfer(&server).await;
+    files
+        .upload(local.path().join("bundle.tar"), "bundle.tar")
+        .await
+        .unwrap();
+    assert_eq!(fs::read(root.path().join("bundle.tar")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_checksum_mismatch_is_rejected() {
+    let (server, root) = file_server();
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let request = client_message::Message::Upload(UploadRequest {
+        path: "config.toml".to_string(),
+        offset: 0,
+        sha256: sha256(b"something else").await,
+    });
+    let response = client
+        .upload(request, &b"debug = true\n"[..])
+        .await
+        .unwrap();
+    let server_message::Message::Error(error) = response else {
+        panic!("Expected an error, got {:?}", response);
+    };
+    assert_eq!(error.code(), error::Code::DataLoss);
+    assert!(!root.path().join("config.toml").exists());
+    assert!(!root.path().join("config.toml.part").exists());
+}
+
+#[tokio::test]
+async fn test_download_resumes_from_partial_file() {
+    let (server, root) = file_server();
+    let files = transfer(&server).await;
+    let local = TempDir::new().unwrap();
+    let data = bundle(700_000);
+    fs::write(root.path().join("firmware.bin"), &data).unwrap();
+
+    let copy = local.path().join("firmware.bin");
+    fs::write(partial(&copy), &data[..300_000]).unwrap();
+    files.download("firmware.bin", &copy).await.unwrap();
+    assert_eq!(fs::read(&copy).unwrap(), data);
+    assert!(!partial(&copy).exists());
+
+    // A stale partial file is thrown away rather than completing a corrupt copy
+    fs::write(partial(&copy), vec![0; 300_000]).unwrap();
+    let error = files.download("firmware.bin", &copy).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    files.download("firmware.bin", &copy).await.unwrap();
+    assert_eq!(fs::read(&copy).unwrap(), data);
+}
+
+// A body that fails after `left` bytes, like a device losing its log file mid-upload.
+struct FailAfter<'a> {
+    data: &'a [u8],
+    left: usize,
+}
+
+impl tokio::io::AsyncRead for FailAfter<'_> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        if self.left == 0 {
+            return std::task::Poll::Ready(Err(io::Error::other("Disconnected")));
+        }
+        let n = buf.remaining().min(self.left).min(self.data.len());
+        buf.put_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        self.left -= n;
+        std::task::Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,457 @@
+// The checked-in seed corpora under `fuzz/seeds` are generated from the messages below.
+// After adding a message type, add seeds for it here and regenerate the files with
+//
+//     UPDATE_FUZZ_SEEDS=1 cargo test --test fuzz_seeds
+
+use embedded_recruitment_task::codec::{encode_frame, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::message::{
+    batch_request, client_message, reading, AddRequest, BatchRequest, Cancel, Chunk, ClientMessage,
+    Compression, DeviceQuery, DownloadRequest, EchoMessage, FileStatusRequest, FirmwareDownload,
+    FirmwareQuery, Hello, InstallReport, InstallStatus, JoinRoom, KvCompareAndSwap, KvDelete,
+    KvGet, KvList, KvSet, KvUnwatch, KvWatch, LeaveRoom, Ping, Pong, PostToRoom, Publish, Reading,
+    RegisterDevice, StreamCredit, Subscribe, TelemetryBatch, TelemetryQuery, Unsubscribe,
+    UploadRequest,
+};
+use embedded_recruitment_task::router::MessageKind;
+use prost::Message;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn seed_messages() -> Vec<(&'static str, ClientMessage)> {
+    let message = |message| ClientMessage {
+        message: Some(message),
+        ..ClientMessage::default()
+    };
+    let echo = |content: &str| {
+        message(client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        }))
+    };
+    let add = |a, b| message(client_message::Message::AddRequest(AddRequest { a, b }));
+
+    vec![
+        ("empty", ClientMessage::default()),
+        ("echo_empty", echo("")),
+        ("echo_hello", echo("Hello, World!")),
+        ("echo_unicode", echo("héllo wörld ✓")),
+        ("echo_large", echo(&"0123456789".repeat(100))),
+        ("add", add(10, 20)),
+        ("add_negative", add(-7, 3)),
+        ("add_overflow", add(i32::MAX, 1)),
+        (
+            "echo_deadline",
+            ClientMessage {
+                deadline_ms: 250,
+                ..echo("Hello, World!")
+            },
+        ),
+        (
+            "add_request_id",
+            ClientMessage {
+                request_id: 1,
+                ..add(1, 2)
+            },
+        ),
+        (
+            "cancel",
+            message(client_message::Message::Cancel(Cancel { request_id: 1 })),
+        ),
+        (
+            "ping",
+            message(client_message::Message::Ping(Ping {
+                timestamp_us: 1_700_000_000_000_000,
+            })),
+        ),
+        (
+            "hello",
+            message(client_message::Message::Hello(Hello {
+                compression: vec![Compression::Zstd.into(), Compression::Lz4.into()],
+                checksum: true,
+            })),
+        ),
+        (
+            "pong",
+            message(client_message::Message::Pong(Pong {
+                timestamp_us: 1_700_000_000_000_000,
+            })),
+        ),
+        (
+            "echo_streaming",
+            ClientMessage {
+                request_id: 2,
+                streaming: true,
+                ..echo("")
+            },
+        ),
+        (
+            "chunk",
+            ClientMessage {
+                request_id: 2,
+                ..message(client_message::Message::Chunk(Chunk {
+                    seq: 0,
+                    data: b"0123456789".repeat(10),
+                    last: false,
+                }))
+            },
+        ),
+        (
+            "chunk_last",
+            ClientMessage {
+                request_id: 2,
+                ..message(client_message::Message::Chunk(Chunk {
+                    seq: 1,
+                    data: Vec::new(),
+                    last: true,
+                }))
+            },
+        ),
+        (
+            "stream_credit",
+            ClientMessage {
+                request_id: 2,
+                ..message(client_message::Message::StreamCredit(StreamCredit {
+                    chunks: 4,
+                }))
+            },
+        ),
+        (
+            "file_status",
+            message(client_message::Message::FileStatus(FileStatusRequest {
+                path: "logs/bundle.tar".to_string(),
+            })),
+        ),
+        (
+            "upload",
+            ClientMessage {
+                request_id: 3,
+                streaming: true,
+                ..message(client_message::Message::Upload(UploadRequest {
+                    path: "logs/bundle.tar".to_string(),
+                    offset: 4096,
+                    sha256: vec![0xab; 32],
+                }))
+            },
+        ),
+        (
+            "download_traversal",
+            ClientMessage {
+                request_id: 4,
+                ..message(client_message::Message::Download(DownloadRequest {
+                    path: "../../etc/passwd".to_string(),
+                    offset: 0,
+                }))
+            },
+        ),
+        (
+            "firmware_query",
+            message(client_message::Message::FirmwareQuery(FirmwareQuery {
+                model: "sensor-v2".to_string(),
+                current_version: "1.9.0".to_string(),
+            })),
+        ),
+        (
+            "firmware_download",
+            ClientMessage {
+                request_id: 5,
+                ..message(client_message::Message::FirmwareDownload(
+                    FirmwareDownload {
+                        model: "sensor-v2".to_string(),
+                        version: "1.10.0".to_string(),
+                        offset: 65536,
+                    },
+                ))
+            },
+        ),
+        (
+            "install_report",
+            message(client_message::Message::InstallReport(InstallReport {
+                device_id: "device-17".to_string(),
+                model: "sensor-v2".to_string(),
+                version: "1.10.0".to_string(),
+                status: InstallStatus::Failed.into(),
+                detail: "Flash write failed".to_string(),
+            })),
+        ),
+        (
+            "telemetry_batch",
+            message(client_message::Message::TelemetryBatch(TelemetryBatch {
+                device_id: "device-17".to_string(),
+                readings: vec![
+                    Reading {
+                        sensor: "temperature".to_string(),
+                        timestamp_ms: 1_700_000_000_000,
+                        value: Some(reading::Value::Number(21.5)),
+                        unit: "degC".to_string(),
+                    },
+                    Reading {
+                        sensor: "door_open".to_string(),
+                        timestamp_ms: 1_700_000_000_000,
+                        value: Some(reading::Value::Flag(true)),
+                        unit: String::new(),
+                    },
+                    Reading {
+                        sensor: "status".to_string(),
+                        timestamp_ms: 1_700_000_001_000,
+                        value: Some(reading::Value::Text("ok".to_string())),
+                        unit: String::new(),
+                    },
+                ],
+            })),
+        ),
+        (
+            "telemetry_query",
+            message(client_message::Message::TelemetryQuery(TelemetryQuery {
+                device_id: "device-17".to_string(),
+                sensor: "temperature".to_string(),
+                start_ms: 1_700_000_000_000,
+                end_ms: 1_700_000_600_000,
+                interval_ms: 60_000,
+                limit: 10,
+            })),
+        ),
+        (
+            "register_device",
+            message(client_message::Message::RegisterDevice(RegisterDevice {
+                device_id: "device-17".to_string(),
+                model: "sensor-v2".to_string(),
+                firmware_version: "1.10.0".to_string(),
+            })),
+        ),
+        (
+            "device_query",
+            message(client_message::Message::DeviceQuery(DeviceQuery {
+                device_id: String::new(),
+                online_only: true,
+                after: "device-16".to_string(),
+            })),
+        ),
+        (
+            "subscribe",
+            message(client_message::Message::Subscribe(Subscribe {
+                pattern: "sensors/+/temperature".to_string(),
+            })),
+        ),
+        (
+            "unsubscribe",
+            message(client_message::Message::Unsubscribe(Unsubscribe {
+                pattern: "alerts/#".to_string(),
+            })),
+        ),
+        (
+            "publish",
+            message(client_message::Message::Publish(Publish {
+                topic: "sensors/device-17/temperature".to_string(),
+                payload: b"21.5".to_vec(),
+            })),
+        ),
+        (
+            "join_room",
+            message(client_message::Message::JoinRoom(JoinRoom {
+                room: "ops".to_string(),
+                name: "console-2".to_string(),
+            })),
+        ),
+        (
+            "leave_room",
+            message(client_message::Message::LeaveRoom(LeaveRoom {
+                room: "ops".to_string(),
+            })),
+        ),
+        (
+            "post_to_room",
+            message(client_message::Message::PostToRoom(PostToRoom {
+                room: "ops".to_string(),
+                text: "Restarting pump-1".to_string(),
+            })),
+        ),
+        (
+            "kv_get",
+            message(client_message::Message::KvGet(KvGet {
+                key: "pump-1/mode".to_string(),
+            })),
+        ),
+        (
+            "kv_set",
+            message(client_message::Message::KvSet(KvSet {
+                key: "leases/pump-1".to_string(),
+                value: b"console-2".to_vec(),
+                ttl_ms: 30_000,
+            })),
+        ),
+        (
+            "kv_delete",
+            message(client_message::Message::KvDelete(KvDelete {
+                key: "pump-1/mode".to_string(),
+            })),
+        ),
+        (
+            "kv_compare_and_swap",
+            message(client_message::Message::KvCompareAndSwap(
+                KvCompareAndSwap {
+                    key: "pump-1/mode".to_string(),
+                    version: 7,
+                    value: b"manual".to_vec(),
+                    ttl_ms: 0,
+                },
+            )),
+        ),
+        (
+            "kv_list",
+            message(client_message::Message::KvList(KvList {
+                prefix: "sensors/".to_string(),
+                after: "sensors/0009".to_string(),
+                limit: 100,
+            })),
+        ),
+        (
+            "kv_watch",
+            message(client_message::Message::KvWatch(KvWatch {
+                key: "config/".to_string(),
+                prefix: true,
+            })),
+        ),
+        (
+            "kv_unwatch",
+            message(client_message::Message::KvUnwatch(KvUnwatch {
+                key: "config/".to_string(),
+                prefix: true,
+            })),
+        ),
+        (
+            "batch_request",
+            message(client_message::Message::BatchRequest(BatchRequest {
+                requests: vec![
+                    ClientMessage {
+                        request_id: 1,
+                        ..echo("pump-1")
+                    },
+                    ClientMessage {
+                        request_id: 2,
+                        ..add(2, 3)
+                    },
+                ],
+                mode: batch_request::Mode::ContinueOnError as i32,
+                sequential: false,
+            })),
+        ),
+        (
+            "batch_request_fail_fast",
+            message(client_message::Message::BatchRequest(BatchRequest {
+                requests: vec![
+                    add(1, 1),
+                    message(client_message::Message::Ping(Ping::default())),
+                    echo("never run"),
+                ],
+                mode: batch_request::Mode::FailFast as i32,
+                sequential: true,
+            })),
+        ),
+    ]
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_frame(payload, DEFAULT_MAX_FRAME_LEN, &mut out).unwrap();
+    out
+}
+
+// Expected seed files per fuzz target.
+fn seeds() -> BTreeMap<&'static str, BTreeMap<String, Vec<u8>>> {
+    let messages = seed_messages();
+    let mut client_message = BTreeMap::new();
+    let mut dispatch = BTreeMap::new();
+    let mut frame_decoder = BTreeMap::new();
+
+    for (name, message) in &messages {
+        let encoded = message.encode_to_vec();
+        dispatch.insert(name.to_string(), frame(&encoded));
+        client_message.insert(name.to_string(), encoded);
+    }
+
+    // Every message pipelined on one connection
+    let pipelined: Vec<u8> = messages
+        .iter()
+        .flat_map(|(_, message)| frame(&message.encode_to_vec()))
+        .collect();
+    dispatch.insert("pipelined".to_string(), pipelined.clone());
+
+    // The first byte of a frame_decoder input is the read size
+    frame_decoder.insert(
+        "pipelined_1_byte_reads".to_string(),
+        [&[1], &pipelined[..]].concat(),
+    );
+    frame_decoder.insert(
+        "pipelined_7_byte_reads".to_string(),
+        [&[7], &pipelined[..]].concat(),
+    );
+    frame_decoder.insert(
+        "truncated".to_string(),
+        [&[16], &pipelined[..pipelined.len() - 3]].concat(),
+    );
+    frame_decoder.insert(
+        "oversized_header".to_string(),
+        [&[4], &u32::MAX.to_be_bytes()[..]].concat(),
+    );
+
+    BTreeMap::from([
+        ("client_message", client_message),
+        ("connection", dispatch.clone()),
+        ("dispatch", dispatch),
+        ("frame_decoder", frame_decoder),
+    ])
+}
+
+fn seeds_dir(target: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/seeds")
+        .join(target)
+}
+
+fn read_dir(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .map(|entry| entry.unwrap())
+                .map(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    (name, fs::read(entry.path()).unwrap())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_fuzz_seeds_are_up_to_date() {
+    let update = std::env::var_os("UPDATE_FUZZ_SEEDS").is_some();
+
+    for (target, expected) in seeds() {
+        let dir = seeds_dir(target);
+        if update {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            for (name, bytes) in &expected {
+                fs::write(dir.join(name), bytes).unwrap();
+            }
+        }
+
+        assert!(
+            read_dir(&dir) == expected,
+            "Seeds in {} are stale; regenerate them with UPDATE_FUZZ_SEEDS=1 cargo test --test fuzz_seeds",
+            dir.display()
+        );
+    }
+}
+
+#[test]
+fn test_fuzz_seeds_cover_every_message_type() {
+    let covered: HashSet<MessageKind> = seed_messages()
+        .iter()
+        .filter_map(|(_, message)| message.message.as_ref().map(MessageKind::of))
+        .collect();
+
+    for kind in MessageKind::ALL {
+        assert!(covered.contains(&kind), "No fuzz seed for {:?}", kind);
+    }
+}
@@ -0,0 +1,107 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::heartbeat::RoundTripTime;
+use embedded_recruitment_task::message::{
+    client_message, server_message, AddResponse, EchoMessage,
+};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::{MessageKind, Router};
+use embedded_recruitment_task::test_util::{add, TestServer};
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+// Ping connections quiet for 100ms and close them 100ms later.
+fn heartbeat_config() -> ServerConfig {
+    ServerConfig {
+        heartbeat_interval_ms: 100,
+        heartbeat_timeout_ms: 100,
+        ..ServerConfig::default()
+    }
+}
+
+#[test]
+fn test_round_trip_time_statistics() {
+    let mut rtt = RoundTripTime::default();
+    assert_eq!(rtt.latest, None);
+
+    rtt.record(Duration::from_millis(8));
+    rtt.record(Duration::from_millis(16));
+    rtt.record(Duration::from_millis(4));
+    assert_eq!(rtt.latest, Some(Duration::from_millis(4)));
+    assert_eq!(rtt.min, Some(Duration::from_millis(4)));
+    // 8 -> (7*8 + 16)/8 = 9 -> (7*9 + 4)/8 = 8.375
+    assert_eq!(rtt.smoothed, Some(Duration::from_micros(8375)));
+}
+
+#[tokio::test]
+async fn test_ping_measures_round_trip_time() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    for _ in 0..3 {
+        let rtt = client.ping().await.unwrap();
+        assert!(rtt < Duration::from_secs(1));
+    }
+    let rtt = client.rtt();
+    assert!(rtt.latest.is_some());
+    assert!(rtt.min <= rtt.latest);
+
+    // Pings don't get in the way of requests
+    let response = client.request(add(1, 2)).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::AddResponse(AddResponse { result: 3 })
+    );
+
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+    client.ping().await.unwrap();
+    assert!(client.rtt().latest.is_some());
+}
+
+#[tokio::test]
+async fn test_silent_connection_is_closed() {
+    let server = TestServer::with_config(heartbeat_config(), Router::default());
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+
+    // The server pings, then gives up on a peer that never answers
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let result = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+        .await
+        .expect("Connection should be closed");
+    assert!(result.is_ok() || result.unwrap_err().kind() == io::ErrorKind::ConnectionReset);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(!received.is_empty(), "Server should ping before closing");
+}
+
+#[tokio::test]
+async fn test_clients_answer_heartbeats() {
+    let router = Router::default().route(MessageKind::Echo, |_, message| async move {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        match message {
+            client_message::Message::EchoMessage(echo) => {
+                Some(server_message::Message::EchoMessage(echo))
+            }
+            _ => None,
+        }
+    });
+    let server = TestServer::with_config(heartbeat_config(), router);
+
+    // A client waiting on a slow request answers the pings meanwhile
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "slow".to_string(),
+    });
+    client.request(echo).await.unwrap();
+
+    // An idle pipelined client answers them in the background
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response = client.request(add(2, 2)).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::AddResponse(AddResponse { result: 4 })
+    );
+}
@@ -0,0 +1,323 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::connections::Connections;
+use embedded_recruitment_task::kv::{
+    KvStore, MAX_ENTRIES_PER_LIST, MAX_KEYS, MAX_WATCHES_PER_CONNECTION,
+};
+use embedded_recruitment_task::message::{kv_change, KvChange, KvSet};
+use embedded_recruitment_task::test_util::TestServer;
+use std::io;
+use std::time::Duration;
+use tokio::time;
+
+async fn next_change(client: &mut Client) -> KvChange {
+    time::timeout(Duration::from_secs(5), client.next_kv_change())
+        .await
+        .expect("No key change")
+        .unwrap()
+}
+
+// The kind of a change, and the key and value it is about.
+fn summary(change: &KvChange) -> (kv_change::Kind, String, Vec<u8>) {
+    let entry = change.entry.clone().unwrap();
+    (change.kind(), entry.key, entry.value)
+}
+
+#[tokio::test]
+async fn test_keys_can_be_set_swapped_and_deleted() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+
+    assert_eq!(client.kv_get("pump-1/mode").await.unwrap(), None);
+    let set = client
+        .kv_set("pump-1/mode", b"auto".to_vec(), None)
+        .await
+        .unwrap();
+    assert_eq!(set.expires_ms, 0);
+    assert_eq!(
+        client.kv_get("pump-1/mode").await.unwrap(),
+        Some(set.clone())
+    );
+
+    // Versions only go up, and a stale one loses the swap
+    let swapped = client
+        .kv_compare_and_swap("pump-1/mode", set.version, b"manual".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(swapped.swapped);
+    let current = swapped.current.unwrap();
+    assert!(current.version > set.version);
+    assert_eq!(current.value, b"manual");
+    let swapped = client
+        .kv_compare_and_swap("pump-1/mode", set.version, b"off".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(!swapped.swapped);
+    assert_eq!(swapped.current, Some(current));
+
+    // Version 0 creates a key that doesn't exist yet
+    let swapped = client
+        .kv_compare_and_swap("pump-2/mode", 0, b"auto".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(swapped.swapped);
+    let swapped = client
+        .kv_compare_and_swap("pump-2/mode", 0, b"off".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(!swapped.swapped);
+    assert_eq!(swapped.current.unwrap().value, b"auto");
+
+    assert!(client.kv_delete("pump-1/mode").await.unwrap());
+    assert!(!client.kv_delete("pump-1/mode").await.unwrap());
+    assert_eq!(client.kv_get("pump-1/mode").await.unwrap(), None);
+
+    let error = client.kv_set("", Vec::new(), None).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_keys_are_listed_by_prefix_in_pages() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    let keys = MAX_ENTRIES_PER_LIST + 10;
+    for i in 0..keys {
+        let key = format!("sensors/{:04}", i);
+        client.kv_set(&key, Vec::new(), None).await.unwrap();
+    }
+    client.kv_set("sensorsx", Vec::new(), None).await.unwrap();
+    client.kv_set("pumps/1", Vec::new(), None).await.unwrap();
+
+    let first = client.kv_list("sensors/", "").await.unwrap();
+    assert_eq!(first.entries.len(), MAX_ENTRIES_PER_LIST);
+    assert!(first.truncated);
+    let last_key = &first.entries.last().unwrap().key;
+    let rest = client.kv_list("sensors/", last_key).await.unwrap();
+    assert!(!rest.truncated);
+    let listed: Vec<_> = first
+        .entries
+        .iter()
+        .chain(&rest.entries)
+        .map(|entry| entry.key.clone())
+        .collect();
+    let expected: Vec<_> = (0..keys).map(|i| format!("sensors/{:04}", i)).collect();
+    assert_eq!(listed, expected);
+
+    let pumps = client.kv_list("pumps/", "").await.unwrap();
+    assert_eq!(pumps.entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_keys_expire_after_their_ttl() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    client.kv_watch("leases/", true).await.unwrap();
+
+    let lease = client
+        .kv_set(
+            "leases/pump-1",
+            b"console-3".to_vec(),
+            Some(Duration::from_millis(200)),
+        )
+        .await
+        .unwrap();
+    assert!(lease.expires_ms > 0);
+    client
+        .kv_set("leases/pump-2", b"console-4".to_vec(), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        summary(&next_change(&mut client).await).0,
+        kv_change::Kind::Set
+    );
+    assert_eq!(
+        summary(&next_change(&mut client).await).0,
+        kv_change::Kind::Set
+    );
+
+    // Watchers hear about it without anyone touching the key
+    let expired = next_change(&mut client).await;
+    assert_eq!(
+        summary(&expired),
+        (
+            kv_change::Kind::Expired,
+            "leases/pump-1".to_string(),
+            b"console-3".to_vec()
+        )
+    );
+    assert_eq!(client.kv_get("leases/pump-1").await.unwrap(), None);
+    let leases = client.kv_list("leases/", "").await.unwrap();
+    assert_eq!(leases.entries.len(), 1);
+
+    // Setting a key again replaces its TTL
+    client
+        .kv_set(
+            "leases/pump-1",
+            b"console-3".to_vec(),
+            Some(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+    client
+        .kv_set("leases/pump-1", b"console-3".to_vec(), None)
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(client.kv_get("leases/pump-1").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_watchers_are_told_about_changes() {
+    let server = TestServer::start();
+    let mut writer = Client::connect(server.addr()).await.unwrap();
+    let mut one_key = Client::connect(server.addr()).await.unwrap();
+    let mut prefix = Client::connect(server.addr()).await.unwrap();
+    one_key.kv_watch("config/interval", false).await.unwrap();
+    prefix.kv_watch("config/", true).await.unwrap();
+    // Overlapping watches still tell it once
+    prefix.kv_watch("config/interval", false).await.unwrap();
+
+    writer
+        .kv_set("config/interval", b"10".to_vec(), None)
+        .await
+        .unwrap();
+    writer
+        .kv_set("config/threshold", b"42".to_vec(), None)
+        .await
+        .unwrap();
+    writer.kv_delete("config/interval").await.unwrap();
+
+    let set = (
+        kv_change::Kind::Set,
+        "config/interval".to_string(),
+        b"10".to_vec(),
+    );
+    let deleted = (
+        kv_change::Kind::Deleted,
+        "config/interval".to_string(),
+        b"10".to_vec(),
+    );
+    assert_eq!(summary(&next_change(&mut one_key).await), set);
+    assert_eq!(summary(&next_change(&mut one_key).await), deleted);
+    assert_eq!(summary(&next_change(&mut prefix).await), set);
+    assert_eq!(
+        summary(&next_change(&mut prefix).await),
+        (
+            kv_change::Kind::Set,
+            "config/threshold".to_string(),
+            b"42".to_vec()
+        )
+    );
+    assert_eq!(summary(&next_change(&mut prefix).await), deleted);
+
+    // Once unwatched, nothing more arrives
+    one_key.kv_unwatch("config/interval", false).await.unwrap();
+    let error = one_key
+        .kv_unwatch("config/interval", false)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    writer
+        .kv_set("config/interval", b"20".to_vec(), None)
+        .await
+        .unwrap();
+    writer.kv_set("marker", Vec::new(), None).await.unwrap();
+    one_key.kv_watch("marker", false).await.unwrap();
+    writer.kv_delete("marker").await.unwrap();
+    assert_eq!(
+        summary(&next_change(&mut one_key).await).1,
+        "marker".to_string()
+    );
+
+    let error = one_key.kv_watch("", false).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_new_keys_are_refused_once_the_store_is_full() {
+    let store = KvStore::new(Connections::default());
+    let set = |key: String, value: &[u8]| {
+        store.set(KvSet {
+            key,
+            value: value.to_vec(),
+            ttl_ms: 0,
+        })
+    };
+    for i in 0..MAX_KEYS {
+        set(format!("key-{}", i), b"1").unwrap();
+    }
+
+    let error = set("one-more".to_string(), b"1").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+    assert_eq!(store.get("one-more"), None);
+    // Keys it already holds can still change, and deleting one makes room
+    set("key-0".to_string(), b"2").unwrap();
+    assert!(store.delete("key-1").unwrap());
+    set("one-more".to_string(), b"1").unwrap();
+}
+
+#[tokio::test]
+async fn test_watches_are_refused_past_the_cap() {
+    let server = TestServer::start();
+    let mut writer = Client::connect(server.addr()).await.unwrap();
+    let mut watcher = Client::connect(server.addr()).await.unwrap();
+    let mut other = Client::connect(server.addr()).await.unwrap();
+    for i in 0..MAX_WATCHES_PER_CONNECTION {
+        watcher
+            .kv_watch(&format!("sensor-{}/", i), true)
+            .await
+            .unwrap();
+    }
+
+    let error = watcher.kv_watch("pump-1/mode", false).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+    // Repeating a watch it has is not a new one
+    watcher.kv_watch("sensor-0/", true).await.unwrap();
+    // The cap is per connection
+    other.kv_watch("pump-1/mode", false).await.unwrap();
+    // Dropping a watch makes room
+    watcher.kv_unwatch("sensor-0/", true).await.unwrap();
+    watcher.kv_watch("pump-1/mode", false).await.unwrap();
+
+    writer
+        .kv_set("pump-1/mode", b"auto".to_vec(), None)
+        .await
+        .unwrap();
+    for client in [&mut watcher, &mut other] {
+        assert_eq!(
+            summary(&next_change(client).await).1,
+            "pump-1/mode".to_string()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_watchers_that_fall_behind_are_disconnected() {
+    let server = TestServer::start();
+    let mut writer = Client::connect(server.addr()).await.unwrap();
+    let mut watcher = Client::connect(server.addr()).await.unwrap();
+    watcher.kv_watch("log/", true).await.unwrap();
+
+    // The watcher reads nothing while far more changes than fit in its queue and the
+    // socket's buffers are made
+    let value = vec![7; 16 * 1024];
+    const CHANGES: usize = 2000;
+    for i in 0..CHANGES {
+        writer
+            .kv_set(&format!("log/{}", i % 10), value.clone(), None)
+            .await
+            .unwrap();
+    }
+
+    // It gets the changes that were queued, then the connection closes
+    let mut received = 0;
+    while time::timeout(Duration::from_secs(5), watcher.next_kv_change())
+        .await
+        .expect("Connection still open")
+        .is_ok()
+    {
+        received += 1;
+    }
+    assert!(received < CHANGES, "Received all {} changes", received);
+    // The writer carries on
+    writer.kv_set("log/0", b"ok".to_vec(), None).await.unwrap();
+}
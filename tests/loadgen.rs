@@ -0,0 +1,140 @@
+use embedded_recruitment_task::test_util::TestServer;
+use std::process::Command;
+use tempfile::tempdir;
+
+fn loadgen() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_LoadGen"))
+}
+
+#[test]
+fn test_closed_loop_report() {
+    let server = TestServer::start();
+    let dir = tempdir().expect("Failed to create temp dir");
+    let json_path = dir.path().join("report.json");
+    let csv_path = dir.path().join("report.csv");
+
+    let output = loadgen()
+        .args(["--addr", &server.addr().to_string()])
+        .args(["--connections", "4", "--duration", "500ms"])
+        .args(["--mix", "echo=3,add=1", "--payload-size", "8-128"])
+        .arg("--json")
+        .arg(&json_path)
+        .arg("--csv")
+        .arg(&csv_path)
+        .output()
+        .expect("Failed to run LoadGen");
+    assert!(output.status.success(), "LoadGen failed: {:?}", output);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Throughput:"),
+        "Missing summary: {}",
+        stdout
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert!(
+        report["requests"].as_u64().unwrap() > 0,
+        "No requests completed"
+    );
+    assert_eq!(report["errors"].as_u64(), Some(0));
+    for quantile in ["p50", "p90", "p99", "p999"] {
+        assert!(
+            report["latency_us"][quantile].is_u64(),
+            "Missing {}",
+            quantile
+        );
+    }
+    let operations: Vec<&str> = report["operations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|op| op["operation"].as_str().unwrap())
+        .collect();
+    assert_eq!(operations, ["echo", "add"]);
+
+    let csv = std::fs::read_to_string(&csv_path).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!(lines[0].starts_with("operation,requests,errors,throughput,p50_us"));
+    assert!(lines[1].starts_with("all,"));
+    assert_eq!(lines.len(), 4, "Expected header, all, echo and add rows");
+}
+
+#[test]
+fn test_open_loop_holds_target_rate() {
+    let server = TestServer::start();
+    let dir = tempdir().expect("Failed to create temp dir");
+    let json_path = dir.path().join("report.json");
+
+    let output = loadgen()
+        .args(["--addr", &server.addr().to_string()])
+        .args(["--connections", "2", "--rate", "200", "--duration", "1s"])
+        .arg("--json")
+        .arg(&json_path)
+        .output()
+        .expect("Failed to run LoadGen");
+    assert!(output.status.success(), "LoadGen failed: {:?}", output);
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert_eq!(report["target_rate"].as_f64(), Some(200.0));
+    let requests = report["requests"].as_u64().unwrap();
+    assert!(
+        (150..=210).contains(&requests),
+        "Expected about 200 requests, got {}",
+        requests
+    );
+}
+
+#[test]
+fn test_connection_errors_are_counted() {
+    // Grab a free port and close it again so nothing is listening there
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let dir = tempdir().expect("Failed to create temp dir");
+    let json_path = dir.path().join("report.json");
+
+    let output = loadgen()
+        .args(["--addr", &addr.to_string()])
+        .args(["--connections", "1", "--duration", "300ms"])
+        .arg("--json")
+        .arg(&json_path)
+        .output()
+        .expect("Failed to run LoadGen");
+    assert!(output.status.success(), "LoadGen failed: {:?}", output);
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    assert_eq!(report["requests"].as_u64(), Some(0));
+    assert!(report["error_counts"]["connect"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_rejects_invalid_mix() {
+    let output = loadgen()
+        .args(["--mix", "echo=1,divide=2"])
+        .output()
+        .expect("Failed to run LoadGen");
+    assert!(!output.status.success(), "Invalid mix should be rejected");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown operation"));
+}
+
+#[test]
+fn test_rejects_rates_it_cannot_send_at() {
+    for rate in ["0", "-5", "NaN", "inf", "1e-300"] {
+        let output = loadgen()
+            .arg(format!("--rate={}", rate))
+            .output()
+            .expect("Failed to run LoadGen");
+        assert_eq!(
+            output.status.code(),
+            Some(2),
+            "--rate {} was accepted",
+            rate
+        );
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--rate"));
+    }
+}
@@ -0,0 +1,249 @@
+use ed25519_dalek::SigningKey;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{InstallReport, InstallStatus};
+use embedded_recruitment_task::ota::{sign_image, FirmwareStore, FirmwareUpdater, Version};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{partial, TestServer};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use tempfile::TempDir;
+
+fn signing_key() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32])
+}
+
+fn image(version: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 253) as u8 ^ version).collect()
+}
+
+// Write `images` of (model, version, contents) and a manifest signing them with `key`.
+fn firmware_dir(key: &SigningKey, images: &[(&str, &str, &[u8])]) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let mut manifest = String::new();
+    for (model, version, contents) in images {
+        let file = format!("{}-{}.bin", model, version);
+        fs::write(dir.path().join(&file), contents).unwrap();
+        manifest += &format!(
+            "[[images]]\nmodel = \"{}\"\nversion = \"{}\"\nfile = \"{}\"\nsignature = \"{}\"\n\n",
+            model,
+            version,
+            file,
+            sign_image(key, model, version, contents)
+        );
+    }
+    fs::write(dir.path().join("manifest.toml"), manifest).unwrap();
+    dir
+}
+
+async fn updater(server: &TestServer) -> FirmwareUpdater {
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+    FirmwareUpdater::new(client, signing_key().verifying_key())
+}
+
+#[test]
+fn test_versions_compare_numerically() {
+    let version = |text| Version::parse(text).unwrap();
+    assert!(version("1.10.0") > version("1.9.0"));
+    assert!(version("2") > version("1.99.99"));
+    assert!(version("1.4.1") > version("1.4"));
+    assert_eq!(Version::parse("1.4-beta"), None);
+    assert_eq!(Version::parse(""), None);
+}
+
+#[tokio::test]
+async fn test_device_updates_to_newest_signed_image() {
+    let (old, new) = (image(1, 100_000), image(2, 300_000));
+    let dir = firmware_dir(
+        &signing_key(),
+        &[
+            ("sensor-v2", "1.9.0", &old),
+            ("sensor-v2", "1.10.0", &new),
+            ("gateway", "3.0.0", b"other"),
+        ],
+    );
+    let store = FirmwareStore::open(dir.path()).unwrap();
+    let server = TestServer::with_router(store.routes(Router::default()));
+    let updater = updater(&server).await;
+
+    let info = updater.check("sensor-v2", "1.9.0").await.unwrap().unwrap();
+    assert_eq!(info.version, "1.10.0");
+    assert_eq!(info.size, new.len() as u64);
+    assert_eq!(updater.check("sensor-v2", "1.10.0").await.unwrap(), None);
+    assert_eq!(updater.check("unknown-model", "").await.unwrap(), None);
+    assert_eq!(
+        updater
+            .check("sensor-v2", "")
+            .await
+            .unwrap()
+            .unwrap()
+            .version,
+        "1.10.0",
+        "A device without a readable version gets the newest image"
+    );
+
+    let local = TempDir::new().unwrap();
+    let path = local.path().join("update.bin");
+    updater
+        .download("sensor-v2", "1.0.0", &info, &path)
+        .await
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), new);
+
+    let report = InstallReport {
+        device_id: "device-17".to_string(),
+        model: "sensor-v2".to_string(),
+        version: info.version.clone(),
+        status: InstallStatus::Installed.into(),
+        detail: String::new(),
+    };
+    updater.report(report.clone()).await.unwrap();
+    assert_eq!(store.install_report("device-17"), Some(report));
+    assert_eq!(store.install_report("device-18"), None);
+}
+
+#[tokio::test]
+async fn test_download_resumes_after_interruption() {
+    let new = image(3, 500_000);
+    let dir = firmware_dir(&signing_key(), &[("sensor-v2", "2.0.0", &new)]);
+    let server = TestServer::with_config(
+        ServerConfig {
+            firmware_dir: Some(dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let updater = updater(&server).await;
+    let info = updater.check("sensor-v2", "1.0.0").await.unwrap().unwrap();
+
+    let local = TempDir::new().unwrap();
+    let path = local.path().join("update.bin");
+    fs::write(partial(&path), &new[..200_000]).unwrap();
+    updater
+        .download("sensor-v2", "1.0.0", &info, &path)
+        .await
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), new);
+    assert!(!partial(&path).exists());
+}
+
+#[tokio::test]
+async fn test_image_with_bad_signature_is_refused() {
+    let forger = SigningKey::from_bytes(&[9; 32]);
+    let dir = firmware_dir(&forger, &[("sensor-v2", "6.6.6", b"malware")]);
+    let store = FirmwareStore::open(dir.path()).unwrap();
+    let server = TestServer::with_router(store.routes(Router::default()));
+    let updater = updater(&server).await;
+
+    let error = updater.check("sensor-v2", "1.0.0").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // Nor will it be downloaded when asked directly
+    let mut info = store.latest("sensor-v2").unwrap();
+    let local = TempDir::new().unwrap();
+    let error = updater
+        .download("sensor-v2", "1.0.0", &info, local.path().join("update.bin"))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(!local.path().join("update.bin.part").exists());
+
+    // Genuine metadata doesn't vouch for whatever the server sends instead
+    let genuine = image(4, 1000);
+    info.sha256 = Sha256::digest(&genuine).to_vec();
+    let signature = sign_image(&signing_key(), "sensor-v2", "6.6.6", &genuine);
+    info.signature = hex::decode(signature).unwrap();
+    let error = updater
+        .download("sensor-v2", "1.0.0", &info, local.path().join("update.bin"))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(!local.path().join("update.bin").exists());
+}
+
+#[tokio::test]
+async fn test_relabelled_image_is_refused() {
+    let old = image(5, 1000);
+    let dir = firmware_dir(
+        &signing_key(),
+        &[
+            ("sensor-v2", "1.0.0", &old),
+            ("gateway", "2.0.0", b"gateway"),
+        ],
+    );
+    // Someone with access to the manifest, but not the key, passes the old image off as new
+    let manifest = fs::read_to_string(dir.path().join("manifest.toml")).unwrap();
+    let relabelled = manifest.replace("version = \"1.0.0\"", "version = \"9.0.0\"");
+    fs::write(dir.path().join("manifest.toml"), relabelled).unwrap();
+    let store = FirmwareStore::open(dir.path()).unwrap();
+    let server = TestServer::with_router(store.routes(Router::default()));
+    let updater = updater(&server).await;
+
+    let error = updater.check("sensor-v2", "1.5.0").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // Nor is one model's image accepted as another's
+    let local = TempDir::new().unwrap();
+    let gateway = store.latest("gateway").unwrap();
+    let error = updater
+        .download(
+            "sensor-v2",
+            "1.5.0",
+            &gateway,
+            local.path().join("update.bin"),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // And a genuine image older than the running one is refused
+    let error = updater
+        .download(
+            "gateway",
+            "2.1.0",
+            &gateway,
+            local.path().join("update.bin"),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    updater
+        .download(
+            "gateway",
+            "2.0.0",
+            &gateway,
+            local.path().join("update.bin"),
+        )
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_manifest_is_validated() {
+    let key = signing_key();
+    let dir = firmware_dir(&key, &[("sensor-v2", "1.0.0", b"image")]);
+    let signature = sign_image(&key, "sensor-v2", "1.0.0", b"image");
+    let entry = |version: &str, file: &str, signature: &str| {
+        format!(
+            "[[images]]\nmodel = \"sensor-v2\"\nversion = \"{}\"\nfile = \"{}\"\nsignature = \"{}\"\n",
+            version, file, signature
+        )
+    };
+
+    for manifest in [
+        entry("1.0-rc1", "sensor-v2-1.0.0.bin", &signature),
+        entry("1.0.0", "../sensor-v2-1.0.0.bin", &signature),
+        entry("1.0.0", "/etc/passwd", &signature),
+        entry("1.0.0", "sensor-v2-1.0.0.bin", "abcd"),
+        entry("1.0.0", "missing.bin", &signature),
+        entry("1.0.0", "sensor-v2-1.0.0.bin", &signature).repeat(2),
+    ] {
+        fs::write(dir.path().join("manifest.toml"), &manifest).unwrap();
+        assert!(
+            FirmwareStore::open(dir.path()).is_err(),
+            "Accepted manifest:\n{}",
+            manifest
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use common::{ServerProcess, WAIT_TIMEOUT};
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    client_message, kv_record, server_message, DeviceQuery, KvEntry, KvRecord, KvSnapshot,
+    RegisterDevice, TelemetryBatch, TelemetryQuery,
+};
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{now_ms, temperature, TestServer};
+use embedded_recruitment_task::wal::{SyncPolicy, Wal, WalOptions};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::time;
+mod common;
+
+const OPTIONS: WalOptions = WalOptions {
+    sync: SyncPolicy::Always,
+    sync_interval: Duration::from_secs(1),
+    snapshot_interval: Duration::from_secs(60),
+};
+
+// A `ServerMain` process keeping its state in `state_dir`.
+fn spawn(state_dir: &Path) -> ServerProcess {
+    ServerProcess::spawn(&format!(
+        "address = \"127.0.0.1:0\"\nstate_dir = {:?}\nwal_sync = \"never\"\n",
+        state_dir
+    ))
+}
+
+fn set(key: &str, version: u64) -> KvRecord {
+    KvRecord {
+        change: Some(kv_record::Change::Set(KvEntry {
+            key: key.to_string(),
+            value: b"on".to_vec(),
+            version,
+            expires_ms: 0,
+        })),
+    }
+}
+
+fn recovered_keys(dir: &Path) -> (Option<KvSnapshot>, Vec<String>) {
+    let (_, recovered) = Wal::open::<KvSnapshot, KvRecord>(dir, OPTIONS).unwrap();
+    let keys = recovered
+        .records
+        .into_iter()
+        .map(|record| match record.change {
+            Some(kv_record::Change::Set(entry)) => entry.key,
+            change => panic!("Expected a set, got {:?}", change),
+        })
+        .collect();
+    (recovered.snapshot, keys)
+}
+
+#[tokio::test]
+async fn test_state_survives_a_killed_server() {
+    let state_dir = TempDir::new().unwrap();
+    let server = spawn(state_dir.path());
+    let mut client = Client::connect(server.addr).await.unwrap();
+
+    let kept = client
+        .kv_set("pump-1/mode", b"auto".to_vec(), None)
+        .await
+        .unwrap();
+    client
+        .kv_set("pump-2/mode", b"auto".to_vec(), None)
+        .await
+        .unwrap();
+    client.kv_delete("pump-2/mode").await.unwrap();
+
+    let registration = RegisterDevice {
+        device_id: "greenhouse-3".to_string(),
+        model: "sensor-v2".to_string(),
+        firmware_version: "1.4.0".to_string(),
+    };
+    client
+        .request(client_message::Message::RegisterDevice(registration))
+        .await
+        .unwrap();
+    let t = now_ms();
+    let batch = TelemetryBatch {
+        device_id: "greenhouse-3".to_string(),
+        readings: vec![temperature(t - 1000, 21.5), temperature(t, 22.0)],
+    };
+    client
+        .request(client_message::Message::TelemetryBatch(batch))
+        .await
+        .unwrap();
+    server.kill();
+
+    let server = spawn(state_dir.path());
+    let mut client = Client::connect(server.addr).await.unwrap();
+    assert_eq!(
+        client.kv_get("pump-1/mode").await.unwrap(),
+        Some(kept.clone())
+    );
+    assert_eq!(client.kv_get("pump-2/mode").await.unwrap(), None);
+    // Versions carry on from where they were, so old ones never come back
+    let next = client
+        .kv_set("pump-3/mode", b"off".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(next.version > kept.version + 1);
+
+    let query = DeviceQuery::default();
+    let devices = match client
+        .request(client_message::Message::DeviceQuery(query))
+        .await
+        .unwrap()
+    {
+        server_message::Message::DeviceList(list) => list.devices,
+        response => panic!("Expected a DeviceList, got {:?}", response),
+    };
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].device_id, "greenhouse-3");
+    assert_eq!(devices[0].firmware_version, "1.4.0");
+    assert!(!devices[0].online);
+
+    let query = TelemetryQuery {
+        device_id: "greenhouse-3".to_string(),
+        ..TelemetryQuery::default()
+    };
+    let data = match client
+        .request(client_message::Message::TelemetryQuery(query))
+        .await
+        .unwrap()
+    {
+        server_message::Message::TelemetryData(data) => data,
+        response => panic!("Expected TelemetryData, got {:?}", response),
+    };
+    assert_eq!(
+        data.readings,
+        [temperature(t - 1000, 21.5), temperature(t, 22.0)]
+    );
+    server.kill();
+}
+
+#[tokio::test]
+async fn test_snapshots_replace_the_log() {
+    let state_dir = TempDir::new().unwrap();
+    let mut server = TestServer::with_config(
+        ServerConfig {
+            state_dir: Some(state_dir.path().to_path_buf()),
+            snapshot_interval_ms: 50,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    for i in 0..10 {
+        let key = format!("config/{}", i);
+        client.kv_set(&key, vec![i], None).await.unwrap();
+    }
+
+    let kv_dir = state_dir.path().join("kv");
+    time::timeout(WAIT_TIMEOUT, async {
+        while fs::metadata(kv_dir.join("log")).unwrap().len() > 0 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The log was never compacted");
+    assert!(kv_dir.join("snapshot").exists());
+
+    // Changes after the snapshot are replayed on top of it
+    client.kv_delete("config/3").await.unwrap();
+    client.kv_set("config/4", vec![40], None).await.unwrap();
+    server.restart();
+
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    let entries = client.kv_list("config/", "").await.unwrap().entries;
+    let values: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry.value.as_slice()))
+        .collect();
+    assert_eq!(
+        values,
+        [
+            ("config/0", &[0][..]),
+            ("config/1", &[1]),
+            ("config/2", &[2]),
+            ("config/4", &[40]),
+            ("config/5", &[5]),
+            ("config/6", &[6]),
+            ("config/7", &[7]),
+            ("config/8", &[8]),
+            ("config/9", &[9]),
+        ]
+    );
+}
+
+#[test]
+fn test_records_cut_short_are_dropped() {
+    let dir = TempDir::new().unwrap();
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+        wal.append(&set(key, i as u64 + 1)).unwrap();
+    }
+    drop(wal);
+
+    // As if the server died halfway through writing a fourth
+    let log = dir.path().join("log");
+    let whole_len = fs::metadata(&log).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+    file.write_all(&[0, 0, 0, 40, 1, 2, 3]).unwrap();
+    drop(file);
+
+    let (wal, recovered) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    assert_eq!(recovered.records, [set("a", 1), set("b", 2), set("c", 3)]);
+    assert_eq!(fs::metadata(&log).unwrap().len(), whole_len);
+    wal.append(&set("d", 4)).unwrap();
+    drop(wal);
+    assert_eq!(recovered_keys(dir.path()).1, ["a", "b", "c", "d"]);
+
+    // Damage before intact records isn't a torn tail, so nothing is dropped or recovered
+    let mut bytes = fs::read(&log).unwrap();
+    let second_record = bytes.len() * 3 / 8;
+    bytes[second_record] ^= 0xff;
+    fs::write(&log, &bytes).unwrap();
+    let error = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(fs::read(&log).unwrap(), bytes);
+}
+
+#[test]
+fn test_snapshots_skip_the_records_they_hold() {
+    let dir = TempDir::new().unwrap();
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    wal.append(&set("a", 1)).unwrap();
+    wal.append(&set("b", 2)).unwrap();
+    let log = dir.path().join("log");
+    let before_snapshot = fs::read(&log).unwrap();
+    let snapshot = KvSnapshot {
+        entries: Vec::new(),
+        version: 2,
+    };
+    wal.compact(&snapshot).unwrap();
+    assert_eq!(wal.records(), 0);
+    drop(wal);
+
+    // As if the server died after writing the snapshot but before emptying the log
+    fs::write(&log, before_snapshot).unwrap();
+    let (recovered, keys) = recovered_keys(dir.path());
+    assert_eq!(recovered, Some(snapshot));
+    assert!(keys.is_empty());
+
+    // Records carry on from the snapshot's
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    wal.append(&set("c", 3)).unwrap();
+    drop(wal);
+    assert_eq!(recovered_keys(dir.path()).1, ["c"]);
+
+    // A damaged snapshot can't be trusted, so nothing is recovered
+    let mut snapshot = fs::read(dir.path().join("snapshot")).unwrap();
+    let last = snapshot.len() - 1;
+    snapshot[last] ^= 0xff;
+    fs::write(dir.path().join("snapshot"), snapshot).unwrap();
+    let error = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_records_logged_while_snapshotting_are_kept() {
+    let dir = TempDir::new().unwrap();
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    wal.append(&set("a", 1)).unwrap();
+    wal.append(&set("b", 2)).unwrap();
+    let snapshot = KvSnapshot {
+        entries: Vec::new(),
+        version: 2,
+    };
+    let mark = wal.mark();
+
+    // The store goes on changing while its copy is written out
+    wal.append(&set("c", 3)).unwrap();
+    wal.compact_at(&snapshot, mark).unwrap();
+    assert_eq!(wal.records(), 1);
+    wal.append(&set("d", 4)).unwrap();
+    drop(wal);
+    let (recovered, keys) = recovered_keys(dir.path());
+    assert_eq!(recovered, Some(snapshot.clone()));
+    assert_eq!(keys, ["c", "d"]);
+
+    // A mark from before the last snapshot is out of date
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    let stale = wal.mark();
+    wal.compact(&snapshot).unwrap();
+    wal.compact_at(&KvSnapshot::default(), stale).unwrap();
+    drop(wal);
+    assert_eq!(recovered_keys(dir.path()).0, Some(snapshot));
+}
+
+#[test]
+fn test_records_after_a_failed_snapshot_are_kept() {
+    let dir = TempDir::new().unwrap();
+    let (wal, _) = Wal::open::<KvSnapshot, KvRecord>(dir.path(), OPTIONS).unwrap();
+    wal.append(&set("a", 1)).unwrap();
+    let mark = wal.mark();
+    wal.append(&set("b", 2)).unwrap();
+
+    // The new log is in place when flushing its directory fails, so records go on to it
+    let snapshot = KvSnapshot {
+        entries: Vec::new(),
+        version: 1,
+    };
+    wal.fail_syncs_after(Some(3));
+    wal.compact_at(&snapshot, mark).unwrap_err();
+    wal.fail_syncs_after(None);
+    wal.append(&set("c", 3)).unwrap();
+    assert_eq!(recovered_keys(dir.path()).1, ["b", "c"]);
+
+    // An emptied log that failed to flush is still known to be empty
+    wal.fail_syncs_after(Some(2));
+    wal.compact(&snapshot).unwrap_err();
+    wal.fail_syncs_after(None);
+    assert_eq!(wal.records(), 0);
+    wal.append(&set("d", 4)).unwrap();
+    let mark = wal.mark();
+    wal.append(&set("e", 5)).unwrap();
+    wal.compact_at(&snapshot, mark).unwrap();
+    drop(wal);
+    assert_eq!(recovered_keys(dir.path()).1, ["e"]);
+}
+
+#[tokio::test]
+async fn test_writes_synced_one_by_one_survive_a_restart() {
+    let state_dir = TempDir::new().unwrap();
+    let mut server = TestServer::with_config(
+        ServerConfig {
+            state_dir: Some(state_dir.path().to_path_buf()),
+            wal_sync: SyncPolicy::Always,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+
+    // Each flush waits on the disk off the server's runtime, while the other clients go on
+    let writers = (0..4).map(|i| {
+        let addr = server.addr();
+        tokio::spawn(async move {
+            let mut client = Client::connect(addr).await.unwrap();
+            for j in 0..10 {
+                let key = format!("valve-{}/{}", i, j);
+                client.kv_set(&key, vec![j], None).await.unwrap();
+            }
+        })
+    });
+    for writer in writers.collect::<Vec<_>>() {
+        writer.await.unwrap();
+    }
+    server.restart();
+
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    let entries = client.kv_list("valve-", "").await.unwrap().entries;
+    assert_eq!(entries.len(), 40);
+}
@@ -0,0 +1,181 @@
+use embedded_recruitment_task::message::{server_message, AddResponse};
+use embedded_recruitment_task::pool::{Pool, PoolConfig, PoolStatus, PooledClient};
+use embedded_recruitment_task::test_util::{add, TestServer};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+fn config(min_connections: usize, max_connections: usize) -> PoolConfig {
+    PoolConfig {
+        min_connections,
+        max_connections,
+        checkout_timeout: Duration::from_millis(500),
+        maintenance_interval: Duration::from_millis(20),
+        ..PoolConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_many_tasks_share_a_bounded_pool() {
+    let server = TestServer::start();
+    let pool = Pool::connect(server.addr().to_string(), config(2, 4))
+        .await
+        .unwrap();
+    assert_eq!(pool.status(), PoolStatus { idle: 2, in_use: 0 });
+
+    let tasks: Vec<_> = (0..32)
+        .map(|task| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for i in 0..10 {
+                    let response = pool.request(add(task, i)).await.unwrap();
+                    assert_eq!(
+                        response,
+                        server_message::Message::AddResponse(AddResponse { result: task + i })
+                    );
+                    assert!(pool.status().in_use <= 4);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let status = pool.status();
+    assert_eq!(status.in_use, 0);
+    assert!((2..=4).contains(&status.idle), "{:?}", status);
+}
+
+#[tokio::test]
+async fn test_checkout_times_out_when_exhausted() {
+    let server = TestServer::start();
+    let pool = Pool::connect(server.addr().to_string(), config(0, 1))
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    let error = pool.get().await.err().expect("Pool should be exhausted");
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+    drop(held);
+    assert!(pool.get().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_checkout_is_first_come_first_served() {
+    let server = TestServer::start();
+    let pool = Pool::connect(server.addr().to_string(), config(1, 1))
+        .await
+        .unwrap();
+    let held = pool.get().await.unwrap();
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut waiters = Vec::new();
+    for waiter in 0..5 {
+        let pool = pool.clone();
+        let order = Arc::clone(&order);
+        waiters.push(tokio::spawn(async move {
+            let mut client = pool.get().await.unwrap();
+            order.lock().unwrap().push(waiter);
+            client.request(add(waiter, 0)).await.unwrap();
+        }));
+        // Let each waiter queue up before the next one
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    drop(held);
+    for waiter in waiters {
+        waiter.await.unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn test_idle_connections_above_minimum_are_evicted() {
+    let server = TestServer::start();
+    let pool = Pool::connect(
+        server.addr().to_string(),
+        PoolConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..config(1, 4)
+        },
+    )
+    .await
+    .unwrap();
+
+    let clients = check_out(&pool, 4).await;
+    drop(clients);
+    assert_eq!(pool.status(), PoolStatus { idle: 4, in_use: 0 });
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(pool.status(), PoolStatus { idle: 1, in_use: 0 });
+}
+
+// Check out `count` connections at once.
+async fn check_out(pool: &Pool, count: usize) -> Vec<PooledClient> {
+    let mut clients = Vec::new();
+    for _ in 0..count {
+        clients.push(pool.get().await.unwrap());
+    }
+    clients
+}
+
+#[test]
+fn test_dead_connections_are_replaced_after_health_check() {
+    let runtime = Runtime::new().unwrap();
+    let mut server = TestServer::start();
+    let pool = runtime
+        .block_on(Pool::connect(
+            server.addr().to_string(),
+            PoolConfig {
+                health_check_after: Duration::ZERO,
+                ..config(2, 2)
+            },
+        ))
+        .unwrap();
+
+    // Every pooled connection dies with the server
+    server.restart();
+
+    runtime.block_on(async {
+        for i in 0..4 {
+            let response = pool.request(add(i, 1)).await.unwrap();
+            assert!(matches!(
+                response,
+                server_message::Message::AddResponse(response) if response.result == i + 1
+            ));
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_health_checks_count_against_the_checkout_timeout() {
+    // A server that accepts connections and never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.push(stream);
+        }
+    });
+    let pool = Pool::connect(
+        addr.to_string(),
+        PoolConfig {
+            health_check_after: Duration::ZERO,
+            ..config(3, 3)
+        },
+    )
+    .await
+    .unwrap();
+
+    // Every idle connection fails its health check, but together they only take as long as
+    // the checkout may
+    let started = Instant::now();
+    let error = pool.get().await.err().expect("No connection is healthy");
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_millis(900));
+}
@@ -0,0 +1,229 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::Delivery;
+use embedded_recruitment_task::pubsub::{matches, SlowSubscriber};
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::TestServer;
+use std::io;
+use std::time::Duration;
+use tokio::time;
+
+// Enough published data to fill the socket buffers between the server and a subscriber
+// that isn't reading, so the server's own queue fills up behind them.
+const FLOOD_MESSAGES: u32 = 5000;
+const FLOOD_PAYLOAD_LEN: usize = 4000;
+
+fn flood_payload(i: u32) -> Vec<u8> {
+    let mut payload = vec![0; FLOOD_PAYLOAD_LEN];
+    payload[..4].copy_from_slice(&i.to_be_bytes());
+    payload
+}
+
+fn flood_index(delivery: &Delivery) -> u32 {
+    u32::from_be_bytes(delivery.payload[..4].try_into().unwrap())
+}
+
+fn slow_subscriber_server(policy: SlowSubscriber) -> TestServer {
+    TestServer::with_config(
+        ServerConfig {
+            subscriber_queue_len: 4,
+            slow_subscriber: policy,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    )
+}
+
+async fn next_delivery(client: &mut Client) -> Delivery {
+    time::timeout(Duration::from_secs(5), client.next_delivery())
+        .await
+        .expect("No delivery")
+        .unwrap()
+}
+
+#[test]
+fn test_topic_patterns() {
+    assert!(matches(
+        "sensors/greenhouse-3/temperature",
+        "sensors/greenhouse-3/temperature"
+    ));
+    assert!(matches(
+        "sensors/+/temperature",
+        "sensors/greenhouse-3/temperature"
+    ));
+    assert!(!matches(
+        "sensors/+/temperature",
+        "sensors/greenhouse-3/humidity"
+    ));
+    assert!(!matches("sensors/+", "sensors/greenhouse-3/temperature"));
+    assert!(matches("sensors/#", "sensors/greenhouse-3/temperature"));
+    assert!(matches("sensors/#", "sensors"));
+    assert!(matches("#", "alerts"));
+    assert!(!matches("sensors/greenhouse-3", "sensors"));
+    assert!(!matches("sensors", "sensors/greenhouse-3"));
+}
+
+#[tokio::test]
+async fn test_published_messages_reach_subscribers() {
+    let server = TestServer::start();
+    let mut all = Client::connect(server.addr()).await.unwrap();
+    let mut temperatures = Client::connect(server.addr()).await.unwrap();
+    let mut publisher = Client::connect(server.addr()).await.unwrap();
+
+    all.subscribe("sensors/#").await.unwrap();
+    // Overlapping patterns still deliver each message once
+    all.subscribe("sensors/+/temperature").await.unwrap();
+    temperatures
+        .subscribe("sensors/+/temperature")
+        .await
+        .unwrap();
+
+    for i in 0..10u8 {
+        let subscribers = publisher
+            .publish("sensors/greenhouse-3/temperature", vec![i])
+            .await
+            .unwrap();
+        assert_eq!(subscribers, 2);
+    }
+    let subscribers = publisher
+        .publish("sensors/greenhouse-3/humidity", b"61%".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(subscribers, 1);
+    assert_eq!(publisher.publish("alerts", Vec::new()).await.unwrap(), 0);
+
+    // In the order they were published
+    for client in [&mut all, &mut temperatures] {
+        for i in 0..10u8 {
+            assert_eq!(
+                next_delivery(client).await,
+                Delivery {
+                    topic: "sensors/greenhouse-3/temperature".to_string(),
+                    payload: vec![i],
+                    dropped: 0,
+                }
+            );
+        }
+    }
+    assert_eq!(next_delivery(&mut all).await.payload, b"61%");
+
+    // Deliveries that arrive while waiting for a response are kept
+    temperatures
+        .subscribe("sensors/greenhouse-3/humidity")
+        .await
+        .unwrap();
+    temperatures
+        .unsubscribe("sensors/+/temperature")
+        .await
+        .unwrap();
+    publisher
+        .publish("sensors/greenhouse-3/temperature", vec![10])
+        .await
+        .unwrap();
+    publisher
+        .publish("sensors/greenhouse-3/humidity", b"62%".to_vec())
+        .await
+        .unwrap();
+    temperatures.ping().await.unwrap();
+    assert_eq!(next_delivery(&mut temperatures).await.payload, b"62%");
+
+    let error = all.subscribe("sensors/#/temperature").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let error = all.subscribe("sensors/room+").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let error = all.unsubscribe("alerts").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    let error = publisher
+        .publish("sensors/+", Vec::new())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_slow_subscribers_lose_the_oldest_messages() {
+    let server = slow_subscriber_server(SlowSubscriber::DropOldest);
+    let mut subscriber = Client::connect(server.addr()).await.unwrap();
+    let mut publisher = Client::connect(server.addr()).await.unwrap();
+    subscriber.subscribe("flood").await.unwrap();
+
+    // The subscriber reads nothing until everything is published
+    for i in 0..FLOOD_MESSAGES {
+        publisher.publish("flood", flood_payload(i)).await.unwrap();
+    }
+
+    // What it gets is in order, ends with the latest, and says what was dropped
+    let mut received = 0;
+    let mut dropped = 0;
+    let mut last = None;
+    while last != Some(FLOOD_MESSAGES - 1) {
+        let delivery = next_delivery(&mut subscriber).await;
+        let index = flood_index(&delivery);
+        assert_eq!(
+            index,
+            last.map_or(0, |last| last + 1) + delivery.dropped as u32
+        );
+        received += 1;
+        dropped += delivery.dropped;
+        last = Some(index);
+    }
+    assert!(dropped > 0);
+    assert_eq!(received + dropped, u64::from(FLOOD_MESSAGES));
+    assert_eq!(server.server().metrics().dropped_deliveries(), dropped);
+}
+
+#[tokio::test]
+async fn test_slow_subscribers_can_be_disconnected() {
+    let server = slow_subscriber_server(SlowSubscriber::Disconnect);
+    let mut subscriber = Client::connect(server.addr()).await.unwrap();
+    let mut publisher = Client::connect(server.addr()).await.unwrap();
+    subscriber.subscribe("flood").await.unwrap();
+
+    let mut i = 0;
+    while publisher.publish("flood", flood_payload(i)).await.unwrap() == 1 {
+        i += 1;
+        assert!(i < FLOOD_MESSAGES, "Slow subscriber was never disconnected");
+    }
+
+    // It gets what was already on its way, then the connection closes
+    let closed = time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Err(e) = subscriber.next_delivery().await {
+                return e;
+            }
+        }
+    })
+    .await
+    .expect("Slow subscriber was left connected");
+    assert_eq!(closed.kind(), io::ErrorKind::ConnectionAborted);
+    assert_eq!(server.server().metrics().slow_subscribers(), 1);
+}
+
+#[tokio::test]
+async fn test_slow_subscribers_can_hold_up_publishers() {
+    let server = slow_subscriber_server(SlowSubscriber::Block);
+    let mut subscriber = Client::connect(server.addr()).await.unwrap();
+    let mut publisher = Client::connect(server.addr()).await.unwrap();
+    subscriber.subscribe("flood").await.unwrap();
+    // Subscribed to its own messages, which doesn't stop it being held up by others
+    publisher.subscribe("flood").await.unwrap();
+
+    let publishing = tokio::spawn(async move {
+        for i in 0..FLOOD_MESSAGES {
+            publisher.publish("flood", flood_payload(i)).await.unwrap();
+        }
+    });
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!publishing.is_finished(), "Publisher wasn't held up");
+
+    // Once the subscriber catches up, it has lost nothing
+    for i in 0..FLOOD_MESSAGES {
+        let delivery = next_delivery(&mut subscriber).await;
+        assert_eq!((flood_index(&delivery), delivery.dropped), (i, 0));
+    }
+    time::timeout(Duration::from_secs(5), publishing)
+        .await
+        .expect("Publisher was never released")
+        .unwrap();
+    assert_eq!(server.server().metrics().dropped_deliveries(), 0);
+}
@@ -0,0 +1,316 @@
+use embedded_recruitment_task::message::{client_message, server_message};
+use embedded_recruitment_task::reconnect::{
+    Backoff, ConnectionState, DisconnectPolicy, ReconnectPolicy, ReconnectingClient,
+};
+use embedded_recruitment_task::router::{MessageKind, Router};
+use embedded_recruitment_task::test_util::{echo, TestServer};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+fn echoed(response: server_message::Message) -> String {
+    match response {
+        server_message::Message::EchoMessage(echo) => echo.content,
+        other => panic!("Expected an EchoMessage, got {:?}", other),
+    }
+}
+
+fn fast_policy(on_disconnect: DisconnectPolicy) -> ReconnectPolicy {
+    ReconnectPolicy {
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+        on_disconnect,
+        ..ReconnectPolicy::default()
+    }
+}
+
+// A client whose state changes are recorded, for asserting on the callbacks.
+fn recorded_client(
+    addr: String,
+    policy: ReconnectPolicy,
+) -> (ReconnectingClient, Arc<Mutex<Vec<ConnectionState>>>) {
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&states);
+    let client = ReconnectingClient::with_state_callback(addr, policy, move |state| {
+        recorded.lock().unwrap().push(state.clone())
+    });
+    (client, states)
+}
+
+fn connected(state: &ConnectionState) -> bool {
+    matches!(state, ConnectionState::Connected { .. })
+}
+
+// The server is started and stopped from the test thread, which blocks, so every test drives
+// the client on a separate multi-threaded runtime.
+
+#[test]
+fn test_backoff_grows_exponentially_up_to_the_cap() {
+    let mut backoff = Backoff::new(ReconnectPolicy {
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(500),
+        multiplier: 2.0,
+        jitter: 0.0,
+        max_retries: Some(5),
+        ..ReconnectPolicy::default()
+    });
+
+    let delays: Vec<u64> = std::iter::from_fn(|| backoff.next_delay())
+        .map(|delay| delay.as_millis() as u64)
+        .collect();
+    assert_eq!(delays, [100, 200, 400, 500, 500]);
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+}
+
+#[test]
+fn test_backoff_jitter_stays_within_bounds() {
+    let mut backoff = Backoff::new(ReconnectPolicy {
+        initial_backoff: Duration::from_millis(100),
+        multiplier: 1.0,
+        jitter: 0.5,
+        ..ReconnectPolicy::default()
+    });
+
+    let delays: Vec<Duration> = (0..100).map(|_| backoff.next_delay().unwrap()).collect();
+    assert!(delays
+        .iter()
+        .all(|delay| (Duration::from_millis(50)..=Duration::from_millis(100)).contains(delay)));
+    assert!(
+        delays.iter().any(|delay| *delay != delays[0]),
+        "Jitter should vary the delays"
+    );
+}
+
+#[test]
+fn test_backoff_survives_nonsensical_policies() {
+    let policies = [
+        ReconnectPolicy {
+            multiplier: -3.0,
+            ..ReconnectPolicy::default()
+        },
+        ReconnectPolicy {
+            multiplier: f64::NAN,
+            jitter: f64::NAN,
+            ..ReconnectPolicy::default()
+        },
+        ReconnectPolicy {
+            multiplier: f64::INFINITY,
+            jitter: -1.0,
+            max_backoff: Duration::MAX,
+            ..ReconnectPolicy::default()
+        },
+    ];
+    for policy in policies {
+        let max = policy.max_backoff;
+        let mut backoff = Backoff::new(policy);
+        for _ in 0..100 {
+            assert!(backoff.next_delay().unwrap() <= max);
+        }
+    }
+}
+
+#[test]
+fn test_queued_request_waits_for_restart() {
+    let runtime = Runtime::new().unwrap();
+    let mut server = TestServer::start();
+    let (mut client, states) = runtime.block_on(async {
+        recorded_client(
+            server.addr().to_string(),
+            fast_policy(DisconnectPolicy::Queue),
+        )
+    });
+
+    let response = runtime.block_on(client.request(echo("before"))).unwrap();
+    assert_eq!(echoed(response), "before");
+
+    server.shutdown();
+    runtime.block_on(client.wait_for_state(|state| !connected(state)));
+
+    let queued = runtime.spawn({
+        let client = client.clone();
+        async move { client.request(echo("during")).await }
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!queued.is_finished(), "Request should wait for the server");
+
+    server.restart();
+    let response = runtime.block_on(queued).unwrap().unwrap();
+    assert_eq!(echoed(response), "during");
+
+    let states = states.lock().unwrap();
+    assert!(matches!(
+        states[0],
+        ConnectionState::Connecting { attempt: 1 }
+    ));
+    assert!(connected(&states[1]));
+    assert!(states
+        .iter()
+        .any(|state| matches!(state, ConnectionState::Connecting { attempt } if *attempt > 1)));
+    assert!(connected(states.last().unwrap()));
+}
+
+#[test]
+fn test_fail_policy_rejects_requests_while_disconnected() {
+    let runtime = Runtime::new().unwrap();
+    let mut server = TestServer::start();
+    let mut client = runtime.block_on(async {
+        ReconnectingClient::new(
+            server.addr().to_string(),
+            fast_policy(DisconnectPolicy::Fail),
+        )
+    });
+    runtime.block_on(client.wait_for_state(connected));
+
+    server.shutdown();
+    runtime.block_on(client.wait_for_state(|state| !connected(state)));
+
+    let error = runtime
+        .block_on(client.request(echo("during")))
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+
+    // The client keeps reconnecting in the background
+    server.restart();
+    runtime.block_on(client.wait_for_state(connected));
+    let response = runtime.block_on(client.request(echo("after"))).unwrap();
+    assert_eq!(echoed(response), "after");
+}
+
+#[test]
+fn test_interrupted_request_is_resent_after_reconnect() {
+    // The first request never gets an answer; the server is restarted underneath it.
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = Router::new().route(MessageKind::Echo, {
+        let calls = Arc::clone(&calls);
+        move |_, message| {
+            let first = calls.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                if first {
+                    std::future::pending::<()>().await;
+                }
+                match message {
+                    client_message::Message::EchoMessage(echo) => {
+                        Some(server_message::Message::EchoMessage(echo))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    });
+    let mut server = TestServer::with_router(router);
+    let runtime = Runtime::new().unwrap();
+    let client = runtime.block_on(async {
+        ReconnectingClient::new(
+            server.addr().to_string(),
+            fast_policy(DisconnectPolicy::Queue),
+        )
+    });
+
+    let request = runtime.spawn({
+        let client = client.clone();
+        async move { client.request(echo("resent")).await }
+    });
+    while calls.load(Ordering::SeqCst) == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    server.restart();
+    let response = runtime.block_on(request).unwrap().unwrap();
+    assert_eq!(echoed(response), "resent");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_fail_policy_fails_interrupted_request_as_not_connected() {
+    // The request never gets an answer; the server is restarted underneath it.
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = Router::new().route(MessageKind::Echo, {
+        let calls = Arc::clone(&calls);
+        move |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            std::future::pending()
+        }
+    });
+    let mut server = TestServer::with_router(router);
+    let runtime = Runtime::new().unwrap();
+    let client = runtime.block_on(async {
+        ReconnectingClient::new(
+            server.addr().to_string(),
+            fast_policy(DisconnectPolicy::Fail),
+        )
+    });
+
+    let request = runtime.spawn({
+        let client = client.clone();
+        async move { client.request(echo("cut off")).await }
+    });
+    while calls.load(Ordering::SeqCst) == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    server.restart();
+    let error = runtime.block_on(request).unwrap().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn test_gives_up_when_retry_budget_is_spent() {
+    // Nothing listens on this port once the listener is dropped
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let runtime = Runtime::new().unwrap();
+    let policy = ReconnectPolicy {
+        max_retries: Some(3),
+        ..fast_policy(DisconnectPolicy::Queue)
+    };
+    let (client, states) = runtime.block_on(async { recorded_client(addr.to_string(), policy) });
+
+    let error = runtime
+        .block_on(client.request(echo("nobody")))
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+    assert_eq!(client.state(), ConnectionState::GaveUp);
+
+    let states = states.lock().unwrap();
+    let attempts = states
+        .iter()
+        .filter(|state| matches!(state, ConnectionState::Connecting { .. }))
+        .count();
+    assert_eq!(attempts, 4, "One attempt plus three retries: {:?}", states);
+    assert_eq!(states.last(), Some(&ConnectionState::GaveUp));
+}
+
+#[test]
+fn test_queued_request_times_out_while_disconnected() {
+    let runtime = Runtime::new().unwrap();
+    let mut server = TestServer::start();
+    let mut client = runtime.block_on(async {
+        ReconnectingClient::new(
+            server.addr().to_string(),
+            fast_policy(DisconnectPolicy::Queue),
+        )
+    });
+    runtime.block_on(client.wait_for_state(connected));
+
+    server.shutdown();
+    runtime.block_on(client.wait_for_state(|state| !connected(state)));
+
+    let error = runtime
+        .block_on(client.request_with_timeout(echo("late"), Duration::from_millis(100)))
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+    // The expired request isn't sent once the server is back
+    server.restart();
+    let response = runtime
+        .block_on(client.request_with_timeout(echo("on time"), Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(echoed(response), "on time");
+}
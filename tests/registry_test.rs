@@ -0,0 +1,240 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{
+    client_message, error, server_message, DeviceInfo, DeviceList, DeviceQuery, DeviceSnapshot,
+    RegisterDevice,
+};
+use embedded_recruitment_task::registry::MAX_DEVICES;
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::TestServer;
+use embedded_recruitment_task::wal::{SyncPolicy, Wal, WalOptions};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::time;
+
+fn registration(device_id: &str, firmware_version: &str) -> client_message::Message {
+    client_message::Message::RegisterDevice(RegisterDevice {
+        device_id: device_id.to_string(),
+        model: "sensor-v2".to_string(),
+        firmware_version: firmware_version.to_string(),
+    })
+}
+
+async fn register(client: &mut Client, device_id: &str, firmware_version: &str) -> DeviceInfo {
+    match client
+        .request(registration(device_id, firmware_version))
+        .await
+        .unwrap()
+    {
+        server_message::Message::DeviceInfo(info) => info,
+        response => panic!("Expected a DeviceInfo, got {:?}", response),
+    }
+}
+
+async fn devices(client: &mut Client, query: DeviceQuery) -> DeviceList {
+    match client
+        .request(client_message::Message::DeviceQuery(query))
+        .await
+        .unwrap()
+    {
+        server_message::Message::DeviceList(list) => list,
+        response => panic!("Expected a DeviceList, got {:?}", response),
+    }
+}
+
+// Wait for the server to notice that a device's connection closed.
+async fn wait_offline(server: &TestServer, device_id: &str) -> DeviceInfo {
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            match server.server().devices().get(device_id) {
+                Some(info) if !info.online => return info,
+                _ => time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("Device never went offline")
+}
+
+#[tokio::test]
+async fn test_devices_register_and_go_offline() {
+    let server = TestServer::start();
+    let mut device = Client::connect(server.addr()).await.unwrap();
+    let local_addr = device.local_addr().unwrap();
+
+    let info = register(&mut device, "greenhouse-3", "1.4.0").await;
+    assert_eq!(
+        info,
+        DeviceInfo {
+            device_id: "greenhouse-3".to_string(),
+            model: "sensor-v2".to_string(),
+            firmware_version: "1.4.0".to_string(),
+            online: true,
+            last_seen_ms: info.connected_ms,
+            peer: local_addr.to_string(),
+            connected_ms: info.connected_ms,
+        }
+    );
+
+    // Anything the device sends counts as seeing it
+    time::sleep(Duration::from_millis(20)).await;
+    device.ping().await.unwrap();
+    let seen = server.server().devices().get("greenhouse-3").unwrap();
+    assert!(seen.last_seen_ms > info.last_seen_ms);
+
+    // Other clients can look it up
+    let mut admin = Client::connect(server.addr()).await.unwrap();
+    let list = devices(&mut admin, DeviceQuery::default()).await;
+    assert_eq!(list.devices, std::slice::from_ref(&seen));
+    assert!(!list.truncated);
+
+    device.disconnect().await.unwrap();
+    let offline = wait_offline(&server, "greenhouse-3").await;
+    assert_eq!(
+        offline,
+        DeviceInfo {
+            online: false,
+            ..seen
+        }
+    );
+    let list = devices(
+        &mut admin,
+        DeviceQuery {
+            online_only: true,
+            ..DeviceQuery::default()
+        },
+    )
+    .await;
+    assert!(list.devices.is_empty());
+}
+
+#[tokio::test]
+async fn test_reconnect_replaces_stale_session() {
+    let server = TestServer::start();
+    let mut stale = Client::connect(server.addr()).await.unwrap();
+    register(&mut stale, "pump-1", "2.0.0").await;
+
+    // The device comes back on a new connection, with new firmware, before the old one
+    // was noticed to be dead
+    let mut fresh = Client::connect(server.addr()).await.unwrap();
+    let info = register(&mut fresh, "pump-1", "2.1.0").await;
+    assert_eq!(info.peer, fresh.local_addr().unwrap().to_string());
+
+    // The old connection is closed, without taking the device offline
+    let closed = time::timeout(Duration::from_secs(5), stale.ping())
+        .await
+        .expect("Stale session was left open");
+    assert!(closed.is_err());
+    let current = server.server().devices().get("pump-1").unwrap();
+    assert!(current.online);
+    assert_eq!(current.firmware_version, "2.1.0");
+    assert_eq!(current.peer, info.peer);
+    fresh.ping().await.unwrap();
+
+    // Registering again on the same connection only updates the device
+    let info = register(&mut fresh, "pump-1", "2.1.1").await;
+    assert!(info.online);
+    fresh.ping().await.unwrap();
+
+    // But a connection can't change which device it is
+    let error = fresh
+        .request(registration("pump-2", "2.1.1"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        error,
+        server_message::Message::Error(error) if error.code() == error::Code::InvalidArgument
+    ));
+}
+
+#[tokio::test]
+async fn test_device_queries() {
+    let server = TestServer::start();
+    let mut connections = Vec::new();
+    for device_id in ["c", "a", "b"] {
+        let mut device = Client::connect(server.addr()).await.unwrap();
+        register(&mut device, device_id, "1.0.0").await;
+        connections.push(device);
+    }
+    connections.remove(2).disconnect().await.unwrap();
+    wait_offline(&server, "b").await;
+
+    let mut admin = Client::connect(server.addr()).await.unwrap();
+    let ids = |list: DeviceList| -> Vec<String> {
+        list.devices
+            .into_iter()
+            .map(|device| device.device_id)
+            .collect()
+    };
+    let all = devices(&mut admin, DeviceQuery::default()).await;
+    assert_eq!(ids(all), ["a", "b", "c"]);
+    let online = DeviceQuery {
+        online_only: true,
+        ..DeviceQuery::default()
+    };
+    assert_eq!(ids(devices(&mut admin, online).await), ["a", "c"]);
+    let after = DeviceQuery {
+        after: "a".to_string(),
+        ..DeviceQuery::default()
+    };
+    assert_eq!(ids(devices(&mut admin, after).await), ["b", "c"]);
+    let one = DeviceQuery {
+        device_id: "b".to_string(),
+        ..DeviceQuery::default()
+    };
+    assert_eq!(ids(devices(&mut admin, one).await), ["b"]);
+
+    let unknown = DeviceQuery {
+        device_id: "z".to_string(),
+        ..DeviceQuery::default()
+    };
+    let error = admin
+        .request(client_message::Message::DeviceQuery(unknown))
+        .await
+        .unwrap();
+    assert!(matches!(
+        error,
+        server_message::Message::Error(error) if error.code() == error::Code::NotFound
+    ));
+}
+
+#[tokio::test]
+async fn test_full_registry_forgets_devices_seen_longest_ago() {
+    // A registry left full by the last run, one device too many as a forgotten one was logged
+    let state_dir = TempDir::new().unwrap();
+    let options = WalOptions {
+        sync: SyncPolicy::Never,
+        sync_interval: Duration::from_secs(1),
+        snapshot_interval: Duration::from_secs(60),
+    };
+    let (wal, _) =
+        Wal::open::<DeviceSnapshot, DeviceInfo>(&state_dir.path().join("devices"), options)
+            .unwrap();
+    for i in 0..=MAX_DEVICES as u64 {
+        let info = DeviceInfo {
+            device_id: format!("sensor-{}", i),
+            last_seen_ms: 1000 + i,
+            ..DeviceInfo::default()
+        };
+        wal.append(&info).unwrap();
+    }
+    drop(wal);
+
+    let server = TestServer::with_config(
+        ServerConfig {
+            state_dir: Some(state_dir.path().to_path_buf()),
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let registry = server.server().devices();
+    assert_eq!(registry.list().len(), MAX_DEVICES);
+    assert!(registry.get("sensor-0").is_none());
+
+    let mut device = Client::connect(server.addr()).await.unwrap();
+    register(&mut device, "greenhouse-3", "1.4.0").await;
+    assert_eq!(registry.list().len(), MAX_DEVICES);
+    assert!(registry.get("sensor-1").is_none());
+    assert!(registry.get("sensor-2").is_some());
+    assert!(registry.get("greenhouse-3").unwrap().online);
+}
@@ -0,0 +1,160 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::codec::{encode_packet, Framing, DEFAULT_MAX_FRAME_LEN};
+use embedded_recruitment_task::config::{SerialPortConfig, ServerConfig};
+use embedded_recruitment_task::message::{
+    client_message, server_message, AddRequest, AddResponse, ClientMessage, EchoMessage,
+    ServerMessage,
+};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::Router;
+use embedded_recruitment_task::test_util::{echo, TestServer};
+use prost::Message;
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPort, SerialStream};
+
+// A server with a device attached to a pseudo-terminal: the server opens the slave end,
+// and the master end returned with it stands in for the device's UART.
+fn serial_server() -> (TestServer, SerialStream) {
+    serial_server_with(ServerConfig::default(), false)
+}
+
+fn serial_server_with(config: ServerConfig, heartbeat: bool) -> (TestServer, SerialStream) {
+    let (master, slave) = SerialStream::pair().unwrap();
+    let path = slave.name().unwrap();
+    drop(slave); // The server locks the port when it opens it
+    let server = TestServer::with_config(
+        ServerConfig {
+            serial_ports: vec![SerialPortConfig {
+                path,
+                baud_rate: 115_200,
+                heartbeat,
+            }],
+            ..config
+        },
+        Router::default(),
+    );
+    (server, master)
+}
+
+#[tokio::test]
+async fn test_requests_over_serial_port() {
+    let (_server, master) = serial_server();
+    let client = PipelinedClient::with_transport(master, Framing::Serial);
+
+    assert_eq!(
+        client.request(echo("sensor online")).await.unwrap(),
+        server_message::Message::EchoMessage(EchoMessage {
+            content: "sensor online".to_string(),
+        })
+    );
+
+    // Zero bytes in the payload are escaped by COBS
+    let add = client_message::Message::AddRequest(AddRequest { a: 0, b: -256 });
+    assert_eq!(
+        client.request(add).await.unwrap(),
+        server_message::Message::AddResponse(AddResponse { result: -256 })
+    );
+
+    // Pipelining and streaming work as they do over TCP
+    let body: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let (streamed, pinged) = tokio::join!(
+        async {
+            let mut response = client
+                .stream(echo(""), Cursor::new(body.clone()))
+                .await
+                .unwrap();
+            response.read_all().await.unwrap()
+        },
+        client.ping()
+    );
+    assert!(streamed == body, "Echoed stream differs from the body");
+    pinged.unwrap();
+}
+
+#[tokio::test]
+async fn test_server_resynchronises_after_line_noise() {
+    let (_server, mut master) = serial_server();
+
+    let packet = |content: &str| {
+        let request = ClientMessage {
+            message: Some(echo(content)),
+            ..ClientMessage::default()
+        };
+        let mut out = Vec::new();
+        encode_packet(&request.encode_to_vec(), DEFAULT_MAX_FRAME_LEN, &mut out).unwrap();
+        out
+    };
+
+    // Garbage before the first delimiter, a packet with a flipped bit and a packet whose
+    // start was lost are all dropped; the packets after them still get through.
+    let mut corrupted = packet("corrupted");
+    corrupted[3] ^= 0x04;
+    let mut stream = b"\x7f\x13boot noise\x00".to_vec();
+    stream.extend(corrupted);
+    stream.extend(packet("first"));
+    stream.extend(&packet("lost start")[5..]);
+    stream.extend(packet("second"));
+    master.write_all(&stream).await.unwrap();
+
+    let mut decoder = Framing::Serial.decoder(DEFAULT_MAX_FRAME_LEN);
+    let mut replies = Vec::new();
+    let mut buffer = [0; 256];
+    while replies.len() < 2 {
+        let n = tokio::time::timeout(Duration::from_secs(5), master.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for replies")
+            .unwrap();
+        decoder.extend(&buffer[..n]);
+        while let Some(payload) = decoder.decode().unwrap() {
+            match ServerMessage::decode(payload.as_slice()).unwrap().message {
+                Some(server_message::Message::EchoMessage(echo)) => replies.push(echo.content),
+                reply => panic!("Unexpected reply {:?}", reply),
+            }
+        }
+    }
+    assert_eq!(replies, ["first", "second"]);
+}
+
+// Waits until the server has `n` connections open.
+async fn connections(server: &TestServer, n: usize) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.server().connections().len() != n {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Never had {} connections open", n));
+}
+
+#[tokio::test]
+async fn test_serial_port_is_reopened() {
+    let (server, master) = serial_server_with(
+        ServerConfig {
+            max_connections: 1,
+            heartbeat_interval_ms: 100,
+            heartbeat_timeout_ms: 100,
+            ..ServerConfig::default()
+        },
+        true,
+    );
+
+    // The serial port leaves room for a TCP client
+    connections(&server, 1).await;
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    client.ping().await.unwrap();
+    drop(client);
+    connections(&server, 1).await;
+
+    // A device that doesn't answer the heartbeat is disconnected, and the port reopened
+    connections(&server, 0).await;
+    connections(&server, 1).await;
+    let device = PipelinedClient::with_transport(master, Framing::Serial);
+    assert_eq!(
+        device.request(echo("back")).await.unwrap(),
+        server_message::Message::EchoMessage(EchoMessage {
+            content: "back".to_string(),
+        })
+    );
+}
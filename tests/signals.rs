@@ -0,0 +1,114 @@
+#![cfg(unix)]
+
+use common::ServerProcess;
+use embedded_recruitment_task::message::{client_message, server_message, EchoMessage};
+mod client;
+mod common;
+
+fn client(server: &ServerProcess) -> client::Client {
+    let addr = server.addr;
+    let mut client = client::Client::new(&addr.ip().to_string(), addr.port() as u32, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    client
+}
+
+fn echo(client: &mut client::Client, content: &str) -> std::io::Result<String> {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    });
+    client.send(message)?;
+
+    match client.receive()?.message {
+        Some(server_message::Message::EchoMessage(echo)) => Ok(echo.content),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+}
+
+#[test]
+fn test_sighup_reloads_limits_without_dropping_connections() {
+    let server = ServerProcess::spawn(
+        r#"
+        address = "127.0.0.1:0"
+        max_connections = 1
+        "#,
+    );
+
+    let mut first = client(&server);
+    assert_eq!(echo(&mut first, "before reload").unwrap(), "before reload");
+
+    // The second connection is over the limit and gets closed straight away.
+    let mut refused = client(&server);
+    server.wait_for_log("connection limit of 1 reached");
+    assert!(
+        echo(&mut refused, "refused").is_err(),
+        "Connection over the limit should be closed"
+    );
+
+    server.rewrite_config(
+        r#"
+        address = "127.0.0.1:0"
+        max_connections = 2
+        log_level = "debug"
+        room_history_len = 5
+        "#,
+    );
+    server.signal(libc::SIGHUP);
+    // Settings only read at startup are left alone
+    server.wait_for_log("Ignoring room_history_len change until restart");
+    server.wait_for_log("Configuration reloaded: max_connections=2");
+
+    // The existing connection survives the reload and the new limit applies.
+    assert_eq!(echo(&mut first, "after reload").unwrap(), "after reload");
+    let mut second = client(&server);
+    assert_eq!(echo(&mut second, "second").unwrap(), "second");
+
+    assert!(
+        first.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        second.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+}
+
+#[test]
+fn test_sighup_keeps_previous_config_when_file_is_invalid() {
+    let server = ServerProcess::spawn(
+        r#"
+        address = "127.0.0.1:0"
+        "#,
+    );
+
+    server.rewrite_config("max_connections = 0");
+    server.signal(libc::SIGHUP);
+    server.wait_for_log("Failed to reload configuration");
+
+    let mut client = client(&server);
+    assert_eq!(echo(&mut client, "still running").unwrap(), "still running");
+}
+
+#[test]
+fn test_shutdown_signals_drain_connections_and_exit() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let mut server = ServerProcess::spawn(
+            r#"
+            address = "127.0.0.1:0"
+            shutdown_timeout_ms = 2000
+            "#,
+        );
+
+        let mut client = client(&server);
+        assert_eq!(echo(&mut client, "hello").unwrap(), "hello");
+
+        server.signal(signal);
+        let status = server.wait_for_exit();
+        assert!(status.success(), "Server exited with {}", status);
+
+        // The idle connection was closed by the server rather than left hanging.
+        assert!(
+            echo(&mut client, "too late").is_err(),
+            "Connection should be closed after shutdown"
+        );
+    }
+}
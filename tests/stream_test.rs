@@ -0,0 +1,130 @@
+use embedded_recruitment_task::config::ServerConfig;
+use embedded_recruitment_task::message::{server_message, EchoMessage};
+use embedded_recruitment_task::pipelined::PipelinedClient;
+use embedded_recruitment_task::router::{Context, MessageKind, Router};
+use embedded_recruitment_task::stream::{CHUNK_SIZE, STREAM_WINDOW};
+use embedded_recruitment_task::test_util::{echo, TestServer};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Bytes that don't repeat on chunk boundaries, so misordered chunks are noticed.
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn test_streamed_echo_round_trips_large_payload() {
+    let server = TestServer::start();
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let body = payload(4 * 1024 * 1024);
+    let mut response = client
+        .stream(echo(""), Cursor::new(body.clone()))
+        .await
+        .unwrap();
+    let echoed = response.read_all().await.unwrap();
+    assert_eq!(echoed.len(), body.len());
+    assert!(echoed == body, "Echoed stream differs from the body");
+
+    // The connection is still good for ordinary requests
+    let response = client.request(echo("after")).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::EchoMessage(EchoMessage {
+            content: "after".to_string(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_slow_reader_throttles_sender() {
+    let sent = Arc::new(AtomicU32::new(0));
+    let router = Router::default().route(MessageKind::Echo, {
+        let sent = Arc::clone(&sent);
+        move |ctx: Context, _| {
+            let sent = Arc::clone(&sent);
+            async move {
+                let mut response = ctx.response_stream()?;
+                for _ in 0..100 {
+                    response.send(&[7; CHUNK_SIZE]).await.ok()?;
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+                response.finish().await.ok()?;
+                None
+            }
+        }
+    });
+    let server = TestServer::with_router(router);
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let mut response = client.download(echo("")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        sent.load(Ordering::SeqCst),
+        STREAM_WINDOW,
+        "The server should stop at the window until the client reads"
+    );
+
+    let data = response.read_all().await.unwrap();
+    assert_eq!(data.len(), 100 * CHUNK_SIZE);
+    assert_eq!(sent.load(Ordering::SeqCst), 100);
+}
+
+#[tokio::test]
+async fn test_upload_gets_single_reply() {
+    let router = Router::default().route(MessageKind::Echo, |ctx: Context, _| async move {
+        let data = ctx.take_body()?.read_all().await.ok()?;
+        let sum: u64 = data.iter().map(|&byte| u64::from(byte)).sum();
+        Some(server_message::Message::EchoMessage(EchoMessage {
+            content: format!("{} bytes, sum {}", data.len(), sum),
+        }))
+    });
+    let server = TestServer::with_router(router);
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    let body = payload(1024 * 1024);
+    let sum: u64 = body.iter().map(|&byte| u64::from(byte)).sum();
+    let response = client.upload(echo(""), &body[..]).await.unwrap();
+    assert_eq!(
+        response,
+        server_message::Message::EchoMessage(EchoMessage {
+            content: format!("{} bytes, sum {}", body.len(), sum),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_more_streams_than_pipelining_slots() {
+    let server = TestServer::with_config(
+        ServerConfig {
+            max_pipelined_requests: 2,
+            ..ServerConfig::default()
+        },
+        Router::default(),
+    );
+    let client = PipelinedClient::connect(server.addr()).await.unwrap();
+
+    // Streams waiting for a slot still have their bodies arriving, and must not stall the
+    // ones running
+    let streams: Vec<_> = (0..4)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let body = vec![i as u8; 256 * 1024];
+                let mut response = client.stream(echo(""), Cursor::new(body.clone())).await?;
+                let echoed = response.read_all().await?;
+                assert!(echoed == body, "Stream {} came back wrong", i);
+                std::io::Result::Ok(())
+            })
+        })
+        .collect();
+    for stream in streams {
+        tokio::time::timeout(Duration::from_secs(10), stream)
+            .await
+            .expect("Streams deadlocked")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use embedded_recruitment_task::client::Client;
+use embedded_recruitment_task::message::{
+    client_message, error, reading, server_message, Reading, ReadingSummary, TelemetryAck,
+    TelemetryBatch, TelemetryData, TelemetryQuery,
+};
+use embedded_recruitment_task::telemetry::{
+    TelemetryStore, MAX_CLOCK_SKEW_MS, MAX_DEVICES, MAX_READINGS_PER_SERIES, MAX_SERIES_PER_DEVICE,
+};
+use embedded_recruitment_task::test_util::{now_ms, reading, temperature, TestServer};
+use embedded_recruitment_task::wal::{SyncPolicy, WalOptions};
+use std::fs;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn query(device_id: &str) -> TelemetryQuery {
+    TelemetryQuery {
+        device_id: device_id.to_string(),
+        ..TelemetryQuery::default()
+    }
+}
+
+async fn request(client: &mut Client, message: client_message::Message) -> server_message::Message {
+    client.request(message).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_devices_report_and_query_readings() {
+    let server = TestServer::start();
+    let mut client = Client::connect(server.addr()).await.unwrap();
+    let t = now_ms() - 60_000;
+
+    let batch = TelemetryBatch {
+        device_id: "greenhouse-3".to_string(),
+        readings: vec![
+            temperature(t + 2000, 21.0),
+            reading("door_open", t + 1000, reading::Value::Flag(true), ""),
+            // Out of order, as after a reconnect
+            temperature(t, 20.5),
+            reading(
+                "status",
+                t + 1000,
+                reading::Value::Text("ok".to_string()),
+                "",
+            ),
+            // Dropped: no sensor, no value, and older than the server keeps
+            reading("", t, reading::Value::Integer(1), ""),
+            Reading {
+                value: None,
+                ..temperature(t, 0.0)
+            },
+            temperature(t - 2 * 24 * 60 * 60 * 1000, 15.0),
+        ],
+    };
+    let ack = request(&mut client, client_message::Message::TelemetryBatch(batch)).await;
+    assert_eq!(
+        ack,
+        server_message::Message::TelemetryAck(TelemetryAck { stored: 4 })
+    );
+
+    // Everything from the device, by time then sensor
+    let data = request(
+        &mut client,
+        client_message::Message::TelemetryQuery(query("greenhouse-3")),
+    )
+    .await;
+    assert_eq!(
+        data,
+        server_message::Message::TelemetryData(TelemetryData {
+            readings: vec![
+                temperature(t, 20.5),
+                reading("door_open", t + 1000, reading::Value::Flag(true), ""),
+                reading(
+                    "status",
+                    t + 1000,
+                    reading::Value::Text("ok".to_string()),
+                    ""
+                ),
+                temperature(t + 2000, 21.0),
+            ],
+            ..TelemetryData::default()
+        })
+    );
+
+    // One sensor in a time range
+    let range = TelemetryQuery {
+        sensor: "temperature".to_string(),
+        start_ms: t + 1,
+        end_ms: t + 5000,
+        ..query("greenhouse-3")
+    };
+    let data = request(&mut client, client_message::Message::TelemetryQuery(range)).await;
+    assert_eq!(
+        data,
+        server_message::Message::TelemetryData(TelemetryData {
+            readings: vec![temperature(t + 2000, 21.0)],
+            ..TelemetryData::default()
+        })
+    );
+
+    // Unknown devices have no readings; a query needs a device
+    let data = request(
+        &mut client,
+        client_message::Message::TelemetryQuery(query("greenhouse-4")),
+    )
+    .await;
+    assert_eq!(
+        data,
+        server_message::Message::TelemetryData(TelemetryData::default())
+    );
+    let error = request(
+        &mut client,
+        client_message::Message::TelemetryQuery(query("")),
+    )
+    .await;
+    assert!(matches!(
+        error,
+        server_message::Message::Error(error) if error.code() == error::Code::InvalidArgument
+    ));
+}
+
+#[test]
+fn test_queries_downsample_numeric_readings() {
+    let store = TelemetryStore::new(Duration::from_secs(3600));
+    let t = now_ms() - 600_000;
+    store
+        .insert(
+            "pump-1",
+            vec![
+                temperature(t + 5_000, 20.0),
+                temperature(t + 30_000, 22.0),
+                temperature(t + 59_999, 27.0),
+                temperature(t + 125_000, 19.0),
+                reading("running", t + 10_000, reading::Value::Flag(true), ""),
+                reading("running", t + 20_000, reading::Value::Flag(false), ""),
+                reading("running", t + 30_000, reading::Value::Flag(true), ""),
+                reading("rpm", t + 70_000, reading::Value::Integer(1200), "rpm"),
+                reading("rpm", t + 80_000, reading::Value::Integer(1300), "1/min"),
+                reading(
+                    "state",
+                    t + 1_000,
+                    reading::Value::Text("idle".to_string()),
+                    "",
+                ),
+            ],
+        )
+        .unwrap();
+
+    let data = store
+        .query(&TelemetryQuery {
+            start_ms: t,
+            interval_ms: 60_000,
+            ..query("pump-1")
+        })
+        .unwrap();
+    let summary = |sensor: &str, start_ms, count, min, max, mean, unit: &str| ReadingSummary {
+        sensor: sensor.to_string(),
+        start_ms,
+        count,
+        min,
+        max,
+        mean,
+        unit: unit.to_string(),
+    };
+    // Intervals are aligned to the start of the query, empty ones are skipped, flags count
+    // as 0 and 1 and text is left out.
+    assert_eq!(
+        data,
+        TelemetryData {
+            summaries: vec![
+                summary("running", t, 3, 0.0, 1.0, 2.0 / 3.0, ""),
+                summary("temperature", t, 3, 20.0, 27.0, 23.0, "degC"),
+                summary("rpm", t + 60_000, 2, 1200.0, 1300.0, 1250.0, "1/min"),
+                summary("temperature", t + 120_000, 1, 19.0, 19.0, 19.0, "degC"),
+            ],
+            ..TelemetryData::default()
+        }
+    );
+}
+
+#[test]
+fn test_limited_queries_continue_where_they_stopped() {
+    let store = TelemetryStore::new(Duration::from_secs(3600));
+    let t = now_ms() - 600_000;
+    let mut readings = Vec::new();
+    for i in 0..5 {
+        readings.push(temperature(t + i * 1000, i as f64));
+        readings.push(reading(
+            "humidity",
+            t + i * 1000,
+            reading::Value::Integer(40),
+            "%",
+        ));
+    }
+    store.insert("shed", readings).unwrap();
+
+    // A limit of 3 would split the readings at t + 1000, so both come back
+    let first = store
+        .query(&TelemetryQuery {
+            limit: 3,
+            ..query("shed")
+        })
+        .unwrap();
+    assert!(first.truncated);
+    assert_eq!(first.readings.len(), 4);
+    let last = first.readings.last().unwrap().timestamp_ms;
+    assert_eq!(last, t + 1000);
+
+    let rest = store
+        .query(&TelemetryQuery {
+            start_ms: last + 1,
+            limit: 100,
+            ..query("shed")
+        })
+        .unwrap();
+    assert!(!rest.truncated);
+    assert_eq!(rest.readings.len(), 6);
+    assert_eq!(rest.readings[0].timestamp_ms, t + 2000);
+
+    let error = store
+        .query(&TelemetryQuery {
+            start_ms: t,
+            end_ms: t,
+            ..query("shed")
+        })
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_readings_expire_after_retention() {
+    let store = TelemetryStore::new(Duration::from_secs(1));
+    let t = now_ms();
+    let stored = store
+        .insert(
+            "valve",
+            vec![temperature(t - 5000, 1.0), temperature(t, 2.0)],
+        )
+        .unwrap();
+    assert_eq!(stored, 1);
+    let data = store.query(&query("valve")).unwrap();
+    assert_eq!(data.readings, vec![temperature(t, 2.0)]);
+
+    // Once the retention period has passed, queries no longer see it
+    std::thread::sleep(Duration::from_millis(1200));
+    let data = store.query(&query("valve")).unwrap();
+    assert!(data.readings.is_empty());
+}
+
+#[test]
+fn test_readings_from_the_future_and_beyond_the_cap_are_dropped() {
+    let store = TelemetryStore::new(Duration::from_secs(3600));
+    let t = now_ms();
+    let ahead = MAX_CLOCK_SKEW_MS + 60_000;
+    let stored = store
+        .insert(
+            "valve",
+            vec![temperature(t + ahead, 1.0), temperature(t + 1000, 2.0)],
+        )
+        .unwrap();
+    assert_eq!(stored, 1);
+    let data = store.query(&query("valve")).unwrap();
+    assert_eq!(data.readings, vec![temperature(t + 1000, 2.0)]);
+
+    // A sensor keeps only its latest readings
+    let start = t - 1_000_000;
+    let readings = (0..MAX_READINGS_PER_SERIES as u64 + 10)
+        .map(|i| temperature(start + i, i as f64))
+        .collect();
+    store.insert("pump", readings).unwrap();
+    let data = store
+        .query(&TelemetryQuery {
+            limit: 1,
+            ..query("pump")
+        })
+        .unwrap();
+    assert_eq!(data.readings, vec![temperature(start + 10, 10.0)]);
+}
+
+#[tokio::test]
+async fn test_sensors_and_devices_beyond_the_caps_are_dropped() {
+    let dir = TempDir::new().unwrap();
+    let options = WalOptions {
+        sync: SyncPolicy::Never,
+        sync_interval: Duration::from_secs(1),
+        snapshot_interval: Duration::from_secs(60),
+    };
+    let store = TelemetryStore::open(Duration::from_secs(3600), dir.path(), options).unwrap();
+    let t = now_ms();
+
+    // A batch with nothing left to keep isn't logged
+    let stored = store.insert("valve", vec![temperature(0, 1.0)]).unwrap();
+    assert_eq!(stored, 0);
+    assert_eq!(fs::metadata(dir.path().join("log")).unwrap().len(), 0);
+
+    // Sensors past the cap are dropped, while those already known go on being kept
+    let sensors = (0..MAX_SERIES_PER_DEVICE + 5)
+        .map(|i| {
+            reading(
+                &format!("probe-{}", i),
+                t,
+                reading::Value::Number(1.0),
+                "degC",
+            )
+        })
+        .collect();
+    assert_eq!(
+        store.insert("valve", sensors).unwrap(),
+        MAX_SERIES_PER_DEVICE
+    );
+    let known = reading("probe-0", t + 1, reading::Value::Number(2.0), "degC");
+    let new = reading("probe-new", t + 1, reading::Value::Number(2.0), "degC");
+    assert_eq!(store.insert("valve", vec![known, new]).unwrap(), 1);
+
+    // The device that reported longest ago is forgotten to make room for a new one
+    for i in 1..MAX_DEVICES {
+        let device_id = format!("sensor-{}", i);
+        store
+            .insert(&device_id, vec![temperature(t + 1 + i as u64, 1.0)])
+            .unwrap();
+    }
+    assert!(!store.query(&query("valve")).unwrap().readings.is_empty());
+    store.insert("pump", vec![temperature(t, 1.0)]).unwrap();
+    assert!(store.query(&query("valve")).unwrap().readings.is_empty());
+    assert!(!store.query(&query("pump")).unwrap().readings.is_empty());
+    assert!(!store.query(&query("sensor-1")).unwrap().readings.is_empty());
+}